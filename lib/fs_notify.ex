@@ -35,8 +35,21 @@ defmodule FSNotify do
   @type start_option ::
           {:recursive, boolean()}
           | {:name, GenServer.name()}
-          | {:backend, :recommended | :poll | :inotify | :fsevent | :kqueue | :windows | :null}
+          | {:backend,
+             :recommended
+             | :poll
+             | :inotify
+             | :fanotify
+             | :fsevent
+             | :kqueue
+             | :windows
+             | :watchman
+             | :null
+             | [atom()]}
           | {:debounce_ms, pos_integer()}
+          | {:debounce_mode, :mini | :full}
+          | {:debounce_edge, :trailing | :leading | :both}
+          | {:throttle_ms, pos_integer()}
 
   @doc """
   Starts a file system watcher process.
@@ -48,9 +61,24 @@ defmodule FSNotify do
     - `:name` - A name to register the process under
     - `:backend` - Watcher backend to use (default: `:recommended`)
       Available backends: `:recommended`, `:poll`, `:inotify` (Linux), 
-      `:fsevent` (macOS), `:kqueue` (BSD/macOS), `:windows`, `:null`
+      `:fanotify` (Linux, with `CAP_SYS_ADMIN`), `:fsevent` (macOS), `:kqueue` (BSD,
+      or macOS when the NIF is built with the `macos_kqueue` Cargo feature, which
+      replaces `:fsevent`), `:windows`, `:watchman` (Unix, when the NIF is built with
+      the `watchman` Cargo feature and a Watchman daemon is running), `:null`.
+      A list, e.g. `[:inotify, :poll]`, is tried in order, as for
+      `FSNotify.Native.start_watcher_with_backend/3`
     - `:debounce_ms` - Enable debouncing with specified timeout in milliseconds
       When enabled, multiple rapid events for the same file are filtered to reduce noise
+    - `:debounce_mode` - `:mini` (default) reports debounced changes as `:modified`;
+      `:full` keeps the original event kinds and merges renames
+    - `:debounce_edge` - `:trailing` (default) reports a burst of changes once it
+      is over; `:leading` reports its first change at once and drops the rest;
+      `:both` does both, the latter only if more changes followed the first
+    - `:throttle_ms` - Report each path at most once per interval instead of
+      debouncing: its first change at once, then the latest one at the end of
+      each interval it kept changing in, so a file written continuously is
+      still reported regularly. Can't be combined with `:debounce_ms`;
+      starting with both fails with `{:error, {:invalid_config, _}}`
 
   ## Examples
 
@@ -109,7 +137,8 @@ defmodule FSNotify do
   ## Examples
       FSNotify.available_backends()
       # => [:recommended, :poll, :inotify] # on Linux
-      # => [:recommended, :poll, :fsevent, :kqueue] # on macOS
+      # => [:recommended, :poll, :fsevent] # on macOS
+      # => [:recommended, :poll, :kqueue] # on FreeBSD, OpenBSD and NetBSD
   """
   @spec available_backends() :: [atom()]
   def available_backends do
@@ -137,10 +166,16 @@ defmodule FSNotify do
       case Map.keys(state.watchers) do
         [path | _] ->
           case Map.get(state.watchers, path) do
-            watcher_id when is_integer(watcher_id) ->
+            watcher_id when is_reference(watcher_id) ->
               case FSNotify.Native.get_watcher_info(watcher_id) do
-                {:ok, native_path, recursive, backend} ->
-                  {:ok, %{path: native_path, recursive: recursive, backend: backend}}
+                {:ok, native_path, recursive, backend, _label, follow_symlinks} ->
+                  {:ok,
+                   %{
+                     path: native_path,
+                     recursive: recursive,
+                     backend: backend,
+                     follow_symlinks: follow_symlinks
+                   }}
 
                 _ ->
                   {:ok, %{paths: state.paths, recursive: state.recursive, backend: state.backend}}
@@ -3,26 +3,253 @@ defmodule FSNotify.Event do
   Defines the structure and types for file system events.
   """
 
-  @type event_kind :: :created | :modified | :removed | :renamed | :meta | :unknown
+  @type event_kind ::
+          :created
+          | :modified
+          | :attrib_changed
+          | :permissions
+          | :ownership
+          | :timestamps
+          | :xattr_changed
+          | :removed
+          | :renamed
+          | :written
+          | :meta
+          | :unknown
+          | :overflow
+          | :root_removed
+          | :root_created
+          | :discovered
+          | :settled
+          | :appended
+          | :rotated
+          | :truncated
+          | :rewritten
+          | :ready
+          | :backend_fallback
+          | :bulk_change
+          | detailed_event_kind()
+
+  @typedoc "Kinds reported instead of `:modified`/`:unknown` with `event_detail: :detailed`"
+  @type detailed_event_kind ::
+          :modified_data
+          | :modified_metadata
+          | :access_open
+          | :access_read
+          | :access_close_write
+          | :access_close
+          | :access
   @type file_type :: :file | :directory | :unknown
 
+  @typedoc "FSEvents stream flags reported with `fsevent_flags: true`"
+  @type fsevent_flag ::
+          :must_scan_sub_dirs
+          | :user_dropped
+          | :kernel_dropped
+          | :event_ids_wrapped
+          | :root_changed
+          | :mount
+          | :unmount
+          | :own_event
+
   @type t :: %__MODULE__{
           kind: event_kind(),
-          path: String.t(),
-          file_type: file_type()
+          path: String.t() | nil,
+          file_type: file_type(),
+          from: String.t() | nil,
+          monotonic_time: integer() | nil,
+          system_time: integer() | nil,
+          seq: pos_integer() | nil,
+          size: non_neg_integer() | nil,
+          mtime: integer() | nil,
+          file_id: non_neg_integer() | nil,
+          hash: non_neg_integer() | nil,
+          data: binary() | nil,
+          fsevent_flags: [fsevent_flag()] | nil,
+          os_pid: non_neg_integer() | nil,
+          permission_id: non_neg_integer() | nil,
+          count: pos_integer() | nil,
+          backend: atom() | nil,
+          label: term()
         }
 
-  defstruct [:kind, :path, :file_type]
+  defstruct [
+    :kind,
+    :path,
+    :file_type,
+    :from,
+    :monotonic_time,
+    :system_time,
+    :seq,
+    :size,
+    :mtime,
+    :file_id,
+    :hash,
+    :data,
+    :fsevent_flags,
+    :os_pid,
+    :permission_id,
+    :count,
+    :backend,
+    :label
+  ]
+
+  # `{tag, value}` elements some options add after `seq`, kept in the field `tag`
+  @tags [:fsevent_flags, :os_pid, :permission_id, :count, :label]
+  defguardp tagged_at(tuple, index)
+            when tuple_size(elem(tuple, index)) == 2 and elem(elem(tuple, index), 0) in @tags
 
   @doc """
   Create a new event struct from the tuple format returned by the NIF.
 
+  Renames whose source and destination were both observed arrive as
+  `{:renamed, from_path, to_path, monotonic_time, system_time, seq}`; the
+  resulting event has `path` set to the destination and `from` set to the
+  source.
+
+  `monotonic_time` and `system_time` are in microseconds and record when the
+  backend reported the change. `system_time` is relative to the Unix epoch;
+  `monotonic_time` has an arbitrary origin and is only meaningful relative to
+  other events.
+
+  `{:overflow, path, monotonic_time, system_time, seq}` means the backend lost
+  events under `path` (e.g. the inotify queue overflowed) and the consumer
+  must rescan it.
+
+  `{:root_removed, path, monotonic_time, system_time, seq}` means the watched
+  root `path` was removed or moved away; nothing more is reported for it
+  unless the watcher was started with `rearm_root: true`. With
+  `allow_missing: true`, `{:root_created, path, monotonic_time, system_time,
+  seq}` reports a root that didn't exist yet being created and watched.
+
+  `seq` numbers a watcher's events consecutively from 1, so a
+  gap means events were lost.
+
+  `{:ready, monotonic_time, system_time, seq}` follows the `:discovered`
+  events of a watcher's initial scan; it has no path.
+
+  `{:backend_fallback, backend, monotonic_time, system_time, seq}` means the
+  watcher uses `backend`, kept in `backend`, instead of the one asked for,
+  e.g. `:poll` because its root is on a network filesystem; it has no path.
+
+  With `summarize_threshold: n`, `{:bulk_change, dir, count, monotonic_time,
+  system_time, seq}` stands for `count` events about entries of `dir`,
+  reported together when there were more than `n` of them; `dir` is kept in
+  `path` and `count` in `count`.
+
+  With `tail: true`, `{:appended, path, data, monotonic_time, system_time,
+  seq}` carries bytes appended to a followed file, kept in `data`. With
+  `classify_writes: true`, `:appended` is also the kind of a write that grew a
+  file, which has a `file_type` rather than data.
+
+  Watchers started with `include_stat: true` or `hash_contents: true` add
+  `%{size: size, mtime: mtime, file_id: file_id, hash: hash}` after `seq` to
+  path and rename events, kept in `size`, `mtime`, `file_id` and `hash`. Watchers started with
+  `fsevent_flags: true` follow that with `{:fsevent_flags, flags}`, kept in
+  `fsevent_flags`, then watchers started with `include_os_pid: true` add
+  `{:os_pid, pid}`, kept in `os_pid`, fanotify opens waiting for
+  `FSNotify.Native.respond/2` add `{:permission_id, id}`, kept in
+  `permission_id`, watchers started with `coalesce_paths: true` add
+  `{:count, n}`, kept in `count`, and watchers started with
+  `label_events: true` append `{:label, label}` to each tuple, kept in
+  `label`.
+
   ## Parameters
-  - {kind, path, file_type}: Tuple from the Rust NIF
+  - {kind, path, file_type, monotonic_time, system_time, seq}: Tuple from the Rust NIF
 
   ## Returns
   %FSNotify.Event{}
   """
+  def from_tuple(tuple) when tagged_at(tuple, tuple_size(tuple) - 1) do
+    take_tagged(tuple, tuple_size(tuple) - 1)
+  end
+
+  def from_tuple({kind, path, file_type, monotonic_time, system_time, seq, %{size: _} = stat}) do
+    %{
+      from_tuple({kind, path, file_type, monotonic_time, system_time, seq})
+      | size: stat.size,
+        mtime: stat.mtime,
+        file_id: stat.file_id,
+        hash: stat.hash
+    }
+  end
+
+  def from_tuple({:appended, path, data, monotonic_time, system_time, seq})
+      when is_binary(data) do
+    %__MODULE__{
+      kind: :appended,
+      path: path,
+      file_type: :file,
+      data: data,
+      monotonic_time: monotonic_time,
+      system_time: system_time,
+      seq: seq
+    }
+  end
+
+  def from_tuple({:bulk_change, dir, count, monotonic_time, system_time, seq}) do
+    %__MODULE__{
+      kind: :bulk_change,
+      path: dir,
+      file_type: :directory,
+      count: count,
+      monotonic_time: monotonic_time,
+      system_time: system_time,
+      seq: seq
+    }
+  end
+
+  def from_tuple({kind, path, file_type, monotonic_time, system_time, seq}) do
+    %{
+      from_tuple({kind, path, file_type})
+      | monotonic_time: monotonic_time,
+        system_time: system_time,
+        seq: seq
+    }
+  end
+
+  def from_tuple({kind, path, monotonic_time, system_time, seq})
+      when kind in [:overflow, :root_removed, :root_created] do
+    %__MODULE__{
+      kind: kind,
+      path: path,
+      file_type: :unknown,
+      monotonic_time: monotonic_time,
+      system_time: system_time,
+      seq: seq
+    }
+  end
+
+  def from_tuple({:ready, monotonic_time, system_time, seq}) do
+    %__MODULE__{
+      kind: :ready,
+      file_type: :unknown,
+      monotonic_time: monotonic_time,
+      system_time: system_time,
+      seq: seq
+    }
+  end
+
+  def from_tuple({:backend_fallback, backend, monotonic_time, system_time, seq}) do
+    %__MODULE__{
+      kind: :backend_fallback,
+      file_type: :unknown,
+      backend: backend,
+      monotonic_time: monotonic_time,
+      system_time: system_time,
+      seq: seq
+    }
+  end
+
+  def from_tuple({:renamed, from, to}) when is_binary(to) do
+    %__MODULE__{
+      kind: :renamed,
+      path: to,
+      file_type: :unknown,
+      from: from
+    }
+  end
+
   def from_tuple({kind, path, file_type}) do
     %__MODULE__{
       kind: kind,
@@ -31,6 +258,56 @@ defmodule FSNotify.Event do
     }
   end
 
+  @doc """
+  Create a new event struct from the map format returned by watchers started
+  with `event_format: :map`, `%{kind: kind, path: path, type: file_type, ts:
+  system_time, seq: seq, ...}`.
+
+  The map has every field the tuple formats can carry, `nil` where it doesn't
+  apply: `from`, `monotonic_time`, `stat` (`%{size: size, mtime: mtime,
+  file_id: file_id, hash: hash}`), `data`, `backend`, `count`,
+  `fsevent_flags`, `os_pid`, `permission_id` and `label`.
+
+  ## Returns
+  %FSNotify.Event{}
+  """
+  def from_map(%{kind: kind, path: path, type: file_type, ts: system_time, seq: seq} = map) do
+    stat = map.stat || %{size: nil, mtime: nil, file_id: nil, hash: nil}
+
+    %__MODULE__{
+      kind: kind,
+      path: path,
+      file_type: file_type,
+      from: map.from,
+      monotonic_time: map.monotonic_time,
+      system_time: system_time,
+      seq: seq,
+      size: stat.size,
+      mtime: stat.mtime,
+      file_id: stat.file_id,
+      hash: stat.hash,
+      data: map.data,
+      fsevent_flags: map.fsevent_flags,
+      os_pid: map.os_pid,
+      permission_id: map.permission_id,
+      count: map.count,
+      backend: map.backend,
+      label: map.label
+    }
+  end
+
+  defp take_tagged(tuple, index) do
+    {tag, value} = elem(tuple, index)
+    Map.replace!(from_tuple(Tuple.delete_at(tuple, index)), tag, value)
+  end
+
+  @doc """
+  The wall-clock time the backend reported the event, or `nil` if unknown.
+  """
+  def timestamp(%__MODULE__{system_time: nil}), do: nil
+  def timestamp(%__MODULE__{system_time: system_time}),
+    do: DateTime.from_unix!(system_time, :microsecond)
+
   @doc """
   Check if an event indicates a file was created.
   """
@@ -40,7 +317,9 @@ defmodule FSNotify.Event do
   @doc """
   Check if an event indicates a file was modified.
   """
-  def modified?(%__MODULE__{kind: :modified}), do: true
+  def modified?(%__MODULE__{kind: kind})
+      when kind in [:modified, :modified_data, :modified_metadata],
+      do: true
   def modified?(_), do: false
 
   @doc """
@@ -55,6 +334,87 @@ defmodule FSNotify.Event do
   def renamed?(%__MODULE__{kind: :renamed}), do: true
   def renamed?(_), do: false
 
+  @doc """
+  Check if an event indicates events were lost and the path must be rescanned.
+  """
+  def overflow?(%__MODULE__{kind: :overflow}), do: true
+  def overflow?(_), do: false
+
+  @doc """
+  Check if an event reports that a watched root was removed or moved away.
+  """
+  def root_removed?(%__MODULE__{kind: :root_removed}), do: true
+  def root_removed?(_), do: false
+
+  @doc """
+  Check if an event reports a change to permissions, ownership or other metadata only.
+  """
+  def attrib_changed?(%__MODULE__{kind: kind})
+      when kind in [
+             :attrib_changed,
+             :modified_metadata,
+             :permissions,
+             :ownership,
+             :timestamps,
+             :xattr_changed
+           ],
+      do: true
+  def attrib_changed?(_), do: false
+
+  @doc """
+  Check if an event reports a change to extended attributes, e.g. a quarantine flag.
+  """
+  def xattr_changed?(%__MODULE__{kind: :xattr_changed}), do: true
+  def xattr_changed?(_), do: false
+
+  @doc """
+  Check if an event carries data appended to a followed file (`tail`).
+  """
+  def appended?(%__MODULE__{kind: :appended}), do: true
+  def appended?(_), do: false
+
+  @doc """
+  Check if an event reports a write that shrank a file (`classify_writes`).
+  """
+  def truncated?(%__MODULE__{kind: :truncated}), do: true
+  def truncated?(_), do: false
+
+  @doc """
+  Check if an event reports a followed file being replaced or truncated (`tail`).
+  """
+  def rotated?(%__MODULE__{kind: :rotated}), do: true
+  def rotated?(_), do: false
+
+  @doc """
+  Check if an event reports a file closed after being written to (Linux only).
+  """
+  def written?(%__MODULE__{kind: :written}), do: true
+  def written?(_), do: false
+
+  @doc """
+  Check if an event reports that a root which didn't exist yet was created.
+  """
+  def root_created?(%__MODULE__{kind: :root_created}), do: true
+  def root_created?(_), do: false
+
+  @doc """
+  Check if an event reports a file that stopped changing (`settle_ms`).
+  """
+  def settled?(%__MODULE__{kind: :settled}), do: true
+  def settled?(_), do: false
+
+  @doc """
+  Check if an event reports an entry found by the initial scan.
+  """
+  def discovered?(%__MODULE__{kind: :discovered}), do: true
+  def discovered?(_), do: false
+
+  @doc """
+  Check if an event marks the end of the initial scan.
+  """
+  def ready?(%__MODULE__{kind: :ready}), do: true
+  def ready?(_), do: false
+
   @doc """
   Check if the event is for a file (not a directory).
   """
@@ -0,0 +1,39 @@
+defmodule FSNotify.LogBridge do
+  @moduledoc """
+  GenServer that passes native warnings on to `Logger`, e.g. backend errors,
+  fallbacks to another backend and events dropped from a full buffer.
+
+  Start it in a supervision tree, once per node:
+
+      children = [FSNotify.LogBridge]
+
+  Without it, the warnings are kept for `FSNotify.Native.drain_logs/0`.
+  """
+
+  use GenServer
+
+  alias FSNotify.Native
+
+  require Logger
+
+  def start_link(opts \\ []) do
+    GenServer.start_link(__MODULE__, nil, Keyword.put_new(opts, :name, __MODULE__))
+  end
+
+  @impl true
+  def init(nil) do
+    :ok = Native.set_log_pid(self())
+    {:ok, nil}
+  end
+
+  @impl true
+  def handle_info({:fs_notify_log, level, message, _system_time}, state) do
+    Logger.log(level, "fs_notify: " <> message)
+    {:noreply, state}
+  end
+
+  @impl true
+  def terminate(_reason, _state) do
+    Native.set_log_pid(nil)
+  end
+end
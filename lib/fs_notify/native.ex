@@ -1,9 +1,68 @@
 defmodule FSNotify.Native do
   @moduledoc """
   NIF module for file system notification using Rust notify library.
+
+  Watchers are returned as opaque references. A watcher is stopped explicitly
+  with `stop_watcher/1`, automatically when the process that started it exits,
+  or once its reference is garbage collected.
+
+  Events pushed to a process arrive as `{:fs_notify, watcher, event}`, where
+  `watcher` is a new reference to the watcher, as `list_watchers/0` returns:
+  it works with every function here, but doesn't compare equal to the
+  reference returned when the watcher was started, which the watcher would
+  otherwise never let go of.
+
+  Starting a watcher with `share: true` on a path that is already watched with
+  the same options, `share: true` among them (and without a push subscriber),
+  shares the running watcher instead of creating a second OS-level watch: the
+  caller gets a new reference to it. Everything about a shared watcher is
+  shared, so events taken through either reference are gone for both, and
+  pausing, updating or clearing it through one affects the other. A shared
+  watcher is only torn down once every process that started it has stopped it
+  or exited, or every reference returned by starting it is garbage collected.
+  Watchers are never shared otherwise.
+
+  On Windows, paths longer than `MAX_PATH` can be watched as is: they are
+  registered in extended-length (`\\\\?\\`) form where needed, and event paths
+  are always reported without that prefix; network paths
+  (`\\\\server\\share\\...`) keep the UNC form.
+
+  Paths on network or FUSE filesystems (NFS, SMB, sshfs, mapped network
+  drives, ...) are watched with the `:poll` backend unless another one is
+  asked for explicitly, since native backends miss the changes other machines
+  make there. The watcher's first event is then
+  `{:backend_fallback, :poll, monotonic_time, system_time, seq}`.
+
+  Wherever a backend is asked for, a list of them, e.g. `[:inotify, :poll]`,
+  can be given instead. Those this platform can't use are left out, the first
+  one that starts is used, and whenever it fails later on (e.g. the Watchman
+  daemon goes away) the watcher moves on to the next one that starts. Either
+  way it reports `{:backend_fallback, backend, monotonic_time, system_time,
+  seq}`, and `get_watcher_info/1` gives the backend in use.
+
+  Calls that may take a while run on dirty schedulers rather than holding up
+  normal ones. Starting, updating and stopping watchers, adding or removing
+  paths (registering a large tree with the backend walks all of it), taking,
+  peeking at, counting or clearing events (translating them stats their
+  paths) and every other call on a watcher, which may have to wait for one of
+  those, run on dirty IO schedulers; `diff_snapshots/2` runs on a dirty CPU
+  scheduler.
+
+  Failures are returned as `{:error, {reason, message}}` where `reason` is one of
+  `:enoent`, `:eacces`, `:watch_limit_reached`, `:watch_not_found`, `:invalid_config`,
+  `:invalid_backend`, `:invalid_pattern`, `:watcher_not_found`, `:timeout`,
+  `:io_error`, `:not_owner` or `:internal`, and `message` is a human-readable description.
+  `:internal` means the NIF panicked; the call fails without taking the VM or
+  any other watcher down, and the panic is also logged (see `set_log_pid/1`).
+  `:watch_limit_reached` means inotify's `fs.inotify.max_user_watches` or
+  `fs.inotify.max_user_instances` is used up; the message gives its current
+  value, and `inotify_limits/0` tells how much is left.
   """
 
-  use Rustler, otp_app: :fs_notify, crate: "fs_notify"
+  use Rustler,
+    otp_app: :fs_notify,
+    crate: "fs_notify",
+    features: if(Mix.env() == :test, do: ["debug_nifs"], else: [])
 
   @doc """
   Start watching a directory or file using the recommended watcher backend.
@@ -13,75 +72,1211 @@ defmodule FSNotify.Native do
   - recursive: Boolean indicating whether to watch recursively
 
   ## Returns
-  {:ok, watcher_id} or {:error, reason}
+  {:ok, watcher} or {:error, reason}
   """
   def start_watcher(_path, _recursive), do: :erlang.nif_error(:nif_not_loaded)
 
   @doc """
   Start watching a directory or file with a specific backend.
 
+  `:fanotify` (Linux, for auditing) marks the whole mount each watched path
+  is on and reports every file opened (`:access_open` with
+  `event_detail: :detailed`), written (`:modified`) and closed after writing
+  within the watched path, along with the ID of the process responsible
+  (see `:include_os_pid`). It only sees files, not entries being created,
+  removed or renamed, skips this VM's own accesses, and needs
+  `CAP_SYS_ADMIN`: without it the backend is left out of
+  `list_available_backends/0` and asking for it returns
+  `{:error, {:invalid_backend, message}}`.
+
+  `:watchman` (Unix, when the NIF is built with the `watchman` Cargo feature)
+  subscribes to a Watchman daemon, found through `$WATCHMAN_SOCK` or
+  `watchman get-sockname` (which starts it if needed), for very large trees:
+  Watchman keeps its own view of them, so they are crawled once per daemon
+  rather than per watcher, and recrawls them by itself when the kernel drops
+  events, which is reported as `{:overflow, path}`. It reports files created,
+  modified and removed, but not renames, which show up as a removal and a
+  creation.
+
   ## Parameters
   - path: String path to watch
   - recursive: Boolean indicating whether to watch recursively
-  - backend: Atom specifying the backend (:recommended, :poll, :inotify, :fsevent, :kqueue, :windows, :null)
+  - backend: Atom specifying the backend (:recommended, :poll, :inotify, :fanotify, :fsevent,
+    :kqueue, :windows, :watchman, :null), or a list of them to fall back through
 
   ## Returns
-  {:ok, watcher_id} or {:error, reason}
+  {:ok, watcher} or {:error, reason}
   """
   def start_watcher_with_backend(_path, _recursive, _backend), do: :erlang.nif_error(:nif_not_loaded)
 
+  @doc """
+  Start watching a directory or file with a specific backend and backend
+  configuration.
+
+  ## Parameters
+  - path: String path to watch
+  - recursive: Boolean indicating whether to watch recursively
+  - backend: Atom specifying the backend (:recommended, :poll, :inotify, :fanotify, :fsevent,
+    :kqueue, :windows, :watchman, :null)
+  - config: Map of backend options; unset keys keep the backend defaults
+    - `:poll_interval_ms` - How often the `:poll` backend rescans the tree
+    - `:compare_contents` - Have the `:poll` backend compare file contents,
+      catching changes mtime misses (FAT, some network mounts) at the cost of
+      reading every file on each scan
+    - `:follow_symlinks` - Whether recursive watches descend into symlinked
+      directories (default true)
+    - `:since_event_id` - Have the `:fsevent` backend (and `:recommended` on
+      macOS) first report the changes after this FSEvents event ID, including
+      those made while the app wasn't running, e.g. the `:last_event_id` of
+      `get_stats/1` saved before it last stopped. Ignored when debouncing
+      and by other backends.
+    - `:latency_ms` - How long the `:fsevent` backend (and `:recommended`
+      on macOS) gathers changes before reporting them together, trading
+      responsiveness for fewer wakeups (default 0). The first change after
+      a quiet spell is still reported at once. Ignored by other backends.
+    - `:read_buffer_size` - Bytes of changes the `:windows` backend (and
+      `:recommended` on Windows) can hold for each watched directory
+      before it overflows, a positive multiple of 4 (default 16384). Raise
+      it for very busy directories; a buffer that overflows is reported as
+      `{:overflow, path}` for the watched path. Network shares allow at
+      most 65536. Ignored by other backends.
+    - `:notify_filters` - Changes the `:windows` backend (and `:recommended`
+      on Windows) subscribes to, cutting the rest out in the kernel: any of
+      `:file_name` (files created, removed or renamed), `:dir_name` (the
+      same for directories), `:attributes`, `:size`, `:last_write`,
+      `:last_access`, `:creation` and `:security`. Defaults to all but
+      `:last_access`. Ignored by other backends.
+    - `:permission_events` - Have the `:fanotify` backend hold every open
+      of a file within the watched path until it is answered with
+      `respond/2`, reporting it with `{:permission_id, id}` after the other
+      added elements (default false). Opens are then reported in place of
+      the usual `:access_open` events. Ignored by other backends.
+    - `:permission_timeout_ms` - How long the `:fanotify` backend holds an
+      open left unanswered before allowing it, so one whose event was
+      filtered out or never read doesn't hang the process opening the file
+      (default 5000).
+
+  ## Returns
+  {:ok, watcher} or {:error, reason}
+  """
+  def start_watcher_with_backend_config(_path, _recursive, _backend, _config),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Start watching a directory or file with any combination of options.
+
+  ## Parameters
+  - path: String path to watch
+  - recursive: Boolean indicating whether to watch recursively
+  - options: Map of options; unset keys keep their defaults
+    - `:recursive` - Overrides the `recursive` argument. `{:depth, n}` watches
+      only the first `n` levels below `path` (`{:depth, 1}` is the same as
+      `false`), registering each directory separately so deeper subtrees
+      never use up OS watches
+    - `:exclude_dirs` - Names of directories, e.g.
+      `["node_modules", ".git", "_build", "deps"]`, whose subtrees are never
+      registered with the backend. Like `{:depth, n}`, this makes the watcher
+      register each directory separately.
+    - `:backend` - Backend atom, or list of them, as for
+      `start_watcher_with_backend/3`
+    - `:debounce_ms` - Debounce timeout in milliseconds
+    - `:debounce_mode` - `:mini` (default) or `:full`, as for
+      `start_watcher_with_full_debounce/4`
+    - `:debounce_edge` - Which changes of a burst to report: `:trailing`
+      (default) once the burst has been quiet for `:debounce_ms`, `:leading`
+      at once for the first change to each path, holding back those following
+      it until the path has been quiet for `:debounce_ms`, or `:both`, which
+      also reports the held back changes once the burst is over. Live reloads
+      want the first change fast; batch jobs want the last. Leading changes
+      take the same form as trailing ones: `:modified` with `:mini`, the
+      backend's own kinds with `:full`
+    - `:throttle_ms` - Report each path at most once per `n` milliseconds
+      instead of debouncing it (so can't be combined with `:debounce_ms`):
+      its first change at once, then, at the end of every interval it changed
+      in again, the latest of those changes. Unlike a debouncer, which waits
+      for a path to go quiet, this keeps reporting a file that is written
+      continuously. The two paths of a rename count as a path of their own.
+      `nil` (default) turns throttling off
+    - `:include` / `:exclude` - Glob patterns, as for `start_watcher_with_filters/4`
+    - `:include_regex` / `:exclude_regex` - Regular expressions (Rust `regex`
+      syntax), matched against the same relative paths as the globs. A path is
+      included if it matches any include glob or regex.
+    - `:extensions` - Only report files with one of these suffixes, e.g.
+      `["ex", "exs", "heex"]`; directories without a matching suffix are
+      dropped too
+    - `:gitignore` - Drop events for paths ignored by git
+    - `:ignore_hidden` - Drop events for dotfiles and anything inside
+      dot-directories (e.g. `.git/index.lock`) below the watched root
+    - `:ignore_presets` - Built-in exclude patterns: `:editors` drops vim swap
+      and backup files, emacs autosave and lock files and JetBrains
+      `___jb_tmp___`/`___jb_old___` files; `:os_junk` drops `.DS_Store`, `._*`,
+      `Thumbs.db`, `ehthumbs.db` and `desktop.ini`
+    - `:event_detail` - `:basic` (default) reports `:created`, `:modified`,
+      `:attrib_changed` (permissions, ownership and other metadata),
+      `:xattr_changed` (extended attributes, as reported by FSEvents),
+      `:removed`, `:renamed`, `:written` and `:meta`; `:detailed` splits
+      `:modified` into `:modified_data` and `:modified_metadata` (instead of
+      `:attrib_changed`) and reports access events as `:access_open`,
+      `:access_read`, `:access_close_write` (`:written` under `:basic`),
+      `:access_close` or `:access`. `:written` means a writer closed the file,
+      so its contents are complete; only inotify (Linux) reports it.
+    - `:kinds` - Only report events of these kinds, e.g. `[:created, :removed]`.
+      `:overflow`, `:root_removed`, `:root_created`, `:ready`,
+      `:backend_fallback` and `:bulk_change` events are always reported.
+    - `:ignore_attrib` - Drop `:attrib_changed`, `:xattr_changed`,
+      `:modified_metadata` and the kinds `:classify_attrib` reports, so e.g. a
+      `chmod -R` run reports nothing (default false)
+    - `:classify_attrib` - Report metadata changes as `:permissions`,
+      `:ownership`, `:xattr_changed` or `:timestamps` where the change can be
+      told apart (default false). Where the backend doesn't say (inotify),
+      every watched path's attributes, and on Linux its extended attributes,
+      are remembered and compared, so this costs a `stat` per event and
+      memory per path; changes to a path before it was first seen stay
+      `:attrib_changed`.
+    - `:include_stat` - Add `%{size: bytes, mtime: microseconds, file_id: id}`
+      after `seq` in path and rename events, read natively as the event is
+      translated (default false). `file_id` is the inode on Unix and the file
+      index on Windows, which stays the same across renames and changes when
+      a file is replaced. All are `nil` if the path no longer exists; renames
+      describe the destination.
+    - `:classify_writes` - Report writes to files whose previous size is known
+      as `:appended` (the file grew), `:truncated` (it shrank) or `:rewritten`
+      (its size didn't change) instead of `:modified`/`:modified_data`
+      (default false), so readers know when to reset their offsets. Sizes are
+      remembered for every watched file, read as the backend reports each
+      change.
+    - `:tail` - Follow watched roots that are files like `tail -F`: each
+      change is followed by `{:appended, path, data, monotonic_time,
+      system_time, seq}` with the bytes appended since the last one (in
+      chunks of at most 64 KiB), starting from the file's size when it was
+      added (default false). A file replaced by another or truncated is
+      reported as `:rotated` and read again from the start; combine with
+      `rearm_root: true` to keep following a path whose file is renamed away.
+    - `:hash_contents` - Hash changed files (XXH3, 64 bits) on a native worker
+      pool and add the hash as `hash` to the map `:include_stat` adds, which
+      this implies (default false). Events are delivered in order once their
+      files are hashed, so two events with the same hash mean a rewrite
+      changed nothing. `hash` is `nil` for directories and unreadable files.
+    - `:replay` - Path of a journal written by `record_events/2` to feed to
+      the watcher instead of watching `path`: its events go through the
+      watcher's options as if the backend had just reported them, with the
+      times they were recorded at, and nothing else is reported. The watcher
+      runs on the `:null` backend, so `path` should be the root the journal
+      was recorded from.
+    - `:event_log` - Path of a write-ahead log every delivered event is
+      appended to, with its sequence number, for `get_events_after/2`
+      (default nil). An existing log is appended to and sequence numbers
+      carry on from its last event, so a consumer's cursor stays valid when
+      the watcher is started again. The log is never truncated.
+    - `:history_size` - Keep this many of the latest events in memory for
+      `get_events_since/2`, so several readers, or one that restarted, can
+      each catch up from their own sequence number (default `nil`, off)
+    - `:buffer_capacity` - Events buffered before `:drop_policy` applies
+      (default 65536). The buffer never grows past it however far behind the
+      consumer falls, and events are translated from it no more than that many
+      at a time, so peeking at or counting a backlog doesn't copy it elsewhere
+    - `:drop_policy` - What a full buffer does with a new event: `:drop_oldest`
+      (default) discards the oldest buffered event, `:drop_newest` discards the
+      new one and `:coalesce` discards the new one if an identical event is
+      already buffered, the oldest otherwise. Whenever events are lost an
+      `:overflow` event for each watched root follows, which has the number
+      of events lost since the previous one as `:count` with
+      `event_format: :map`. `get_stats/1` counts them all as `:dropped`.
+    - `:coalesce_paths` - Keep only the latest event about each path until it
+      is taken, in the place of the first, so a burst such as an
+      `npm install` leaves one event per file rather than tens of thousands
+      (default false). Every event then carries `{:count, n}`, the number of
+      events it stands for, after `{:permission_id, id}`. Renames aren't
+      coalesced, since their halves are paired up
+    - `:label` - Any term, returned by `get_watcher_info/1` and
+      `list_watchers/0`, e.g. to tell multiplexed watchers apart
+    - `:label_events` - Append `{:label, label}` to every event tuple, e.g.
+      `{kind, path, file_type, monotonic_time, system_time, seq, {:label,
+      label}}`, so no label is mistaken for the other tagged elements (default
+      false; requires `:label`)
+    - `:relative_paths` - Report event paths relative to the watched root
+      containing them (as returned by `get_watcher_info/1`) instead of
+      absolute; the root itself is reported as `""` (default false)
+    - `:canonicalize` - Resolve `path` (and paths given to `add_path/3`)
+      and every event path to canonical form, following `..`, symlinks and
+      Windows 8.3 short names, so they compare equal however the watch was
+      opened (default false). `path` must exist; `get_watcher_info/1`
+      returns the resolved root.
+    - `:normalize_unicode` - `:nfc` or `:nfd` to convert event paths to that
+      Unicode normalization form, e.g. `:nfc` so the decomposed names FSEvents
+      reports on macOS compare equal to strings from Elixir (default `nil`,
+      paths are reported as the OS gives them)
+    - `:watch_link` - When `path` (or a path given to `add_path/3`) is a
+      symlink, also report the link itself being replaced or retargeted, e.g.
+      a `current -> releases/N` deploy link, and follow it to its new target
+      (default false). Changes to the target's contents are reported as usual.
+      Has no effect together with `:canonicalize`, which resolves the link.
+    - `:rearm_root` - Once a watched root that was removed or moved away
+      (reported as `:root_removed`) exists again, watch it again and report it
+      as `:created` (default false). Checked every 500ms.
+    - `:allow_missing` - Accept a `path` (or a path given to `add_path/3`)
+      that doesn't exist yet: its closest existing ancestor is watched in its
+      place, and once it is created it is watched and reported as
+      `{:root_created, path, monotonic_time, system_time, seq}` (default false)
+    - `:settle_ms` - Also report each written file as
+      `{:settled, path, file_type, monotonic_time, system_time, seq}` once it
+      has gone this long without changing, so large writes can be processed
+      exactly once when complete (default `nil`, off). Files removed or moved
+      away first are not reported; combine with `kinds: [:settled]` to get
+      nothing else.
+    - `:coalesce_saves` - Report an editor's atomic save, writing a temporary
+      file and renaming it over the target (`file.txt.tmp` -> `file.txt`), as
+      a single `:modified` event for the target instead of the create, write
+      and rename burst (default false). A rename within a directory counts as
+      a save when its source was created in the same burst or is named after
+      the target with a temporary suffix (`~`, `.tmp`, `.temp`, `.swp`, `.swx`,
+      `.new`, `.part`), possibly hidden (`.file.txt.swp`). Only events still
+      waiting to be delivered are folded together.
+    - `:summarize_threshold` - When more than this many events about the
+      entries of one directory are delivered together, report them as one
+      `{:bulk_change, dir, count, monotonic_time, system_time, seq}` instead,
+      e.g. for an unpacked archive or a `git checkout` (default `nil`, off).
+      Renames count towards their destination's directory, and fanotify
+      permission events are never summarized.
+    - `:raw_paths` - Deliver event paths as the raw bytes of the OS path
+      instead of UTF-8 with invalid sequences replaced by U+FFFD, so
+      non-UTF-8 filenames survive intact (default false). On Windows the
+      bytes are WTF-8.
+    - `:event_format` - `:tuple` (default) delivers events as tuples;
+      `:map` delivers them as maps with named fields, `%{kind: kind, path:
+      path, type: file_type, ts: system_time, seq: seq, ...}`, with every
+      other field the tuples can carry set or `nil`, so matches keep working
+      as fields are added. `FSNotify.Event.from_map/1` turns them into
+      `FSNotify.Event` structs. `{shape, version}` pins a version of the
+      shape, so events keep the shape a caller was written against whatever
+      the NIF adds later; a bare shape is the latest version, 2. Version 1 is
+      the original `{kind, path, file_type}` tuple (`{:renamed, from, to}` for
+      renames), without times, sequence numbers or any of the fields options
+      add; maps start at version 2. Versions the NIF doesn't know are
+      `{:error, {:invalid_config, _}}`. Change it on a running watcher with
+      `set_event_format/2`.
+    - `:fsevent_flags` - Add `{:fsevent_flags, flags}` after `seq` (and the
+      map `:include_stat` adds) to every event, listing the FSEvents stream
+      flags of the change it came from: `:must_scan_sub_dirs` with
+      `:user_dropped` or `:kernel_dropped` when events were lost and the
+      path must be rescanned, `:root_changed` when a watched root was moved
+      or removed, `:mount`, `:unmount`, `:event_ids_wrapped` and
+      `:own_event` (default false). `flags` is `[]` for other changes and
+      on other backends.
+    - `:include_os_pid` - Add `{:os_pid, pid}` after `seq` (and the map
+      `:include_stat` adds, and `{:fsevent_flags, flags}`) to every event,
+      with the ID of the OS process that made the change where the backend
+      tells, i.e. `:fanotify`, or `nil` (default false).
+    - `:initial_scan` - First report every existing entry below `path` as
+      `{:discovered, path, file_type, monotonic_time, system_time, seq}`,
+      then send `{:ready, monotonic_time, system_time, seq}` (default false).
+      Changes made during the scan are reported as they happen, so they may
+      be interleaved with the discovered entries.
+    - `:on_demand` - Push events to subscribers only as they ask for them
+      with `request_events/2`, for GenStage producers and other consumers
+      that need backpressure (default false). Events not asked for yet stay
+      in the watcher's buffer, where `:buffer_capacity` and `:drop_policy`
+      apply. Has no effect while events are taken with `get_events/1`.
+    - `:share` - Share the watcher with other callers starting one on the same
+      path with the same options and `share: true`, as described in the module
+      documentation (default false)
+    - `:max_events_per_second` - Push no more than this many events to
+      subscribers each second, so a mass file operation can't flood their
+      mailboxes (default `nil`, unlimited). The rest wait for the next second
+      in the watcher's buffer, where `:buffer_capacity` and `:drop_policy`
+      apply, so a burst that outlasts the buffer is reported with an
+      `:overflow` event and counted in `get_stats/1` as `:dropped`. Has no
+      effect while events are taken with `get_events/1`.
+    - Any backend option accepted by `start_watcher_with_backend_config/4`
+
+  ## Returns
+  {:ok, watcher} or {:error, reason}
+  """
+  def start_watcher_with_options(_path, _recursive, _options), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Start watching a directory or file and push events to a process.
+
+  Instead of being queued for `get_events/1`, each event is sent to `pid` as
+  `{:fs_notify, watcher, event}` as soon as the
+  backend reports it.
+
+  ## Parameters
+  - path: String path to watch
+  - recursive: Boolean indicating whether to watch recursively
+  - pid: Process that receives the event messages
+
+  ## Returns
+  {:ok, watcher} or {:error, reason}
+  """
+  def start_watcher_with_pid(_path, _recursive, _pid), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Start a watcher from a single config map instead of positional arguments.
+
+  The map holds everything the other `start_watcher_*` functions take apart:
+
+    - `:path` - Path to watch, or a list of paths, all watched by the one
+      watcher as if added with `add_path/3` (required)
+    - `:recursive` - As for `start_watcher_with_options/3`, but defaults to
+      true
+    - `:subscriber` - Push events to this process as
+      `{:fs_notify, watcher, event}`, as `start_watcher_with_pid/3` does,
+      instead of queueing them for `get_events/1`
+
+  along with any option of `start_watcher_with_options/3`: `:backend`,
+  debouncing, filters, buffer limits and so on.
+
+  ## Examples
+
+      Native.start_watcher_with_config(%{
+        path: ["/tmp/a", "/tmp/b"],
+        backend: :poll,
+        debounce_ms: 100,
+        exclude: ["*.tmp"],
+        buffer_capacity: 1_000
+      })
+
+  ## Returns
+  {:ok, watcher} or {:error, reason}
+  """
+  def start_watcher_with_config(_config), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Wait for a single change to a file or directory without managing a watcher.
+
+  The first event of one of `kinds` is sent to the calling process as
+  `{:fs_notify, watcher, event}`, after which the watcher stops itself.
+  `path` need not exist yet: it is then watched from its closest existing
+  ancestor, and being created is reported as `:root_created`, whatever
+  `kinds` is.
+
+  ## Parameters
+  - path: String path to watch (not recursively)
+  - kinds: Event kinds to wait for, e.g. `[:created, :modified]`, or `[]` for
+    any event
+
+  ## Returns
+  {:ok, watcher} or {:error, reason}
+  """
+  def start_oneshot_watcher(_path, _kinds), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Wait for a single change, as `start_oneshot_watcher/2` does, with `options`
+  as for `start_watcher_with_options/3`.
+
+  `:allow_missing` still defaults to true; with `allow_missing: false` a
+  missing `path` is an error, as it is for other watchers.
+
+  ## Returns
+  {:ok, watcher} or {:error, reason}
+  """
+  def start_oneshot_watcher(_path, _kinds, _options), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Send a watcher's events to `pid`, alongside any other subscribers.
+
+  Every subscriber receives each event as `{:fs_notify, watcher, event}`, so
+  several processes can consume one directory through a single OS-level
+  watch. While a watcher has subscribers its events are no longer queued for
+  `get_events/1`; events queued before the first subscriber are kept.
+  Subscribers that exit are unsubscribed automatically. Subscribers don't keep
+  the watcher running: it is still garbage collected with its reference.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - pid: Process that receives the event messages
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def subscribe(_watcher, _pid), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Stop sending a watcher's events to `pid`.
+
+  Once the last subscriber is gone, events are queued for `get_events/1`
+  again.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - pid: Process passed to `subscribe/2` or `start_watcher_with_pid/3`
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def unsubscribe(_watcher, _pid), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Ask for `demand` more events from a watcher started with `on_demand: true`.
+
+  Its subscribers are sent at most as many events as have been asked for in
+  total, then nothing more until more are asked for, like a GenStage
+  producer's `handle_demand/2`:
+
+      def handle_demand(demand, watcher) do
+        :ok = FSNotify.Native.request_events(watcher, demand)
+        {:noreply, [], watcher}
+      end
+
+  Demand is counted per event, however many subscribers receive it.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - demand: Positive number of events to add to the demand
+
+  ## Returns
+  :ok or {:error, reason}; `:invalid_config` if the watcher wasn't started
+  with `on_demand: true`
+  """
+  def request_events(_watcher, _demand), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Start watching a directory or file, keeping only events whose paths pass
+  the given glob filters.
+
+  Patterns are matched natively against paths relative to the watched root,
+  before events are queued. A path is kept when it matches at least one
+  include pattern (or the include list is empty) and no exclude pattern.
+
+  ## Parameters
+  - path: String path to watch
+  - recursive: Boolean indicating whether to watch recursively
+  - include: List of glob patterns to keep, e.g. `["**/*.ex"]`
+  - exclude: List of glob patterns to drop, e.g. `["_build/**"]`
+
+  ## Returns
+  {:ok, watcher} or {:error, reason}
+  """
+  def start_watcher_with_filters(_path, _recursive, _include, _exclude), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Start watching a directory, dropping events for paths ignored by git.
+
+  Every `.gitignore` under the watched root is loaded when the watcher starts
+  and evaluated natively, with nested files taking precedence over their
+  parents. `.gitignore` files created afterwards are not picked up.
+
+  ## Parameters
+  - path: String path to watch
+  - recursive: Boolean indicating whether to watch recursively
+
+  ## Returns
+  {:ok, watcher} or {:error, reason}
+  """
+  def start_watcher_with_gitignore(_path, _recursive), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Set the defaults every watcher started from now on starts from, e.g.
+  `%{debounce_ms: 100, ignore_presets: [:editors, :os_junk], buffer_capacity:
+  50_000}`, instead of repeating them at every `start_watcher*` call.
+
+  Takes the options of `start_watcher_with_options/3` except `:recursive`,
+  `:label`, `:label_events`, `:replay` and `:event_log`, which only make
+  sense for one watcher. Options a watcher is started with take precedence
+  over the defaults, so e.g. `debounce_ms: nil` turns a default debounce off
+  (as it has to be to set `:throttle_ms`). Each call replaces the previous
+  defaults; `set_defaults(%{})` restores the built-in ones. Running watchers
+  are not affected.
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def set_defaults(_defaults), do: :erlang.nif_error(:nif_not_loaded)
+
   @doc """
   Stop a file watcher.
 
+  A watcher shared by several `start_watcher*` calls keeps running until each
+  of them has been matched by a stop (or its process has exited). Only the
+  processes that started a shared watcher can stop it; a watcher nobody
+  shares can be stopped by any process.
+
   ## Parameters
-  - watcher_id: ID returned from start_watcher
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  :ok, :watcher_not_found, or {:error, {:not_owner, message}} when the watcher
+  is shared and the calling process didn't start it
+  """
+  def stop_watcher(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Stop every running watcher, e.g. in an application shutdown hook or test
+  teardown.
 
   ## Returns
-  :ok or :watcher_not_found
+  :ok
   """
-  def stop_watcher(_watcher_id), do: :erlang.nif_error(:nif_not_loaded)
+  def stop_all_watchers, do: :erlang.nif_error(:nif_not_loaded)
 
   @doc """
   Get events from a watcher.
 
   ## Parameters
-  - watcher_id: ID returned from start_watcher
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  List of events in format
+  [{event_type, path, file_type, monotonic_time, system_time, seq}] or
+  {:error, reason}. Renames pair up as
+  {:renamed, from_path, to_path, monotonic_time, system_time, seq}. Times are
+  in microseconds and record when the backend reported the change; `seq`
+  numbers the watcher's events consecutively from 1. When the backend loses
+  events (e.g. the inotify queue or a Windows read buffer overflows) a
+  {:overflow, path, monotonic_time, system_time, seq} event asks for `path`
+  to be rescanned, and {:root_removed, path, monotonic_time, system_time, seq}
+  reports a watched root going away. Watchers started with `initial_scan: true` report
+  `:discovered` entries and then {:ready, monotonic_time, system_time, seq}.
+  """
+  def get_events(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Get at most `max` events from a watcher.
+
+  Prefer this over `get_events/1` when a large backlog is possible (a build
+  touching thousands of files), since each call only does a bounded amount of
+  work. Events not returned stay queued for the next call.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - max: Maximum number of events to return
+
+  ## Returns
+  {events, more} where `events` is in the same format as `get_events/1` and
+  `more` is true when further events are waiting, or {:error, reason}
+  """
+  def get_events(_watcher, _max), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Wait for events from a watcher.
+
+  Blocks on a dirty IO scheduler until at least one event is available or
+  `timeout_ms` elapses, so consumers don't have to poll `get_events/1`.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - timeout_ms: Maximum time to wait in milliseconds
+
+  ## Returns
+  List of events in the same format as `get_events/1` (empty on timeout) or
+  {:error, reason}
+  """
+  def await_events(_watcher, _timeout_ms), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Wait until any of several watchers has events to take.
+
+  Blocks on a dirty IO scheduler until at least one of `watchers` has events
+  or `timeout_ms` elapses, so a consumer multiplexing many watchers doesn't
+  have to poll each one. The events are left for `get_events/1`. Watchers
+  pushing their events to subscribers never have any to take.
+
+  ## Parameters
+  - watchers: Non-empty list of watcher references returned from
+    start_watcher
+  - timeout_ms: Maximum time to wait in milliseconds
+
+  ## Returns
+  List of the watchers with events, in the order given (empty on timeout),
+  or {:error, reason}, e.g. when one of them is stopped
+  """
+  def await_any(_watchers, _timeout_ms), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Block until `path` exists or `timeout_ms` elapses.
+
+  Runs on a dirty IO scheduler. The closest existing ancestor of `path` is
+  watched while waiting, so the path is noticed as soon as it is created
+  rather than on the next poll, and a path created while the watch is being
+  set up is not missed.
+
+  ## Parameters
+  - path: String path to wait for
+  - timeout_ms: Maximum time to wait in milliseconds
+
+  ## Returns
+  :ok, or {:error, {:timeout, message}} if `path` still doesn't exist
+  """
+  def wait_for_path(_path, _timeout_ms), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Block until a watcher has received no events for `quiet_ms`, e.g. until an
+  upload or extraction has finished writing.
+
+  Runs on a dirty IO scheduler. The quiet period starts no earlier than the
+  call, so this always waits at least `quiet_ms`. Events are left queued for
+  `get_events/1`; ones dropped by filters or received while paused don't count.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - quiet_ms: How long the watcher must go without events
+  - timeout_ms: Maximum time to wait in milliseconds
+
+  ## Returns
+  :ok, or {:error, {:timeout, message}} if events kept arriving
+  """
+  def wait_for_quiet(_watcher, _quiet_ms, _timeout_ms), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Record the entries below `path` to compare with a later snapshot.
+
+  Walks the tree on a dirty IO scheduler, noting each entry's type, size,
+  mtime and file id. Take one before downtime or on an `:overflow` event and
+  another afterwards, then pass both to `diff_snapshots/2` to reconcile.
+
+  ## Parameters
+  - path: String path of the directory (or file) to snapshot
+  - opts: Map with `:recursive` (default true, or `{:depth, n}`) and
+    `:exclude_dirs`, as for `start_watcher_with_options/3`
+
+  ## Returns
+  {:ok, snapshot} or {:error, reason}
+  """
+  def snapshot(_path, _opts), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Compare two snapshots taken by `snapshot/2`.
+
+  Files are modified when their size, mtime, file id or type differ;
+  directories are only reported as created or removed.
+
+  ## Parameters
+  - before: The earlier snapshot
+  - after: The later snapshot
+
+  ## Returns
+  %{created: paths, modified: paths, removed: paths}, each sorted
+  """
+  def diff_snapshots(_before, _after), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Compute a checksum of a directory tree, walking it in parallel.
+
+  Each directory's digest rolls up the names, types and digests of its
+  entries, so the result only depends on the tree's contents, not on the
+  order it was walked in. Files contribute their size and mtime, or their
+  size and the XXH3 hash of their contents with `contents: true`.
+
+  ## Parameters
+  - path: String path of the directory (or file) to digest
+  - opts: Map with `:recursive` (default true, or `{:depth, n}`),
+    `:exclude_dirs`, as for `start_watcher_with_options/3`, and
+    `:contents` (default false)
+
+  ## Returns
+  {:ok, digest} with a 64-bit integer digest, or {:error, reason}
+  """
+  def tree_digest(_path, _opts), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Get the events a watcher started with `:event_log` has logged after a
+  sequence number, e.g. the last one a consumer handled before it restarted.
+
+  Buffered events are numbered and logged first, so the log is up to date,
+  but stay queued for `get_events/1` and subscribers.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - seq: Sequence number to read after; 0 reads the whole log
+
+  ## Returns
+  List of events in the same format as `get_events/1` or {:error, reason}
+  """
+  def get_events_after(_watcher, _seq), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Get the events a watcher started with `:history_size` still keeps after a
+  sequence number, without taking them.
+
+  Each reader passes the `seq` of the last event it handled, so readers at
+  different positions catch up independently of each other and of
+  `get_events/1`. Events still buffered are numbered first, so they are
+  included.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - seq: Sequence number to read after; 0 reads the whole history
+
+  ## Returns
+  {events, missed} where `events` is in the same format as `get_events/1`
+  and `missed` is true when events after `seq` are no longer kept, or
+  {:error, reason}
+  """
+  def get_events_since(_watcher, _seq), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Get the events queued on a watcher without consuming them.
+
+  At most `:buffer_capacity` events, plus an `:overflow`, are queued at a
+  time, so that is all a large backlog shows; `get_events/1` takes the rest.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  List of events in the same format as `get_events/1` or {:error, reason}
+  """
+  def peek_events(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Drop every event queued on a watcher.
+
+  Sequence numbers of dropped events are not reused, so later events show
+  the gap.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def clear_events(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Count the events queued on a watcher.
+
+  Events still in the watcher's buffer count once each, before renames are
+  paired up or bursts summarized.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  Number of queued events or {:error, reason}
+  """
+  def pending_count(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Change the options of a running watcher.
+
+  Queued events are kept. Filters are swapped in place, a new `:recursive`
+  flag re-registers every watched root with the OS, and only a change of
+  backend, backend option or debouncing replaces the underlying watcher.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - config: Map with any of the options of `start_watcher_with_options/3`
+    that choose what is watched and how: `:recursive`, `:exclude_dirs`,
+    `:backend` and the backend options, debouncing, `:throttle_ms`, filters,
+    `:watch_link`, `:rearm_root`, `:allow_missing` and
+    `:max_events_per_second`, which applies from the next batch of events
+    pushed (`nil` lifts it). Every other option is
+    fixed once the watcher starts; changing one is
+    `{:error, {:invalid_config, _}}`. `debounce_ms: nil` turns debouncing off.
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def update_watcher(_watcher, _config), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Change the format a watcher delivers events in, from the next event
+  delivered on, including those already queued.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - format: `:tuple`, `:map` or `{shape, version}`, as the `:event_format`
+    option of `start_watcher_with_options/3`
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def set_event_format(_watcher, _format), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Ask for a message once a watcher has events to take, instead of polling
+  `get_events/1` or blocking in `await_events/2`.
+
+  The watcher's buffer is watched with `enif_select`, so no thread is kept
+  per watcher. Once at least one event can be taken, right away if some
+  already can, the caller receives `{:select, handle, ref, :ready_input}`
+  with the `ref` returned here. The message is sent once; call this again
+  after taking the events to be told about the next ones. It may
+  occasionally come when filters leave nothing to take. Stopping the watcher
+  cancels it. Unix only.
+
+      {:ok, ref} = FSNotify.Native.select_ready(watcher)
+
+      receive do
+        {:select, _handle, ^ref, :ready_input} ->
+          events = FSNotify.Native.get_events(watcher)
+      end
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher. Watchers pushing
+    events to a process are rejected.
+
+  ## Returns
+  {:ok, ref}, or {:error, reason}: `:invalid_config` for watchers pushing
+  events, `:invalid_backend` on Windows
+  """
+  def select_ready(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Pause a watcher.
+
+  Events reported while paused are discarded, but the underlying OS watch
+  stays registered so resuming is cheap. Useful for ignoring the
+  application's own bulk writes. Events queued before pausing are kept.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def pause_watcher(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Resume a watcher paused with `pause_watcher/1`.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def resume_watcher(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Get health counters for a watcher.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  A map or {:error, reason}. The map has the keys:
+  - `:received` - Events reported by the backend that passed the filters
+  - `:delivered` - Events taken with `get_events` or sent to the subscriber
+  - `:dropped` - Events discarded because the buffer was full
+  - `:errors` - Errors reported by the backend
+  - `:queue_depth` - Events waiting to be taken
+  - `:last_event_at` - Wall-clock time of the latest event in microseconds
+    since the Unix epoch, or `nil` if there has been none
+  - `:last_event_id` - FSEvents event ID of the latest event taken with
+    `get_events` or sent to the subscriber, to resume from with
+    `:since_event_id`, or `nil` on other backends
+  - `:demand` - Events subscribers asked for with `request_events/2` and
+    weren't sent yet, or `nil` without `:on_demand`
+  - `:rate_limited` - Events pushed late, held back by
+    `:max_events_per_second`; those lost to a full buffer meanwhile count as
+    `:dropped`
+  """
+  def get_stats(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Estimate the memory a watcher holds on to in the NIF, e.g. to find the
+  watcher behind the VM's memory growing while the processes' doesn't.
+
+  Counts are approximate: the room collections have allocated plus the paths
+  they hold, without allocator overhead or the backend's own state. Watchers
+  pushing events to a process count what is buffered but not what is on its
+  way to the process.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  A map of bytes or {:error, reason}. The map has the keys:
+  - `:events` - Events buffered, queued for `get_events` and kept for
+    `get_events_since/2`
+  - `:paths` - The watcher's roots, including those that don't exist yet
+  - `:maps` - What the watcher tracks per path: renames awaiting their pair,
+    coalesced events and the files followed by `:settle_ms`,
+    `:classify_attrib`, `:classify_writes`, `:hash_contents` and `:tail`
+  - `:total` - The sum of the above
+  """
+  def memory_info(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Give back the memory a watcher no longer needs after a burst, the way
+  hibernating does for a process.
+
+  Buffers, queues and per-path maps keep the room a burst grew them to, so a
+  long-lived watcher that sees the occasional huge spike holds on to it for
+  good. Compacting shrinks them to what they still hold, so call it once the
+  burst's events have been taken; `memory_info/1` shows what it gave back.
+  The room is allocated again should another burst come.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def compact(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  if Mix.env() == :test do
+    # Panics while holding the watcher's lock, for tests of recovering from it
+    @doc false
+    def debug_panic(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+  end
+
+  @doc """
+  Sample the counters kept across every watcher, cheaply enough to do often,
+  e.g. from a `:telemetry_poller` measurement:
+
+      def measure_fs_notify do
+        snapshot = FSNotify.Native.telemetry_snapshot()
+        counters = Map.delete(snapshot, :watchers)
+        :telemetry.execute([:fs_notify], counters)
+      end
+
+  ## Returns
+  A map with the keys:
+  - `:events_received` - Events reported by every backend since the NIF was
+    loaded
+  - `:events_dropped` - Events discarded because a watcher's buffer was full
+  - `:errors` - Errors reported by every backend
+  - `:watchers_active` - Watchers started and not yet stopped
+  - `:watchers` - A map per running watcher with its `:watcher` reference,
+    `:label`, `:events_received`, `:events_dropped`, `:errors` and
+    `:queue_depth`. A watcher busy replacing its backend is left out rather
+    than waited on.
+  """
+  def telemetry_snapshot, do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Register the process native warnings are sent to, e.g. backend errors,
+  fallbacks to another backend and events dropped from a full buffer.
+
+  Each is sent as `{:fs_notify_log, level, message, system_time}`, where
+  `level` is `:warning` or `:error` and `system_time` is microseconds since
+  the Unix epoch, starting with those kept since the last `drain_logs/0`.
+  `FSNotify.LogBridge` passes them on to `Logger`.
+
+  ## Parameters
+  - pid: Process to send them to, or `nil` to keep them for `drain_logs/0`
+    again. They are also kept once the process exits.
+
+  ## Returns
+  :ok
+  """
+  def set_log_pid(_pid), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Take the native warnings kept while no process is registered with
+  `set_log_pid/1`, oldest first. Only the latest 1000 are kept.
 
   ## Returns
-  List of events in format [{event_type, path, file_type}]
+  A list of `{level, message, system_time}`
   """
-  def get_events(_watcher_id), do: :erlang.nif_error(:nif_not_loaded)
+  def drain_logs, do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Add another path to an existing watcher.
+
+  Events for every path are delivered through the same watcher, so one
+  watcher can cover many directories.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - path: String path to watch
+  - recursive: Boolean indicating whether to watch recursively
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def add_path(_watcher, _path, _recursive), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Stop watching a path previously added to a watcher.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - path: String path to stop watching
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def remove_path(_watcher, _path), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Record the events a watcher's backend reports to a journal file.
+
+  Events are written as the backend reported them, before filtering or any
+  other option applies, in a compact binary format. Start a watcher with
+  `replay: journal` to feed them through again, e.g. to reproduce an odd
+  sequence of events from a bug report in a test. Recording again replaces
+  the journal being written.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+  - path: String path of the journal, created or truncated
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def record_events(_watcher, _path), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Stop recording a watcher's events, writing out the rest of its journal.
+
+  Stopping the watcher finishes its journal too.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  :ok or {:error, reason}
+  """
+  def stop_recording(_watcher), do: :erlang.nif_error(:nif_not_loaded)
 
   @doc """
   Get information about a watcher.
 
+  The path and recursive flag are the ones the watcher was started with;
+  paths added with `add_path/3` are not included. The backend is the one in
+  use, which may be a fallback from the list the watcher was started with.
+
+  ## Parameters
+  - watcher: Watcher reference returned from start_watcher
+
+  ## Returns
+  {:ok, path, recursive, backend, label, follow_symlinks} or {:error, reason},
+  where `label` is `nil` for watchers started without one
+  """
+  def get_watcher_info(_watcher), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Get the full effective configuration of a watcher, to tell why it does or
+  doesn't report something.
+
+  The map has every option of `start_watcher_with_options/3`, including the
+  defaults that were applied (e.g. the poll backend's `poll_interval_ms`), as
+  last set by `update_watcher/2` or `set_event_format/2`, along with:
+
+    - `:path` - Every root watched, including those added with `add_path/3`
+    - `:subscriber` - Process events are pushed to, or `nil`
+    - `:active_backend` - Backend in use, which may be a fallback from
+      `:backend`
+
+  `:label` is only there for watchers started with one.
+
   ## Parameters
-  - watcher_id: ID returned from start_watcher
+  - watcher: Watcher reference returned from start_watcher
 
   ## Returns
-  {:ok, path, recursive, backend} or {:error, reason}
+  A map or {:error, reason}
   """
-  def get_watcher_info(_watcher_id), do: :erlang.nif_error(:nif_not_loaded)
+  def get_config(_watcher), do: :erlang.nif_error(:nif_not_loaded)
 
   @doc """
   Start watching a directory or file with debouncing enabled.
 
+  Each path is timed on its own: it is reported as `:modified` once it has
+  been quiet for `debounce_ms`, however busy other paths in the watch are.
+  A path that keeps changing is still reported once every `debounce_ms`, as
+  `:unknown`.
+
   ## Parameters
   - path: String path to watch
   - recursive: Boolean indicating whether to watch recursively
-  - backend: Atom specifying the backend (:recommended, :poll, :inotify, :fsevent, :windows, :null)
+  - backend: Atom specifying the backend the debouncer runs on, as for
+    `start_watcher_with_backend/3`
   - debounce_ms: Debounce timeout in milliseconds
 
   ## Returns
-  {:ok, watcher_id} or {:error, reason}
+  {:ok, watcher} or {:error, reason}
   """
   def start_watcher_with_debounce(_path, _recursive, _backend, _debounce_ms), do: :erlang.nif_error(:nif_not_loaded)
 
+  @doc """
+  Start watching a directory or file with rename-aware debouncing.
+
+  Unlike `start_watcher_with_debounce/4`, which reports every change as
+  `:modified`, this keeps the original event kinds and their ordering and
+  merges rename pairs into `{:renamed, from_path, to_path}` events.
+
+  ## Parameters
+  - path: String path to watch
+  - recursive: Boolean indicating whether to watch recursively
+  - backend: Atom specifying the backend the debouncer runs on, as for
+    `start_watcher_with_backend/3`
+  - debounce_ms: Debounce timeout in milliseconds
+
+  ## Returns
+  {:ok, watcher} or {:error, reason}
+  """
+  def start_watcher_with_full_debounce(_path, _recursive, _backend, _debounce_ms),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  List every running watcher, for debugging from an IEx shell.
+
+  The references returned are new handles to the running watchers: they work
+  with every function here, but don't compare equal to the reference returned
+  when the watcher was started, and don't keep the watcher running once that
+  one is garbage collected.
+
+  ## Returns
+  List of `{watcher, path, recursive, backend, debounce_ms, queue_depth, label}`
+  tuples, where `debounce_ms` and `label` are `nil` for watchers without
+  debouncing or a label
+  """
+  def list_watchers, do: :erlang.nif_error(:nif_not_loaded)
+
   @doc """
   List available watcher backends on the current platform.
 
+  `:fanotify` is only listed when this process has `CAP_SYS_ADMIN`, and
+  `:watchman` when a Watchman daemon can be reached.
+
   ## Returns
   List of atoms representing available backends
   """
   def list_available_backends, do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Describe the filesystem a path is on, to choose a backend before watching it.
+
+  `case_sensitive` is found by looking up the path's name (or an ancestor's,
+  or an entry's) with its case swapped, and is `nil` when none of them has
+  letters. `fs_type` is `nil` where the filesystem can't be named.
+
+  ## Parameters
+  - path: An existing file or directory
+
+  ## Returns
+  {:ok, %{fs_type: name, network: boolean, case_sensitive: boolean | nil,
+  recommended_backend: backend}}, where `network` is true for network and
+  FUSE filesystems and `recommended_backend` is the one `:recommended` would
+  use there (`:poll` on network filesystems), or {:error, reason}
+  """
+  def probe_path(_path), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Report the inotify limits on Linux and how much of them the current user
+  has left.
+
+  Every inotify watcher is an instance, and each directory it watches takes a
+  watch; both are limited per user, across all of their processes. Usage is
+  counted over the user's processes visible in `/proc`, so it is a snapshot.
+
+  ## Returns
+  {:ok, %{max_user_watches: n, watches: n, remaining_watches: n,
+  max_user_instances: n, instances: n, remaining_instances: n}}, or
+  {:error, {:invalid_backend, message}} on other platforms
+  """
+  def inotify_limits, do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Allow or deny the open a `:fanotify` watcher started with
+  `permission_events: true` reported with `{:permission_id, id}`.
+
+  The process opening the file waits until this is called, the
+  `:permission_timeout_ms` passes or the watcher stops, and a denied open
+  fails with `EPERM`.
+
+  ## Parameters
+  - permission_id: The ID the event was reported with
+  - decision: `:allow` or `:deny`
+
+  ## Returns
+  :ok, {:error, {:permission_not_found, message}} if the open is no longer
+  waiting, or {:error, {:invalid_backend, message}} on platforms without
+  fanotify
+  """
+  def respond(_permission_id, _decision), do: :erlang.nif_error(:nif_not_loaded)
 end
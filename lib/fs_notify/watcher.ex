@@ -15,14 +15,20 @@ defmodule FSNotify.Watcher do
             recursive: true,
             backend: :recommended,
             debounce_ms: nil,
+            debounce_mode: :mini,
+            debounce_edge: :trailing,
+            throttle_ms: nil,
             subscribers: %{}
 
   @type t :: %__MODULE__{
           paths: [String.t()],
-          watchers: %{String.t() => non_neg_integer()},
+          watchers: %{String.t() => reference()},
           recursive: boolean(),
-          backend: atom(),
+          backend: atom() | [atom()],
           debounce_ms: non_neg_integer() | nil,
+          debounce_mode: :mini | :full,
+          debounce_edge: :trailing | :leading | :both,
+          throttle_ms: pos_integer() | nil,
           subscribers: %{reference() => pid()}
         }
 
@@ -43,17 +49,30 @@ defmodule FSNotify.Watcher do
     recursive = Keyword.get(opts, :recursive, true)
     backend = Keyword.get(opts, :backend, :recommended)
     debounce_ms = Keyword.get(opts, :debounce_ms)
+    debounce_mode = Keyword.get(opts, :debounce_mode, :mini)
+    debounce_edge = Keyword.get(opts, :debounce_edge, :trailing)
+    throttle_ms = Keyword.get(opts, :throttle_ms)
+
+    pacing =
+      if throttle_ms,
+        do: {:throttle, throttle_ms},
+        else: {debounce_ms, debounce_mode, debounce_edge}
 
     # Start watchers for each path
     watchers =
       paths
       |> Enum.map(fn path ->
-        case start_watcher_for_backend(path, recursive, backend, debounce_ms) do
+        case start_watcher_for_backend(path, recursive, backend, pacing) do
           {:ok, watcher_id} ->
-            debounce_info = if debounce_ms, do: ", debounce: #{debounce_ms}ms", else: ""
+            debounce_info =
+              cond do
+                throttle_ms -> ", throttle: #{throttle_ms}ms"
+                debounce_ms -> ", debounce: #{debounce_ms}ms"
+                true -> ""
+              end
 
             Logger.debug(
-              "Started file watcher for path: #{path} (recursive: #{recursive}, backend: #{backend}#{debounce_info})"
+              "Started file watcher for path: #{path} (recursive: #{recursive}, backend: #{inspect(backend)}#{debounce_info})"
             )
 
             {path, watcher_id}
@@ -76,6 +95,9 @@ defmodule FSNotify.Watcher do
         recursive: recursive,
         backend: backend,
         debounce_ms: debounce_ms,
+        debounce_mode: debounce_mode,
+        debounce_edge: debounce_edge,
+        throttle_ms: throttle_ms,
         subscribers: %{}
       }
 
@@ -150,17 +172,17 @@ defmodule FSNotify.Watcher do
 
   # Private functions
 
-  defp start_watcher_for_backend(path, recursive, backend, nil) do
-    # No debouncing - use regular watcher
-    case backend do
-      :recommended -> Native.start_watcher(path, recursive)
-      _ -> Native.start_watcher_with_backend(path, recursive, backend)
-    end
+  defp start_watcher_for_backend(path, recursive, backend, pacing) do
+    %{path: path, recursive: recursive, backend: backend}
+    |> Map.merge(pacing_options(pacing))
+    |> Native.start_watcher_with_config()
   end
 
-  defp start_watcher_for_backend(path, recursive, backend, debounce_ms) when is_integer(debounce_ms) do
-    # Debouncing enabled - use debounced watcher
-    Native.start_watcher_with_debounce(path, recursive, backend, debounce_ms)
+  defp pacing_options({:throttle, throttle_ms}), do: %{throttle_ms: throttle_ms}
+
+  # `debounce_ms: nil` starts a regular watcher
+  defp pacing_options({debounce_ms, mode, edge}) do
+    %{debounce_ms: debounce_ms, debounce_mode: mode, debounce_edge: edge}
   end
 
   defp schedule_event_polling do
@@ -0,0 +1,210 @@
+use crate::error::lock_recovered;
+use crate::memory;
+use crate::scan::{self, Recursion};
+use notify::event::{AccessKind, AccessMode, MetadataKind, ModifyKind, RenameMode};
+use notify::{Event, EventKind};
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The attributes whose changes are told apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Attribs {
+    permissions: u32,
+    owner: (u32, u32),
+    /// Hash of the extended attributes' names and values, where they can be read
+    xattrs: u64,
+    modified: Option<SystemTime>,
+    accessed: Option<SystemTime>,
+}
+
+impl Attribs {
+    fn read(path: &Path) -> Option<Attribs> {
+        let metadata = fs::symlink_metadata(path).ok()?;
+        Some(Attribs {
+            permissions: permissions(&metadata),
+            owner: owner(&metadata),
+            xattrs: xattrs(path),
+            modified: metadata.modified().ok(),
+            accessed: metadata.accessed().ok(),
+        })
+    }
+
+    /// What changed between `self` and `new`, most significant first: ownership changes
+    /// often clear setuid bits too.
+    fn change(&self, new: &Attribs) -> MetadataKind {
+        if self.owner != new.owner {
+            MetadataKind::Ownership
+        } else if self.permissions != new.permissions {
+            MetadataKind::Permissions
+        } else if self.xattrs != new.xattrs {
+            MetadataKind::Extended
+        } else if self.modified != new.modified {
+            MetadataKind::WriteTime
+        } else if self.accessed != new.accessed {
+            MetadataKind::AccessTime
+        } else {
+            MetadataKind::Any
+        }
+    }
+}
+
+/// Note the current attributes of `path`, or forget it if it's gone.
+fn refresh(known: &mut HashMap<PathBuf, Attribs>, path: &Path) {
+    match Attribs::read(path) {
+        Some(attribs) => known.insert(path.to_path_buf(), attribs),
+        None => known.remove(path),
+    };
+}
+
+#[cfg(unix)]
+fn permissions(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permissions(metadata: &Metadata) -> u32 {
+    metadata.permissions().readonly() as u32
+}
+
+#[cfg(unix)]
+fn owner(metadata: &Metadata) -> (u32, u32) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.uid(), metadata.gid())
+}
+
+#[cfg(not(unix))]
+fn owner(_metadata: &Metadata) -> (u32, u32) {
+    (0, 0)
+}
+
+/// Extended attributes are only compared on Linux, where inotify doesn't say when they
+/// change; FSEvents reports them on its own.
+#[cfg(target_os = "linux")]
+fn xattrs(path: &Path) -> u64 {
+    use std::ffi::CString;
+    use std::hash::{DefaultHasher, Hasher};
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return 0;
+    };
+    // Each call is asked for the size first; a list that grows in between is caught by
+    // the next event
+    let read = |fill: &dyn Fn(*mut libc::c_char, usize) -> isize| -> Vec<u8> {
+        let size = fill(std::ptr::null_mut(), 0);
+        if size <= 0 {
+            return Vec::new();
+        }
+        let mut buffer = vec![0u8; size as usize];
+        let size = fill(buffer.as_mut_ptr().cast(), buffer.len());
+        buffer.truncate(size.max(0) as usize);
+        buffer
+    };
+
+    // SAFETY: `path` and `name` are NUL-terminated and each buffer is `size` bytes long
+    let names = read(&|buffer, size| unsafe { libc::llistxattr(path.as_ptr(), buffer, size) });
+    let mut hasher = DefaultHasher::new();
+    for name in names
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        let Ok(name) = CString::new(name) else {
+            continue;
+        };
+        let value = read(&|buffer, size| unsafe {
+            libc::lgetxattr(path.as_ptr(), name.as_ptr(), buffer.cast(), size)
+        });
+        hasher.write(name.as_bytes_with_nul());
+        hasher.write(&value);
+    }
+    hasher.finish()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn xattrs(_path: &Path) -> u64 {
+    0
+}
+
+/// Remembers the attributes of every path seen, so that a metadata change the backend
+/// doesn't describe (inotify's `IN_ATTRIB`) can be classified by what differs since.
+/// A path is first seen through the walk of its root or its first event; a change before
+/// then can't be classified and is left as is.
+pub struct AttribTracker {
+    known: Mutex<HashMap<PathBuf, Attribs>>,
+}
+
+impl AttribTracker {
+    pub fn new() -> Arc<AttribTracker> {
+        Arc::new(AttribTracker {
+            known: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Record the attributes of everything below `root` within `recursion`, on a
+    /// background thread.
+    pub fn spawn_seed(self: &Arc<Self>, root: PathBuf, recursive: bool, recursion: Recursion) {
+        let tracker = Arc::downgrade(self);
+        scan::spawn_walk(root, recursive, recursion, move |path| {
+            // Stop once the watcher is gone
+            let Some(tracker) = tracker.upgrade() else {
+                return false;
+            };
+            if let Some(attribs) = Attribs::read(&path) {
+                lock_recovered(&tracker.known)
+                    .entry(path)
+                    .or_insert(attribs);
+            }
+            true
+        });
+    }
+
+    /// Classify an unspecified metadata change in `event`, and note the attributes of the
+    /// paths of any other event.
+    pub fn track(&self, event: &mut Event) {
+        let mut known = lock_recovered(&self.known);
+        match event.kind {
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any)) => {
+                if let [path] = &event.paths[..] {
+                    let old = known.get(path).copied();
+                    refresh(&mut known, path);
+                    if let (Some(old), Some(new)) = (old, known.get(path)) {
+                        event.kind = EventKind::Modify(ModifyKind::Metadata(old.change(new)));
+                    }
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let [from, to] = &event.paths[..] {
+                    known.remove(from);
+                    refresh(&mut known, to);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+                for path in &event.paths {
+                    known.remove(path);
+                }
+            }
+            EventKind::Create(_)
+            | EventKind::Modify(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                for path in &event.paths {
+                    refresh(&mut known, path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Bytes taken by the attributes known, for `memory_info`.
+    pub fn memory(&self) -> usize {
+        memory::path_map(&lock_recovered(&self.known))
+    }
+
+    /// Give back the room the attributes known no longer need, for `compact`.
+    pub fn compact(&self) {
+        lock_recovered(&self.known).shrink_to_fit();
+    }
+}
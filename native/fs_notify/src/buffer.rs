@@ -0,0 +1,488 @@
+use crate::atoms;
+use crate::error::lock_recovered;
+use crate::event::Timestamp;
+use crate::logs;
+use crate::memory;
+use crate::telemetry;
+use notify::event::{Flag, ModifyKind};
+use notify::{Event, EventKind};
+use rustler::Atom;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, OnceLock, PoisonError, Weak};
+use std::time::Duration;
+
+/// Backend results, stamped with the time they were received.
+pub type Received = (Result<Event, notify::Error>, Timestamp);
+
+/// A buffered backend result, with the number of events about its paths it stands for
+/// when the buffer coalesces them.
+pub struct Queued {
+    pub received: Received,
+    pub count: Option<u64>,
+    /// Events the buffer lost, for the rescan request it queues once full
+    pub lost: Option<u64>,
+}
+
+/// Events a watcher buffers by default before its drop policy kicks in.
+pub const DEFAULT_CAPACITY: usize = 65_536;
+
+/// Events a drained buffer keeps room for; anything beyond is given back.
+const SHRINK_ABOVE: usize = 1_024;
+
+/// What a full buffer does with a new event.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DropPolicy {
+    /// Discard the oldest buffered event to make room
+    #[default]
+    DropOldest,
+    /// Discard the new event
+    DropNewest,
+    /// Discard the new event if one with the same kind and paths is already buffered,
+    /// otherwise the oldest
+    Coalesce,
+}
+
+impl DropPolicy {
+    pub fn from_atom(atom: Atom) -> Option<Self> {
+        if atom == atoms::drop_oldest() {
+            Some(DropPolicy::DropOldest)
+        } else if atom == atoms::drop_newest() {
+            Some(DropPolicy::DropNewest)
+        } else if atom == atoms::coalesce() {
+            Some(DropPolicy::Coalesce)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_atom(self) -> Atom {
+        match self {
+            DropPolicy::DropOldest => atoms::drop_oldest(),
+            DropPolicy::DropNewest => atoms::drop_newest(),
+            DropPolicy::Coalesce => atoms::coalesce(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferOptions {
+    pub capacity: usize,
+    pub policy: DropPolicy,
+    /// Keep only the latest event about each set of paths until it is taken
+    pub coalesce_paths: bool,
+}
+
+impl Default for BufferOptions {
+    fn default() -> Self {
+        BufferOptions {
+            capacity: DEFAULT_CAPACITY,
+            policy: DropPolicy::default(),
+            coalesce_paths: false,
+        }
+    }
+}
+
+/// Bounded queue between a backend and whoever consumes its events: a ring of at most
+/// `capacity` events, where a new event overwrites the oldest or is discarded, as the drop
+/// policy says, so a watcher's memory use doesn't grow with its backlog.
+///
+/// When events had to be discarded to stay within capacity, the next event taken is a
+/// rescan request, which the translator reports as `{:overflow, root}`, along with the
+/// number of events lost since the previous one.
+pub struct EventBuffer {
+    options: BufferOptions,
+    state: Mutex<BufferState>,
+    ready: Condvar,
+    /// Called as each event is buffered, once set with `on_ready`
+    wake: OnceLock<Box<dyn Fn() + Send + Sync>>,
+}
+
+/// Counters kept by a buffer over its lifetime.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BufferStats {
+    /// Events pushed by the backend
+    pub received: u64,
+    /// Events discarded because the buffer was full
+    pub dropped: u64,
+    /// Errors pushed by the backend
+    pub errors: u64,
+    /// Events currently buffered
+    pub len: usize,
+    /// When the most recent event was received
+    pub last_event: Option<Timestamp>,
+}
+
+#[derive(Default)]
+struct BufferState {
+    events: VecDeque<Queued>,
+    /// Position of the buffered event about each set of paths, counted from the first
+    /// event ever buffered, with `coalesce_paths`
+    positions: HashMap<Vec<PathBuf>, u64>,
+    /// Number of buffered events of each kind about each set of paths, with the coalesce
+    /// drop policy, so a full buffer finds duplicates without a scan
+    kinds: Option<HashMap<(EventKind, Vec<PathBuf>), usize>>,
+    /// Events taken or discarded from the front since the buffer was created
+    removed: u64,
+    stats: BufferStats,
+    /// Events were lost since the consumer last took one
+    overflowed: bool,
+    /// Events lost since the consumer last took the rescan request
+    lost: u64,
+    /// New events are discarded until the watcher is resumed
+    paused: bool,
+    /// The producer is gone; nothing more will be pushed
+    closed: bool,
+    /// Woken along with `ready`, for those waiting on several buffers at once
+    wakers: Vec<Weak<Waker>>,
+}
+
+impl BufferState {
+    fn wake(&mut self) {
+        self.wakers.retain(|waker| match waker.upgrade() {
+            Some(waker) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+/// Wakes whoever waits on several buffers at once, e.g. `await_any`.
+#[derive(Default)]
+pub struct Waker {
+    woken: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Waker {
+    fn wake(&self) {
+        *lock_recovered(&self.woken) = true;
+        self.cond.notify_all();
+    }
+
+    /// Block for up to `timeout` until one of the buffers the waker was added to is pushed to
+    /// or closed, and reset it.
+    pub fn wait(&self, timeout: Duration) {
+        let woken = lock_recovered(&self.woken);
+        let (mut woken, _) = self
+            .cond
+            .wait_timeout_while(woken, timeout, |woken| !*woken)
+            .unwrap_or_else(PoisonError::into_inner);
+        *woken = false;
+    }
+}
+
+impl BufferState {
+    /// Note that events were lost, warning once for each time the buffer fills up.
+    fn overflow(&mut self, capacity: usize) {
+        if !self.overflowed {
+            logs::warning(format!(
+                "event buffer full ({capacity} events), dropping events until it is drained"
+            ));
+        }
+        self.overflowed = true;
+    }
+
+    fn pop(&mut self) -> Option<Queued> {
+        if std::mem::take(&mut self.overflowed) {
+            let rescan = Event::new(EventKind::Other).set_flag(Flag::Rescan);
+            return Some(Queued {
+                received: (Ok(rescan), Timestamp::now()),
+                count: None,
+                lost: Some(std::mem::take(&mut self.lost)),
+            });
+        }
+
+        let queued = self.pop_front();
+        // Give back what a burst grew the ring to once it is drained
+        if self.events.is_empty() && self.events.capacity() > SHRINK_ABOVE {
+            self.events.shrink_to(SHRINK_ABOVE);
+        }
+        queued
+    }
+
+    fn pop_front(&mut self) -> Option<Queued> {
+        let queued = self.events.pop_front()?;
+        if let (Ok(event), Some(_)) = (&queued.received.0, queued.count) {
+            if self.positions.get(&event.paths) == Some(&self.removed) {
+                self.positions.remove(&event.paths);
+            }
+        }
+        self.uncount_kind(&queued.received);
+        self.removed += 1;
+        Some(queued)
+    }
+
+    /// Count a newly buffered event towards its kind and paths.
+    fn count_kind(&mut self, received: &Received) {
+        if let (Some(kinds), (Ok(event), _)) = (&mut self.kinds, received) {
+            *kinds.entry((event.kind, event.paths.clone())).or_default() += 1;
+        }
+    }
+
+    /// Stop counting an event that is no longer buffered.
+    fn uncount_kind(&mut self, received: &Received) {
+        let (Some(kinds), (Ok(event), _)) = (&mut self.kinds, received) else {
+            return;
+        };
+        let key = (event.kind, event.paths.clone());
+        if let Some(count) = kinds.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                kinds.remove(&key);
+            }
+        }
+    }
+
+    /// Fold `received` into the buffered event about the same paths, if there is one,
+    /// returning it otherwise.
+    fn coalesce(&mut self, received: Received) -> Option<Received> {
+        let (Ok(event), _) = &received else {
+            return Some(received);
+        };
+        if !coalescable(event) {
+            return Some(received);
+        }
+        let Some(&position) = self.positions.get(&event.paths) else {
+            return Some(received);
+        };
+        let index = (position - self.removed) as usize;
+        self.count_kind(&received);
+        let replaced = mem::replace(&mut self.events[index].received, received);
+        self.uncount_kind(&replaced);
+        let queued = &mut self.events[index];
+        queued.count = queued.count.map(|count| count + 1);
+        None
+    }
+
+    fn is_ready(&self) -> bool {
+        self.overflowed || !self.events.is_empty()
+    }
+
+    fn contains(&self, event: &Event) -> bool {
+        self.kinds
+            .as_ref()
+            .is_some_and(|kinds| kinds.contains_key(&(event.kind, event.paths.clone())))
+    }
+}
+
+/// Whether an event can stand in for those about the same paths before it. Renames are
+/// kept, since their halves are paired up, as are markers and rescans.
+fn coalescable(event: &Event) -> bool {
+    !event.paths.is_empty()
+        && !matches!(event.kind, EventKind::Modify(ModifyKind::Name(_)))
+        && event.attrs.info().is_none()
+        && event.attrs.flag().is_none()
+}
+
+impl EventBuffer {
+    pub fn new(options: BufferOptions) -> Self {
+        EventBuffer {
+            options,
+            state: Mutex::new(BufferState {
+                kinds: (options.policy == DropPolicy::Coalesce).then(HashMap::new),
+                ..BufferState::default()
+            }),
+            ready: Condvar::new(),
+            wake: OnceLock::new(),
+        }
+    }
+
+    pub fn push(&self, received: Received) {
+        let mut state = lock_recovered(&self.state);
+        if state.paused {
+            return;
+        }
+
+        state.stats.received += 1;
+        state.stats.last_event = Some(received.1);
+        telemetry::increment(&telemetry::EVENTS_RECEIVED);
+        if received.0.is_err() {
+            state.stats.errors += 1;
+            telemetry::increment(&telemetry::ERRORS);
+        }
+
+        let received = if self.options.coalesce_paths {
+            match state.coalesce(received) {
+                Some(received) => received,
+                None => return,
+            }
+        } else {
+            received
+        };
+
+        if state.events.len() >= self.options.capacity {
+            state.stats.dropped += 1;
+            state.lost += 1;
+            telemetry::increment(&telemetry::EVENTS_DROPPED);
+
+            match self.options.policy {
+                DropPolicy::DropOldest => {
+                    state.pop_front();
+                }
+                DropPolicy::DropNewest => {
+                    state.overflow(self.options.capacity);
+                    return;
+                }
+                DropPolicy::Coalesce => {
+                    // A duplicate carries nothing new, so nothing is lost by skipping it
+                    if let (Ok(event), _) = &received {
+                        if state.contains(event) {
+                            return;
+                        }
+                    }
+                    state.pop_front();
+                }
+            }
+            state.overflow(self.options.capacity);
+        }
+
+        state.count_kind(&received);
+        let count = self.options.coalesce_paths.then_some(1);
+        if let (Ok(event), Some(_)) = (&received.0, count) {
+            if coalescable(event) {
+                let position = state.removed + state.events.len() as u64;
+                state.positions.insert(event.paths.clone(), position);
+            }
+        }
+        state.events.push_back(Queued {
+            received,
+            count,
+            lost: None,
+        });
+        state.wake();
+        self.ready.notify_all();
+        if let Some(wake) = self.wake.get() {
+            wake();
+        }
+    }
+
+    /// Call `wake` as each event is buffered from now on. Only the first call has an effect.
+    #[cfg(unix)]
+    pub fn on_ready(&self, wake: impl Fn() + Send + Sync + 'static) {
+        let _ = self.wake.set(Box::new(wake));
+    }
+
+    /// Whether an event can be taken right away.
+    pub fn is_ready(&self) -> bool {
+        lock_recovered(&self.state).is_ready()
+    }
+
+    /// Take the next event, if any.
+    pub fn try_pop(&self) -> Option<Queued> {
+        lock_recovered(&self.state).pop()
+    }
+
+    /// Take the next event, blocking until one arrives. Returns `None` once the buffer is
+    /// closed and drained.
+    pub fn pop_wait(&self) -> Option<Queued> {
+        let mut state = lock_recovered(&self.state);
+
+        loop {
+            if let Some(received) = state.pop() {
+                return Some(received);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self
+                .ready
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Block for up to `timeout` until an event can be taken, returning whether one can.
+    pub fn wait_ready(&self, timeout: Duration) -> bool {
+        let state = lock_recovered(&self.state);
+        let (state, _) = self
+            .ready
+            .wait_timeout_while(state, timeout, |state| !state.is_ready() && !state.closed)
+            .unwrap_or_else(PoisonError::into_inner);
+        state.is_ready()
+    }
+
+    /// Discard (or stop discarding) everything the backend reports. Events buffered before
+    /// pausing are kept.
+    pub fn set_paused(&self, paused: bool) {
+        lock_recovered(&self.state).paused = paused;
+    }
+
+    /// Bytes taken by the buffered events and, with `coalesce_paths`, by where the event
+    /// about each set of paths is, for `memory_info`.
+    pub fn memory(&self) -> (usize, usize) {
+        let state = lock_recovered(&self.state);
+        let paths = state.events.iter().map(|queued| match &queued.received.0 {
+            Ok(event) => memory::paths(&event.paths),
+            Err(err) => memory::paths(&err.paths),
+        });
+        let events = state.events.capacity() * mem::size_of::<Queued>() + paths.sum::<usize>();
+        let positions = memory::table::<Vec<PathBuf>, u64>(state.positions.capacity())
+            + state
+                .positions
+                .keys()
+                .map(|paths| memory::paths(paths))
+                .sum::<usize>();
+        let kinds = state.kinds.as_ref().map_or(0, |kinds| {
+            memory::table::<(EventKind, Vec<PathBuf>), usize>(kinds.capacity())
+                + kinds
+                    .keys()
+                    .map(|(_, paths)| memory::paths(paths))
+                    .sum::<usize>()
+        });
+        (events, positions + kinds)
+    }
+
+    /// Give back the room a burst left behind, for `compact`.
+    pub fn compact(&self) {
+        let mut state = lock_recovered(&self.state);
+        state.events.shrink_to_fit();
+        state.positions.shrink_to_fit();
+        if let Some(kinds) = &mut state.kinds {
+            kinds.shrink_to_fit();
+        }
+    }
+
+    /// Events the buffer holds at most, besides a rescan request.
+    pub fn capacity(&self) -> usize {
+        self.options.capacity
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        let state = lock_recovered(&self.state);
+        BufferStats {
+            len: state.events.len(),
+            ..state.stats
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        lock_recovered(&self.state).closed
+    }
+
+    /// Mark the buffer as finished, waking anyone blocked on it.
+    pub fn close(&self) {
+        // Called as watchers are dropped, possibly from a resource callback, which mustn't panic
+        let mut state = lock_recovered(&self.state);
+        state.closed = true;
+        state.wake();
+        self.ready.notify_all();
+    }
+
+    /// Wake `waker` whenever an event is pushed, until it is removed.
+    pub fn add_waker(&self, waker: &Arc<Waker>) {
+        lock_recovered(&self.state)
+            .wakers
+            .push(Arc::downgrade(waker));
+    }
+
+    pub fn remove_waker(&self, waker: &Arc<Waker>) {
+        let waker = Arc::downgrade(waker);
+        lock_recovered(&self.state)
+            .wakers
+            .retain(|added| !added.ptr_eq(&waker));
+    }
+}
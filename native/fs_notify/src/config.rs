@@ -0,0 +1,248 @@
+use crate::atoms;
+use crate::error::WatchError;
+use notify::Config;
+use rustler::types::map::MapIterator;
+use rustler::{Atom, Encoder, Env, Term};
+use std::time::Duration;
+
+/// Backend tuning decoded from the Elixir config map, e.g. `%{poll_interval_ms: 5000}`.
+///
+/// Unset keys keep notify's defaults; options a backend has no use for are ignored by it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackendConfig {
+    pub poll_interval_ms: Option<u64>,
+    /// Have the poll backend compare file contents instead of relying on mtime alone
+    pub compare_contents: bool,
+    /// Descend into symlinked directories when watching recursively (notify's default)
+    pub follow_symlinks: bool,
+    /// Have FSEvents report the events after this event ID, including those from before the
+    /// watcher started
+    pub since_event_id: Option<u64>,
+    /// How long FSEvents gathers events before reporting them together
+    pub latency_ms: u64,
+    /// Bytes of changes ReadDirectoryChangesW can hold for each watched directory between
+    /// reads before it overflows
+    pub read_buffer_size: u32,
+    /// Changes ReadDirectoryChangesW reports
+    pub notify_filters: Vec<NotifyFilter>,
+    /// Have fanotify hold opens until Elixir allows or denies them
+    pub permission_events: bool,
+    /// How long fanotify holds an open before allowing it without a decision
+    pub permission_timeout_ms: u64,
+}
+
+/// A kind of change the Windows backend can subscribe to, one of the `FILE_NOTIFY_CHANGE_*`
+/// filters of ReadDirectoryChangesW.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotifyFilter {
+    /// Files created, removed or renamed
+    FileName,
+    /// Directories created, removed or renamed
+    DirName,
+    Attributes,
+    Size,
+    LastWrite,
+    LastAccess,
+    Creation,
+    /// Security descriptors
+    Security,
+}
+
+impl NotifyFilter {
+    /// The filters notify subscribes to, which leave out only `LastAccess`.
+    pub const DEFAULT: [NotifyFilter; 7] = [
+        NotifyFilter::FileName,
+        NotifyFilter::DirName,
+        NotifyFilter::Attributes,
+        NotifyFilter::Size,
+        NotifyFilter::LastWrite,
+        NotifyFilter::Creation,
+        NotifyFilter::Security,
+    ];
+
+    pub fn from_atom(atom: Atom) -> Option<Self> {
+        if atom == atoms::file_name() {
+            Some(NotifyFilter::FileName)
+        } else if atom == atoms::dir_name() {
+            Some(NotifyFilter::DirName)
+        } else if atom == atoms::attributes() {
+            Some(NotifyFilter::Attributes)
+        } else if atom == atoms::size() {
+            Some(NotifyFilter::Size)
+        } else if atom == atoms::last_write() {
+            Some(NotifyFilter::LastWrite)
+        } else if atom == atoms::last_access() {
+            Some(NotifyFilter::LastAccess)
+        } else if atom == atoms::creation() {
+            Some(NotifyFilter::Creation)
+        } else if atom == atoms::security() {
+            Some(NotifyFilter::Security)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_atom(self) -> Atom {
+        match self {
+            NotifyFilter::FileName => atoms::file_name(),
+            NotifyFilter::DirName => atoms::dir_name(),
+            NotifyFilter::Attributes => atoms::attributes(),
+            NotifyFilter::Size => atoms::size(),
+            NotifyFilter::LastWrite => atoms::last_write(),
+            NotifyFilter::LastAccess => atoms::last_access(),
+            NotifyFilter::Creation => atoms::creation(),
+            NotifyFilter::Security => atoms::security(),
+        }
+    }
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            poll_interval_ms: None,
+            compare_contents: false,
+            follow_symlinks: true,
+            since_event_id: None,
+            latency_ms: 0,
+            read_buffer_size: 16384,
+            notify_filters: NotifyFilter::DEFAULT.to_vec(),
+            permission_events: false,
+            permission_timeout_ms: 5000,
+        }
+    }
+}
+
+impl BackendConfig {
+    /// Set the options of a backend config map, keeping the others as they are.
+    pub fn apply_term(&mut self, term: Term) -> Result<(), WatchError> {
+        let iter =
+            MapIterator::new(term).ok_or_else(|| invalid_config("backend config must be a map"))?;
+
+        for (key, value) in iter {
+            let key: Atom = key
+                .decode()
+                .map_err(|_| invalid_config("backend config keys must be atoms"))?;
+
+            if !self.apply(key, value)? {
+                return Err(invalid_config("unknown backend config key"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set the option named by `key`, returning `false` if it isn't a backend option.
+    pub fn apply(&mut self, key: Atom, value: Term) -> Result<bool, WatchError> {
+        if key == atoms::poll_interval_ms() {
+            let ms: u64 = decode_value(value, "poll_interval_ms")?;
+            if ms == 0 {
+                return Err(invalid_config("poll_interval_ms must be positive"));
+            }
+            self.poll_interval_ms = Some(ms);
+        } else if key == atoms::compare_contents() {
+            self.compare_contents = decode_value(value, "compare_contents")?;
+        } else if key == atoms::follow_symlinks() {
+            self.follow_symlinks = decode_value(value, "follow_symlinks")?;
+        } else if key == atoms::since_event_id() {
+            self.since_event_id = decode_value(value, "since_event_id")?;
+        } else if key == atoms::latency_ms() {
+            self.latency_ms = decode_value(value, "latency_ms")?;
+        } else if key == atoms::read_buffer_size() {
+            let size: u32 = decode_value(value, "read_buffer_size")?;
+            if size == 0 || !size.is_multiple_of(4) {
+                return Err(invalid_config(
+                    "read_buffer_size must be a positive multiple of 4",
+                ));
+            }
+            self.read_buffer_size = size;
+        } else if key == atoms::notify_filters() {
+            let filters: Vec<Atom> = decode_value(value, "notify_filters")?;
+            if filters.is_empty() {
+                return Err(invalid_config(
+                    "notify_filters must name at least one filter",
+                ));
+            }
+            self.notify_filters = filters
+                .into_iter()
+                .map(|filter| {
+                    NotifyFilter::from_atom(filter).ok_or_else(|| {
+                        invalid_config(
+                            "notify_filters must be :file_name, :dir_name, :attributes, :size, \
+                             :last_write, :last_access, :creation or :security",
+                        )
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+        } else if key == atoms::permission_events() {
+            self.permission_events = decode_value(value, "permission_events")?;
+        } else if key == atoms::permission_timeout_ms() {
+            let ms: u64 = decode_value(value, "permission_timeout_ms")?;
+            if ms == 0 {
+                return Err(invalid_config("permission_timeout_ms must be positive"));
+            }
+            self.permission_timeout_ms = ms;
+        } else {
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// The options as the keys `apply` takes, with notify's defaults in place of those
+    /// left unset.
+    pub fn to_pairs<'a>(&self, env: Env<'a>) -> Vec<(Atom, Term<'a>)> {
+        let poll_interval = self.notify_config().poll_interval();
+        let notify_filters: Vec<Atom> = self
+            .notify_filters
+            .iter()
+            .map(|filter| filter.to_atom())
+            .collect();
+        vec![
+            (
+                atoms::poll_interval_ms(),
+                poll_interval
+                    .map(|interval| interval.as_millis() as u64)
+                    .encode(env),
+            ),
+            (atoms::compare_contents(), self.compare_contents.encode(env)),
+            (atoms::follow_symlinks(), self.follow_symlinks.encode(env)),
+            (atoms::since_event_id(), self.since_event_id.encode(env)),
+            (atoms::latency_ms(), self.latency_ms.encode(env)),
+            (atoms::read_buffer_size(), self.read_buffer_size.encode(env)),
+            (atoms::notify_filters(), notify_filters.encode(env)),
+            (
+                atoms::permission_events(),
+                self.permission_events.encode(env),
+            ),
+            (
+                atoms::permission_timeout_ms(),
+                self.permission_timeout_ms.encode(env),
+            ),
+        ]
+    }
+
+    pub fn notify_config(&self) -> Config {
+        let mut config = Config::default();
+
+        if let Some(ms) = self.poll_interval_ms {
+            config = config.with_poll_interval(Duration::from_millis(ms));
+        }
+
+        config
+            .with_compare_contents(self.compare_contents)
+            .with_follow_symlinks(self.follow_symlinks)
+    }
+}
+
+pub fn decode_value<'a, T: rustler::Decoder<'a>>(
+    value: Term<'a>,
+    key: &str,
+) -> Result<T, WatchError> {
+    value
+        .decode()
+        .map_err(|_| invalid_config(format!("invalid value for {key}")))
+}
+
+pub fn invalid_config(message: impl Into<String>) -> WatchError {
+    WatchError::new(atoms::invalid_config(), message)
+}
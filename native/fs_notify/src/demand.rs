@@ -0,0 +1,54 @@
+//! Demand for the events pushed to subscribers, with `on_demand`: no more are sent than have
+//! been asked for with `request_events`, GenStage-style, so a slow consumer leaves them in
+//! the watcher's buffer, where its drop policy applies, rather than in its mailbox.
+
+use crate::error::lock_recovered;
+use std::sync::{Condvar, Mutex, PoisonError};
+
+#[derive(Default)]
+pub struct Demand {
+    state: Mutex<DemandState>,
+    requested: Condvar,
+}
+
+#[derive(Default)]
+struct DemandState {
+    /// Events that may still be sent
+    pending: u64,
+    /// The watcher is gone; nothing more will be sent
+    closed: bool,
+}
+
+impl Demand {
+    /// Allow `count` more events to be sent.
+    pub fn request(&self, count: u64) {
+        let mut state = lock_recovered(&self.state);
+        state.pending = state.pending.saturating_add(count);
+        self.requested.notify_all();
+    }
+
+    /// Block until an event may be sent and count it as sent. Returns false once closed.
+    pub fn take(&self) -> bool {
+        let state = lock_recovered(&self.state);
+        let mut state = self
+            .requested
+            .wait_while(state, |state| state.pending == 0 && !state.closed)
+            .unwrap_or_else(PoisonError::into_inner);
+        if state.closed {
+            return false;
+        }
+        state.pending -= 1;
+        true
+    }
+
+    /// Events that may still be sent.
+    pub fn pending(&self) -> u64 {
+        lock_recovered(&self.state).pending
+    }
+
+    /// Wake the sender for good, as the watcher is torn down.
+    pub fn close(&self) {
+        lock_recovered(&self.state).closed = true;
+        self.requested.notify_all();
+    }
+}
@@ -0,0 +1,120 @@
+use crate::error::lock_recovered;
+use crate::hash;
+use crate::scan::Recursion;
+use ignore::WalkState;
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::UNIX_EPOCH;
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+/// What a file contributes to the digest of its directory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DigestMode {
+    /// Size and mtime, so nothing but the directory entries is read
+    Metadata,
+    /// Size and a hash of the contents, so files rewritten unchanged don't count
+    Contents,
+}
+
+const FILE: u8 = 0;
+const DIRECTORY: u8 = 1;
+const OTHER: u8 = 2;
+
+/// A stable XXH3 digest of everything below `root` within `recursion`, walked on several
+/// threads. Each directory's digest rolls up the sorted names, types and digests of its
+/// entries, so it only changes when something below it does, whatever the walk order.
+/// Entries that can't be read are left out.
+pub fn tree_digest(root: &Path, recursion: &Recursion, mode: DigestMode) -> u64 {
+    let Ok(metadata) = root.symlink_metadata() else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return leaf_digest(root, &metadata, mode);
+    }
+
+    // Leaf digests are taken during the walk, where hashing contents runs in parallel
+    let entries = Mutex::new(Vec::new());
+    recursion.walk_parallel(root).run(|| {
+        let entries = &entries;
+        Box::new(move |entry| {
+            let Ok(entry) = entry else {
+                return WalkState::Continue;
+            };
+            if entry.depth() == 0 {
+                return WalkState::Continue;
+            }
+            let Ok(metadata) = entry.path().symlink_metadata() else {
+                return WalkState::Continue;
+            };
+            let (kind, digest) = if metadata.is_dir() {
+                (DIRECTORY, 0)
+            } else if metadata.is_file() {
+                (FILE, leaf_digest(entry.path(), &metadata, mode))
+            } else {
+                (
+                    OTHER,
+                    leaf_digest(entry.path(), &metadata, DigestMode::Metadata),
+                )
+            };
+            let depth = entry.depth();
+            let path = entry.into_path();
+            lock_recovered(entries).push((depth, path, kind, digest));
+            WalkState::Continue
+        })
+    });
+
+    // Deepest first, so every directory's entries are complete before it is rolled up
+    let mut entries = entries.into_inner().unwrap_or_else(PoisonError::into_inner);
+    entries.sort_by_key(|(depth, ..)| std::cmp::Reverse(*depth));
+    let mut children: BTreeMap<PathBuf, BTreeMap<Vec<u8>, (u8, u64)>> = BTreeMap::new();
+    let mut top = BTreeMap::new();
+    for (depth, path, kind, digest) in entries {
+        let digest = match kind {
+            DIRECTORY => roll_up(children.remove(&path).unwrap_or_default()),
+            _ => digest,
+        };
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        // The root's entries are kept apart, since its path may be spelled differently
+        let siblings = match depth {
+            1 => &mut top,
+            _ => children.entry(parent.to_path_buf()).or_default(),
+        };
+        siblings.insert(name.as_encoded_bytes().to_vec(), (kind, digest));
+    }
+
+    roll_up(top)
+}
+
+fn roll_up(entries: BTreeMap<Vec<u8>, (u8, u64)>) -> u64 {
+    let mut hasher = Xxh3::new();
+    for (name, (kind, digest)) in entries {
+        hasher.update(&(name.len() as u64).to_le_bytes());
+        hasher.update(&name);
+        hasher.update(&[kind]);
+        hasher.update(&digest.to_le_bytes());
+    }
+    hasher.digest()
+}
+
+fn leaf_digest(path: &Path, metadata: &Metadata, mode: DigestMode) -> u64 {
+    let mut bytes = metadata.len().to_le_bytes().to_vec();
+    match mode {
+        DigestMode::Metadata => {
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_nanos());
+            bytes.extend(mtime.to_le_bytes());
+        }
+        DigestMode::Contents => {
+            let contents = hash::hash_file(path).unwrap_or(0);
+            bytes.extend(contents.to_le_bytes());
+        }
+    }
+    xxh3_64(&bytes)
+}
@@ -0,0 +1,112 @@
+//! Leading-edge debouncing: the first change to a path in a while is reported at once, and
+//! the changes following it within the debounce window are held back, or handed on to the
+//! trailing debouncer when both edges are reported.
+
+use crate::event::{self, Timestamp};
+use crate::sink::EventSink;
+use notify::{Event, EventHandler};
+use notify_debouncer_mini::{DebouncedEvent, DebouncedEventKind};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub struct LeadingEdge {
+    window: Duration,
+    /// When each path last changed; those quiet for longer than `window` are dropped
+    last_seen: HashMap<PathBuf, Instant>,
+    last_pruned: Instant,
+    /// Whether changes are reported as `notify-debouncer-mini` does, one `modified` event
+    /// per path, rather than as the backend reported them
+    per_path: bool,
+    sink: EventSink,
+    /// Debouncer the changes held back are handed to, when both edges are reported
+    followers: Option<Box<dyn EventHandler>>,
+}
+
+impl LeadingEdge {
+    pub fn new(window: Duration, per_path: bool, sink: EventSink) -> Self {
+        LeadingEdge {
+            window,
+            last_seen: HashMap::new(),
+            last_pruned: Instant::now(),
+            per_path,
+            sink,
+            followers: None,
+        }
+    }
+
+    pub fn with_followers(mut self, followers: impl EventHandler) -> Self {
+        self.followers = Some(Box::new(followers));
+        self
+    }
+
+    /// Note that `path` changed, returning whether it had been quiet for the whole window.
+    fn leads(&mut self, path: &Path, now: Instant) -> bool {
+        let previous = self.last_seen.insert(path.to_path_buf(), now);
+        previous.is_none_or(|seen| now.duration_since(seen) >= self.window)
+    }
+
+    fn follow(&mut self, event: Event) {
+        if let Some(followers) = &mut self.followers {
+            followers.handle_event(Ok(event));
+        }
+    }
+}
+
+impl EventHandler for LeadingEdge {
+    fn handle_event(&mut self, event: notify::Result<Event>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                match &mut self.followers {
+                    Some(followers) => followers.handle_event(Err(err)),
+                    None => self.sink.send(Err(err), Timestamp::now()),
+                }
+                return;
+            }
+        };
+
+        let now = Instant::now();
+        if now.duration_since(self.last_pruned) >= self.window {
+            let window = self.window;
+            self.last_seen
+                .retain(|_, seen| now.duration_since(*seen) < window);
+            self.last_pruned = now;
+        }
+
+        // Events about no path in particular, e.g. markers, aren't held back
+        if event.paths.is_empty() {
+            self.sink.send(Ok(event), Timestamp::now());
+            return;
+        }
+        if !self.per_path {
+            // Every path is noted, not just those up to the first that leads
+            let mut leads = false;
+            for path in &event.paths {
+                leads |= self.leads(path, now);
+            }
+            if leads {
+                self.sink.send(Ok(event), Timestamp::now());
+            } else {
+                self.follow(event);
+            }
+            return;
+        }
+
+        let mut followers = Vec::new();
+        for path in &event.paths {
+            if self.leads(path, now) {
+                let debounced = DebouncedEvent::new(path.clone(), DebouncedEventKind::Any);
+                self.sink
+                    .send(Ok(event::from_debounced(debounced)), Timestamp::now());
+            } else {
+                followers.push(path.clone());
+            }
+        }
+        if !followers.is_empty() {
+            let mut event = event;
+            event.paths = followers;
+            self.follow(event);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{atoms, logs};
+use notify::ErrorKind;
+use rustler::{Atom, Encoder, Env, NifResult, Term};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Failure reason returned to Elixir as `{:error, {reason, message}}`.
+pub struct WatchError {
+    reason: Atom,
+    message: String,
+}
+
+impl WatchError {
+    pub fn new(reason: Atom, message: impl Into<String>) -> Self {
+        WatchError {
+            reason,
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid_backend(message: impl Into<String>) -> Self {
+        WatchError::new(atoms::invalid_backend(), message)
+    }
+
+    /// A bug in the NIF, e.g. a panic, rather than anything the caller did.
+    pub fn internal(message: impl Into<String>) -> Self {
+        WatchError::new(atoms::internal(), message)
+    }
+
+    pub fn watcher_not_found() -> Self {
+        WatchError::new(atoms::watcher_not_found(), "watcher has been stopped")
+    }
+
+    /// The user has as many inotify watches as `fs.inotify.max_user_watches` allows.
+    fn watch_limit_reached() -> Self {
+        #[cfg(target_os = "linux")]
+        let limit = crate::inotify::max_user_watches().map_or_else(
+            |_| "fs.inotify.max_user_watches".to_string(),
+            |limit| format!("fs.inotify.max_user_watches = {limit}"),
+        );
+        #[cfg(not(target_os = "linux"))]
+        let limit = "the OS limit".to_string();
+        WatchError::new(
+            atoms::watch_limit_reached(),
+            format!("watch limit reached ({limit}); raise it or watch fewer directories"),
+        )
+    }
+
+    /// The user has as many inotify instances as `fs.inotify.max_user_instances` allows.
+    fn instance_limit_reached() -> Self {
+        #[cfg(target_os = "linux")]
+        let limit = crate::inotify::max_user_instances().map_or_else(
+            |_| "fs.inotify.max_user_instances".to_string(),
+            |limit| format!("fs.inotify.max_user_instances = {limit}"),
+        );
+        #[cfg(not(target_os = "linux"))]
+        let limit = "the OS limit".to_string();
+        WatchError::new(
+            atoms::watch_limit_reached(),
+            format!("watcher instance limit reached ({limit}); raise it or share watchers"),
+        )
+    }
+}
+
+impl Encoder for WatchError {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        (self.reason, &self.message).encode(env)
+    }
+}
+
+impl From<WatchError> for rustler::Error {
+    fn from(err: WatchError) -> Self {
+        rustler::Error::Term(Box::new(err))
+    }
+}
+
+impl From<notify::Error> for WatchError {
+    fn from(err: notify::Error) -> Self {
+        let message = err.to_string();
+
+        match err.kind {
+            ErrorKind::PathNotFound => WatchError::new(atoms::enoent(), message),
+            ErrorKind::MaxFilesWatch => WatchError::watch_limit_reached(),
+            ErrorKind::WatchNotFound => WatchError::new(atoms::watch_not_found(), message),
+            ErrorKind::InvalidConfig(_) => WatchError::new(atoms::invalid_config(), message),
+            ErrorKind::Io(ref io_err) if is_instance_limit(io_err) => {
+                WatchError::instance_limit_reached()
+            }
+            ErrorKind::Io(ref io_err) => WatchError::new(io_error_reason(io_err), message),
+            ErrorKind::Generic(_) => WatchError::new(atoms::io_error(), message),
+        }
+    }
+}
+
+/// Whether creating an inotify instance failed because the user has too many.
+#[cfg(target_os = "linux")]
+fn is_instance_limit(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EMFILE) && crate::inotify::instances_exhausted()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_instance_limit(_err: &io::Error) -> bool {
+    false
+}
+
+fn io_error_reason(err: &io::Error) -> Atom {
+    match err.kind() {
+        io::ErrorKind::NotFound => atoms::enoent(),
+        io::ErrorKind::PermissionDenied => atoms::eacces(),
+        _ => atoms::io_error(),
+    }
+}
+
+/// Convert a notify error straight into a NIF error, for use with `map_err`.
+pub fn nif_error(err: notify::Error) -> rustler::Error {
+    WatchError::from(err).into()
+}
+
+/// Run the body of a NIF, returning a panic in it as `{:error, {:internal, message}}` rather
+/// than raising it in the caller.
+pub fn guarded<T>(body: impl FnOnce() -> NifResult<T>) -> NifResult<T> {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        logs::error(format!("NIF panicked: {message}"));
+        Err(WatchError::internal(message).into())
+    })
+}
+
+/// Lock `mutex` even if a thread panicked while holding it, so one panic, in a NIF, a
+/// backend callback or a delivery thread, doesn't take down everything else using it.
+pub fn lock_recovered<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Read `lock` even if a thread panicked while writing to it, as for `lock_recovered`.
+pub fn read_recovered<T: ?Sized>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Write to `lock` even if a thread panicked while writing to it, as for `lock_recovered`.
+pub fn write_recovered<T: ?Sized>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
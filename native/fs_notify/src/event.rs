@@ -0,0 +1,1265 @@
+use crate::error::{lock_recovered, read_recovered};
+use crate::hash::Hashes;
+use crate::tail::{self, TailRead, Tails};
+use crate::{atoms, backend_named, longpath, memory, scan, settle, sink, size, Roots};
+use notify::event::{AccessKind, AccessMode, MetadataKind, ModifyKind, RenameMode};
+use notify::{Event, EventKind};
+use notify_debouncer_mini::{DebouncedEvent, DebouncedEventKind};
+use rustler::{Atom, Encoder, Env, NewBinary, NifMap, Term};
+use std::collections::HashMap;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use unicode_normalization::UnicodeNormalization;
+
+/// When an event was received from the backend, in microseconds.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    /// Monotonic time since an arbitrary fixed origin; only meaningful relative to other events
+    pub monotonic: u64,
+    /// Wall-clock time since the Unix epoch
+    pub system: u64,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Timestamp::from_instant(Instant::now())
+    }
+
+    /// Stamp an event that happened at `instant`, e.g. as recorded by the full debouncer.
+    pub fn from_instant(instant: Instant) -> Self {
+        static ORIGIN: OnceLock<Instant> = OnceLock::new();
+        let origin = *ORIGIN.get_or_init(Instant::now);
+
+        let system = SystemTime::now() - instant.elapsed();
+
+        Timestamp {
+            monotonic: instant.saturating_duration_since(origin).as_micros() as u64,
+            system: system
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_micros() as u64),
+        }
+    }
+}
+
+/// Whether backend events' trackers are FSEvents event IDs: on macOS only the FSEvents
+/// backend sets them, while elsewhere they pair up the halves of renames.
+const TRACKERS_ARE_EVENT_IDS: bool = cfg!(all(target_os = "macos", not(feature = "macos_kqueue")));
+
+/// Start of the info the FSEvents backend gives events whose stream flags are worth
+/// knowing, followed by their names separated by spaces, e.g. `fsevent flags:
+/// must_scan_sub_dirs user_dropped`.
+pub const FSEVENT_FLAGS: &str = "fsevent flags:";
+
+/// Start of the info the fanotify backend gives permission events, followed by the ID
+/// they're answered by, e.g. `fanotify permission: 12`.
+pub const FANOTIFY_PERMISSION: &str = "fanotify permission:";
+
+/// An FSEvents stream flag reported on events, by the name the backend gives it.
+fn fsevent_flag_atom(name: &str) -> Option<Atom> {
+    Some(match name {
+        "must_scan_sub_dirs" => atoms::must_scan_sub_dirs(),
+        "user_dropped" => atoms::user_dropped(),
+        "kernel_dropped" => atoms::kernel_dropped(),
+        "event_ids_wrapped" => atoms::event_ids_wrapped(),
+        "root_changed" => atoms::root_changed(),
+        "mount" => atoms::mount(),
+        "unmount" => atoms::unmount(),
+        "own_event" => atoms::own_event(),
+        _ => return None,
+    })
+}
+
+/// The FSEvents stream flags in a backend event's info, or none.
+fn fsevent_flags(event: &Event) -> Vec<Atom> {
+    let Some(names) = event
+        .info()
+        .and_then(|info| info.strip_prefix(FSEVENT_FLAGS))
+    else {
+        return Vec::new();
+    };
+    names
+        .split_whitespace()
+        .filter_map(fsevent_flag_atom)
+        .collect()
+}
+
+/// The ID a fanotify permission event is answered by, if the backend event is one.
+fn permission_id(event: &Event) -> Option<u64> {
+    event
+        .info()?
+        .strip_prefix(FANOTIFY_PERMISSION)?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// An arbitrary term attached to a watcher by its owner, kept in external term format so
+/// it can be re-created in whichever env it is returned to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label(Arc<[u8]>);
+
+impl Label {
+    pub fn from_term(term: Term) -> Self {
+        Label(Arc::from(term.to_binary().as_slice()))
+    }
+}
+
+impl Encoder for Label {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let (term, _) = env
+            .binary_to_term(&self.0)
+            .expect("label is valid external term format");
+        term
+    }
+}
+
+/// A single event as delivered to Elixir: the change, followed by when it was received and
+/// its sequence number, e.g. `{kind, path, file_type, monotonic_us, system_us, seq}`. With
+/// `include_stat: true` the path's size and mtime follow, then with `fsevent_flags: true`
+/// `{:fsevent_flags, flags}` and with `include_os_pid: true` `{:os_pid, pid}`, then
+/// fanotify permission events add `{:permission_id, id}`, with `coalesce_paths: true`
+/// `{:count, n}` follows, and with `label_events: true` `{:label, label}` is appended.
+/// With `event_format: :map` it is delivered as an [`EventMap`] instead, and with
+/// `event_format: {:tuple, 1}` as the change alone.
+#[derive(Clone)]
+pub struct WatchEvent {
+    change: Change,
+    time: Timestamp,
+    /// Per-watcher sequence number, assigned when the event is queued
+    pub seq: u64,
+    /// Size and mtime of the path, when they are included in events
+    stat: Option<FileStat>,
+    /// Watcher label, when it is included in events
+    pub label: Option<Label>,
+    /// Deliver paths as their raw bytes rather than as UTF-8 with invalid sequences replaced
+    pub raw_paths: bool,
+    /// FSEvents ID of the backend event this was translated from; not delivered with it
+    pub event_id: Option<u64>,
+    /// FSEvents stream flags of the backend event this was translated from
+    fsevent_flags: Vec<Atom>,
+    /// Deliver `fsevent_flags` with the event
+    pub include_fsevent_flags: bool,
+    /// ID of the OS process that made the change, where the backend tells
+    os_pid: Option<u32>,
+    /// Deliver `os_pid` with the event
+    pub include_os_pid: bool,
+    /// What the access this reports is to be allowed or denied by, if it waits for that
+    permission_id: Option<u64>,
+    /// Number of backend events about the path this stands for, when they are coalesced
+    count: Option<u64>,
+    /// Deliver the event as a map with named fields rather than as a tuple
+    pub as_map: bool,
+    /// Deliver only the change, e.g. `{kind, path, file_type}`, as version 1 of the format
+    pub baseline: bool,
+}
+
+#[derive(Clone)]
+enum Change {
+    /// `kind, path, file_type`
+    Path {
+        kind: Atom,
+        path: PathBuf,
+        file_type: Atom,
+    },
+    /// `:renamed, from_path, to_path`
+    Renamed { from: PathBuf, to: PathBuf },
+    /// `:overflow, path`: events under `path` were lost and it needs a full rescan. How
+    /// many is known when the watcher's own buffer lost them
+    Overflow { path: PathBuf, lost: Option<u64> },
+    /// `:root_removed, path`: the watched root `path` was removed or moved away
+    RootRemoved { path: PathBuf },
+    /// `:root_created, path`: the root `path`, which didn't exist yet, is now watched
+    RootCreated { path: PathBuf },
+    /// `:appended, path, data`: `data` was appended to the followed file `path`
+    Appended { path: PathBuf, data: Vec<u8> },
+    /// `:ready`: the initial scan is complete
+    Ready,
+    /// `:backend_fallback, backend`: the watcher uses `backend` in place of the one asked
+    /// for, e.g. because its root is on a network filesystem
+    BackendFallback { backend: Atom },
+    /// `:bulk_change, dir, count`: `count` events about entries of `dir` were summarized
+    Bulk { dir: PathBuf, count: u64 },
+}
+
+impl WatchEvent {
+    fn with_change(change: Change, time: Timestamp) -> Self {
+        WatchEvent {
+            change,
+            time,
+            seq: 0,
+            stat: None,
+            label: None,
+            raw_paths: false,
+            event_id: None,
+            fsevent_flags: Vec::new(),
+            include_fsevent_flags: false,
+            os_pid: None,
+            include_os_pid: false,
+            permission_id: None,
+            count: None,
+            as_map: false,
+            baseline: false,
+        }
+    }
+
+    fn new(kind: Atom, path: &Path, time: Timestamp) -> Self {
+        let change = Change::Path {
+            kind,
+            path: path.to_path_buf(),
+            file_type: file_type_atom(path),
+        };
+        WatchEvent::with_change(change, time)
+    }
+
+    fn renamed(from: &Path, to: &Path, time: Timestamp) -> Self {
+        let change = Change::Renamed {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        };
+        WatchEvent::with_change(change, time)
+    }
+
+    fn appended(path: &Path, data: Vec<u8>, time: Timestamp) -> Self {
+        let change = Change::Appended {
+            path: path.to_path_buf(),
+            data,
+        };
+        WatchEvent::with_change(change, time)
+    }
+
+    fn ready(time: Timestamp) -> Self {
+        WatchEvent::with_change(Change::Ready, time)
+    }
+
+    fn backend_fallback(backend: Atom, time: Timestamp) -> Self {
+        WatchEvent::with_change(Change::BackendFallback { backend }, time)
+    }
+
+    fn bulk_change(dir: &Path, count: u64, time: Timestamp) -> Self {
+        let change = Change::Bulk {
+            dir: dir.to_path_buf(),
+            count,
+        };
+        WatchEvent::with_change(change, time)
+    }
+
+    /// Directory of the entry changed, or of a rename's destination, for summarizing.
+    /// Events waiting on a permission decision aren't summarized, so they can still be
+    /// answered.
+    fn summary_dir(&self) -> Option<&Path> {
+        if self.permission_id.is_some() {
+            return None;
+        }
+        match &self.change {
+            Change::Path { path, .. } | Change::Renamed { to: path, .. } => path.parent(),
+            _ => None,
+        }
+    }
+
+    /// The kind `kinds` filtering applies to; overflow, root, ready and fallback markers,
+    /// and summaries, are always kept.
+    fn kind(&self) -> Option<Atom> {
+        match &self.change {
+            Change::Path { kind, .. } => Some(*kind),
+            Change::Renamed { .. } => Some(atoms::renamed()),
+            Change::Appended { .. } => Some(atoms::appended()),
+            Change::Overflow { .. }
+            | Change::RootRemoved { .. }
+            | Change::RootCreated { .. }
+            | Change::Ready
+            | Change::BackendFallback { .. }
+            | Change::Bulk { .. } => None,
+        }
+    }
+
+    fn root_removed(path: &Path, time: Timestamp) -> Self {
+        let change = Change::RootRemoved {
+            path: path.to_path_buf(),
+        };
+        WatchEvent::with_change(change, time)
+    }
+
+    fn root_created(path: &Path, time: Timestamp) -> Self {
+        let change = Change::RootCreated {
+            path: path.to_path_buf(),
+        };
+        WatchEvent::with_change(change, time)
+    }
+
+    fn overflow(path: &Path, time: Timestamp) -> Self {
+        let change = Change::Overflow {
+            path: path.to_path_buf(),
+            lost: None,
+        };
+        WatchEvent::with_change(change, time)
+    }
+
+    /// Note how many events an overflow stands for; other events are left alone.
+    pub fn set_lost(&mut self, count: u64) {
+        if let Change::Overflow { lost, .. } = &mut self.change {
+            *lost = Some(count);
+        }
+    }
+
+    /// Record the size, mtime and, if it has been hashed, content hash of the path
+    /// changed, or of a rename's destination.
+    fn read_stat(&mut self, hashes: Option<&Hashes>) {
+        let path = match &self.change {
+            Change::Path { path, .. } | Change::Renamed { to: path, .. } => path,
+            _ => return,
+        };
+        let mut stat = FileStat::read(path);
+        stat.hash = hashes.and_then(|hashes| lock_recovered(hashes).get(path).copied());
+        self.stat = Some(stat);
+    }
+
+    /// Bytes the event takes up, besides its own size, for `memory_info`.
+    pub fn memory(&self) -> usize {
+        let paths = match &self.change {
+            Change::Path { path, .. }
+            | Change::Overflow { path, .. }
+            | Change::RootRemoved { path }
+            | Change::RootCreated { path }
+            | Change::Bulk { dir: path, .. } => memory::path(path),
+            Change::Appended { path, data } => memory::path(path) + data.capacity(),
+            Change::Renamed { from, to } => memory::path(from) + memory::path(to),
+            Change::Ready | Change::BackendFallback { .. } => 0,
+        };
+        paths + self.fsevent_flags.capacity() * mem::size_of::<Atom>()
+    }
+
+    fn map_paths(&mut self, mut f: impl FnMut(&mut PathBuf)) {
+        match &mut self.change {
+            Change::Path { path, .. }
+            | Change::Overflow { path, .. }
+            | Change::RootRemoved { path }
+            | Change::RootCreated { path }
+            | Change::Appended { path, .. }
+            | Change::Bulk { dir: path, .. } => f(path),
+            Change::Renamed { from, to } => {
+                f(from);
+                f(to);
+            }
+            Change::Ready | Change::BackendFallback { .. } => {}
+        }
+    }
+
+    fn encode_path<'a>(&self, path: &Path, env: Env<'a>) -> Term<'a> {
+        if !self.raw_paths {
+            return path.to_string_lossy().encode(env);
+        }
+
+        let bytes = path.as_os_str().as_encoded_bytes();
+        let mut binary = NewBinary::new(env, bytes.len());
+        binary.as_mut_slice().copy_from_slice(bytes);
+        binary.into()
+    }
+
+    fn to_map<'a>(&self, env: Env<'a>) -> EventMap<'a> {
+        let mut map = EventMap {
+            kind: atoms::unknown(),
+            path: None,
+            from: None,
+            r#type: atoms::unknown(),
+            ts: self.time.system,
+            monotonic_time: self.time.monotonic,
+            seq: self.seq,
+            stat: self.stat.clone(),
+            data: None,
+            backend: None,
+            count: self.count,
+            fsevent_flags: self
+                .include_fsevent_flags
+                .then(|| self.fsevent_flags.clone()),
+            os_pid: self.os_pid.filter(|_| self.include_os_pid),
+            permission_id: self.permission_id,
+            label: self.label.as_ref().map(|label| label.encode(env)),
+        };
+        match &self.change {
+            Change::Path {
+                kind,
+                path,
+                file_type,
+            } => {
+                map.kind = *kind;
+                map.path = Some(self.encode_path(path, env));
+                map.r#type = *file_type;
+            }
+            Change::Renamed { from, to } => {
+                map.kind = atoms::renamed();
+                map.path = Some(self.encode_path(to, env));
+                map.from = Some(self.encode_path(from, env));
+            }
+            Change::Overflow { path, lost } => {
+                map.kind = atoms::overflow();
+                map.path = Some(self.encode_path(path, env));
+                if lost.is_some() {
+                    map.count = *lost;
+                }
+            }
+            Change::RootRemoved { path } => {
+                map.kind = atoms::root_removed();
+                map.path = Some(self.encode_path(path, env));
+            }
+            Change::RootCreated { path } => {
+                map.kind = atoms::root_created();
+                map.path = Some(self.encode_path(path, env));
+            }
+            Change::Appended { path, data } => {
+                let mut binary = NewBinary::new(env, data.len());
+                binary.as_mut_slice().copy_from_slice(data);
+                map.kind = atoms::appended();
+                map.path = Some(self.encode_path(path, env));
+                map.data = Some(Term::from(binary));
+            }
+            Change::Ready => map.kind = atoms::ready(),
+            Change::BackendFallback { backend } => {
+                map.kind = atoms::backend_fallback();
+                map.backend = Some(*backend);
+            }
+            Change::Bulk { dir, count } => {
+                map.kind = atoms::bulk_change();
+                map.path = Some(self.encode_path(dir, env));
+                map.r#type = atoms::directory();
+                map.count = Some(*count);
+            }
+        }
+        map
+    }
+}
+
+/// An event as delivered with `event_format: :map`, `%{kind: kind, path: path, type:
+/// file_type, ts: system_us, seq: seq, ...}`, so fields can be added without breaking
+/// pattern matches. Fields that don't apply to an event are `nil`.
+#[derive(NifMap)]
+struct EventMap<'a> {
+    kind: Atom,
+    path: Option<Term<'a>>,
+    /// Source of a rename, whose destination is `path`
+    from: Option<Term<'a>>,
+    r#type: Atom,
+    /// Wall-clock time since the Unix epoch in microseconds
+    ts: u64,
+    monotonic_time: u64,
+    seq: u64,
+    /// Only set with `include_stat` or `hash_contents`
+    stat: Option<FileStat>,
+    /// Bytes appended to a followed file
+    data: Option<Term<'a>>,
+    /// Backend fallen back to
+    backend: Option<Atom>,
+    /// Number of events coalesced or summarized into this one, or lost, for an overflow
+    count: Option<u64>,
+    fsevent_flags: Option<Vec<Atom>>,
+    os_pid: Option<u32>,
+    permission_id: Option<u64>,
+    label: Option<Term<'a>>,
+}
+
+impl Encoder for WatchEvent {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        if self.as_map {
+            return self.to_map(env).encode(env);
+        }
+        let (monotonic, system, seq) = (self.time.monotonic, self.time.system, self.seq);
+
+        let mut elements = match &self.change {
+            Change::Path {
+                kind,
+                path,
+                file_type,
+            } => vec![
+                kind.encode(env),
+                self.encode_path(path, env),
+                file_type.encode(env),
+            ],
+            Change::Renamed { from, to } => {
+                vec![
+                    atoms::renamed().encode(env),
+                    self.encode_path(from, env),
+                    self.encode_path(to, env),
+                ]
+            }
+            Change::Overflow { path, .. } => {
+                vec![atoms::overflow().encode(env), self.encode_path(path, env)]
+            }
+            Change::RootRemoved { path } => {
+                vec![
+                    atoms::root_removed().encode(env),
+                    self.encode_path(path, env),
+                ]
+            }
+            Change::RootCreated { path } => {
+                vec![
+                    atoms::root_created().encode(env),
+                    self.encode_path(path, env),
+                ]
+            }
+            Change::Appended { path, data } => {
+                let mut binary = NewBinary::new(env, data.len());
+                binary.as_mut_slice().copy_from_slice(data);
+                vec![
+                    atoms::appended().encode(env),
+                    self.encode_path(path, env),
+                    Term::from(binary),
+                ]
+            }
+            Change::Ready => vec![atoms::ready().encode(env)],
+            Change::BackendFallback { backend } => {
+                vec![atoms::backend_fallback().encode(env), backend.encode(env)]
+            }
+            Change::Bulk { dir, count } => vec![
+                atoms::bulk_change().encode(env),
+                self.encode_path(dir, env),
+                count.encode(env),
+            ],
+        };
+        if self.baseline {
+            return rustler::types::tuple::make_tuple(env, &elements);
+        }
+        elements.extend([monotonic.encode(env), system.encode(env), seq.encode(env)]);
+        if let Some(stat) = &self.stat {
+            elements.push(stat.encode(env));
+        }
+        if self.include_fsevent_flags {
+            elements.push((atoms::fsevent_flags(), &self.fsevent_flags).encode(env));
+        }
+        if self.include_os_pid {
+            elements.push((atoms::os_pid(), self.os_pid).encode(env));
+        }
+        if let Some(id) = self.permission_id {
+            elements.push((atoms::permission_id(), id).encode(env));
+        }
+        if let Some(count) = self.count {
+            elements.push((atoms::count(), count).encode(env));
+        }
+        if let Some(label) = &self.label {
+            elements.push((atoms::label(), label).encode(env));
+        }
+
+        rustler::types::tuple::make_tuple(env, &elements)
+    }
+}
+
+/// `%{size: bytes, mtime: us, file_id: id, hash: xxh3}` of an event's path when it was
+/// translated, with `nil`s if it no longer exists. `hash` is only set with `hash_contents`.
+#[derive(Debug, Clone, NifMap)]
+struct FileStat {
+    size: Option<u64>,
+    /// Modification time since the Unix epoch in microseconds
+    mtime: Option<u64>,
+    /// Inode (Unix) or file index (Windows), which a file keeps across renames
+    file_id: Option<u64>,
+    /// XXH3 hash of the contents of a changed file
+    hash: Option<u64>,
+}
+
+impl FileStat {
+    fn read(path: &Path) -> Self {
+        let metadata = path.metadata().ok();
+        let mtime = metadata
+            .as_ref()
+            .and_then(|metadata| metadata.modified().ok());
+        FileStat {
+            size: metadata.as_ref().map(|metadata| metadata.len()),
+            mtime: mtime.and_then(|mtime| {
+                let since = mtime.duration_since(UNIX_EPOCH).ok()?;
+                Some(since.as_micros() as u64)
+            }),
+            file_id: metadata
+                .as_ref()
+                .and_then(|metadata| file_id(path, metadata)),
+            hash: None,
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn file_id(_path: &Path, metadata: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+/// The file index isn't in `Metadata` on stable, so the file is opened to ask for it.
+#[cfg(windows)]
+pub fn file_id(path: &Path, _metadata: &std::fs::Metadata) -> Option<u64> {
+    let handle = winapi_util::Handle::from_path_any(path).ok()?;
+    let information = winapi_util::file::information(&handle).ok()?;
+    Some(information.file_index())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn file_id(_path: &Path, _metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// How finely backend event kinds are reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EventDetail {
+    /// `:created`, `:modified`, `:attrib_changed`, `:removed`, `:renamed`, `:written`, `:meta`
+    /// and `:unknown`
+    #[default]
+    Basic,
+    /// Splits `:modified` into `:modified_data` and `:modified_metadata` and reports access
+    /// events (`:access_open`, `:access_read`, `:access_close_write`, ...) instead of `:unknown`
+    Detailed,
+}
+
+impl EventDetail {
+    pub fn from_atom(atom: Atom) -> Option<Self> {
+        if atom == atoms::basic() {
+            Some(EventDetail::Basic)
+        } else if atom == atoms::detailed() {
+            Some(EventDetail::Detailed)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_atom(self) -> Atom {
+        match self {
+            EventDetail::Basic => atoms::basic(),
+            EventDetail::Detailed => atoms::detailed(),
+        }
+    }
+}
+
+/// Unicode normalization form event paths are converted to. FSEvents reports decomposed
+/// (NFD) names, which don't compare equal to the composed (NFC) strings Elixir usually has.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    Nfc,
+    Nfd,
+}
+
+impl Normalization {
+    pub fn from_atom(atom: Atom) -> Option<Self> {
+        if atom == atoms::nfc() {
+            Some(Normalization::Nfc)
+        } else if atom == atoms::nfd() {
+            Some(Normalization::Nfd)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_atom(self) -> Atom {
+        match self {
+            Normalization::Nfc => atoms::nfc(),
+            Normalization::Nfd => atoms::nfd(),
+        }
+    }
+
+    /// `path` in this form. Paths that aren't valid Unicode are left alone.
+    fn apply(self, path: &mut PathBuf) {
+        let Some(name) = path.to_str() else {
+            return;
+        };
+        let normalized: String = match self {
+            Normalization::Nfc => name.nfc().collect(),
+            Normalization::Nfd => name.nfd().collect(),
+        };
+        *path = PathBuf::from(normalized);
+    }
+}
+
+/// Translates backend events into the events delivered to Elixir.
+///
+/// Pairs the `From` and `To` halves of a rename into a single `{:renamed, from, to}` event.
+/// Backends that report a tracker (inotify cookies) are matched by tracker, the others
+/// (Windows) by order. A `To` without a matching `From` was moved in from outside the watch
+/// and is reported as `:created`; a `From` that is still unpaired when the batch is flushed
+/// was moved out and is reported as `:removed`.
+///
+/// A backend queue overflow or rescan request is reported as `{:overflow, path}` for the
+/// affected path, or for every watched root when the backend doesn't say which.
+///
+/// Entries found by the initial scan are reported as `:discovered`, followed by `{:ready}`.
+/// Files that stopped changing are reported as `:settled`.
+/// A watched root that goes away is reported as `{:root_removed, path}`, and one that
+/// didn't exist yet as `{:root_created, path}` once it is created.
+///
+/// When `kinds` is set, events of any other kind are dropped once translated, and with
+/// `ignore_attrib` so are metadata-only changes. With
+/// `canonicalize`, paths are resolved with [`canonicalize`], and with `normalization` they
+/// are converted to that Unicode form. With `relative_paths`, paths are made relative to the
+/// deepest watched root containing them; a root itself becomes an empty path.
+pub struct EventTranslator {
+    detail: EventDetail,
+    kinds: Option<Vec<Atom>>,
+    ignore_attrib: bool,
+    attrib_classes: bool,
+    include_stat: bool,
+    hashes: Option<Hashes>,
+    tails: Option<Tails>,
+    canonicalize: bool,
+    normalization: Option<Normalization>,
+    coalesce_saves: bool,
+    relative_paths: bool,
+    /// Events about a directory's entries in one batch above which they are summarized
+    summary_threshold: Option<usize>,
+    roots: Roots,
+    pending: Vec<(Option<usize>, PathBuf, Timestamp)>,
+    last_paired: Option<usize>,
+}
+
+impl EventTranslator {
+    pub fn new(detail: EventDetail, roots: Roots) -> Self {
+        EventTranslator {
+            detail,
+            kinds: None,
+            ignore_attrib: false,
+            attrib_classes: false,
+            include_stat: false,
+            hashes: None,
+            tails: None,
+            canonicalize: false,
+            normalization: None,
+            coalesce_saves: false,
+            relative_paths: false,
+            summary_threshold: None,
+            roots,
+            pending: Vec::new(),
+            last_paired: None,
+        }
+    }
+
+    /// Only report events of these kinds.
+    pub fn with_kinds(self, kinds: Option<Vec<Atom>>) -> Self {
+        EventTranslator { kinds, ..self }
+    }
+
+    /// Drop changes to metadata only, e.g. from `chmod` or `chown`.
+    pub fn with_ignore_attrib(self, ignore_attrib: bool) -> Self {
+        EventTranslator {
+            ignore_attrib,
+            ..self
+        }
+    }
+
+    /// Report metadata changes as `:permissions`, `:ownership` or `:timestamps` where
+    /// the backend or [`AttribTracker`](crate::attrib::AttribTracker) tells which.
+    pub fn with_attrib_classes(self, attrib_classes: bool) -> Self {
+        EventTranslator {
+            attrib_classes,
+            ..self
+        }
+    }
+
+    /// Include the size and mtime of each changed path.
+    pub fn with_stat(self, include_stat: bool) -> Self {
+        EventTranslator {
+            include_stat,
+            ..self
+        }
+    }
+
+    /// Include the content hashes of changed files, which implies their stats.
+    pub fn with_hashes(self, hashes: Option<Hashes>) -> Self {
+        EventTranslator { hashes, ..self }
+    }
+
+    /// Report data appended to these followed files.
+    pub fn with_tails(self, tails: Option<Tails>) -> Self {
+        EventTranslator { tails, ..self }
+    }
+
+    /// Report paths in canonical form.
+    pub fn with_canonical_paths(self, canonicalize: bool) -> Self {
+        EventTranslator {
+            canonicalize,
+            ..self
+        }
+    }
+
+    /// Report atomic saves as a single modification of the saved file.
+    pub fn with_save_coalescing(self, coalesce_saves: bool) -> Self {
+        EventTranslator {
+            coalesce_saves,
+            ..self
+        }
+    }
+
+    /// Report paths in this Unicode normalization form.
+    pub fn with_normalization(self, normalization: Option<Normalization>) -> Self {
+        EventTranslator {
+            normalization,
+            ..self
+        }
+    }
+
+    /// Report paths relative to the watched root containing them.
+    pub fn with_relative_paths(self, relative_paths: bool) -> Self {
+        EventTranslator {
+            relative_paths,
+            ..self
+        }
+    }
+
+    /// Summarize the events about a directory's entries once there are more than
+    /// `threshold` of them in a batch.
+    pub fn with_summary_threshold(self, summary_threshold: Option<usize>) -> Self {
+        EventTranslator {
+            summary_threshold,
+            ..self
+        }
+    }
+
+    /// Replace the events from `start` on about the entries of each directory that has more
+    /// than the summary threshold of them with one `{:bulk_change, dir, count}`, where the
+    /// first of them was.
+    pub fn summarize(&self, events: &mut Vec<WatchEvent>, start: usize) {
+        let Some(threshold) = self.summary_threshold else {
+            return;
+        };
+        let mut counts: HashMap<PathBuf, u64> = HashMap::new();
+        for event in &events[start..] {
+            if let Some(dir) = event.summary_dir() {
+                *counts.entry(dir.to_path_buf()).or_default() += event.count.unwrap_or(1);
+            }
+        }
+        counts.retain(|_, count| *count > threshold as u64);
+        if counts.is_empty() {
+            return;
+        }
+
+        let batch = events.split_off(start);
+        for event in batch {
+            let Some(dir) = event.summary_dir().filter(|dir| counts.contains_key(*dir)) else {
+                events.push(event);
+                continue;
+            };
+            // The first of a directory's events takes the count, and its place, for the summary
+            let count = counts.get_mut(dir).map_or(0, std::mem::take);
+            if count > 0 {
+                events.push(WatchEvent::bulk_change(dir, count, event.time));
+            }
+        }
+    }
+
+    /// Translate `event`, which stands for `count` backend events when they are coalesced.
+    pub fn process(
+        &mut self,
+        event: Event,
+        time: Timestamp,
+        count: Option<u64>,
+        events: &mut Vec<WatchEvent>,
+    ) {
+        let start = events.len();
+        let event_id = event
+            .attrs
+            .tracker()
+            .filter(|_| TRACKERS_ARE_EVENT_IDS)
+            .map(|id| id as u64);
+        let flags = fsevent_flags(&event);
+        let os_pid = event.attrs.process_id();
+        let permission_id = permission_id(&event);
+        let appended_to = match &self.tails {
+            Some(_) if tail::may_append(&event.kind) => event.paths.clone(),
+            _ => Vec::new(),
+        };
+        self.translate(event, time, events);
+        if let Some(tails) = &self.tails {
+            // Right after the change that appended the data
+            for path in appended_to {
+                events.extend(tail::read(tails, &path).into_iter().map(|read| match read {
+                    TailRead::Rotated => WatchEvent::new(atoms::rotated(), &path, time),
+                    TailRead::Appended(data) => WatchEvent::appended(&path, data, time),
+                }));
+            }
+        }
+        for event in &mut events[start..] {
+            event.event_id = event_id;
+            event.fsevent_flags.clone_from(&flags);
+            event.os_pid = os_pid;
+            event.permission_id = permission_id;
+            event.count = count;
+        }
+        if self.coalesce_saves {
+            self.coalesce_saves(events, start);
+        }
+        self.finish(events, start);
+    }
+
+    /// Turn a temporary file renamed over its target, the way editors save atomically, into
+    /// a single modification of the target, dropping the events about the temporary file
+    /// earlier in the batch that haven't been numbered yet. A rename within a directory is
+    /// taken for a save when the source was created in the same batch or its name contains
+    /// the target's, e.g. `file.txt.tmp`, `.file.txt.swp` or `file.txt~`.
+    fn coalesce_saves(&self, events: &mut Vec<WatchEvent>, start: usize) {
+        let roots = read_recovered(&self.roots);
+        let unnumbered_path = |event: &WatchEvent, temp: &Path| {
+            event.seq == 0 && matches!(&event.change, Change::Path { path, .. } if path == temp)
+        };
+
+        let mut temps = Vec::new();
+        for index in start..events.len() {
+            let Change::Renamed { from, to } = &events[index].change else {
+                continue;
+            };
+            if from.parent() != to.parent() {
+                continue;
+            }
+
+            // Earlier events are already in their reported form
+            let mut temp = from.clone();
+            self.present(&mut temp, &roots);
+            let created = events[..start].iter().any(|event| {
+                unnumbered_path(event, &temp) && event.kind() == Some(atoms::created())
+            });
+            if !created && !is_temp_name(from, to) {
+                continue;
+            }
+
+            let kind = match self.detail {
+                EventDetail::Basic => atoms::modified(),
+                EventDetail::Detailed => atoms::modified_data(),
+            };
+            // Only the change is replaced; what `process` learned about the rename stays
+            let time = events[index].time;
+            events[index].change = WatchEvent::new(kind, &to.clone(), time).change;
+            temps.push(temp);
+        }
+
+        if temps.is_empty() {
+            return;
+        }
+        let mut index = 0;
+        events.retain(|event| {
+            index += 1;
+            index > start || !temps.iter().any(|temp| unnumbered_path(event, temp))
+        });
+    }
+
+    /// Bytes taken by the halves of renames awaiting their pair, for `memory_info`.
+    pub fn memory(&self) -> usize {
+        let pending = self.pending.iter().map(|(_, path, _)| memory::path(path));
+        self.pending.capacity() * mem::size_of::<(Option<usize>, PathBuf, Timestamp)>()
+            + pending.sum::<usize>()
+    }
+
+    /// Give back the room a burst of renames left behind, for `compact`.
+    pub fn compact(&mut self) {
+        self.pending.shrink_to_fit();
+    }
+
+    /// Report every still-unpaired `From` as removed.
+    pub fn flush(&mut self, events: &mut Vec<WatchEvent>) {
+        let start = events.len();
+        for (_, path, time) in self.pending.drain(..) {
+            events.push(WatchEvent::new(atoms::removed(), &path, time));
+        }
+        self.finish(events, start);
+    }
+
+    /// Drop the events from `start` on whose kind wasn't asked for, stat the rest if asked
+    /// to and put their paths in the form they are reported in.
+    fn finish(&self, events: &mut Vec<WatchEvent>, start: usize) {
+        if self.kinds.is_some() || self.ignore_attrib {
+            let added = events.split_off(start);
+            events.extend(
+                added
+                    .into_iter()
+                    .filter(|event| event.kind().is_none_or(|kind| self.is_wanted(kind))),
+            );
+        }
+
+        // Before the paths are changed, since they may be relative
+        if self.include_stat || self.hashes.is_some() {
+            for event in &mut events[start..] {
+                event.read_stat(self.hashes.as_ref());
+            }
+        }
+
+        if self.canonicalize || self.normalization.is_some() || self.relative_paths {
+            let roots = read_recovered(&self.roots);
+            for event in &mut events[start..] {
+                event.map_paths(|path| self.present(path, &roots));
+            }
+        }
+    }
+
+    fn is_wanted(&self, kind: Atom) -> bool {
+        let attrib = [
+            atoms::attrib_changed(),
+            atoms::modified_metadata(),
+            atoms::permissions(),
+            atoms::ownership(),
+            atoms::timestamps(),
+            atoms::xattr_changed(),
+        ];
+        if self.ignore_attrib && attrib.contains(&kind) {
+            return false;
+        }
+        self.kinds
+            .as_ref()
+            .is_none_or(|kinds| kinds.contains(&kind))
+    }
+
+    /// Canonicalize, normalize or make relative a backend path if asked to.
+    fn present(&self, path: &mut PathBuf, roots: &[(PathBuf, bool)]) {
+        if self.canonicalize {
+            *path = canonicalize(path);
+        }
+
+        if let Some(normalization) = self.normalization {
+            normalization.apply(path);
+        }
+
+        if self.relative_paths {
+            let relative = roots
+                .iter()
+                .filter_map(|(root, _)| path.strip_prefix(root).ok())
+                .min_by_key(|relative| relative.components().count())
+                .map(Path::to_path_buf);
+            if let Some(relative) = relative {
+                *path = relative;
+            }
+        }
+    }
+
+    fn translate(&mut self, event: Event, time: Timestamp, events: &mut Vec<WatchEvent>) {
+        let tracker = event.attrs.tracker();
+
+        match event.info() {
+            Some(scan::DISCOVERED) => {
+                events.extend(
+                    event
+                        .paths
+                        .iter()
+                        .map(|path| WatchEvent::new(atoms::discovered(), path, time)),
+                );
+                return;
+            }
+            Some(scan::READY) => {
+                events.push(WatchEvent::ready(time));
+                return;
+            }
+            Some(info) if info.starts_with(sink::BACKEND_FALLBACK) => {
+                let name = &info[sink::BACKEND_FALLBACK.len()..];
+                if let Some(backend) = backend_named(name) {
+                    events.push(WatchEvent::backend_fallback(backend, time));
+                }
+                return;
+            }
+            Some(info @ (size::APPENDED | size::TRUNCATED | size::REWRITTEN)) => {
+                let kind = match info {
+                    size::APPENDED => atoms::appended(),
+                    size::TRUNCATED => atoms::truncated(),
+                    _ => atoms::rewritten(),
+                };
+                events.extend(
+                    event
+                        .paths
+                        .iter()
+                        .map(|path| WatchEvent::new(kind, path, time)),
+                );
+                return;
+            }
+            Some(settle::SETTLED) => {
+                events.extend(
+                    event
+                        .paths
+                        .iter()
+                        .map(|path| WatchEvent::new(atoms::settled(), path, time)),
+                );
+                return;
+            }
+            Some(sink::ROOT_CREATED) => {
+                events.extend(
+                    event
+                        .paths
+                        .iter()
+                        .map(|path| WatchEvent::root_created(path, time)),
+                );
+                return;
+            }
+            Some(sink::ROOT_REMOVED) => {
+                events.extend(
+                    event
+                        .paths
+                        .iter()
+                        .map(|path| WatchEvent::root_removed(path, time)),
+                );
+                return;
+            }
+            _ => {}
+        }
+
+        if event.need_rescan() {
+            if event.paths.is_empty() {
+                let roots = read_recovered(&self.roots);
+                events.extend(
+                    roots
+                        .iter()
+                        .map(|(root, _)| WatchEvent::overflow(root, time)),
+                );
+            } else {
+                events.extend(
+                    event
+                        .paths
+                        .iter()
+                        .map(|path| WatchEvent::overflow(path, time)),
+                );
+            }
+            return;
+        }
+
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                for path in event.paths {
+                    self.pending.push((tracker, path, time));
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                for path in event.paths {
+                    match self.take_pending(tracker) {
+                        Some(from) => {
+                            self.last_paired = tracker;
+                            events.push(WatchEvent::renamed(&from, &path, time));
+                        }
+                        None => events.push(WatchEvent::new(atoms::created(), &path, time)),
+                    }
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                // inotify follows a matched `To` with a `Both` carrying the same cookie
+                if tracker.is_some() && tracker == self.last_paired {
+                    return;
+                }
+
+                if let [from, to] = &event.paths[..] {
+                    events.push(WatchEvent::renamed(from, to, time));
+                }
+            }
+            kind => {
+                let class = self.attrib_classes.then(|| attrib_class_atom(&kind));
+                let event_atom = class.flatten().unwrap_or_else(|| match self.detail {
+                    EventDetail::Basic => event_kind_to_atom(&kind),
+                    EventDetail::Detailed => detailed_event_kind_to_atom(&kind),
+                });
+                events.extend(
+                    event
+                        .paths
+                        .iter()
+                        .map(|path| WatchEvent::new(event_atom, path, time)),
+                );
+            }
+        }
+    }
+
+    fn take_pending(&mut self, tracker: Option<usize>) -> Option<PathBuf> {
+        let index = self.pending.iter().position(|(t, _, _)| *t == tracker)?;
+        Some(self.pending.remove(index).1)
+    }
+}
+
+/// Suffixes editors and atomic writers give the temporary file they rename over a target,
+/// after the target's name, e.g. `file.txt.tmp` or `file.txt~`.
+const TEMP_SUFFIXES: &[&str] = &["~", ".tmp", ".temp", ".swp", ".swx", ".new", ".part"];
+
+/// Whether `from` is named as a temporary file for `to`: `to`'s name followed by one of
+/// [`TEMP_SUFFIXES`], possibly with more after it (`file.txt.tmp.1234`), and possibly
+/// hidden with a leading `.` (`.file.txt.swp`). Hidden, it may instead be followed by a
+/// random extension, as rsync's are (`.file.txt.Ab12Cd`).
+fn is_temp_name(from: &Path, to: &Path) -> bool {
+    let (Some(from), Some(to)) = (from.file_name(), to.file_name()) else {
+        return false;
+    };
+    let (from, to) = (from.to_string_lossy(), to.to_string_lossy());
+    let hidden = from
+        .strip_prefix('.')
+        .and_then(|from| from.strip_prefix(&*to));
+    let Some(rest) = from.strip_prefix(&*to).or(hidden) else {
+        return false;
+    };
+
+    let suffixed = TEMP_SUFFIXES.iter().any(|suffix| {
+        rest.strip_prefix(suffix)
+            .is_some_and(|after| !after.starts_with(|c: char| c.is_ascii_alphabetic()))
+    });
+    let random = hidden.is_some_and(|rest| {
+        rest.strip_prefix('.')
+            .is_some_and(|ext| ext.len() == 6 && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+    });
+    suffixed || random
+}
+
+fn attrib_class_atom(kind: &EventKind) -> Option<Atom> {
+    match kind {
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)) => {
+            Some(atoms::permissions())
+        }
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::Ownership)) => {
+            Some(atoms::ownership())
+        }
+        EventKind::Modify(ModifyKind::Metadata(
+            MetadataKind::WriteTime | MetadataKind::AccessTime,
+        )) => Some(atoms::timestamps()),
+        _ => None,
+    }
+}
+
+fn event_kind_to_atom(kind: &EventKind) -> Atom {
+    match kind {
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::Extended)) => atoms::xattr_changed(),
+        EventKind::Create(_) => atoms::created(),
+        EventKind::Modify(ModifyKind::Name(_)) => atoms::renamed(),
+        EventKind::Modify(ModifyKind::Metadata(_)) => atoms::attrib_changed(),
+        EventKind::Modify(_) => atoms::modified(),
+        EventKind::Remove(_) => atoms::removed(),
+        // A writer closed the file (inotify's `IN_CLOSE_WRITE`), so its contents are complete
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => atoms::written(),
+        EventKind::Other => atoms::meta(),
+        _ => atoms::unknown(),
+    }
+}
+
+fn detailed_event_kind_to_atom(kind: &EventKind) -> Atom {
+    match kind {
+        EventKind::Access(AccessKind::Open(_)) => atoms::access_open(),
+        EventKind::Access(AccessKind::Read) => atoms::access_read(),
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => atoms::access_close_write(),
+        EventKind::Access(AccessKind::Close(_)) => atoms::access_close(),
+        EventKind::Access(_) => atoms::access(),
+        EventKind::Modify(ModifyKind::Data(_)) => atoms::modified_data(),
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::Extended)) => atoms::xattr_changed(),
+        EventKind::Modify(ModifyKind::Metadata(_)) => atoms::modified_metadata(),
+        kind => event_kind_to_atom(kind),
+    }
+}
+
+/// Resolve `..`, symlinks and (on Windows) short names in `path`, without the `\\?\`
+/// prefix Windows adds where it isn't needed. A path that no longer exists, e.g. a removed
+/// file, has its parent resolved instead; one that can't be resolved at all is returned as is.
+pub fn canonicalize(path: &Path) -> PathBuf {
+    // dunce keeps the prefix on network paths, which are reported as `\\server\share`
+    if let Ok(mut canonical) = dunce::canonicalize(path) {
+        longpath::strip_extended(&mut canonical);
+        return canonical;
+    }
+
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => canonicalize(parent).join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Turn a mini debouncer event into a notify event, reported as `:modified`.
+pub fn from_debounced(event: DebouncedEvent) -> Event {
+    let kind = match event.kind {
+        DebouncedEventKind::Any => EventKind::Modify(ModifyKind::Any),
+        _ => EventKind::Any,
+    };
+    Event::new(kind).add_path(event.path)
+}
+
+fn file_type_atom(path: &Path) -> Atom {
+    if path.is_dir() {
+        atoms::directory()
+    } else {
+        atoms::file()
+    }
+}
@@ -0,0 +1,115 @@
+use crate::event::WatchEvent;
+use rustler::{Encoder, OwnedEnv};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Start of every event log, ending in the format version.
+const MAGIC: &[u8; 5] = b"FSNL\x01";
+
+/// Write-ahead log of the events a watcher delivers, each kept in external term format
+/// along with its sequence number, so they can be read again after the consumer (or the
+/// whole VM) restarts.
+pub struct EventLog {
+    file: File,
+}
+
+impl EventLog {
+    /// Open the log at `path` for appending, creating it if needed, and return it along
+    /// with the last sequence number it holds. A record cut short by a crash is dropped.
+    pub fn open(path: &Path) -> io::Result<(EventLog, u64)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let (end, last_seq) = if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+            (MAGIC.len() as u64, 0)
+        } else {
+            let mut last_seq = 0;
+            let end = scan(&mut file, |seq, _| {
+                last_seq = seq;
+                Ok(())
+            })?;
+            (end, last_seq)
+        };
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+
+        Ok((EventLog { file }, last_seq))
+    }
+
+    /// Append `events` and wait for them to reach the disk.
+    pub fn append(&mut self, events: &[WatchEvent]) -> io::Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let env = OwnedEnv::new();
+        let mut records = Vec::new();
+        for event in events {
+            let term = env.run(|env| event.encode(env).to_binary());
+            records.extend(event.seq.to_le_bytes());
+            records.extend((term.len() as u32).to_le_bytes());
+            records.extend(term.as_slice());
+        }
+        self.file.write_all(&records)?;
+        self.file.sync_data()
+    }
+}
+
+/// Read the events in the log at `path` numbered after `seq`, in external term format.
+pub fn read_after(path: &Path, seq: u64) -> io::Result<Vec<Vec<u8>>> {
+    let mut events = Vec::new();
+    scan(&mut File::open(path)?, |logged, record| {
+        if logged > seq {
+            let mut event = Vec::new();
+            record.read_to_end(&mut event)?;
+            events.push(event);
+        }
+        Ok(())
+    })?;
+    Ok(events)
+}
+
+/// Go through the records of a log from the start, handing each sequence number to
+/// `visit` along with a reader of its event (which it may leave unread). Returns where the
+/// last complete record ends.
+fn scan(
+    file: &mut File,
+    mut visit: impl FnMut(u64, &mut dyn Read) -> io::Result<()>,
+) -> io::Result<u64> {
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+    let mut input = BufReader::new(file);
+    let mut magic = [0; MAGIC.len()];
+    input.read_exact(&mut magic)?;
+    if magic != *MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not an event log"));
+    }
+
+    let mut end = MAGIC.len() as u64;
+    let mut header = [0; 12];
+    loop {
+        match input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(end),
+            Err(err) => return Err(err),
+        }
+        let seq = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[8..].try_into().unwrap());
+
+        // Only complete records count, so a torn one at the end is never visited
+        let start = end + header.len() as u64;
+        if start + u64::from(len) > size {
+            return Ok(end);
+        }
+        let mut record = (&mut input).take(len.into());
+        visit(seq, &mut record)?;
+        io::copy(&mut record, &mut io::sink())?;
+        end = start + u64::from(len);
+    }
+}
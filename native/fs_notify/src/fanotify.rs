@@ -0,0 +1,389 @@
+//! fanotify backend: marks the whole mount each watched root is on, so that every file
+//! opened or written there is reported along with the ID of the process that did it, and
+//! keeps the events within the watched roots. Needs `CAP_SYS_ADMIN`.
+//!
+//! Mount marks only report access to files, not directory changes, so creations, removals
+//! and renames aren't seen.
+//!
+//! With `permission_events`, opening a file also waits for a decision: opens within the
+//! watched roots are reported with an ID that `respond` allows or denies them by, and any
+//! left unanswered are allowed after `permission_timeout_ms`, so that one lost on its way
+//! to Elixir doesn't hang the process that made it.
+
+use crate::config::BackendConfig;
+use crate::error::lock_recovered;
+use crate::event::FANOTIFY_PERMISSION;
+use notify::event::{AccessKind, AccessMode, DataChange, EventKind, Flag, ModifyKind};
+use notify::{Config, Error, Event, EventHandler, RecursiveMode, Result, Watcher, WatcherKind};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Changes reported for every file on a marked mount.
+const MASK: u64 = libc::FAN_OPEN | libc::FAN_MODIFY | libc::FAN_CLOSE_WRITE;
+
+/// Permission events of every fanotify watcher waiting for a decision, by ID.
+static PENDING: Mutex<BTreeMap<u64, Pending>> = Mutex::new(BTreeMap::new());
+static NEXT_PERMISSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// An open waiting to be allowed or denied.
+struct Pending {
+    fanotify: Arc<OwnedFd>,
+    /// The file being opened, as the kernel handed it to us
+    file: OwnedFd,
+    /// When it is allowed if no decision was made
+    deadline: Instant,
+}
+
+/// Watched roots, and whether each is watched recursively.
+type Roots = Arc<Mutex<HashMap<PathBuf, bool>>>;
+
+pub struct FanotifyWatcher {
+    fanotify: Arc<OwnedFd>,
+    mask: u64,
+    roots: Roots,
+    /// Written to when the watcher is dropped, to stop the reading thread
+    stop: OwnedFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Whether this process may use fanotify, which takes `CAP_SYS_ADMIN`.
+pub fn available() -> bool {
+    init(libc::FAN_CLASS_NOTIF).is_ok()
+}
+
+fn init(class: u32) -> io::Result<OwnedFd> {
+    // SAFETY: no pointers are involved
+    let fd = unsafe {
+        libc::fanotify_init(
+            class | libc::FAN_CLOEXEC,
+            (libc::O_RDONLY | libc::O_CLOEXEC | libc::O_LARGEFILE) as u32,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just opened, and nothing else owns it
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+impl FanotifyWatcher {
+    pub fn from_config(handler: impl EventHandler, config: &BackendConfig) -> Result<Self> {
+        // Permission events need the class that is told about accesses before they happen,
+        // and report opens in place of `FAN_OPEN`
+        let (class, mask, timeout) = if config.permission_events {
+            let timeout = Duration::from_millis(config.permission_timeout_ms);
+            (
+                libc::FAN_CLASS_CONTENT,
+                MASK & !libc::FAN_OPEN | libc::FAN_OPEN_PERM,
+                Some(timeout),
+            )
+        } else {
+            (libc::FAN_CLASS_NOTIF, MASK, None)
+        };
+        let fanotify = Arc::new(init(class).map_err(Error::io)?);
+        let mut pipe = [0; 2];
+        // SAFETY: `pipe` has room for both ends
+        if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(Error::io(io::Error::last_os_error()));
+        }
+        // SAFETY: both ends were just opened, and nothing else owns them
+        let (wake, stop) =
+            unsafe { (OwnedFd::from_raw_fd(pipe[0]), OwnedFd::from_raw_fd(pipe[1])) };
+
+        let roots = Roots::default();
+        let thread = thread::Builder::new()
+            .name("fs_notify fanotify".to_string())
+            .spawn({
+                let (fanotify, roots) = (fanotify.clone(), roots.clone());
+                move || read_events(&fanotify, &wake, &roots, timeout, handler)
+            })
+            .map_err(Error::io)?;
+
+        Ok(FanotifyWatcher {
+            fanotify,
+            mask,
+            roots,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    fn mark(&self, flags: u32, path: &Path) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // SAFETY: `path` is NUL-terminated
+        let result = unsafe {
+            libc::fanotify_mark(
+                self.fanotify.as_raw_fd(),
+                flags | libc::FAN_MARK_MOUNT,
+                self.mask,
+                libc::AT_FDCWD,
+                path.as_ptr(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Watcher for FanotifyWatcher {
+    fn new<F: EventHandler>(handler: F, _config: Config) -> Result<Self> {
+        FanotifyWatcher::from_config(handler, &BackendConfig::default())
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        let root = path
+            .canonicalize()
+            .map_err(|err| Error::io(err).add_path(path.to_path_buf()))?;
+        self.mark(libc::FAN_MARK_ADD, &root)
+            .map_err(|err| Error::io(err).add_path(path.to_path_buf()))?;
+        let recursive = recursive_mode == RecursiveMode::Recursive;
+        lock_recovered(&self.roots).insert(root, recursive);
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        let root = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let mut roots = lock_recovered(&self.roots);
+        if roots.remove(&root).is_none() {
+            return Err(Error::watch_not_found().add_path(path.to_path_buf()));
+        }
+
+        // Another root may share the mount, so start over with the ones left
+        self.mark(libc::FAN_MARK_FLUSH, Path::new("/"))
+            .map_err(Error::io)?;
+        for root in roots.keys() {
+            self.mark(libc::FAN_MARK_ADD, root)
+                .map_err(|err| Error::io(err).add_path(root.clone()))?;
+        }
+        Ok(())
+    }
+
+    fn kind() -> WatcherKind {
+        // notify has no kind for fanotify; this one is never reported
+        WatcherKind::NullWatcher
+    }
+}
+
+impl Drop for FanotifyWatcher {
+    fn drop(&mut self) {
+        // SAFETY: writing one byte from a valid buffer
+        unsafe { libc::write(self.stop.as_raw_fd(), [0u8].as_ptr().cast(), 1) };
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        // Nothing would answer them any more
+        for pending in take_pending(&self.fanotify, |_| true) {
+            let _ = pending.answer(libc::FAN_ALLOW);
+        }
+    }
+}
+
+impl Pending {
+    fn answer(self, response: u32) -> io::Result<()> {
+        answer(&self.fanotify, &self.file, response)
+    }
+}
+
+/// Allow or deny the open reported with permission ID `id`. Returns `false` if it isn't
+/// waiting for a decision, having been answered already, timed out or its watcher stopped.
+pub fn respond(id: u64, allow: bool) -> io::Result<bool> {
+    let Some(pending) = lock_recovered(&PENDING).remove(&id) else {
+        return Ok(false);
+    };
+    let response = if allow {
+        libc::FAN_ALLOW
+    } else {
+        libc::FAN_DENY
+    };
+    pending.answer(response)?;
+    Ok(true)
+}
+
+/// Tell the kernel whether the access to `file` reported by `fanotify` may go ahead.
+fn answer(fanotify: &OwnedFd, file: &OwnedFd, response: u32) -> io::Result<()> {
+    let response = libc::fanotify_response {
+        fd: file.as_raw_fd(),
+        response,
+    };
+    let size = mem::size_of_val(&response);
+    // SAFETY: writing `size` bytes from a valid struct
+    let written = unsafe { libc::write(fanotify.as_raw_fd(), (&raw const response).cast(), size) };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Remove the permission events of `fanotify` that `select` picks from those pending.
+fn take_pending(fanotify: &Arc<OwnedFd>, select: impl Fn(&Pending) -> bool) -> Vec<Pending> {
+    let mut pending = lock_recovered(&PENDING);
+    let ids: Vec<u64> = pending
+        .iter()
+        .filter(|(_, entry)| Arc::ptr_eq(&entry.fanotify, fanotify) && select(entry))
+        .map(|(id, _)| *id)
+        .collect();
+    ids.iter().filter_map(|id| pending.remove(id)).collect()
+}
+
+/// When the next permission event of `fanotify` times out, if it has any pending.
+fn next_deadline(fanotify: &Arc<OwnedFd>) -> Option<Instant> {
+    lock_recovered(&PENDING)
+        .values()
+        .filter(|entry| Arc::ptr_eq(&entry.fanotify, fanotify))
+        .map(|entry| entry.deadline)
+        .min()
+}
+
+/// Report the events read from `fanotify` that are within `roots` until `wake` is written
+/// to. Permission events, if there are any, are left to Elixir for up to `timeout`.
+fn read_events(
+    fanotify: &Arc<OwnedFd>,
+    wake: &OwnedFd,
+    roots: &Roots,
+    timeout: Option<Duration>,
+    mut handler: impl EventHandler,
+) {
+    let own_pid = std::process::id();
+    // Aligned for the event headers read from it
+    let mut buffer = vec![0u64; 1024];
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: fanotify.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: wake.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        // Wake up in time to allow the first unanswered permission event
+        let wait = next_deadline(fanotify).map_or(-1, |deadline| {
+            let left = deadline.saturating_duration_since(Instant::now());
+            left.as_millis().saturating_add(1).min(i32::MAX as u128) as i32
+        });
+        // SAFETY: `fds` holds two valid entries
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, wait) } < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return;
+        }
+        if fds[1].revents != 0 {
+            return;
+        }
+        let now = Instant::now();
+        for pending in take_pending(fanotify, |entry| entry.deadline <= now) {
+            let _ = pending.answer(libc::FAN_ALLOW);
+        }
+        if fds[0].revents == 0 {
+            continue;
+        }
+
+        let size = mem::size_of_val(buffer.as_slice());
+        // SAFETY: `buffer` has room for `size` bytes
+        let read = unsafe { libc::read(fanotify.as_raw_fd(), buffer.as_mut_ptr().cast(), size) };
+        if read <= 0 {
+            continue;
+        }
+
+        let bytes = buffer.as_ptr().cast::<u8>();
+        let mut offset = 0;
+        while offset + mem::size_of::<libc::fanotify_event_metadata>() <= read as usize {
+            // SAFETY: the kernel wrote a whole header here, 8-byte aligned like the buffer
+            let metadata = unsafe { &*bytes.add(offset).cast::<libc::fanotify_event_metadata>() };
+            if metadata.vers != libc::FANOTIFY_METADATA_VERSION || metadata.event_len == 0 {
+                break;
+            }
+            offset += metadata.event_len as usize;
+
+            if metadata.mask & libc::FAN_Q_OVERFLOW != 0 {
+                handler.handle_event(Ok(Event::new(EventKind::Other).set_flag(Flag::Rescan)));
+                continue;
+            }
+            if metadata.fd < 0 {
+                continue;
+            }
+            // SAFETY: the kernel opened the descriptor for us to close
+            let file = unsafe { OwnedFd::from_raw_fd(metadata.fd) };
+            let pid = metadata.pid as u32;
+            let path = fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()));
+            // Our own reads, e.g. to hash a file, would otherwise be reported back
+            let reported = match path {
+                Ok(path) if pid != own_pid && is_watched(&lock_recovered(roots), &path) => {
+                    Some(path)
+                }
+                _ => None,
+            };
+
+            let mut permission = None;
+            if metadata.mask & libc::FAN_OPEN_PERM != 0 {
+                match (&reported, timeout) {
+                    (Some(_), Some(timeout)) => {
+                        let id = NEXT_PERMISSION_ID.fetch_add(1, Ordering::Relaxed);
+                        let pending = Pending {
+                            fanotify: fanotify.clone(),
+                            file,
+                            deadline: Instant::now() + timeout,
+                        };
+                        // Registered before it's reported, so it can be answered right away
+                        lock_recovered(&PENDING).insert(id, pending);
+                        permission = Some(id);
+                    }
+                    _ => {
+                        let _ = answer(fanotify, &file, libc::FAN_ALLOW);
+                    }
+                }
+            }
+            let Some(path) = reported else {
+                continue;
+            };
+
+            for kind in kinds(metadata.mask) {
+                let mut event = Event::new(kind).add_path(path.clone()).set_process_id(pid);
+                if let Some(id) = permission {
+                    event = event.set_info(&format!("{FANOTIFY_PERMISSION} {id}"));
+                }
+                handler.handle_event(Ok(event));
+            }
+        }
+    }
+}
+
+/// Whether `path` is within a watched root, or directly in it if that isn't recursive.
+fn is_watched(roots: &HashMap<PathBuf, bool>, path: &Path) -> bool {
+    roots.iter().any(|(root, recursive)| {
+        path.starts_with(root)
+            && (*recursive || path == root || path.parent() == Some(root.as_path()))
+    })
+}
+
+/// The changes described by an event's mask, as notify describes them.
+fn kinds(mask: u64) -> Vec<EventKind> {
+    let mut kinds = Vec::new();
+    if mask & (libc::FAN_OPEN | libc::FAN_OPEN_PERM) != 0 {
+        kinds.push(EventKind::Access(AccessKind::Open(AccessMode::Any)));
+    }
+    if mask & libc::FAN_MODIFY != 0 {
+        kinds.push(EventKind::Modify(ModifyKind::Data(DataChange::Any)));
+    }
+    if mask & libc::FAN_CLOSE_WRITE != 0 {
+        kinds.push(EventKind::Access(AccessKind::Close(AccessMode::Write)));
+    }
+    kinds
+}
@@ -0,0 +1,321 @@
+use crate::atoms;
+use crate::error::{read_recovered, write_recovered, WatchError};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+use ignore::{Match, WalkBuilder};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind};
+use regex::RegexSet;
+use rustler::Atom;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// A watcher's filter, shared with its event sink so `update_watcher` can replace it.
+pub type SharedFilter = Arc<RwLock<Option<EventFilter>>>;
+
+/// Filtering requested when a watcher is started.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilterOptions {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub include_regex: Vec<String>,
+    pub exclude_regex: Vec<String>,
+    pub gitignore: bool,
+    pub ignore_hidden: bool,
+    pub ignore_presets: Vec<IgnorePreset>,
+    pub extensions: Vec<String>,
+}
+
+impl FilterOptions {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.include_regex.is_empty()
+            && self.exclude_regex.is_empty()
+            && !self.gitignore
+            && !self.ignore_hidden
+            && self.ignore_presets.is_empty()
+            && self.extensions.is_empty()
+    }
+
+    /// Build the filter for `roots`, or `None` when nothing is filtered.
+    pub fn build(&self, roots: &[PathBuf]) -> Result<Option<EventFilter>, WatchError> {
+        if self.is_empty() {
+            Ok(None)
+        } else {
+            EventFilter::new(roots, self).map(Some)
+        }
+    }
+}
+
+/// Built-in sets of exclude patterns for files nobody wants events about.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IgnorePreset {
+    /// Swap, backup and lock files written by vim, emacs and JetBrains IDEs
+    Editors,
+    /// Metadata files dropped by macOS and Windows
+    OsJunk,
+}
+
+impl IgnorePreset {
+    pub fn from_atom(atom: Atom) -> Option<Self> {
+        if atom == atoms::editors() {
+            Some(IgnorePreset::Editors)
+        } else if atom == atoms::os_junk() {
+            Some(IgnorePreset::OsJunk)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_atom(self) -> Atom {
+        match self {
+            IgnorePreset::Editors => atoms::editors(),
+            IgnorePreset::OsJunk => atoms::os_junk(),
+        }
+    }
+
+    fn patterns(self) -> &'static [&'static str] {
+        match self {
+            IgnorePreset::Editors => &[
+                // vim
+                "**/*.sw[a-p]",
+                "**/4913",
+                "**/*~",
+                // emacs
+                "**/#*#",
+                "**/.#*",
+                // JetBrains safe write
+                "**/*___jb_tmp___",
+                "**/*___jb_old___",
+            ],
+            IgnorePreset::OsJunk => &[
+                "**/.DS_Store",
+                "**/._*",
+                "**/Thumbs.db",
+                "**/ehthumbs.db",
+                "**/desktop.ini",
+            ],
+        }
+    }
+}
+
+/// Native filter applied to events before they are queued.
+///
+/// Patterns are matched against paths relative to the watched root containing them, so
+/// `_build/**` excludes the build directory wherever the watcher was started. A path passes
+/// when it matches no exclude glob or regex and, if any include patterns are given, at
+/// least one include glob or regex.
+pub struct EventFilter {
+    roots: RwLock<Vec<PathBuf>>,
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    include_regex: Option<RegexSet>,
+    exclude_regex: Option<RegexSet>,
+    gitignore: Option<RwLock<GitignoreFilter>>,
+    ignore_hidden: bool,
+    /// File suffixes without the leading dot; empty to allow any
+    extensions: Vec<String>,
+}
+
+impl EventFilter {
+    pub fn new(roots: &[PathBuf], options: &FilterOptions) -> Result<Self, WatchError> {
+        let gitignore = options.gitignore.then(|| {
+            let mut gitignore = GitignoreFilter::default();
+            for root in roots {
+                gitignore.extend(GitignoreFilter::load(root));
+            }
+            RwLock::new(gitignore)
+        });
+
+        let mut exclude = options.exclude.clone();
+        for preset in &options.ignore_presets {
+            exclude.extend(preset.patterns().iter().map(|pattern| pattern.to_string()));
+        }
+
+        Ok(EventFilter {
+            roots: RwLock::new(roots.to_vec()),
+            include: build_glob_set(&options.include)?,
+            exclude: build_glob_set(&exclude)?,
+            include_regex: build_regex_set(&options.include_regex)?,
+            exclude_regex: build_regex_set(&options.exclude_regex)?,
+            gitignore,
+            ignore_hidden: options.ignore_hidden,
+            extensions: options
+                .extensions
+                .iter()
+                .map(|extension| extension.trim_start_matches('.').to_string())
+                .collect(),
+        })
+    }
+
+    pub fn add_root(&self, root: &Path) {
+        write_recovered(&self.roots).push(root.to_path_buf());
+
+        if let Some(gitignore) = &self.gitignore {
+            write_recovered(gitignore).extend(GitignoreFilter::load(root));
+        }
+    }
+
+    pub fn remove_root(&self, root: &Path) {
+        write_recovered(&self.roots).retain(|r| r != root);
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let roots = read_recovered(&self.roots);
+        let relative = roots
+            .iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .min_by_key(|relative| relative.components().count())
+            .unwrap_or(path);
+
+        if self.ignore_hidden && is_hidden(relative) {
+            return false;
+        }
+
+        if !self.extensions.is_empty() {
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            if !extension.is_some_and(|extension| self.extensions.iter().any(|e| e == extension)) {
+                return false;
+            }
+        }
+
+        if let Some(exclude) = &self.exclude {
+            if exclude.is_match(relative) {
+                return false;
+            }
+        }
+
+        let relative_str = relative.to_string_lossy();
+        if let Some(exclude) = &self.exclude_regex {
+            if exclude.is_match(&relative_str) {
+                return false;
+            }
+        }
+
+        if let Some(gitignore) = &self.gitignore {
+            if read_recovered(gitignore).is_ignored(path) {
+                return false;
+            }
+        }
+
+        match (&self.include, &self.include_regex) {
+            (None, None) => true,
+            (include, include_regex) => {
+                include
+                    .as_ref()
+                    .is_some_and(|include| include.is_match(relative))
+                    || include_regex
+                        .as_ref()
+                        .is_some_and(|include| include.is_match(&relative_str))
+            }
+        }
+    }
+
+    /// Drop the paths of `event` that don't pass the filter, returning whether anything is
+    /// left. Both halves of a rename are kept as long as either side matches.
+    pub fn apply(&self, event: &mut Event) -> bool {
+        if let EventKind::Modify(ModifyKind::Name(RenameMode::Both)) = event.kind {
+            return event.paths.iter().any(|path| self.matches(path));
+        }
+
+        if event.paths.is_empty() {
+            return true;
+        }
+
+        event.paths.retain(|path| self.matches(path));
+        !event.paths.is_empty()
+    }
+}
+
+/// Every `.gitignore` found under the watched root when the watcher was started, deepest
+/// directory first so nested files take precedence over their parents.
+#[derive(Default)]
+struct GitignoreFilter {
+    matchers: Vec<Gitignore>,
+}
+
+impl GitignoreFilter {
+    fn load(root: &Path) -> Self {
+        let walker = WalkBuilder::new(root)
+            .hidden(false)
+            .require_git(false)
+            .filter_entry(|entry| entry.file_name() != ".git")
+            .build();
+
+        let mut filter = GitignoreFilter {
+            matchers: walker
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_name() == ".gitignore")
+                .map(|entry| Gitignore::new(entry.path()).0)
+                .filter(|gitignore| !gitignore.is_empty())
+                .collect(),
+        };
+        filter.sort();
+        filter
+    }
+
+    fn extend(&mut self, other: GitignoreFilter) {
+        self.matchers.extend(other.matchers);
+        self.sort();
+    }
+
+    fn sort(&mut self) {
+        self.matchers
+            .sort_by_key(|gitignore| std::cmp::Reverse(gitignore.path().components().count()));
+    }
+
+    fn is_ignored(&self, path: &Path) -> bool {
+        let is_dir = path.is_dir();
+
+        for gitignore in &self.matchers {
+            if !path.starts_with(gitignore.path()) {
+                continue;
+            }
+
+            match gitignore.matched_path_or_any_parents(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => continue,
+            }
+        }
+
+        false
+    }
+}
+
+/// Whether any component of `path` is a dotfile or dot-directory.
+fn is_hidden(path: &Path) -> bool {
+    path.components().any(|component| match component {
+        Component::Normal(name) => name.as_encoded_bytes().starts_with(b"."),
+        _ => false,
+    })
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<Option<GlobSet>, WatchError> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern)
+            .map_err(|err| WatchError::new(atoms::invalid_pattern(), err.to_string()))?;
+        builder.add(glob);
+    }
+
+    builder
+        .build()
+        .map(Some)
+        .map_err(|err| WatchError::new(atoms::invalid_pattern(), err.to_string()))
+}
+
+fn build_regex_set(patterns: &[String]) -> Result<Option<RegexSet>, WatchError> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    RegexSet::new(patterns)
+        .map(Some)
+        .map_err(|err| WatchError::new(atoms::invalid_pattern(), err.to_string()))
+}
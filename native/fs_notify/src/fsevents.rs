@@ -0,0 +1,455 @@
+//! FSEvents backend, in place of notify's so that streams can start from an earlier event
+//! ID, report the ID of each event and have their latency set. Events are translated the
+//! way notify translates them, with the ID as the event's tracker and the stream flags
+//! worth knowing about listed in its info.
+
+use crate::config::BackendConfig;
+use crate::error::lock_recovered;
+use crate::event::FSEVENT_FLAGS;
+use fsevent_sys as fs;
+use fsevent_sys::core_foundation as cf;
+use notify::event::{
+    CreateKind, DataChange, EventKind, Flag, MetadataKind, ModifyKind, RemoveKind, RenameMode,
+};
+use notify::{Config, Error, Event, EventHandler, RecursiveMode, Result, Watcher, WatcherKind};
+use std::collections::HashMap;
+use std::ffi::{c_void, CStr, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    fn CFRunLoopIsWaiting(runloop: cf::CFRunLoopRef) -> cf::Boolean;
+}
+
+type Handler = Arc<Mutex<dyn EventHandler>>;
+
+/// What the stream's callback needs, owned by the stream and freed with it.
+struct StreamContext {
+    handler: Handler,
+    /// Watched paths, canonicalized, and whether each is watched recursively
+    roots: HashMap<PathBuf, bool>,
+}
+
+/// A `CFRef` moved to the stream's thread; Core Foundation objects may be used from any
+/// thread.
+struct SendRef(cf::CFRef);
+
+// SAFETY: see above
+unsafe impl Send for SendRef {}
+
+pub struct FsEventWatcher {
+    /// `CFMutableArray` of the watched paths
+    paths: cf::CFMutableArrayRef,
+    roots: HashMap<PathBuf, bool>,
+    /// Event ID the next stream starts after; only the first stream looks back in time
+    since_when: fs::FSEventStreamEventId,
+    latency: Duration,
+    handler: Handler,
+    runloop: Option<(cf::CFRunLoopRef, thread::JoinHandle<()>)>,
+}
+
+// SAFETY: the paths array and run loop are only used through `&mut self`, and Core
+// Foundation objects may be used from any thread
+unsafe impl Send for FsEventWatcher {}
+
+impl FsEventWatcher {
+    /// Watch for events after `config.since_event_id`, including those that happened before
+    /// the watcher was created, or from now on when it is `None`, reporting them after
+    /// `config.latency_ms`.
+    pub fn from_config(handler: impl EventHandler, config: &BackendConfig) -> Self {
+        FsEventWatcher {
+            // SAFETY: creating an empty array has no preconditions
+            paths: unsafe {
+                cf::CFArrayCreateMutable(cf::kCFAllocatorDefault, 0, &cf::kCFTypeArrayCallBacks)
+            },
+            roots: HashMap::new(),
+            since_when: config
+                .since_event_id
+                .unwrap_or(fs::kFSEventStreamEventIdSinceNow),
+            latency: Duration::from_millis(config.latency_ms),
+            handler: Arc::new(Mutex::new(handler)),
+            runloop: None,
+        }
+    }
+
+    fn stop(&mut self) {
+        let Some((runloop, thread)) = self.runloop.take() else {
+            return;
+        };
+        // SAFETY: the run loop stays valid until its thread, joined below, returns
+        unsafe {
+            while CFRunLoopIsWaiting(runloop) == 0 {
+                thread::yield_now();
+            }
+            cf::CFRunLoopStop(runloop);
+        }
+        let _ = thread.join();
+    }
+
+    fn add(&mut self, path: &Path, recursive: bool) -> Result<()> {
+        if !path.exists() {
+            return Err(Error::path_not_found().add_path(path.to_path_buf()));
+        }
+        let canonical = path.canonicalize()?;
+        let cf_path = cf_path(path)?;
+        // SAFETY: `cf_path` is a valid `CFString`, retained by the array
+        unsafe {
+            cf::CFArrayAppendValue(self.paths, cf_path);
+            cf::CFRelease(cf_path);
+        }
+        self.roots.insert(canonical, recursive);
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        let cf_path = cf_path(path).map_err(|_| Error::watch_not_found())?;
+        // SAFETY: `cf_path` is a valid `CFString` and indices stay within the array
+        unsafe {
+            for index in (0..cf::CFArrayGetCount(self.paths)).rev() {
+                let item = cf::CFArrayGetValueAtIndex(self.paths, index);
+                if cf::CFStringCompare(item, cf_path, cf::kCFCompareCaseInsensitive)
+                    == cf::kCFCompareEqualTo
+                {
+                    cf::CFArrayRemoveValueAtIndex(self.paths, index);
+                }
+            }
+            cf::CFRelease(cf_path);
+        }
+
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        match self.roots.remove(&canonical) {
+            Some(_) => Ok(()),
+            None => Err(Error::watch_not_found()),
+        }
+    }
+
+    /// Start a stream over the watched paths on a thread of its own, if there are any.
+    fn run(&mut self) -> Result<()> {
+        // SAFETY: `paths` is a valid array
+        if unsafe { cf::CFArrayGetCount(self.paths) } == 0 {
+            return Ok(());
+        }
+
+        let context = Box::into_raw(Box::new(StreamContext {
+            handler: self.handler.clone(),
+            roots: self.roots.clone(),
+        }));
+        let stream_context = fs::FSEventStreamContext {
+            version: 0,
+            info: context.cast(),
+            retain: None,
+            release: Some(release_context),
+            copy_description: None,
+        };
+        // SAFETY: the stream takes ownership of `context`, freeing it through
+        // `release_context`, and copies `stream_context` and `paths`
+        let stream = unsafe {
+            fs::FSEventStreamCreate(
+                cf::kCFAllocatorDefault,
+                callback,
+                &stream_context,
+                self.paths,
+                self.since_when,
+                self.latency.as_secs_f64(),
+                // Without `NoDefer` the first event of a burst would wait out the latency too
+                fs::kFSEventStreamCreateFlagFileEvents | fs::kFSEventStreamCreateFlagNoDefer,
+            )
+        };
+        self.since_when = fs::kFSEventStreamEventIdSinceNow;
+
+        let stream = SendRef(stream);
+        let (started, runloop) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("fs_notify fsevents".to_string())
+            .spawn(move || {
+                // The whole wrapper is moved, not just its pointer
+                let stream = stream;
+                let stream = stream.0;
+                // SAFETY: the stream is only used on this thread from here on, and released
+                // once its run loop is stopped
+                unsafe {
+                    let runloop = cf::CFRunLoopGetCurrent();
+                    fs::FSEventStreamScheduleWithRunLoop(
+                        stream,
+                        runloop,
+                        cf::kCFRunLoopDefaultMode,
+                    );
+                    fs::FSEventStreamStart(stream);
+                    let _ = started.send(SendRef(runloop));
+
+                    cf::CFRunLoopRun();
+                    // Unlike notify, the volume's event history isn't purged: it is what
+                    // `since_event_id` reads
+                    fs::FSEventStreamStop(stream);
+                    fs::FSEventStreamInvalidate(stream);
+                    fs::FSEventStreamRelease(stream);
+                }
+            })?;
+
+        let runloop = runloop
+            .recv()
+            .map_err(|_| Error::generic("FSEvents stream thread exited"))?;
+        self.runloop = Some((runloop.0, thread));
+        Ok(())
+    }
+}
+
+impl Watcher for FsEventWatcher {
+    fn new<F: EventHandler>(handler: F, _config: Config) -> Result<Self> {
+        Ok(FsEventWatcher::from_config(
+            handler,
+            &BackendConfig::default(),
+        ))
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        self.stop();
+        let result = self.add(path, recursive_mode == RecursiveMode::Recursive);
+        self.run()?;
+        result
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        self.stop();
+        let result = self.remove(path);
+        self.run()?;
+        result
+    }
+
+    fn kind() -> WatcherKind {
+        WatcherKind::Fsevent
+    }
+}
+
+impl Drop for FsEventWatcher {
+    fn drop(&mut self) {
+        self.stop();
+        // SAFETY: the array is no longer used by any stream
+        unsafe { cf::CFRelease(self.paths) };
+    }
+}
+
+fn cf_path(path: &Path) -> Result<cf::CFStringRef> {
+    let str_path = path
+        .to_str()
+        .ok_or_else(|| Error::path_not_found().add_path(path.to_path_buf()))?;
+    let mut err: cf::CFErrorRef = ptr::null_mut();
+    // SAFETY: `err` is only read when no string is returned
+    let cf_path = unsafe { cf::str_path_to_cfstring_ref(str_path, &mut err) };
+    if cf_path.is_null() {
+        // Most likely removed or made unreadable meanwhile
+        if !err.is_null() {
+            // SAFETY: `err` was set by the failed call
+            unsafe { cf::CFRelease(err.cast()) };
+        }
+        return Err(Error::path_not_found().add_path(path.to_path_buf()));
+    }
+    Ok(cf_path)
+}
+
+extern "C" fn release_context(info: *const c_void) {
+    // SAFETY: `info` is the context boxed in `run`, released once with its stream
+    unsafe { drop(Box::from_raw(info as *mut StreamContext)) };
+}
+
+extern "C" fn callback(
+    _stream: fs::FSEventStreamRef,
+    info: *mut c_void,
+    count: usize,
+    paths: *mut c_void,
+    flags: *const fs::FSEventStreamEventFlags,
+    ids: *const fs::FSEventStreamEventId,
+) {
+    // SAFETY: FSEvents passes `count` C string paths, flags and IDs, and the context the
+    // stream was created with
+    let (context, paths, flags, ids) = unsafe {
+        (
+            &*(info as *const StreamContext),
+            std::slice::from_raw_parts(paths as *const *const std::ffi::c_char, count),
+            std::slice::from_raw_parts(flags, count),
+            std::slice::from_raw_parts(ids, count),
+        )
+    };
+
+    for ((&path, &flags), &id) in paths.iter().zip(flags).zip(ids) {
+        // SAFETY: see above
+        let path = unsafe { CStr::from_ptr(path) };
+        let path = PathBuf::from(OsStr::from_bytes(path.to_bytes()));
+        if !context.is_watched(&path) {
+            continue;
+        }
+
+        let mut handler = lock_recovered(&context.handler);
+        for event in translate(flags) {
+            let event = event.add_path(path.clone()).set_tracker(id as usize);
+            handler.handle_event(Ok(event));
+        }
+    }
+}
+
+impl StreamContext {
+    /// Whether `path` is within a watched path, or directly in it if that isn't recursive.
+    fn is_watched(&self, path: &Path) -> bool {
+        self.roots.iter().any(|(root, recursive)| {
+            path.starts_with(root)
+                && (*recursive || path == root || path.parent() == Some(root.as_path()))
+        })
+    }
+}
+
+fn has(flags: fs::FSEventStreamEventFlags, flag: fs::FSEventStreamEventFlags) -> bool {
+    flags & flag != 0
+}
+
+/// The events described by an FSEvents item's flags, as notify describes them.
+fn translate(flags: fs::FSEventStreamEventFlags) -> Vec<Event> {
+    let mut events = Vec::new();
+
+    // Marks the end of the events from before the stream was created, and carries no path
+    if has(flags, fs::kFSEventStreamEventFlagHistoryDone) {
+        return events;
+    }
+
+    if has(flags, fs::kFSEventStreamEventFlagMustScanSubDirs) {
+        let event = Event::new(EventKind::Other).set_flag(Flag::Rescan);
+        events.push(if has(flags, fs::kFSEventStreamEventFlagUserDropped) {
+            event.set_info("rescan: user dropped")
+        } else if has(flags, fs::kFSEventStreamEventFlagKernelDropped) {
+            event.set_info("rescan: kernel dropped")
+        } else {
+            event
+        });
+    }
+
+    if has(flags, fs::kFSEventStreamEventFlagRootChanged) {
+        events.push(
+            Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+                .set_info("root changed"),
+        );
+    }
+    if has(flags, fs::kFSEventStreamEventFlagMount) {
+        events.push(Event::new(EventKind::Create(CreateKind::Other)).set_info("mount"));
+    }
+    if has(flags, fs::kFSEventStreamEventFlagUnmount) {
+        events.push(Event::new(EventKind::Remove(RemoveKind::Other)).set_info("mount"));
+    }
+
+    if has(flags, fs::kFSEventStreamEventFlagItemCreated) {
+        events.push(item_event(
+            flags,
+            EventKind::Create(CreateKind::Folder),
+            EventKind::Create(CreateKind::File),
+            EventKind::Create(CreateKind::Other),
+            EventKind::Create(CreateKind::Any),
+        ));
+    }
+    if has(flags, fs::kFSEventStreamEventFlagItemRemoved) {
+        events.push(item_event(
+            flags,
+            EventKind::Remove(RemoveKind::Folder),
+            EventKind::Remove(RemoveKind::File),
+            EventKind::Remove(RemoveKind::Other),
+            EventKind::Remove(RemoveKind::Any),
+        ));
+    }
+
+    // FSEvents doesn't say which side of a rename a path is
+    if has(flags, fs::kFSEventStreamEventFlagItemRenamed) {
+        events.push(Event::new(EventKind::Modify(ModifyKind::Name(
+            RenameMode::Any,
+        ))));
+    }
+    if has(flags, fs::kFSEventStreamEventFlagItemInodeMetaMod) {
+        events.push(Event::new(EventKind::Modify(ModifyKind::Metadata(
+            MetadataKind::Any,
+        ))));
+    }
+    if has(flags, fs::kFSEventStreamEventFlagItemFinderInfoMod) {
+        events.push(
+            Event::new(EventKind::Modify(ModifyKind::Metadata(MetadataKind::Other)))
+                .set_info("meta: finder info"),
+        );
+    }
+    if has(flags, fs::kFSEventStreamEventFlagItemChangeOwner) {
+        events.push(Event::new(EventKind::Modify(ModifyKind::Metadata(
+            MetadataKind::Ownership,
+        ))));
+    }
+    if has(flags, fs::kFSEventStreamEventFlagItemXattrMod) {
+        events.push(Event::new(EventKind::Modify(ModifyKind::Metadata(
+            MetadataKind::Extended,
+        ))));
+    }
+    if has(flags, fs::kFSEventStreamEventFlagItemModified) {
+        events.push(Event::new(EventKind::Modify(ModifyKind::Data(
+            DataChange::Content,
+        ))));
+    }
+
+    if has(flags, fs::kFSEventStreamEventFlagOwnEvent) {
+        for event in &mut events {
+            event.attrs.set_process_id(std::process::id());
+        }
+    }
+
+    let names = STREAM_FLAGS
+        .iter()
+        .filter(|(flag, _)| has(flags, *flag))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>();
+    if !names.is_empty() {
+        let info = format!("{FSEVENT_FLAGS} {}", names.join(" "));
+        for event in &mut events {
+            event.attrs.set_info(&info);
+        }
+    }
+    events
+}
+
+/// Flags about the stream rather than the item, which consumers may need to act on, by the
+/// names [`crate::event`] reports them as.
+const STREAM_FLAGS: &[(fs::FSEventStreamEventFlags, &str)] = &[
+    (
+        fs::kFSEventStreamEventFlagMustScanSubDirs,
+        "must_scan_sub_dirs",
+    ),
+    (fs::kFSEventStreamEventFlagUserDropped, "user_dropped"),
+    (fs::kFSEventStreamEventFlagKernelDropped, "kernel_dropped"),
+    (
+        fs::kFSEventStreamEventFlagEventIdsWrapped,
+        "event_ids_wrapped",
+    ),
+    (fs::kFSEventStreamEventFlagRootChanged, "root_changed"),
+    (fs::kFSEventStreamEventFlagMount, "mount"),
+    (fs::kFSEventStreamEventFlagUnmount, "unmount"),
+    (fs::kFSEventStreamEventFlagOwnEvent, "own_event"),
+];
+
+/// An item created or removed, by the type of item it was.
+fn item_event(
+    flags: fs::FSEventStreamEventFlags,
+    folder: EventKind,
+    file: EventKind,
+    other: EventKind,
+    any: EventKind,
+) -> Event {
+    if has(flags, fs::kFSEventStreamEventFlagItemIsDir) {
+        Event::new(folder)
+    } else if has(flags, fs::kFSEventStreamEventFlagItemIsFile) {
+        Event::new(file)
+    } else if has(flags, fs::kFSEventStreamEventFlagItemIsSymlink) {
+        Event::new(other).set_info("is: symlink")
+    } else if has(flags, fs::kFSEventStreamEventFlagItemIsHardlink) {
+        Event::new(other).set_info("is: hardlink")
+    } else if has(flags, fs::kFSEventStreamEventFlagItemCloned) {
+        Event::new(other).set_info("is: clone")
+    } else {
+        Event::new(any)
+    }
+}
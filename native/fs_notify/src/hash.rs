@@ -0,0 +1,156 @@
+use crate::buffer::{EventBuffer, Received};
+use crate::error::lock_recovered;
+use notify::event::{AccessKind, AccessMode, CreateKind, ModifyKind, RenameMode};
+use notify::EventKind;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use xxhash_rust::xxh3::Xxh3;
+
+/// Latest content hash of each file hashed, as attached to its events.
+pub type Hashes = Arc<Mutex<HashMap<PathBuf, u64>>>;
+
+/// Most files hashed at once.
+const MAX_WORKERS: usize = 4;
+
+/// A file to hash, and where to send its hash (`None` if it couldn't be read).
+struct Job {
+    path: PathBuf,
+    done: Sender<Option<u64>>,
+}
+
+/// An event waiting for the hashes of the files it changed.
+struct Delivery {
+    received: Received,
+    hashed: Vec<(PathBuf, Receiver<Option<u64>>)>,
+}
+
+/// Hashes the contents of changed files (XXH3) on a pool of worker threads. Events still go
+/// to the buffer in the order they were received, each once its files have been hashed,
+/// so the hash in [`Hashes`] is never older than the event it is read for.
+pub struct ContentHasher {
+    jobs: Sender<Job>,
+    deliveries: Sender<Delivery>,
+    hashes: Hashes,
+}
+
+impl ContentHasher {
+    pub fn spawn(buffer: Arc<EventBuffer>) -> Arc<ContentHasher> {
+        let (jobs, queued) = mpsc::channel::<Job>();
+        let queued = Arc::new(Mutex::new(queued));
+        let workers = thread::available_parallelism().map_or(1, |count| count.get());
+        for _ in 0..workers.min(MAX_WORKERS) {
+            let queued = queued.clone();
+            thread::spawn(move || loop {
+                // Ends once the hasher, and with it the job sender, is dropped
+                let Ok(job) = lock_recovered(&queued).recv() else {
+                    return;
+                };
+                let _ = job.done.send(hash_file(&job.path).ok());
+            });
+        }
+
+        let hashes = Hashes::default();
+        let (deliveries, pending) = mpsc::channel::<Delivery>();
+        let delivered = hashes.clone();
+        thread::spawn(move || {
+            for Delivery { received, hashed } in pending {
+                record(&delivered, &received, hashed);
+                buffer.push(received);
+            }
+        });
+
+        Arc::new(ContentHasher {
+            jobs,
+            deliveries,
+            hashes,
+        })
+    }
+
+    pub fn hashes(&self) -> Hashes {
+        self.hashes.clone()
+    }
+
+    /// Queue `received` for the buffer, hashing the files whose contents it says changed.
+    pub fn submit(&self, received: Received) {
+        let mut hashed = Vec::new();
+        if let Ok(event) = &received.0 {
+            for path in changed_files(&event.kind, &event.paths) {
+                let (done, hash) = mpsc::channel();
+                let job = Job {
+                    path: path.clone(),
+                    done,
+                };
+                if self.jobs.send(job).is_ok() {
+                    hashed.push((path.clone(), hash));
+                }
+            }
+        }
+
+        let _ = self.deliveries.send(Delivery { received, hashed });
+    }
+}
+
+/// The files among `paths` whose contents an event of `kind` may have changed.
+fn changed_files<'a>(kind: &EventKind, paths: &'a [PathBuf]) -> Vec<&'a PathBuf> {
+    let changed = match kind {
+        EventKind::Create(CreateKind::Folder) => &[][..],
+        // Only the destination of a rename
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => paths.get(1..).unwrap_or(&[]),
+        EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        | EventKind::Create(_)
+        | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any)
+        | EventKind::Access(AccessKind::Close(AccessMode::Write)) => paths,
+        _ => &[][..],
+    };
+    changed.iter().filter(|path| path.is_file()).collect()
+}
+
+/// Note the hashes of the files `received` changed once they are done, and forget removed
+/// ones.
+fn record(hashes: &Hashes, received: &Received, hashed: Vec<(PathBuf, Receiver<Option<u64>>)>) {
+    // Waited for before locking, so looking hashes up never waits on a large file
+    let hashed: Vec<_> = hashed
+        .into_iter()
+        .map(|(path, hash)| (path, hash.recv().ok().flatten()))
+        .collect();
+
+    let mut hashes = lock_recovered(hashes);
+    if let Ok(event) = &received.0 {
+        if let EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) =
+            event.kind
+        {
+            for path in &event.paths {
+                hashes.remove(path);
+            }
+        } else if let (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, _]) =
+            (event.kind, &event.paths[..])
+        {
+            hashes.remove(from);
+        }
+    }
+
+    for (path, hash) in hashed {
+        match hash {
+            Some(hash) => hashes.insert(path, hash),
+            None => hashes.remove(&path),
+        };
+    }
+}
+
+/// XXH3 hash of the contents of the file `path`.
+pub fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = Xxh3::new();
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut chunk)? {
+            0 => return Ok(hasher.digest()),
+            read => hasher.update(&chunk[..read]),
+        }
+    }
+}
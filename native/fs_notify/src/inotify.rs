@@ -0,0 +1,95 @@
+//! The per-user inotify limits, and how much of them is in use. Watches and instances are
+//! counted over every process of the current user readable through `/proc`, since the
+//! limits apply to all of them together.
+
+use rustler::NifMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+const MAX_USER_WATCHES: &str = "/proc/sys/fs/inotify/max_user_watches";
+const MAX_USER_INSTANCES: &str = "/proc/sys/fs/inotify/max_user_instances";
+
+/// `%{max_user_watches: n, watches: n, remaining_watches: n, max_user_instances: n, ...}`
+#[derive(Debug, NifMap)]
+pub struct InotifyUsage {
+    pub max_user_watches: u64,
+    pub watches: u64,
+    pub remaining_watches: u64,
+    pub max_user_instances: u64,
+    pub instances: u64,
+    pub remaining_instances: u64,
+}
+
+pub fn max_user_watches() -> io::Result<u64> {
+    read_limit(MAX_USER_WATCHES)
+}
+
+pub fn max_user_instances() -> io::Result<u64> {
+    read_limit(MAX_USER_INSTANCES)
+}
+
+fn read_limit(path: &str) -> io::Result<u64> {
+    fs::read_to_string(path)?.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{path} is not a number"),
+        )
+    })
+}
+
+pub fn usage() -> io::Result<InotifyUsage> {
+    let max_user_watches = max_user_watches()?;
+    let max_user_instances = max_user_instances()?;
+    // SAFETY: `getuid` has no preconditions and can't fail
+    let uid = unsafe { libc::getuid() };
+
+    let (mut watches, mut instances) = (0, 0);
+    for process in fs::read_dir("/proc")?.flatten() {
+        let is_pid = process.file_name().to_string_lossy().parse::<u32>().is_ok();
+        let owned = || {
+            process
+                .metadata()
+                .is_ok_and(|metadata| metadata.uid() == uid)
+        };
+        if !is_pid || !owned() {
+            continue;
+        }
+        // Processes may exit, or keep their descriptors from us, while they're looked at
+        let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let is_inotify = fs::read_link(fd.path())
+                .is_ok_and(|target| target == Path::new("anon_inode:inotify"));
+            if !is_inotify {
+                continue;
+            }
+            instances += 1;
+            let fdinfo = process.path().join("fdinfo").join(fd.file_name());
+            if let Ok(info) = fs::read_to_string(fdinfo) {
+                watches += info
+                    .lines()
+                    .filter(|line| line.starts_with("inotify wd:"))
+                    .count() as u64;
+            }
+        }
+    }
+
+    Ok(InotifyUsage {
+        max_user_watches,
+        watches,
+        remaining_watches: max_user_watches.saturating_sub(watches),
+        max_user_instances,
+        instances,
+        remaining_instances: max_user_instances.saturating_sub(instances),
+    })
+}
+
+/// Whether the user has as many inotify instances as they're allowed, which is why
+/// creating another fails with `EMFILE` (as it also does when the process runs out of file
+/// descriptors).
+pub fn instances_exhausted() -> bool {
+    usage().is_ok_and(|usage| usage.remaining_instances == 0)
+}
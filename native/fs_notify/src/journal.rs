@@ -0,0 +1,290 @@
+use crate::event::Timestamp;
+use notify::event::{
+    AccessKind, AccessMode, CreateKind, DataChange, Flag, MetadataKind, ModifyKind, RemoveKind,
+    RenameMode,
+};
+use notify::{Event, EventKind};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Start of every journal, ending in the format version.
+const MAGIC: &[u8; 5] = b"FSNJ\x01";
+
+const RESCAN: u8 = 1;
+const TRACKER: u8 = 2;
+const INFO: u8 = 4;
+const PID: u8 = 8;
+
+/// Where a watcher records the events its backend reports, if it is recording. Shared
+/// between the watcher and its event sink so recording can start and stop at any time.
+pub type Recorder = Arc<Mutex<Option<Journal>>>;
+
+/// A journal being written. Records are buffered, and written out once recording stops or
+/// the watcher is stopped.
+pub struct Journal {
+    out: BufWriter<File>,
+}
+
+impl Journal {
+    pub fn create(path: &Path) -> io::Result<Journal> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        Ok(Journal { out })
+    }
+
+    /// Append an event, as received from the backend. Errors aren't recorded, since they
+    /// never reach Elixir.
+    pub fn record(&mut self, event: &notify::Result<Event>, time: Timestamp) -> io::Result<()> {
+        let Ok(event) = event else {
+            return Ok(());
+        };
+        let out = &mut self.out;
+        out.write_all(&time.monotonic.to_le_bytes())?;
+        out.write_all(&time.system.to_le_bytes())?;
+        out.write_all(&encode_kind(event.kind))?;
+
+        let attrs = &event.attrs;
+        let mut flags = 0;
+        if attrs.flag() == Some(Flag::Rescan) {
+            flags |= RESCAN;
+        }
+        if attrs.tracker().is_some() {
+            flags |= TRACKER;
+        }
+        if attrs.info().is_some() {
+            flags |= INFO;
+        }
+        if attrs.process_id().is_some() {
+            flags |= PID;
+        }
+        out.write_all(&[flags])?;
+        if let Some(tracker) = attrs.tracker() {
+            out.write_all(&(tracker as u64).to_le_bytes())?;
+        }
+        if let Some(info) = attrs.info() {
+            write_bytes(out, info.as_bytes())?;
+        }
+        if let Some(pid) = attrs.process_id() {
+            out.write_all(&pid.to_le_bytes())?;
+        }
+
+        out.write_all(&(event.paths.len() as u32).to_le_bytes())?;
+        for path in &event.paths {
+            write_bytes(out, &path_bytes(path))?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Read back every event recorded in the journal at `path`, in order.
+pub fn read(path: &Path) -> io::Result<Vec<(Event, Timestamp)>> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0; MAGIC.len()];
+    input.read_exact(&mut magic)?;
+    if magic != *MAGIC {
+        return Err(invalid("not an event journal"));
+    }
+
+    let mut events = Vec::new();
+    loop {
+        // A journal ends cleanly between records
+        let monotonic = match read_u64(&mut input) {
+            Ok(monotonic) => monotonic,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(events),
+            Err(err) => return Err(err),
+        };
+        let system = read_u64(&mut input)?;
+        let mut kind = [0; 3];
+        input.read_exact(&mut kind)?;
+        let kind = decode_kind(kind).ok_or_else(|| invalid("unknown event kind"))?;
+
+        let mut event = Event::new(kind);
+        let mut flags = [0];
+        input.read_exact(&mut flags)?;
+        if flags[0] & RESCAN != 0 {
+            event = event.set_flag(Flag::Rescan);
+        }
+        if flags[0] & TRACKER != 0 {
+            event = event.set_tracker(read_u64(&mut input)? as usize);
+        }
+        if flags[0] & INFO != 0 {
+            let info = String::from_utf8(read_bytes(&mut input)?)
+                .map_err(|_| invalid("event info is not UTF-8"))?;
+            event = event.set_info(&info);
+        }
+        if flags[0] & PID != 0 {
+            let mut pid = [0; 4];
+            input.read_exact(&mut pid)?;
+            event = event.set_process_id(u32::from_le_bytes(pid));
+        }
+
+        let mut count = [0; 4];
+        input.read_exact(&mut count)?;
+        for _ in 0..u32::from_le_bytes(count) {
+            event = event.add_path(bytes_path(read_bytes(&mut input)?));
+        }
+
+        events.push((event, Timestamp { monotonic, system }));
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    out.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    out.write_all(bytes)
+}
+
+fn read_bytes(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    input.read_exact(&mut len)?;
+    let mut bytes = Vec::new();
+    input
+        .take(u32::from_le_bytes(len).into())
+        .read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Paths are kept as raw bytes on Unix, and as UTF-8 elsewhere, so a journal recorded on
+/// one platform can be replayed on another.
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(unix)]
+fn bytes_path(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_path(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// An event kind as three bytes: the kind, its subkind and the subkind's detail.
+fn encode_kind(kind: EventKind) -> [u8; 3] {
+    match kind {
+        EventKind::Any => [0, 0, 0],
+        EventKind::Access(access) => match access {
+            AccessKind::Any => [1, 0, 0],
+            AccessKind::Read => [1, 1, 0],
+            AccessKind::Open(mode) => [1, 2, ACCESS_MODES.code(mode)],
+            AccessKind::Close(mode) => [1, 3, ACCESS_MODES.code(mode)],
+            AccessKind::Other => [1, 4, 0],
+        },
+        EventKind::Create(create) => match create {
+            CreateKind::Any => [2, 0, 0],
+            CreateKind::File => [2, 1, 0],
+            CreateKind::Folder => [2, 2, 0],
+            CreateKind::Other => [2, 3, 0],
+        },
+        EventKind::Modify(modify) => match modify {
+            ModifyKind::Any => [3, 0, 0],
+            ModifyKind::Data(change) => [3, 1, DATA_CHANGES.code(change)],
+            ModifyKind::Metadata(metadata) => [3, 2, METADATA_KINDS.code(metadata)],
+            ModifyKind::Name(mode) => [3, 3, RENAME_MODES.code(mode)],
+            ModifyKind::Other => [3, 4, 0],
+        },
+        EventKind::Remove(remove) => match remove {
+            RemoveKind::Any => [4, 0, 0],
+            RemoveKind::File => [4, 1, 0],
+            RemoveKind::Folder => [4, 2, 0],
+            RemoveKind::Other => [4, 3, 0],
+        },
+        EventKind::Other => [5, 0, 0],
+    }
+}
+
+fn decode_kind([kind, sub, detail]: [u8; 3]) -> Option<EventKind> {
+    Some(match (kind, sub) {
+        (0, 0) => EventKind::Any,
+        (1, 0) => EventKind::Access(AccessKind::Any),
+        (1, 1) => EventKind::Access(AccessKind::Read),
+        (1, 2) => EventKind::Access(AccessKind::Open(ACCESS_MODES.get(detail)?)),
+        (1, 3) => EventKind::Access(AccessKind::Close(ACCESS_MODES.get(detail)?)),
+        (1, 4) => EventKind::Access(AccessKind::Other),
+        (2, 0) => EventKind::Create(CreateKind::Any),
+        (2, 1) => EventKind::Create(CreateKind::File),
+        (2, 2) => EventKind::Create(CreateKind::Folder),
+        (2, 3) => EventKind::Create(CreateKind::Other),
+        (3, 0) => EventKind::Modify(ModifyKind::Any),
+        (3, 1) => EventKind::Modify(ModifyKind::Data(DATA_CHANGES.get(detail)?)),
+        (3, 2) => EventKind::Modify(ModifyKind::Metadata(METADATA_KINDS.get(detail)?)),
+        (3, 3) => EventKind::Modify(ModifyKind::Name(RENAME_MODES.get(detail)?)),
+        (3, 4) => EventKind::Modify(ModifyKind::Other),
+        (4, 0) => EventKind::Remove(RemoveKind::Any),
+        (4, 1) => EventKind::Remove(RemoveKind::File),
+        (4, 2) => EventKind::Remove(RemoveKind::Folder),
+        (4, 3) => EventKind::Remove(RemoveKind::Other),
+        (5, 0) => EventKind::Other,
+        _ => return None,
+    })
+}
+
+/// The values of a subkind's detail, in the order of their codes.
+struct Codes<T: 'static>(&'static [T]);
+
+impl<T: Copy + PartialEq> Codes<T> {
+    fn get(&self, code: u8) -> Option<T> {
+        self.0.get(usize::from(code)).copied()
+    }
+
+    fn code(&self, value: T) -> u8 {
+        self.0.iter().position(|known| *known == value).unwrap_or(0) as u8
+    }
+}
+
+const ACCESS_MODES: Codes<AccessMode> = Codes(&[
+    AccessMode::Any,
+    AccessMode::Execute,
+    AccessMode::Read,
+    AccessMode::Write,
+    AccessMode::Other,
+]);
+
+const DATA_CHANGES: Codes<DataChange> = Codes(&[
+    DataChange::Any,
+    DataChange::Size,
+    DataChange::Content,
+    DataChange::Other,
+]);
+
+const METADATA_KINDS: Codes<MetadataKind> = Codes(&[
+    MetadataKind::Any,
+    MetadataKind::AccessTime,
+    MetadataKind::WriteTime,
+    MetadataKind::Permissions,
+    MetadataKind::Ownership,
+    MetadataKind::Extended,
+    MetadataKind::Other,
+]);
+
+const RENAME_MODES: Codes<RenameMode> = Codes(&[
+    RenameMode::Any,
+    RenameMode::To,
+    RenameMode::From,
+    RenameMode::Both,
+    RenameMode::Other,
+]);
@@ -1,17 +1,93 @@
+use attrib::AttribTracker;
+use buffer::{BufferOptions, DropPolicy, EventBuffer, Queued, Waker};
+use config::{decode_value, invalid_config, BackendConfig};
+use demand::Demand;
+use digest::DigestMode;
+use edge::LeadingEdge;
+use error::{guarded, lock_recovered, nif_error, read_recovered, write_recovered, WatchError};
+use event::{EventDetail, EventTranslator, Label, Normalization, Timestamp, WatchEvent};
+use eventlog::EventLog;
+use filter::{FilterOptions, IgnorePreset, SharedFilter};
+use hash::ContentHasher;
+use journal::{Journal, Recorder};
+use notify::event::CreateKind;
 use notify::{
-    recommended_watcher, Config, Event, EventKind, PollWatcher, RecursiveMode, Watcher, WatcherKind,
+    Event, EventHandler, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+    WatcherKind,
 };
-use notify_debouncer_mini::{new_debouncer, DebounceEventResult, DebouncedEventKind, Debouncer};
-use rustler::{Atom, Error, NifResult};
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::{mpsc, Arc, Mutex};
-use std::time::Duration;
+use notify_debouncer_full::RecommendedCache;
+use notify_debouncer_mini::{new_debouncer_opt, DebounceEventResult, Debouncer};
+use rate::RateLimit;
+use relay::Relay;
+use rustler::types::map::MapIterator;
+use rustler::{
+    Atom, Encoder, Env, Error, LocalPid, Monitor, NifMap, NifResult, OwnedEnv, Resource,
+    ResourceArc, Term,
+};
+use scan::Recursion;
+use settle::Settler;
+use sink::{DirWatch, EventSink, Failover, LinkWatch, PendingWatch, RootWatch};
+use size::SizeTracker;
+use snapshot::{Snapshot, SnapshotDiff};
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock, TryLockError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tail::Tails;
+use throttle::Throttle;
+
+mod attrib;
+mod buffer;
+mod config;
+mod demand;
+mod digest;
+mod edge;
+mod error;
+mod event;
+mod eventlog;
+#[cfg(target_os = "linux")]
+mod fanotify;
+mod filter;
+#[cfg(all(target_os = "macos", not(feature = "macos_kqueue")))]
+mod fsevents;
+mod hash;
+#[cfg(target_os = "linux")]
+mod inotify;
+mod journal;
+mod logs;
+mod longpath;
+mod memory;
+mod netfs;
+mod probe;
+mod rate;
+#[cfg(unix)]
+mod ready;
+mod relay;
+mod scan;
+mod settle;
+mod sink;
+mod size;
+mod snapshot;
+mod tail;
+mod telemetry;
+mod throttle;
+#[cfg(all(unix, feature = "watchman"))]
+mod watchman;
+#[cfg(windows)]
+mod windows;
 
 mod atoms {
     rustler::atoms! {
         ok,
+        undefined,
         error,
+        warning,
+        fs_notify_log,
         created,
         modified,
         removed,
@@ -23,391 +99,3868 @@ mod atoms {
         recommended,
         poll,
         inotify,
+        fanotify,
+        watchman,
         fsevent,
+        fsevent_flags,
+        include_os_pid,
+        os_pid,
+        permission_events,
+        permission_timeout_ms,
+        permission_id,
+        count,
+        permission_not_found,
+        allow,
+        deny,
+        must_scan_sub_dirs,
+        user_dropped,
+        kernel_dropped,
+        event_ids_wrapped,
+        root_changed,
+        mount,
+        unmount,
+        own_event,
+        kqueue,
         windows,
         null,
         invalid_backend,
-        watcher_not_found
+        watcher_not_found,
+        watch_not_found,
+        invalid_config,
+        invalid_pattern,
+        enoent,
+        eacces,
+        watch_limit_reached,
+        internal,
+        not_owner,
+        io_error,
+        poll_interval_ms,
+        compare_contents,
+        fs_notify,
+        overflow,
+        recursive,
+        buffer_capacity,
+        drop_policy,
+        coalesce_paths,
+        drop_oldest,
+        drop_newest,
+        coalesce,
+        modified_data,
+        modified_metadata,
+        access,
+        access_open,
+        access_read,
+        access_close,
+        access_close_write,
+        written,
+        basic,
+        detailed,
+        backend,
+        debounce_ms,
+        debounce_mode,
+        mini,
+        full,
+        debounce_edge,
+        throttle_ms,
+        trailing,
+        leading,
+        both,
+        include,
+        exclude,
+        gitignore,
+        event_detail,
+        label,
+        label_events,
+        initial_scan,
+        on_demand,
+        share,
+        history_size,
+        max_events_per_second,
+        discovered,
+        ready,
+        backend_fallback,
+        depth,
+        exclude_dirs,
+        ignore_hidden,
+        ignore_presets,
+        editors,
+        os_junk,
+        extensions,
+        kinds,
+        include_regex,
+        exclude_regex,
+        raw_paths,
+        relative_paths,
+        canonicalize,
+        normalize_unicode,
+        follow_symlinks,
+        watch_link,
+        root_removed,
+        rearm_root,
+        allow_missing,
+        root_created,
+        timeout,
+        settle_ms,
+        settled,
+        coalesce_saves,
+        summarize_threshold,
+        bulk_change,
+        event_format,
+        tuple,
+        map,
+        path,
+        subscriber,
+        active_backend,
+        attrib_changed,
+        ignore_attrib,
+        classify_attrib,
+        permissions,
+        ownership,
+        timestamps,
+        xattr_changed,
+        include_stat,
+        hash_contents,
+        tail,
+        appended,
+        rotated,
+        classify_writes,
+        contents,
+        truncated,
+        rewritten,
+        replay,
+        event_log,
+        since_event_id,
+        last_event_id,
+        latency_ms,
+        read_buffer_size,
+        notify_filters,
+        file_name,
+        dir_name,
+        attributes,
+        size,
+        last_write,
+        last_access,
+        creation,
+        security,
+        nfc,
+        nfd
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 enum BackendType {
+    #[default]
     Recommended,
     Poll,
     #[cfg(target_os = "linux")]
     INotify,
-    #[cfg(target_os = "macos")]
+    #[cfg(target_os = "linux")]
+    Fanotify,
+    #[cfg(all(target_os = "macos", not(feature = "macos_kqueue")))]
     FsEvent,
+    #[cfg(any(
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly",
+        target_os = "ios",
+        all(target_os = "macos", feature = "macos_kqueue")
+    ))]
+    Kqueue,
     #[cfg(target_os = "windows")]
     Windows,
+    #[cfg(all(unix, feature = "watchman"))]
+    Watchman,
     Null,
 }
 
-type WatcherResult = Result<
-    (
-        Box<dyn Watcher + Send>,
-        mpsc::Receiver<Result<Event, notify::Error>>,
-        WatcherKind,
-    ),
-    Error,
->;
+/// Watched roots and whether each is recursive, shared with the event translators.
+type Roots = Arc<RwLock<Vec<(PathBuf, bool)>>>;
+
+/// Roots that don't exist yet, each with the closest existing ancestor watched in its place.
+type PendingRoots = Arc<RwLock<Vec<(PathBuf, PathBuf)>>>;
 
+/// A backend, along with the name `get_watcher_info` reports it by.
+type WatcherResult = Result<(Box<dyn Watcher + Send>, Atom), Error>;
+
+/// The notify watcher or debouncer feeding a watcher's event buffer.
 enum WatcherType {
     Regular {
-        #[allow(dead_code)] // Keep watcher alive for file monitoring
         watcher: Box<dyn Watcher + Send>,
-        receiver: mpsc::Receiver<Result<Event, notify::Error>>,
     },
     Debounced {
-        #[allow(dead_code)] // Keep debouncer alive for file monitoring
-        debouncer: Debouncer<notify::RecommendedWatcher>,
-        receiver: mpsc::Receiver<DebounceEventResult>,
+        debouncer: Debouncer<Relay>,
+    },
+    FullDebounced {
+        debouncer: notify_debouncer_full::Debouncer<Relay, RecommendedCache>,
     },
 }
 
-struct WatcherInfo {
-    watcher_type: WatcherType,
-    backend_kind: WatcherKind,
-    path: String,
+/// Which debouncer backs a debounced watcher.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum DebounceMode {
+    /// `notify-debouncer-mini`: one `modified` event per path per window
+    #[default]
+    Mini,
+    /// `notify-debouncer-full`: keeps event kinds and ordering, merges rename pairs
+    Full,
+}
+
+impl DebounceMode {
+    fn to_atom(self) -> Atom {
+        match self {
+            DebounceMode::Mini => atoms::mini(),
+            DebounceMode::Full => atoms::full(),
+        }
+    }
+}
+
+/// Which changes of a burst a debounced watcher reports.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum DebounceEdge {
+    /// The last, once the burst is over
+    #[default]
+    Trailing,
+    /// The first, at once, holding back the rest
+    Leading,
+    /// The first at once, and the last if any followed it
+    Both,
+}
+
+impl DebounceEdge {
+    fn to_atom(self) -> Atom {
+        match self {
+            DebounceEdge::Trailing => atoms::trailing(),
+            DebounceEdge::Leading => atoms::leading(),
+            DebounceEdge::Both => atoms::both(),
+        }
+    }
+}
+
+/// Latest version of the event shapes, which callers get unless they ask for an earlier
+/// one. Version 1 is the original `{kind, path, file_type}` tuple; version 2 adds when
+/// the event was received, its sequence number and the optional fields, and maps.
+const EVENT_FORMAT_VERSION: u32 = 2;
+
+/// What events delivered to Elixir look like.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EventShape {
+    /// `{kind, path, file_type, monotonic_us, system_us, seq, ...}`
+    Tuple,
+    /// `%{kind: kind, path: path, type: file_type, ts: system_us, seq: seq, ...}`
+    Map,
+}
+
+/// How events are delivered to Elixir: their shape, and which version of it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct EventFormat {
+    shape: EventShape,
+    version: u32,
+}
+
+impl Default for EventFormat {
+    fn default() -> Self {
+        EventFormat {
+            shape: EventShape::Tuple,
+            version: EVENT_FORMAT_VERSION,
+        }
+    }
+}
+
+impl EventFormat {
+    /// Decode `:tuple`, `:map` or `{shape, version}`; a bare shape is the latest version.
+    fn decode(term: Term) -> NifResult<Self> {
+        let (shape, version) = match term.decode::<Atom>() {
+            Ok(shape) => (shape, EVENT_FORMAT_VERSION),
+            Err(_) => term.decode::<(Atom, u32)>().map_err(|_| {
+                invalid_config("event_format must be :tuple, :map or {shape, version}")
+            })?,
+        };
+        let shape = if shape == atoms::tuple() {
+            EventShape::Tuple
+        } else if shape == atoms::map() {
+            EventShape::Map
+        } else {
+            return Err(invalid_config("event_format must be :tuple or :map").into());
+        };
+        if !(1..=EVENT_FORMAT_VERSION).contains(&version) {
+            let message = format!(
+                "event_format version {version} isn't supported; the latest is \
+                 {EVENT_FORMAT_VERSION}"
+            );
+            return Err(invalid_config(message).into());
+        }
+        if shape == EventShape::Map && version < 2 {
+            return Err(invalid_config("event_format :map starts at version 2").into());
+        }
+        Ok(EventFormat { shape, version })
+    }
+
+    /// Events are delivered as version 1's `{kind, path, file_type}` tuples.
+    fn is_baseline(self) -> bool {
+        self.version == 1
+    }
+
+    /// `{shape, version}`, as `decode` takes it.
+    fn to_tuple(self) -> (Atom, u32) {
+        let shape = match self.shape {
+            EventShape::Tuple => atoms::tuple(),
+            EventShape::Map => atoms::map(),
+        };
+        (shape, self.version)
+    }
+}
+
+/// Options gathered from the various `start_watcher_*` NIFs.
+#[derive(Clone, Default, PartialEq)]
+struct WatchOptions {
     recursive: bool,
-    #[allow(dead_code)] // Used for info/debugging purposes
+    /// How far recursive roots are followed
+    recursion: Recursion,
+    backend: BackendType,
+    /// Backends to move on to, in order, if `backend` can't be started or fails
+    fallbacks: Vec<BackendType>,
     debounce_ms: Option<u64>,
+    debounce_mode: DebounceMode,
+    debounce_edge: DebounceEdge,
+    /// Interval paths are reported at most once in, instead of being debounced
+    throttle_ms: Option<u64>,
+    subscriber: Option<LocalPid>,
+    /// Roots watched along with the one the watcher is started with, as `add_path` would
+    extra_roots: Vec<String>,
+    filters: FilterOptions,
+    backend_config: BackendConfig,
+    event_detail: EventDetail,
+    /// Kinds of events to report, or `None` for all of them
+    kinds: Option<Vec<Atom>>,
+    buffer: BufferOptions,
+    /// Returned by `get_watcher_info` and `list_watchers`
+    label: Option<Label>,
+    /// Append `label` to every event
+    label_events: bool,
+    /// Report existing entries as `:discovered`, then `:ready`, when the watcher starts
+    initial_scan: bool,
+    /// Deliver event paths as raw bytes instead of (lossily converted) UTF-8
+    raw_paths: bool,
+    event_format: EventFormat,
+    /// Add the FSEvents stream flags of the backend event to every event
+    fsevent_flags: bool,
+    /// Add the ID of the process behind each change to its events, where the backend tells
+    include_os_pid: bool,
+    /// Deliver event paths relative to the watched root
+    relative_paths: bool,
+    /// Resolve watched roots and event paths to their canonical form
+    canonicalize: bool,
+    /// Unicode form event paths are converted to, if any
+    normalize_unicode: Option<Normalization>,
+    /// Also watch the parent of symlinked roots, to report the link itself changing
+    watch_link: bool,
+    /// Watch roots again when they reappear after being removed
+    rearm_root: bool,
+    /// Accept roots that don't exist yet, watching them once they are created
+    allow_missing: bool,
+    /// Stop the watcher once the first event has been pushed to the subscriber
+    oneshot: bool,
+    /// Push no more events to subscribers than they asked for with `request_events`
+    on_demand: bool,
+    /// Share the watcher with other callers starting one on the same path with the same
+    /// options, and `share: true`
+    share: bool,
+    /// Report files as `:settled` once they have gone this long without changing
+    settle_ms: Option<u64>,
+    /// Report a temporary file renamed over its target as one modification of the target
+    coalesce_saves: bool,
+    /// Events about a directory's entries in one batch above which they are reported as one
+    /// `:bulk_change`
+    summarize_threshold: Option<usize>,
+    /// Drop changes to permissions, ownership and other metadata only
+    ignore_attrib: bool,
+    /// Report metadata changes as `:permissions`, `:ownership` or `:timestamps`
+    classify_attrib: bool,
+    /// Add each path's size and mtime to its events
+    include_stat: bool,
+    /// Add each changed file's content hash to its events
+    hash_contents: bool,
+    /// Report data appended to watched files
+    tail: bool,
+    /// Report writes as `:appended`, `:truncated` or `:rewritten`
+    classify_writes: bool,
+    /// Journal whose events are fed to the watcher, in place of a backend
+    replay: Option<String>,
+    /// Write-ahead log delivered events are appended to, for `get_events_after`
+    event_log: Option<String>,
+    /// Latest events kept for `get_events_since`, if any
+    history_size: Option<usize>,
+    /// Push no more events than this to subscribers each second
+    max_events_per_second: Option<u32>,
 }
 
-// Global storage for watchers
-static NEXT_WATCHER_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
-static WATCHERS: once_cell::sync::Lazy<Arc<Mutex<HashMap<u64, WatcherInfo>>>> =
-    once_cell::sync::Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+impl WatchOptions {
+    /// Whether `update_watcher` can turn `current` into these options: only what is watched
+    /// and how can change, everything else is fixed once the watcher starts.
+    fn updatable_from(&self, current: &WatchOptions) -> bool {
+        let fixed = WatchOptions {
+            recursive: current.recursive,
+            recursion: current.recursion.clone(),
+            backend: current.backend.clone(),
+            fallbacks: current.fallbacks.clone(),
+            backend_config: current.backend_config.clone(),
+            debounce_ms: current.debounce_ms,
+            debounce_mode: current.debounce_mode,
+            debounce_edge: current.debounce_edge,
+            throttle_ms: current.throttle_ms,
+            filters: current.filters.clone(),
+            watch_link: current.watch_link,
+            rearm_root: current.rearm_root,
+            allow_missing: current.allow_missing,
+            max_events_per_second: current.max_events_per_second,
+            ..self.clone()
+        };
+        fixed == *current
+    }
 
-impl BackendType {
-    fn from_atom(atom: Atom) -> Result<Self, Error> {
-        if atom == atoms::recommended() {
-            Ok(BackendType::Recommended)
-        } else if atom == atoms::poll() {
-            Ok(BackendType::Poll)
-        } else if atom == atoms::inotify() {
-            #[cfg(target_os = "linux")]
-            {
-                Ok(BackendType::INotify)
-            }
-            #[cfg(not(target_os = "linux"))]
-            {
-                Err(Error::BadArg)
-            }
-        } else if atom == atoms::fsevent() {
-            #[cfg(target_os = "macos")]
-            {
-                Ok(BackendType::FsEvent)
-            }
-            #[cfg(not(target_os = "macos"))]
-            {
-                Err(Error::BadArg)
-            }
-        } else if atom == atoms::windows() {
-            #[cfg(target_os = "windows")]
-            {
-                Ok(BackendType::Windows)
-            }
-            #[cfg(not(target_os = "windows"))]
-            {
-                Err(Error::BadArg)
+    /// Apply an options map as given to `start_watcher_with_options` or `update_watcher`,
+    /// e.g. `%{backend: :poll, poll_interval_ms: 500, event_detail: :detailed}`.
+    fn apply_term(&mut self, term: Term) -> NifResult<()> {
+        let iter = MapIterator::new(term).ok_or_else(|| invalid_config("options must be a map"))?;
+        let options = self;
+
+        for (key, value) in iter {
+            let key: Atom = key
+                .decode()
+                .map_err(|_| invalid_config("option keys must be atoms"))?;
+
+            if key == atoms::recursive() {
+                (options.recursive, options.recursion.depth) = decode_recursive(value)?;
+            } else if key == atoms::exclude_dirs() {
+                options.recursion.exclude_dirs = decode_value(value, "exclude_dirs")?;
+            } else if key == atoms::backend() {
+                (options.backend, options.fallbacks) = decode_backends(value)?;
+            } else if key == atoms::debounce_ms() {
+                // `nil` turns debouncing off
+                options.debounce_ms = decode_value(value, "debounce_ms")?;
+            } else if key == atoms::debounce_mode() {
+                let mode: Atom = decode_value(value, "debounce_mode")?;
+                options.debounce_mode = if mode == atoms::mini() {
+                    DebounceMode::Mini
+                } else if mode == atoms::full() {
+                    DebounceMode::Full
+                } else {
+                    return Err(invalid_config("debounce_mode must be :mini or :full").into());
+                };
+            } else if key == atoms::throttle_ms() {
+                let ms: Option<u64> = decode_value(value, "throttle_ms")?;
+                if ms == Some(0) {
+                    return Err(invalid_config("throttle_ms must be positive").into());
+                }
+                options.throttle_ms = ms;
+            } else if key == atoms::debounce_edge() {
+                let edge: Atom = decode_value(value, "debounce_edge")?;
+                options.debounce_edge = if edge == atoms::trailing() {
+                    DebounceEdge::Trailing
+                } else if edge == atoms::leading() {
+                    DebounceEdge::Leading
+                } else if edge == atoms::both() {
+                    DebounceEdge::Both
+                } else {
+                    return Err(invalid_config(
+                        "debounce_edge must be :trailing, :leading or :both",
+                    )
+                    .into());
+                };
+            } else if key == atoms::include() {
+                options.filters.include = decode_value(value, "include")?;
+            } else if key == atoms::exclude() {
+                options.filters.exclude = decode_value(value, "exclude")?;
+            } else if key == atoms::include_regex() {
+                options.filters.include_regex = decode_value(value, "include_regex")?;
+            } else if key == atoms::exclude_regex() {
+                options.filters.exclude_regex = decode_value(value, "exclude_regex")?;
+            } else if key == atoms::gitignore() {
+                options.filters.gitignore = decode_value(value, "gitignore")?;
+            } else if key == atoms::ignore_hidden() {
+                options.filters.ignore_hidden = decode_value(value, "ignore_hidden")?;
+            } else if key == atoms::extensions() {
+                options.filters.extensions = decode_value(value, "extensions")?;
+            } else if key == atoms::ignore_presets() {
+                let presets: Vec<Atom> = decode_value(value, "ignore_presets")?;
+                options.filters.ignore_presets = presets
+                    .into_iter()
+                    .map(IgnorePreset::from_atom)
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| {
+                        invalid_config("ignore_presets may only contain :editors and :os_junk")
+                    })?;
+            } else if key == atoms::event_detail() {
+                let detail: Atom = decode_value(value, "event_detail")?;
+                options.event_detail = EventDetail::from_atom(detail)
+                    .ok_or_else(|| invalid_config("event_detail must be :basic or :detailed"))?;
+            } else if key == atoms::kinds() {
+                options.kinds = decode_value(value, "kinds")?;
+            } else if key == atoms::buffer_capacity() {
+                let capacity: usize = decode_value(value, "buffer_capacity")?;
+                if capacity == 0 {
+                    return Err(invalid_config("buffer_capacity must be positive").into());
+                }
+                options.buffer.capacity = capacity;
+            } else if key == atoms::drop_policy() {
+                let policy: Atom = decode_value(value, "drop_policy")?;
+                options.buffer.policy = DropPolicy::from_atom(policy).ok_or_else(|| {
+                    invalid_config("drop_policy must be :drop_oldest, :drop_newest or :coalesce")
+                })?;
+            } else if key == atoms::coalesce_paths() {
+                options.buffer.coalesce_paths = decode_value(value, "coalesce_paths")?;
+            } else if key == atoms::label() {
+                options.label = Some(Label::from_term(value));
+            } else if key == atoms::label_events() {
+                options.label_events = decode_value(value, "label_events")?;
+            } else if key == atoms::relative_paths() {
+                options.relative_paths = decode_value(value, "relative_paths")?;
+            } else if key == atoms::canonicalize() {
+                options.canonicalize = decode_value(value, "canonicalize")?;
+            } else if key == atoms::normalize_unicode() {
+                let form: Option<Atom> = decode_value(value, "normalize_unicode")?;
+                options.normalize_unicode = form
+                    .map(|form| {
+                        Normalization::from_atom(form).ok_or_else(|| {
+                            invalid_config("normalize_unicode must be :nfc, :nfd or nil")
+                        })
+                    })
+                    .transpose()?;
+            } else if key == atoms::watch_link() {
+                options.watch_link = decode_value(value, "watch_link")?;
+            } else if key == atoms::rearm_root() {
+                options.rearm_root = decode_value(value, "rearm_root")?;
+            } else if key == atoms::allow_missing() {
+                options.allow_missing = decode_value(value, "allow_missing")?;
+            } else if key == atoms::settle_ms() {
+                let ms: Option<u64> = decode_value(value, "settle_ms")?;
+                if ms == Some(0) {
+                    return Err(invalid_config("settle_ms must be positive").into());
+                }
+                options.settle_ms = ms;
+            } else if key == atoms::event_log() {
+                options.event_log = decode_value(value, "event_log")?;
+            } else if key == atoms::replay() {
+                options.replay = decode_value(value, "replay")?;
+            } else if key == atoms::classify_writes() {
+                options.classify_writes = decode_value(value, "classify_writes")?;
+            } else if key == atoms::tail() {
+                options.tail = decode_value(value, "tail")?;
+            } else if key == atoms::hash_contents() {
+                options.hash_contents = decode_value(value, "hash_contents")?;
+            } else if key == atoms::include_stat() {
+                options.include_stat = decode_value(value, "include_stat")?;
+            } else if key == atoms::classify_attrib() {
+                options.classify_attrib = decode_value(value, "classify_attrib")?;
+            } else if key == atoms::ignore_attrib() {
+                options.ignore_attrib = decode_value(value, "ignore_attrib")?;
+            } else if key == atoms::coalesce_saves() {
+                options.coalesce_saves = decode_value(value, "coalesce_saves")?;
+            } else if key == atoms::summarize_threshold() {
+                let threshold: Option<usize> = decode_value(value, "summarize_threshold")?;
+                if threshold == Some(0) {
+                    return Err(invalid_config("summarize_threshold must be positive").into());
+                }
+                options.summarize_threshold = threshold;
+            } else if key == atoms::event_format() {
+                options.event_format = EventFormat::decode(value)?;
+            } else if key == atoms::raw_paths() {
+                options.raw_paths = decode_value(value, "raw_paths")?;
+            } else if key == atoms::fsevent_flags() {
+                options.fsevent_flags = decode_value(value, "fsevent_flags")?;
+            } else if key == atoms::include_os_pid() {
+                options.include_os_pid = decode_value(value, "include_os_pid")?;
+            } else if key == atoms::initial_scan() {
+                options.initial_scan = decode_value(value, "initial_scan")?;
+            } else if key == atoms::on_demand() {
+                options.on_demand = decode_value(value, "on_demand")?;
+            } else if key == atoms::share() {
+                options.share = decode_value(value, "share")?;
+            } else if key == atoms::history_size() {
+                let size: Option<usize> = decode_value(value, "history_size")?;
+                if size == Some(0) {
+                    return Err(invalid_config("history_size must be positive").into());
+                }
+                options.history_size = size;
+            } else if key == atoms::max_events_per_second() {
+                let rate: Option<u32> = decode_value(value, "max_events_per_second")?;
+                if rate == Some(0) {
+                    return Err(invalid_config("max_events_per_second must be positive").into());
+                }
+                options.max_events_per_second = rate;
+            } else if !options.backend_config.apply(key, value)? {
+                return Err(invalid_config("unknown option").into());
             }
-        } else if atom == atoms::null() {
-            Ok(BackendType::Null)
-        } else {
-            Err(Error::BadArg)
         }
+
+        if options.label_events && options.label.is_none() {
+            return Err(invalid_config("label_events requires a label").into());
+        }
+        if options.throttle_ms.is_some() && options.debounce_ms.is_some() {
+            return Err(invalid_config("throttle_ms and debounce_ms can't both be set").into());
+        }
+
+        Ok(())
     }
 
-    fn create_watcher(&self) -> WatcherResult {
-        let (tx, rx) = mpsc::channel();
+    /// The options as a map in the form `apply_term` takes, with every default filled in.
+    fn to_term<'a>(&self, env: Env<'a>) -> NifResult<Term<'a>> {
+        let recursive = match self.recursion.depth {
+            Some(depth) => (atoms::depth(), depth).encode(env),
+            None => self.recursive.encode(env),
+        };
+        let backend = match &self.fallbacks[..] {
+            [] => self.backend.to_atom().encode(env),
+            fallbacks => std::iter::once(&self.backend)
+                .chain(fallbacks)
+                .map(BackendType::to_atom)
+                .collect::<Vec<_>>()
+                .encode(env),
+        };
+        let ignore_presets: Vec<Atom> = self
+            .filters
+            .ignore_presets
+            .iter()
+            .map(|preset| preset.to_atom())
+            .collect();
+        let filters = &self.filters;
 
-        match self {
-            BackendType::Recommended => {
-                let watcher = recommended_watcher(tx).map_err(|_| Error::BadArg)?;
-                // Determine the backend kind based on the platform
-                #[cfg(target_os = "linux")]
-                let kind = WatcherKind::Inotify;
-                #[cfg(target_os = "macos")]
-                let kind = WatcherKind::Fsevent;
-                #[cfg(target_os = "windows")]
-                let kind = WatcherKind::ReadDirectoryChangesWatcher;
-                #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
-                let kind = WatcherKind::PollWatcher;
-                Ok((Box::new(watcher), rx, kind))
-            }
-            BackendType::Poll => {
-                let watcher = PollWatcher::new(tx, Config::default()).map_err(|_| Error::BadArg)?;
-                let kind = WatcherKind::PollWatcher;
-                Ok((Box::new(watcher), rx, kind))
+        let mut pairs = vec![
+            (atoms::recursive(), recursive),
+            (
+                atoms::exclude_dirs(),
+                self.recursion.exclude_dirs.encode(env),
+            ),
+            (atoms::backend(), backend),
+            (atoms::debounce_ms(), self.debounce_ms.encode(env)),
+            (
+                atoms::debounce_mode(),
+                self.debounce_mode.to_atom().encode(env),
+            ),
+            (
+                atoms::debounce_edge(),
+                self.debounce_edge.to_atom().encode(env),
+            ),
+            (atoms::throttle_ms(), self.throttle_ms.encode(env)),
+            (atoms::include(), filters.include.encode(env)),
+            (atoms::exclude(), filters.exclude.encode(env)),
+            (atoms::include_regex(), filters.include_regex.encode(env)),
+            (atoms::exclude_regex(), filters.exclude_regex.encode(env)),
+            (atoms::gitignore(), filters.gitignore.encode(env)),
+            (atoms::ignore_hidden(), filters.ignore_hidden.encode(env)),
+            (atoms::extensions(), filters.extensions.encode(env)),
+            (atoms::ignore_presets(), ignore_presets.encode(env)),
+            (
+                atoms::event_detail(),
+                self.event_detail.to_atom().encode(env),
+            ),
+            (atoms::kinds(), self.kinds.encode(env)),
+            (atoms::buffer_capacity(), self.buffer.capacity.encode(env)),
+            (
+                atoms::drop_policy(),
+                self.buffer.policy.to_atom().encode(env),
+            ),
+            (
+                atoms::coalesce_paths(),
+                self.buffer.coalesce_paths.encode(env),
+            ),
+            (atoms::label_events(), self.label_events.encode(env)),
+            (atoms::relative_paths(), self.relative_paths.encode(env)),
+            (atoms::canonicalize(), self.canonicalize.encode(env)),
+            (
+                atoms::normalize_unicode(),
+                self.normalize_unicode
+                    .map(Normalization::to_atom)
+                    .encode(env),
+            ),
+            (atoms::watch_link(), self.watch_link.encode(env)),
+            (atoms::rearm_root(), self.rearm_root.encode(env)),
+            (atoms::allow_missing(), self.allow_missing.encode(env)),
+            (atoms::settle_ms(), self.settle_ms.encode(env)),
+            (atoms::event_log(), self.event_log.encode(env)),
+            (atoms::replay(), self.replay.encode(env)),
+            (atoms::classify_writes(), self.classify_writes.encode(env)),
+            (atoms::tail(), self.tail.encode(env)),
+            (atoms::hash_contents(), self.hash_contents.encode(env)),
+            (atoms::include_stat(), self.include_stat.encode(env)),
+            (atoms::classify_attrib(), self.classify_attrib.encode(env)),
+            (atoms::ignore_attrib(), self.ignore_attrib.encode(env)),
+            (atoms::coalesce_saves(), self.coalesce_saves.encode(env)),
+            (
+                atoms::summarize_threshold(),
+                self.summarize_threshold.encode(env),
+            ),
+            (
+                atoms::event_format(),
+                self.event_format.to_tuple().encode(env),
+            ),
+            (atoms::raw_paths(), self.raw_paths.encode(env)),
+            (atoms::fsevent_flags(), self.fsevent_flags.encode(env)),
+            (atoms::include_os_pid(), self.include_os_pid.encode(env)),
+            (atoms::initial_scan(), self.initial_scan.encode(env)),
+            (atoms::on_demand(), self.on_demand.encode(env)),
+            (atoms::share(), self.share.encode(env)),
+            (atoms::history_size(), self.history_size.encode(env)),
+            (
+                atoms::max_events_per_second(),
+                self.max_events_per_second.encode(env),
+            ),
+        ];
+        // A label of `nil` would be a label all the same
+        if let Some(label) = &self.label {
+            pairs.push((atoms::label(), label.encode(env)));
+        }
+        pairs.extend(self.backend_config.to_pairs(env));
+        Term::map_from_pairs(env, &pairs)
+    }
+
+    /// Create the backend or debouncer these options describe, feeding events to `sink`.
+    /// Past events are only asked for with `history`, so a backend replaced later doesn't
+    /// report them again.
+    fn create_watcher_type(
+        &self,
+        sink: EventSink,
+        history: bool,
+    ) -> NifResult<(WatcherType, Atom)> {
+        let backend_config = BackendConfig {
+            since_event_id: self.backend_config.since_event_id.filter(|_| history),
+            ..self.backend_config.clone()
+        };
+        if self.replay.is_some() {
+            // Replayed events are all sent when the watcher starts
+            let (watcher, backend) = BackendType::Null.create_watcher(sink, &backend_config)?;
+            return Ok((WatcherType::Regular { watcher }, backend));
+        }
+
+        if let Some(ms) = self.throttle_ms {
+            let throttle = Throttle::new(Duration::from_millis(ms), sink);
+            let (watcher, backend) = self.backend.create_watcher(throttle, &backend_config)?;
+            return Ok((WatcherType::Regular { watcher }, backend));
+        }
+
+        // The leading edge is reported before events reach the debouncer, which only sees
+        // those held back, if any
+        let leading = self.debounce_ms.map(|ms| {
+            let per_path = self.debounce_mode == DebounceMode::Mini;
+            LeadingEdge::new(Duration::from_millis(ms), per_path, sink.clone())
+        });
+        let followed = match (leading, self.debounce_edge) {
+            (Some(leading), DebounceEdge::Leading) => {
+                let (watcher, backend) = self.backend.create_watcher(leading, &backend_config)?;
+                return Ok((WatcherType::Regular { watcher }, backend));
             }
-            #[cfg(target_os = "linux")]
-            BackendType::INotify => {
-                let watcher = notify::INotifyWatcher::new(tx, Config::default())
-                    .map_err(|_| Error::BadArg)?;
-                let kind = WatcherKind::Inotify;
-                Ok((Box::new(watcher), rx, kind))
+            (leading, DebounceEdge::Both) => leading,
+            _ => None,
+        };
+
+        match (self.debounce_ms, self.debounce_mode) {
+            (Some(ms), DebounceMode::Mini) => {
+                // Create debounced watcher, timing each path on its own: in batch mode, paths
+                // are reported together, so a quiet one may wait on a busy one
+                let config = notify_debouncer_mini::Config::default()
+                    .with_timeout(Duration::from_millis(ms))
+                    .with_batch_mode(false)
+                    .with_notify_config(backend_config.notify_config());
+                let (debouncer, backend) =
+                    Relay::with_backend(&self.backend, &backend_config, followed, || {
+                        new_debouncer_opt(config, move |result: DebounceEventResult| {
+                            let time = Timestamp::now();
+                            match result {
+                                Ok(events) => {
+                                    for event in events {
+                                        sink.send(Ok(event::from_debounced(event)), time);
+                                    }
+                                }
+                                Err(err) => sink.send(Err(err), time),
+                            }
+                        })
+                    })?;
+                Ok((WatcherType::Debounced { debouncer }, backend))
             }
-            #[cfg(target_os = "macos")]
-            BackendType::FsEvent => {
-                let watcher = notify::FsEventWatcher::new(tx, Config::default())
-                    .map_err(|_| Error::BadArg)?;
-                let kind = WatcherKind::Fsevent;
-                Ok((Box::new(watcher), rx, kind))
+            (Some(ms), DebounceMode::Full) => {
+                // Create rename-aware debounced watcher, which already times every event on
+                // its own
+                let (debouncer, backend) =
+                    Relay::with_backend(&self.backend, &backend_config, followed, || {
+                        notify_debouncer_full::new_debouncer_opt(
+                            Duration::from_millis(ms),
+                            None,
+                            move |result: notify_debouncer_full::DebounceEventResult| match result {
+                                Ok(events) => {
+                                    for event in events {
+                                        let time = Timestamp::from_instant(event.time);
+                                        sink.send(Ok(event.event), time);
+                                    }
+                                }
+                                Err(errors) => {
+                                    for err in errors {
+                                        sink.send(Err(err), Timestamp::now());
+                                    }
+                                }
+                            },
+                            RecommendedCache::new(),
+                            backend_config.notify_config(),
+                        )
+                    })?;
+                Ok((WatcherType::FullDebounced { debouncer }, backend))
             }
-            #[cfg(target_os = "windows")]
-            BackendType::Windows => {
-                let watcher = notify::ReadDirectoryChangesWatcher::new(tx, Config::default())
-                    .map_err(|_| Error::BadArg)?;
-                let kind = WatcherKind::ReadDirectoryChangesWatcher;
-                Ok((Box::new(watcher), rx, kind))
+            (None, _) => {
+                // Create regular watcher
+                let (watcher, backend) = self.backend.create_watcher(sink, &backend_config)?;
+                Ok((WatcherType::Regular { watcher }, backend))
             }
-            BackendType::Null => {
-                let watcher =
-                    notify::NullWatcher::new(tx, Config::default()).map_err(|_| Error::BadArg)?;
-                let kind = WatcherKind::NullWatcher;
-                Ok((Box::new(watcher), rx, kind))
+        }
+    }
+}
+
+/// Decode a backend, or a list of backends to try in order, into the first one and those
+/// to fall back on. Backends this platform (or process) can't use are left out of a list,
+/// as long as any are left.
+fn decode_backends(term: Term) -> NifResult<(BackendType, Vec<BackendType>)> {
+    if let Ok(atom) = term.decode::<Atom>() {
+        return Ok((BackendType::from_atom(atom)?, Vec::new()));
+    }
+    let atoms: Vec<Atom> = term
+        .decode()
+        .map_err(|_| invalid_config("backend must be an atom or a list of atoms"))?;
+
+    let mut first_error = None;
+    let mut backends = Vec::new();
+    for atom in atoms {
+        match BackendType::from_atom(atom) {
+            Ok(backend) => backends.push(backend),
+            Err(err) => {
+                first_error.get_or_insert(err);
             }
         }
     }
+    if backends.is_empty() {
+        return Err(first_error.unwrap_or_else(|| invalid_config("backend list is empty").into()));
+    }
+    let first = backends.remove(0);
+    Ok((first, backends))
 }
 
-fn start_watcher_internal(
-    path: String,
-    recursive: bool,
-    backend: BackendType,
-    debounce_ms: Option<u64>,
-) -> NifResult<(Atom, u64)> {
-    let watch_path = Path::new(&path);
-    let mode = if recursive {
+/// Decode `recursive` as either a boolean or `{:depth, n}`.
+fn decode_recursive(value: Term) -> NifResult<(bool, Option<usize>)> {
+    if let Ok(recursive) = value.decode() {
+        return Ok((recursive, None));
+    }
+
+    match value.decode::<(Atom, usize)>() {
+        Ok((tag, depth)) if tag == atoms::depth() && depth > 0 => Ok((true, Some(depth))),
+        _ => Err(invalid_config("recursive must be a boolean or {:depth, n} with n > 0").into()),
+    }
+}
+
+fn recursive_mode(recursive: bool) -> RecursiveMode {
+    if recursive {
         RecursiveMode::Recursive
     } else {
         RecursiveMode::NonRecursive
-    };
+    }
+}
 
-    let id = NEXT_WATCHER_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-
-    let watcher_info = match debounce_ms {
-        Some(ms) => {
-            // Create debounced watcher
-            let (tx, rx) = mpsc::channel();
-            let mut debouncer = new_debouncer(
-                Duration::from_millis(ms),
-                move |result: DebounceEventResult| {
-                    let _ = tx.send(result);
-                },
-            )
-            .map_err(|_| Error::BadArg)?;
+impl WatcherType {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> notify::Result<()> {
+        let path = &*longpath::extended(path);
+        match self {
+            WatcherType::Regular { watcher } => watcher.watch(path, mode),
+            WatcherType::Debounced { debouncer } => debouncer.watcher().watch(path, mode),
+            WatcherType::FullDebounced { debouncer } => debouncer.watch(path, mode),
+        }
+    }
 
-            // Watch the path
-            debouncer
-                .watcher()
-                .watch(watch_path, mode)
-                .map_err(|_| Error::BadArg)?;
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        let path = &*longpath::extended(path);
+        match self {
+            WatcherType::Regular { watcher } => watcher.unwatch(path),
+            WatcherType::Debounced { debouncer } => debouncer.watcher().unwatch(path),
+            WatcherType::FullDebounced { debouncer } => debouncer.unwatch(path),
+        }
+    }
 
-            // Determine the backend kind based on the platform (debouncer uses recommended watcher)
-            #[cfg(target_os = "linux")]
-            let backend_kind = WatcherKind::Inotify;
-            #[cfg(target_os = "macos")]
-            let backend_kind = WatcherKind::Fsevent;
-            #[cfg(target_os = "windows")]
-            let backend_kind = WatcherKind::ReadDirectoryChangesWatcher;
-            #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
-            let backend_kind = WatcherKind::PollWatcher;
-
-            WatcherInfo {
-                watcher_type: WatcherType::Debounced {
-                    debouncer,
-                    receiver: rx,
-                },
-                backend_kind,
-                path: path.clone(),
-                recursive,
-                debounce_ms: Some(ms),
-            }
-        }
-        None => {
-            // Create regular watcher
-            let (mut watcher, receiver, backend_kind) = backend.create_watcher()?;
-            watcher.watch(watch_path, mode).map_err(|_| Error::BadArg)?;
-
-            WatcherInfo {
-                watcher_type: WatcherType::Regular { watcher, receiver },
-                backend_kind,
-                path: path.clone(),
-                recursive,
-                debounce_ms: None,
+    /// Watch a root, one directory at a time when `recursion` is limited.
+    fn watch_root(
+        &mut self,
+        root: &Path,
+        recursive: bool,
+        recursion: &Recursion,
+    ) -> notify::Result<()> {
+        if recursive && recursion.is_manual() {
+            recursion
+                .directories(root)
+                .try_for_each(|dir| self.watch(&dir, RecursiveMode::NonRecursive))
+        } else {
+            self.watch(root, recursive_mode(recursive))
+        }
+    }
+
+    fn unwatch_root(
+        &mut self,
+        root: &Path,
+        recursive: bool,
+        recursion: &Recursion,
+    ) -> notify::Result<()> {
+        self.unwatch(root)?;
+
+        if recursive && recursion.is_manual() {
+            for dir in recursion.directories(root).skip(1) {
+                // Directories removed since are no longer watched anyway
+                let _ = self.unwatch(&dir);
             }
         }
-    };
+        Ok(())
+    }
+
+    /// Watch the directory holding `root` if `root` is a symlink, so the link being
+    /// replaced is reported along with changes to its target.
+    fn watch_link(&mut self, root: &Path) -> notify::Result<()> {
+        match root.parent().filter(|_| root.is_symlink()) {
+            Some(parent) => self.watch(parent, RecursiveMode::NonRecursive),
+            None => Ok(()),
+        }
+    }
 
-    let mut watchers = WATCHERS.lock().unwrap();
-    watchers.insert(id, watcher_info);
+    /// Watch the closest existing ancestor of `root`, which doesn't exist yet, in its place.
+    fn watch_pending(&mut self, root: &Path, pending: &PendingRoots) -> notify::Result<()> {
+        let ancestor = existing_ancestor(root)
+            .ok_or_else(|| notify::Error::path_not_found().add_path(root.to_path_buf()))?;
+        self.watch(ancestor, RecursiveMode::NonRecursive)?;
 
-    Ok((atoms::ok(), id))
-}
+        let mut pending = write_recovered(pending);
+        pending.retain(|(pending_root, _)| pending_root != root);
+        pending.push((root.to_path_buf(), ancestor.to_path_buf()));
+        Ok(())
+    }
 
-#[rustler::nif]
-fn start_watcher(path: String, recursive: bool) -> NifResult<(Atom, u64)> {
-    start_watcher_internal(path, recursive, BackendType::Recommended, None)
+    fn unwatch_link(&mut self, root: &Path) {
+        if let Some(parent) = root.parent().filter(|_| root.is_symlink()) {
+            // The link may be gone, or its parent no longer watched
+            let _ = self.unwatch(parent);
+        }
+    }
 }
 
-#[rustler::nif]
-fn start_watcher_with_backend(
-    path: String,
-    recursive: bool,
-    backend_atom: Atom,
-) -> NifResult<(Atom, u64)> {
-    let backend = BackendType::from_atom(backend_atom)?;
-    start_watcher_internal(path, recursive, backend, None)
+fn existing_ancestor(root: &Path) -> Option<&Path> {
+    root.ancestors().skip(1).find(|dir| dir.is_dir())
 }
 
-#[rustler::nif]
-fn start_watcher_with_debounce(
+struct WatcherInfo {
+    watcher_type: WatcherType,
+    /// Backend in use, as reported by `get_watcher_info`
+    backend: Atom,
+    /// Root the watcher was started with, as reported by `get_watcher_info`
     path: String,
-    recursive: bool,
-    backend_atom: Atom,
-    debounce_ms: u64,
-) -> NifResult<(Atom, u64)> {
-    let backend = BackendType::from_atom(backend_atom)?;
-    start_watcher_internal(path, recursive, backend, Some(debounce_ms))
+    /// Effective options, as last set by `start_watcher_*` or `update_watcher`
+    options: WatchOptions,
+    /// Every root currently watched, including `path` unless it was removed
+    roots: Roots,
+    filter: SharedFilter,
+    buffer: Arc<EventBuffer>,
+    /// Translates buffered events for `get_events`; `None` when they are pushed to a process
+    translator: Option<EventTranslator>,
+    /// Translated events not yet taken by `get_events`
+    queue: Vec<WatchEvent>,
+    /// One entry per `start_watcher_*` call sharing this watcher; it is torn down once every
+    /// owner has stopped it or exited
+    owners: Vec<(LocalPid, Option<Monitor>)>,
+    /// Monitor on the push subscriber the watcher was started with, when it isn't the owner;
+    /// the watcher is torn down when it exits
+    subscriber_monitor: Option<Monitor>,
+    /// Processes events are sent to instead of being queued for `get_events`
+    subscribers: Vec<(LocalPid, Option<Monitor>)>,
+    /// Directories to watch as they appear under roots watched one directory at a time,
+    /// once recursion has been limited
+    new_dirs: Option<Sender<(PathBuf, usize)>>,
+    /// Symlinked roots to watch again once replaced, with `watch_link`
+    relinked: Option<Sender<PathBuf>>,
+    /// Removed roots to watch again once they reappear, with `rearm_root`
+    removed_roots: Option<Sender<PathBuf>>,
+    /// Roots that don't exist yet, with `allow_missing`
+    pending: PendingRoots,
+    /// Pending roots to look at again as they or their parents are created
+    pending_changed: Option<Sender<PathBuf>>,
+    /// Reports files once they stop changing, with `settle_ms`
+    settler: Option<Arc<Settler>>,
+    /// Classifies metadata changes, with `classify_attrib`
+    attribs: Option<Arc<AttribTracker>>,
+    /// Classifies writes by how they change files' sizes, with `classify_writes`
+    sizes: Option<Arc<SizeTracker>>,
+    /// Hashes changed files, with `hash_contents`
+    hasher: Option<Arc<ContentHasher>>,
+    /// Watched files whose appended data is reported, with `tail`
+    tails: Option<Tails>,
+    /// Journal the backend's events are written to, while `record_events` is on
+    recorder: Recorder,
+    /// Backend failures, once the watcher has backends to fall back on
+    failover: Option<Sender<usize>>,
+    /// Events subscribers asked for, with `on_demand`
+    demand: Arc<Demand>,
+    /// Pipe readable while events can be taken, once `select_ready` has been called
+    #[cfg(unix)]
+    ready: Option<Arc<ready::ReadyPipe>>,
 }
 
-#[rustler::nif]
-fn stop_watcher(id: u64) -> Atom {
-    let mut watchers = WATCHERS.lock().unwrap();
-    if watchers.remove(&id).is_some() {
-        atoms::ok()
-    } else {
-        atoms::watcher_not_found()
+impl Drop for WatcherInfo {
+    fn drop(&mut self) {
+        // The backend is going away, so end any thread waiting on the buffer or for demand
+        self.buffer.close();
+        self.demand.close();
+        telemetry::decrement(&telemetry::WATCHERS_ACTIVE);
     }
 }
 
-#[rustler::nif]
-fn get_events(id: u64) -> NifResult<Vec<(Atom, String, Atom)>> {
-    let mut watchers = WATCHERS.lock().unwrap();
-
-    if let Some(watcher_info) = watchers.get_mut(&id) {
-        let mut events = Vec::new();
-
-        match &mut watcher_info.watcher_type {
-            WatcherType::Regular { receiver, .. } => {
-                // Handle regular watcher events
-                while let Ok(result) = receiver.try_recv() {
-                    match result {
-                        Ok(event) => {
-                            for path in event.paths {
-                                let event_atom = event_kind_to_atom(&event.kind);
-                                let path_str = path_to_string(&path);
-                                let file_type_atom = if path.is_dir() {
-                                    atoms::directory()
-                                } else {
-                                    atoms::file()
-                                };
-
-                                events.push((event_atom, path_str, file_type_atom));
-                            }
-                        }
-                        Err(_) => {
-                            // Error in file watching, but we'll continue
-                            continue;
-                        }
-                    }
-                }
+impl WatcherInfo {
+    fn dir_watch(&self, recursion: &Recursion) -> Option<DirWatch> {
+        if !recursion.is_manual() {
+            return None;
+        }
+        Some(DirWatch::new(
+            self.roots.clone(),
+            recursion.clone(),
+            self.new_dirs.clone()?,
+        ))
+    }
+
+    fn root_watch(&self, rearm: bool) -> RootWatch {
+        let removed_roots = self.removed_roots.clone().filter(|_| rearm);
+        RootWatch::new(self.roots.clone(), removed_roots)
+    }
+
+    fn pending_watch(&self) -> Option<PendingWatch> {
+        Some(PendingWatch::new(
+            self.roots.clone(),
+            self.pending.clone(),
+            self.pending_changed.clone()?,
+        ))
+    }
+
+    /// Replace the backend with the one `options` describe, watching `roots` with it. The
+    /// old backend is kept if the new one can't be started.
+    fn rebuild_backend(
+        &mut self,
+        options: &WatchOptions,
+        roots: &[(PathBuf, bool)],
+        history: bool,
+    ) -> NifResult<()> {
+        let failover = self
+            .failover
+            .clone()
+            .map(|failed| Failover::new(failed, options.fallbacks.len()));
+        // The buffer outlives the old backend, so queued events are kept
+        let sink = EventSink::new(self.buffer.clone(), self.filter.clone())
+            .with_dirs(self.dir_watch(&options.recursion))
+            .with_links(self.link_watch().filter(|_| options.watch_link))
+            .with_root_watch(self.root_watch(options.rearm_root))
+            .with_pending(self.pending_watch())
+            .with_settler(self.settler.clone())
+            .with_attribs(self.attribs.clone())
+            .with_sizes(self.sizes.clone())
+            .with_hasher(self.hasher.clone())
+            .with_recorder(self.recorder.clone())
+            .with_failover(failover);
+        let (mut watcher_type, backend) = options.create_watcher_type(sink, history)?;
+        for (root, recursive) in roots {
+            if let Some(ancestor) = self.pending_ancestor(root) {
+                watcher_type
+                    .watch(&ancestor, RecursiveMode::NonRecursive)
+                    .map_err(nif_error)?;
+                continue;
             }
-            WatcherType::Debounced { receiver, .. } => {
-                // Handle debounced watcher events
-                while let Ok(result) = receiver.try_recv() {
-                    match result {
-                        Ok(debounced_events) => {
-                            for event in debounced_events {
-                                let event_atom = debounced_event_kind_to_atom(&event.kind);
-                                let path_str = path_to_string(&event.path);
-                                let file_type_atom = if event.path.is_dir() {
-                                    atoms::directory()
-                                } else {
-                                    atoms::file()
-                                };
-
-                                events.push((event_atom, path_str, file_type_atom));
-                            }
-                        }
-                        Err(_) => {
-                            // Error in file watching, but we'll continue
-                            continue;
+            watcher_type
+                .watch_root(root, *recursive, &options.recursion)
+                .map_err(nif_error)?;
+            if options.watch_link {
+                watcher_type.watch_link(root).map_err(nif_error)?;
+            }
+        }
+        self.watcher_type = watcher_type;
+        self.backend = backend;
+        Ok(())
+    }
+
+    fn pending_ancestor(&self, root: &Path) -> Option<PathBuf> {
+        let pending = read_recovered(&self.pending);
+        pending
+            .iter()
+            .find(|(pending_root, _)| pending_root == root)
+            .map(|(_, ancestor)| ancestor.clone())
+    }
+
+    /// Catch up with a pending root: watch it if it exists now, reporting it as created,
+    /// or else move the watch standing in for it down to the closest ancestor that exists.
+    fn advance_pending(&mut self, root: &Path) {
+        let Some(ancestor) = self.pending_ancestor(root) else {
+            return;
+        };
+        let recursive = read_recovered(&self.roots)
+            .iter()
+            .any(|(watched, recursive)| watched == root && *recursive);
+
+        let recursion = &self.options.recursion;
+        if root.exists()
+            && self
+                .watcher_type
+                .watch_root(root, recursive, recursion)
+                .is_ok()
+        {
+            write_recovered(&self.pending).retain(|(pending_root, _)| pending_root != root);
+            self.release_ancestor(&ancestor);
+
+            let created = Event::new(EventKind::Create(CreateKind::Any))
+                .add_path(root.to_path_buf())
+                .set_info(sink::ROOT_CREATED);
+            self.buffer.push((Ok(created), Timestamp::now()));
+            return;
+        }
+
+        let Some(closer) = existing_ancestor(root).filter(|closer| *closer != ancestor) else {
+            return;
+        };
+        if self
+            .watcher_type
+            .watch(closer, RecursiveMode::NonRecursive)
+            .is_err()
+        {
+            return;
+        }
+        for (pending_root, pending_ancestor) in write_recovered(&self.pending).iter_mut() {
+            if pending_root == root {
+                *pending_ancestor = closer.to_path_buf();
+            }
+        }
+        self.release_ancestor(&ancestor);
+
+        // The rest of the way may have been created before the new watch was in place
+        self.advance_pending(root);
+    }
+
+    /// Stop watching a directory that stood in for pending roots, unless it still does or
+    /// is a root in its own right.
+    fn release_ancestor(&mut self, ancestor: &Path) {
+        let in_use = read_recovered(&self.pending)
+            .iter()
+            .any(|(_, pending_ancestor)| pending_ancestor == ancestor)
+            || read_recovered(&self.roots)
+                .iter()
+                .any(|(root, _)| root == ancestor);
+        if !in_use {
+            let _ = self.watcher_type.unwatch(ancestor);
+        }
+    }
+
+    fn link_watch(&self) -> Option<LinkWatch> {
+        Some(LinkWatch::new(self.roots.clone(), self.relinked.clone()?))
+    }
+
+    /// Drop `pid`'s claim on the watcher, returning whether any owners are left. A watcher
+    /// nobody shares can be stopped by any process; `None` if it is shared and `pid` holds
+    /// no claim on it.
+    fn release(&mut self, pid: LocalPid) -> Option<bool> {
+        match self.owners.iter().position(|(owner, _)| *owner == pid) {
+            Some(index) => {
+                self.owners.remove(index);
+            }
+            None if self.owners.len() > 1 => return None,
+            None => self.owners.clear(),
+        }
+        Some(!self.owners.is_empty())
+    }
+
+    /// Events waiting to be taken, whether translated yet or not.
+    fn queue_depth(&self) -> usize {
+        self.buffer.stats().len + self.queue.len()
+    }
+
+    /// Translate buffered events into `queue` until it holds at least `want` of them or the
+    /// buffer is empty.
+    fn receive(&mut self, want: usize) {
+        let Some(translator) = &mut self.translator else {
+            // Events are sent straight to the subscribing process
+            return;
+        };
+
+        // No more is translated than the buffer holds, so taking or peeking at a backlog
+        // doesn't move it somewhere unbounded; the rest waits in the buffer
+        let want = want.min(self.buffer.capacity().saturating_add(1));
+        let start = self.queue.len();
+        while self.queue.len() < want {
+            match self.buffer.try_pop() {
+                Some(Queued {
+                    received: (Ok(event), time),
+                    count,
+                    lost,
+                }) => {
+                    let first = self.queue.len();
+                    translator.process(event, time, count, &mut self.queue);
+                    if let Some(lost) = lost {
+                        for event in &mut self.queue[first..] {
+                            event.set_lost(lost);
                         }
                     }
                 }
+                Some(Queued {
+                    received: (Err(err), _),
+                    ..
+                }) => {
+                    // Error in file watching, but we'll continue
+                    logs::error(format!("backend error: {err}"));
+                    continue;
+                }
+                None => {
+                    // Only give up on pairing renames once everything has been seen
+                    translator.flush(&mut self.queue);
+                    break;
+                }
             }
         }
-
-        Ok(events)
-    } else {
-        Err(Error::BadArg)
+        translator.summarize(&mut self.queue, start);
     }
 }
 
-#[rustler::nif]
-fn get_watcher_info(id: u64) -> NifResult<(Atom, String, bool, Atom)> {
-    let watchers = WATCHERS.lock().unwrap();
-
-    if let Some(watcher_info) = watchers.get(&id) {
-        let backend_atom = match watcher_info.backend_kind {
-            WatcherKind::Inotify => atoms::inotify(),
-            WatcherKind::Fsevent => atoms::fsevent(),
-            WatcherKind::PollWatcher => atoms::poll(),
-            WatcherKind::ReadDirectoryChangesWatcher => atoms::windows(),
-            WatcherKind::NullWatcher => atoms::null(),
-            _ => atoms::unknown(),
-        };
-
-        Ok((
-            atoms::ok(),
-            watcher_info.path.clone(),
-            watcher_info.recursive,
-            backend_atom,
-        ))
-    } else {
-        Err(Error::BadArg)
-    }
+/// State shared by every handle to a watcher.
+struct WatcherCore {
+    info: Mutex<Option<WatcherInfo>>,
+    /// Handles returned by starting or sharing the watcher that haven't been stopped or
+    /// garbage collected; the watcher is torn down with the last
+    claims: AtomicUsize,
+    /// Sequence number of the last event queued for Elixir
+    last_seq: AtomicU64,
+    /// Events taken with `get_events` or sent to the subscriber
+    delivered: AtomicU64,
+    /// Events sent to the subscriber late, held back by `max_events_per_second`
+    rate_limited: AtomicU64,
+    /// Label attached to every event, if the watcher was started with `label_events: true`
+    event_label: Option<Label>,
+    /// Deliver event paths as raw bytes
+    raw_paths: bool,
+    /// Deliver events as maps, as changed by `set_event_format`
+    map_events: AtomicBool,
+    /// Deliver events as version 1 tuples, likewise
+    baseline_events: AtomicBool,
+    /// Deliver the FSEvents stream flags of every event
+    fsevent_flags: bool,
+    /// Deliver the process behind every event
+    include_os_pid: bool,
+    /// Log every event numbered, with `event_log`
+    event_log: Option<Mutex<EventLog>>,
+    /// Keep the latest events numbered, with `history_size`
+    history: Option<History>,
+    /// FSEvents ID of the last event numbered, or 0 before the first
+    last_event_id: AtomicU64,
 }
 
-#[rustler::nif]
-fn list_available_backends() -> Vec<Atom> {
-    let mut backends = vec![atoms::recommended(), atoms::poll(), atoms::null()];
+/// The latest events numbered, for `get_events_since`, oldest first.
+struct History {
+    size: usize,
+    events: Mutex<VecDeque<WatchEvent>>,
+}
 
-    #[cfg(target_os = "linux")]
-    backends.push(atoms::inotify());
+impl History {
+    fn new(size: usize) -> Self {
+        History {
+            size,
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
 
-    #[cfg(target_os = "macos")]
-    backends.push(atoms::fsevent());
+    fn record(&self, events: &[WatchEvent]) {
+        let mut retained = lock_recovered(&self.events);
+        for event in events {
+            if retained.len() == self.size {
+                retained.pop_front();
+            }
+            retained.push_back(event.clone());
+        }
+    }
 
-    #[cfg(target_os = "windows")]
-    backends.push(atoms::windows());
+    /// Bytes taken by the events kept, for `memory_info`.
+    fn memory(&self) -> usize {
+        let events = lock_recovered(&self.events);
+        events.capacity() * mem::size_of::<WatchEvent>()
+            + events.iter().map(WatchEvent::memory).sum::<usize>()
+    }
 
-    backends
-}
+    /// Give back the room beyond the events kept, for `compact`.
+    fn compact(&self) {
+        lock_recovered(&self.events).shrink_to_fit();
+    }
 
-fn event_kind_to_atom(kind: &EventKind) -> Atom {
-    match kind {
-        EventKind::Create(_) => atoms::created(),
-        EventKind::Modify(_) => atoms::modified(),
-        EventKind::Remove(_) => atoms::removed(),
-        EventKind::Other => atoms::meta(),
-        _ => atoms::unknown(),
+    /// Events numbered after `seq`, and whether any of those are no longer kept.
+    fn since(&self, seq: u64) -> (Vec<WatchEvent>, bool) {
+        let retained = lock_recovered(&self.events);
+        let missed = retained
+            .front()
+            .is_some_and(|oldest| oldest.seq > seq.saturating_add(1));
+        let events = retained
+            .iter()
+            .filter(|event| event.seq > seq)
+            .cloned()
+            .collect();
+        (events, missed)
     }
 }
 
-fn debounced_event_kind_to_atom(kind: &DebouncedEventKind) -> Atom {
-    match kind {
-        DebouncedEventKind::Any => atoms::modified(),
-        _ => atoms::unknown(),
+impl WatcherCore {
+    /// Number `events` in delivery order, continuing from the previous batch, and label them.
+    fn sequence(&self, events: &mut [WatchEvent]) {
+        for event in events.iter_mut() {
+            event.seq = self.last_seq.fetch_add(1, Ordering::Relaxed) + 1;
+            event.label = self.event_label.clone();
+            event.raw_paths = self.raw_paths;
+            event.as_map = self.map_events.load(Ordering::Relaxed);
+            event.baseline = self.baseline_events.load(Ordering::Relaxed);
+            event.include_fsevent_flags = self.fsevent_flags;
+            event.include_os_pid = self.include_os_pid;
+            if let Some(id) = event.event_id {
+                self.last_event_id.fetch_max(id, Ordering::Relaxed);
+            }
+        }
+
+        if let Some(log) = &self.event_log {
+            // Events are still delivered when they can't be logged; `get_events_after`
+            // just won't find them
+            let _ = lock_recovered(log).append(events);
+        }
+        if let Some(history) = &self.history {
+            history.record(events);
+        }
     }
+
+    /// Receive from the backend until `want` events are queued, numbering the new arrivals.
+    fn fill(&self, info: &mut WatcherInfo, want: usize) {
+        let before = info.queue.len();
+        info.receive(want);
+        self.sequence(&mut info.queue[before..]);
+    }
+}
+
+/// Handle returned to Elixir for each watcher. The watcher is torn down by `stop_watcher`,
+/// when the process that started it exits, or when every handle claiming it is garbage
+/// collected.
+struct WatcherResource {
+    core: Arc<WatcherCore>,
+    /// Counted in `claims`, as returned by starting or sharing the watcher rather than by
+    /// listing it or with an event
+    claimed: AtomicBool,
+}
+
+impl WatcherResource {
+    /// A handle for the caller that started or shared the watcher, keeping it running.
+    fn claim(core: Arc<WatcherCore>) -> Self {
+        core.claims.fetch_add(1, Ordering::SeqCst);
+        WatcherResource {
+            core,
+            claimed: AtomicBool::new(true),
+        }
+    }
+
+    /// A handle that doesn't keep the watcher running, e.g. for `list_watchers`.
+    fn view(core: Arc<WatcherCore>) -> Self {
+        WatcherResource {
+            core,
+            claimed: AtomicBool::new(false),
+        }
+    }
+
+    /// Give up this handle's claim, if it has one. Returns whether it was the last.
+    fn release(&self) -> bool {
+        self.claimed.swap(false, Ordering::SeqCst)
+            && self.core.claims.fetch_sub(1, Ordering::SeqCst) == 1
+    }
+}
+
+impl Deref for WatcherResource {
+    type Target = WatcherCore;
+
+    fn deref(&self) -> &WatcherCore {
+        &self.core
+    }
+}
+
+#[rustler::resource_impl]
+impl Resource for WatcherResource {
+    fn down<'a>(&'a self, env: Env<'a>, _pid: LocalPid, monitor: Monitor) {
+        let mut info = lock_recovered(&self.info);
+        let Some(watcher_info) = info.as_mut() else {
+            return;
+        };
+
+        if watcher_info.subscriber_monitor != Some(monitor) {
+            // A subscriber added with `subscribe` exited
+            watcher_info
+                .subscribers
+                .retain(|(_, subscriber)| *subscriber != Some(monitor));
+
+            // An owner exited; ignore it if it already stopped the watcher
+            let Some(index) = watcher_info
+                .owners
+                .iter()
+                .position(|(_, owner)| *owner == Some(monitor))
+            else {
+                return;
+            };
+            watcher_info.owners.remove(index);
+            if !watcher_info.owners.is_empty() {
+                return;
+            }
+        }
+
+        // Dropping the watcher releases the OS watch and ends any sender thread
+        tear_down(env, &mut info);
+    }
+
+    fn destructor(self, env: Env<'_>) {
+        // The watcher goes with its last claim, while its readiness pipe can still be
+        // unselected
+        if self.release() {
+            tear_down(env, &mut lock_recovered(&self.info));
+        }
+    }
+}
+
+/// Drop the watcher, from a NIF or callback, whose `env` its readiness pipe is unselected
+/// with.
+fn tear_down(env: Env, info: &mut Option<WatcherInfo>) {
+    #[cfg(unix)]
+    if let Some(pipe) = info
+        .as_ref()
+        .and_then(|watcher_info| watcher_info.ready.as_ref())
+    {
+        pipe.stop(env);
+    }
+    #[cfg(not(unix))]
+    let _ = env;
+    info.take();
+}
+
+type WatcherHandle = ResourceArc<WatcherResource>;
+
+/// Every watcher created, for `list_watchers`. Entries don't keep watchers alive.
+static WATCHERS: Mutex<Vec<Weak<WatcherCore>>> = Mutex::new(Vec::new());
+
+/// Options watchers start from, as set with `set_defaults`, or `None` for the built-in ones.
+static DEFAULTS: Mutex<Option<WatchOptions>> = Mutex::new(None);
+
+fn default_options() -> WatchOptions {
+    lock_recovered(&DEFAULTS).clone().unwrap_or_default()
+}
+
+fn register_watcher(core: &Arc<WatcherCore>) {
+    let mut watchers = lock_recovered(&WATCHERS);
+    watchers.retain(|watcher| watcher.strong_count() > 0);
+    watchers.push(Arc::downgrade(core));
+}
+
+/// Find a running watcher started on `path` with the same options, `share: true` among them,
+/// and claim it for the calling process, so it isn't watched twice. Watchers pushing to
+/// subscribers, with roots added or removed, or whose caller expects an initial scan are
+/// never shared.
+fn share_watcher(env: Env, path: &str, options: &WatchOptions) -> Option<WatcherHandle> {
+    if !options.share
+        || options.subscriber.is_some()
+        || options.initial_scan
+        || options.replay.is_some()
+    {
+        return None;
+    }
+
+    let watchers = lock_recovered(&WATCHERS);
+    let roots: Vec<_> = std::iter::once(path)
+        .chain(options.extra_roots.iter().map(String::as_str))
+        .map(|root| (PathBuf::from(root), options.recursive))
+        .collect();
+
+    for core in watchers.iter().filter_map(Weak::upgrade) {
+        let mut info = lock_recovered(&core.info);
+        let Some(watcher_info) = info.as_mut() else {
+            continue;
+        };
+        if watcher_info.path != path
+            || !watcher_info.subscribers.is_empty()
+            || watcher_info.options != *options
+            || *read_recovered(&watcher_info.roots) != roots
+        {
+            continue;
+        }
+
+        let handle = ResourceArc::new(WatcherResource::claim(core.clone()));
+        let monitor = env.monitor(&handle, &env.pid());
+        watcher_info.owners.push((env.pid(), monitor));
+        return Some(handle);
+    }
+
+    None
+}
+
+fn live_watchers() -> Vec<Arc<WatcherCore>> {
+    let mut watchers = lock_recovered(&WATCHERS);
+    watchers.retain(|watcher| watcher.strong_count() > 0);
+    watchers.iter().filter_map(Weak::upgrade).collect()
+}
+
+impl BackendType {
+    /// The atom the backend is asked for by.
+    fn to_atom(&self) -> Atom {
+        backend_named(self.name()).expect("every backend is named by an atom")
+    }
+
+    /// Name of the atom the backend is asked for by.
+    fn name(&self) -> &'static str {
+        match self {
+            BackendType::Recommended => "recommended",
+            BackendType::Poll => "poll",
+            #[cfg(target_os = "linux")]
+            BackendType::INotify => "inotify",
+            #[cfg(target_os = "linux")]
+            BackendType::Fanotify => "fanotify",
+            #[cfg(all(target_os = "macos", not(feature = "macos_kqueue")))]
+            BackendType::FsEvent => "fsevent",
+            #[cfg(any(
+                target_os = "freebsd",
+                target_os = "openbsd",
+                target_os = "netbsd",
+                target_os = "dragonfly",
+                target_os = "ios",
+                all(target_os = "macos", feature = "macos_kqueue")
+            ))]
+            BackendType::Kqueue => "kqueue",
+            #[cfg(target_os = "windows")]
+            BackendType::Windows => "windows",
+            #[cfg(all(unix, feature = "watchman"))]
+            BackendType::Watchman => "watchman",
+            BackendType::Null => "null",
+        }
+    }
+
+    fn from_atom(atom: Atom) -> Result<Self, Error> {
+        if atom == atoms::recommended() {
+            Ok(BackendType::Recommended)
+        } else if atom == atoms::poll() {
+            Ok(BackendType::Poll)
+        } else if atom == atoms::inotify() {
+            #[cfg(target_os = "linux")]
+            {
+                Ok(BackendType::INotify)
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                Err(WatchError::invalid_backend("backend is not available on this platform").into())
+            }
+        } else if atom == atoms::fanotify() {
+            #[cfg(target_os = "linux")]
+            {
+                if !fanotify::available() {
+                    return Err(WatchError::invalid_backend(
+                        "fanotify needs CAP_SYS_ADMIN, which this process lacks",
+                    )
+                    .into());
+                }
+                Ok(BackendType::Fanotify)
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                Err(WatchError::invalid_backend("backend is not available on this platform").into())
+            }
+        } else if atom == atoms::fsevent() {
+            #[cfg(all(target_os = "macos", not(feature = "macos_kqueue")))]
+            {
+                Ok(BackendType::FsEvent)
+            }
+            #[cfg(not(all(target_os = "macos", not(feature = "macos_kqueue"))))]
+            {
+                Err(WatchError::invalid_backend("backend is not available on this platform").into())
+            }
+        } else if atom == atoms::kqueue() {
+            #[cfg(any(
+                target_os = "freebsd",
+                target_os = "openbsd",
+                target_os = "netbsd",
+                target_os = "dragonfly",
+                target_os = "ios",
+                all(target_os = "macos", feature = "macos_kqueue")
+            ))]
+            {
+                Ok(BackendType::Kqueue)
+            }
+            #[cfg(not(any(
+                target_os = "freebsd",
+                target_os = "openbsd",
+                target_os = "netbsd",
+                target_os = "dragonfly",
+                target_os = "ios",
+                all(target_os = "macos", feature = "macos_kqueue")
+            )))]
+            {
+                Err(WatchError::invalid_backend("backend is not available on this platform").into())
+            }
+        } else if atom == atoms::windows() {
+            #[cfg(target_os = "windows")]
+            {
+                Ok(BackendType::Windows)
+            }
+            #[cfg(not(target_os = "windows"))]
+            {
+                Err(WatchError::invalid_backend("backend is not available on this platform").into())
+            }
+        } else if atom == atoms::watchman() {
+            #[cfg(all(unix, feature = "watchman"))]
+            {
+                if !watchman::available() {
+                    return Err(
+                        WatchError::invalid_backend("no Watchman daemon could be reached").into(),
+                    );
+                }
+                Ok(BackendType::Watchman)
+            }
+            #[cfg(not(all(unix, feature = "watchman")))]
+            {
+                Err(WatchError::invalid_backend(
+                    "backend needs fs_notify built with the watchman feature, on Unix",
+                )
+                .into())
+            }
+        } else if atom == atoms::null() {
+            Ok(BackendType::Null)
+        } else {
+            Err(WatchError::invalid_backend("unknown backend").into())
+        }
+    }
+
+    fn create_watcher(
+        &self,
+        tx: impl EventHandler,
+        backend_config: &BackendConfig,
+    ) -> WatcherResult {
+        let config = backend_config.notify_config();
+
+        match self {
+            BackendType::Recommended => {
+                #[cfg(all(target_os = "macos", not(feature = "macos_kqueue")))]
+                let watcher = fsevents::FsEventWatcher::from_config(tx, backend_config);
+                #[cfg(windows)]
+                let watcher = windows::ReadDirectoryChangesWatcher::from_config(tx, backend_config)
+                    .map_err(nif_error)?;
+                #[cfg(not(any(windows, all(target_os = "macos", not(feature = "macos_kqueue")))))]
+                let watcher = RecommendedWatcher::new(tx, config).map_err(nif_error)?;
+                Ok((Box::new(watcher), backend_atom(recommended_backend_kind())))
+            }
+            BackendType::Poll => {
+                let watcher = PollWatcher::new(tx, config).map_err(nif_error)?;
+                let kind = WatcherKind::PollWatcher;
+                Ok((Box::new(watcher), backend_atom(kind)))
+            }
+            #[cfg(target_os = "linux")]
+            BackendType::INotify => {
+                let watcher = notify::INotifyWatcher::new(tx, config).map_err(nif_error)?;
+                let kind = WatcherKind::Inotify;
+                Ok((Box::new(watcher), backend_atom(kind)))
+            }
+            #[cfg(target_os = "linux")]
+            BackendType::Fanotify => {
+                let watcher = fanotify::FanotifyWatcher::from_config(tx, backend_config)
+                    .map_err(nif_error)?;
+                Ok((Box::new(watcher), atoms::fanotify()))
+            }
+            #[cfg(all(unix, feature = "watchman"))]
+            BackendType::Watchman => {
+                let watcher = watchman::WatchmanWatcher::create(tx).map_err(nif_error)?;
+                Ok((Box::new(watcher), atoms::watchman()))
+            }
+            #[cfg(all(target_os = "macos", not(feature = "macos_kqueue")))]
+            BackendType::FsEvent => {
+                let watcher = fsevents::FsEventWatcher::from_config(tx, backend_config);
+                let kind = WatcherKind::Fsevent;
+                Ok((Box::new(watcher), backend_atom(kind)))
+            }
+            #[cfg(any(
+                target_os = "freebsd",
+                target_os = "openbsd",
+                target_os = "netbsd",
+                target_os = "dragonfly",
+                target_os = "ios",
+                all(target_os = "macos", feature = "macos_kqueue")
+            ))]
+            BackendType::Kqueue => {
+                let watcher = notify::KqueueWatcher::new(tx, config).map_err(nif_error)?;
+                let kind = WatcherKind::Kqueue;
+                Ok((Box::new(watcher), backend_atom(kind)))
+            }
+            #[cfg(target_os = "windows")]
+            BackendType::Windows => {
+                let watcher = windows::ReadDirectoryChangesWatcher::from_config(tx, backend_config)
+                    .map_err(nif_error)?;
+                let kind = WatcherKind::ReadDirectoryChangesWatcher;
+                Ok((Box::new(watcher), backend_atom(kind)))
+            }
+            BackendType::Null => {
+                let watcher = notify::NullWatcher::new(tx, config).map_err(nif_error)?;
+                let kind = WatcherKind::NullWatcher;
+                Ok((Box::new(watcher), backend_atom(kind)))
+            }
+        }
+    }
+}
+
+fn start_watcher_internal(
+    env: Env,
+    path: String,
+    mut options: WatchOptions,
+) -> NifResult<(Atom, WatcherHandle)> {
+    let path = if options.canonicalize {
+        canonical_root(Path::new(&path), options.allow_missing)?
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        path
+    };
+
+    // Native backends miss changes on network filesystems (ReadDirectoryChangesW on SMB
+    // shares, inotify on NFS, ...), so those are polled unless a backend was asked for
+    // explicitly
+    let fell_back =
+        options.backend == BackendType::Recommended && netfs::is_network(Path::new(&path));
+    if fell_back {
+        options.backend = BackendType::Poll;
+        logs::warning(format!("{path}: on a network filesystem, so polled"));
+    }
+
+    if let Some(handle) = share_watcher(env, &path, &options) {
+        return Ok((atoms::ok(), handle));
+    }
+
+    let watch_path = Path::new(&path);
+
+    let filter: SharedFilter = Arc::new(RwLock::new(
+        options.filters.build(&[watch_path.to_path_buf()])?,
+    ));
+    let roots: Roots = Arc::new(RwLock::new(vec![(
+        watch_path.to_path_buf(),
+        options.recursive,
+    )]));
+    let buffer = Arc::new(EventBuffer::new(options.buffer));
+
+    // Sequence numbers carry on from where the log left off, so cursors stay valid
+    let (event_log, last_seq) = match &options.event_log {
+        Some(path) => {
+            let path = Path::new(path);
+            let (log, last_seq) = EventLog::open(path)
+                .map_err(|err| nif_error(notify::Error::io(err).add_path(path.to_path_buf())))?;
+            (Some(Mutex::new(log)), last_seq)
+        }
+        None => (None, 0),
+    };
+
+    let core = Arc::new(WatcherCore {
+        info: Mutex::new(None),
+        claims: AtomicUsize::new(0),
+        last_seq: AtomicU64::new(last_seq),
+        delivered: AtomicU64::new(0),
+        rate_limited: AtomicU64::new(0),
+        event_label: options.label.clone().filter(|_| options.label_events),
+        raw_paths: options.raw_paths,
+        map_events: AtomicBool::new(options.event_format.shape == EventShape::Map),
+        baseline_events: AtomicBool::new(options.event_format.is_baseline()),
+        fsevent_flags: options.fsevent_flags,
+        include_os_pid: options.include_os_pid,
+        event_log,
+        history: options.history_size.map(History::new),
+        last_event_id: AtomicU64::new(0),
+    });
+
+    let recursion = &options.recursion.clone();
+    let new_dirs = recursion
+        .is_manual()
+        .then(|| spawn_dir_watcher(Arc::downgrade(&core)));
+    let dir_watch = new_dirs
+        .clone()
+        .map(|new_dirs| DirWatch::new(roots.clone(), recursion.clone(), new_dirs));
+    let relinked = options
+        .watch_link
+        .then(|| spawn_link_watcher(Arc::downgrade(&core)));
+    let link_watch = relinked
+        .clone()
+        .map(|relinked| LinkWatch::new(roots.clone(), relinked));
+
+    let removed_roots = options
+        .rearm_root
+        .then(|| spawn_root_rearmer(Arc::downgrade(&core)));
+    let root_watch = RootWatch::new(roots.clone(), removed_roots.clone());
+
+    let pending: PendingRoots = Arc::default();
+    let pending_changed = options
+        .allow_missing
+        .then(|| spawn_pending_watcher(Arc::downgrade(&core)));
+    let pending_watch = pending_changed
+        .clone()
+        .map(|changed| PendingWatch::new(roots.clone(), pending.clone(), changed));
+
+    let settler = options
+        .settle_ms
+        .map(|ms| Settler::spawn(Duration::from_millis(ms), buffer.clone()));
+    let attribs = options.classify_attrib.then(AttribTracker::new);
+    let sizes = options.classify_writes.then(SizeTracker::new);
+    let hasher = options
+        .hash_contents
+        .then(|| ContentHasher::spawn(buffer.clone()));
+    let recorder = Recorder::default();
+
+    let sink = EventSink::new(buffer.clone(), filter.clone())
+        .with_dirs(dir_watch)
+        .with_links(link_watch)
+        .with_root_watch(root_watch)
+        .with_pending(pending_watch)
+        .with_settler(settler.clone())
+        .with_attribs(attribs.clone())
+        .with_sizes(sizes.clone())
+        .with_hasher(hasher.clone())
+        .with_recorder(recorder.clone());
+    let failover =
+        (!options.fallbacks.is_empty()).then(|| spawn_backend_failover(Arc::downgrade(&core)));
+    let asked_for = options.backend.clone();
+    let (watcher_type, backend) = loop {
+        let sink = sink.clone().with_failover(
+            failover
+                .clone()
+                .map(|failed| Failover::new(failed, options.fallbacks.len())),
+        );
+        match start_backend(&options, sink, watch_path, &pending) {
+            Ok(started) => break started,
+            Err(_) if !options.fallbacks.is_empty() => {
+                let failed = std::mem::replace(&mut options.backend, options.fallbacks.remove(0));
+                logs::warning(format!(
+                    "{path}: backend {} failed to start, trying {}",
+                    failed.name(),
+                    options.backend.name()
+                ));
+            }
+            Err(err) => return Err(err),
+        }
+    };
+
+    // Scan once the watch is in place, so no change falls between the two
+    if let Some(attribs) = &attribs {
+        attribs.spawn_seed(
+            watch_path.to_path_buf(),
+            options.recursive,
+            recursion.clone(),
+        );
+    }
+    if let Some(sizes) = &sizes {
+        sizes.spawn_seed(
+            watch_path.to_path_buf(),
+            options.recursive,
+            recursion.clone(),
+        );
+    }
+    let tails = options.tail.then(Tails::default);
+    if let Some(tails) = &tails {
+        tail::follow(tails, watch_path);
+    }
+    if fell_back || options.backend != asked_for {
+        let notice = fallback_notice(&options.backend);
+        EventSink::new(buffer.clone(), filter.clone()).send(Ok(notice), Timestamp::now());
+    }
+    if options.initial_scan {
+        let sink = EventSink::new(buffer.clone(), filter.clone());
+        let root = watch_path.to_path_buf();
+        scan::spawn_initial_scan(root, options.recursive, recursion.clone(), sink);
+    }
+
+    let translator = EventTranslator::new(options.event_detail, roots.clone())
+        .with_kinds(options.kinds.clone())
+        .with_ignore_attrib(options.ignore_attrib)
+        .with_attrib_classes(options.classify_attrib)
+        .with_stat(options.include_stat)
+        .with_hashes(hasher.as_ref().map(|hasher| hasher.hashes()))
+        .with_tails(tails.clone())
+        .with_canonical_paths(options.canonicalize)
+        .with_normalization(options.normalize_unicode)
+        .with_save_coalescing(options.coalesce_saves)
+        .with_relative_paths(options.relative_paths)
+        .with_summary_threshold(options.summarize_threshold);
+    let (translator, event_sender) = match options.subscriber {
+        Some(pid) => (None, Some((pid, translator))),
+        None => (Some(translator), None),
+    };
+
+    let mut watcher_info = WatcherInfo {
+        watcher_type,
+        backend,
+        roots,
+        path,
+        options,
+        filter,
+        buffer: buffer.clone(),
+        translator,
+        queue: Vec::new(),
+        owners: Vec::new(),
+        subscriber_monitor: None,
+        subscribers: Vec::new(),
+        new_dirs,
+        relinked,
+        removed_roots,
+        pending,
+        pending_changed,
+        settler,
+        attribs,
+        sizes,
+        hasher,
+        tails,
+        recorder,
+        failover,
+        demand: Arc::default(),
+        #[cfg(unix)]
+        ready: None,
+    };
+    telemetry::increment(&telemetry::WATCHERS_ACTIVE);
+    for root in watcher_info.options.extra_roots.clone() {
+        let recursive = watcher_info.options.recursive;
+        add_root(&mut watcher_info, root, recursive)?;
+    }
+
+    *lock_recovered(&core.info) = Some(watcher_info);
+    register_watcher(&core);
+    let handle = ResourceArc::new(WatcherResource::claim(core));
+
+    // Tear the watcher down when its owner (or push subscriber) exits. The monitors are
+    // recorded before `down` can look for them.
+    if let Some(watcher_info) = lock_recovered(&handle.info).as_mut() {
+        let monitor = env.monitor(&handle, &env.pid());
+        watcher_info.owners.push((env.pid(), monitor));
+
+        if let Some((pid, _)) = &event_sender {
+            if *pid != env.pid() {
+                watcher_info.subscriber_monitor = env.monitor(&handle, pid);
+            }
+            watcher_info
+                .subscribers
+                .push((*pid, watcher_info.subscriber_monitor));
+        }
+    }
+
+    if let Some((_, translator)) = event_sender {
+        spawn_event_sender(Arc::downgrade(&handle.core), buffer, translator);
+    }
+
+    Ok((atoms::ok(), handle))
+}
+
+/// Create the backend `options` describe and watch `watch_path` with it.
+fn start_backend(
+    options: &WatchOptions,
+    sink: EventSink,
+    watch_path: &Path,
+    pending: &PendingRoots,
+) -> NifResult<(WatcherType, Atom)> {
+    let (mut watcher_type, backend) = match &options.replay {
+        Some(journal) => replay_journal(Path::new(journal), sink, options)?,
+        None => options.create_watcher_type(sink, true)?,
+    };
+    if options.allow_missing && !watch_path.exists() {
+        watcher_type
+            .watch_pending(watch_path, pending)
+            .map_err(nif_error)?;
+    } else {
+        watcher_type
+            .watch_root(watch_path, options.recursive, &options.recursion)
+            .map_err(nif_error)?;
+    }
+    if options.watch_link {
+        watcher_type.watch_link(watch_path).map_err(nif_error)?;
+    }
+    Ok((watcher_type, backend))
+}
+
+/// The event telling that a watcher moved on to `backend`, reported as
+/// `{:backend_fallback, backend}`.
+fn fallback_notice(backend: &BackendType) -> Event {
+    let info = format!("{}{}", sink::BACKEND_FALLBACK, backend.name());
+    Event::new(EventKind::Other).set_info(&info)
+}
+
+/// Send the events recorded in `journal` through `sink`, in order and with the times they
+/// were recorded at, before it is handed to the null backend the replaying watcher idles on.
+fn replay_journal(
+    journal: &Path,
+    sink: EventSink,
+    options: &WatchOptions,
+) -> NifResult<(WatcherType, Atom)> {
+    let events = journal::read(journal)
+        .map_err(|err| nif_error(notify::Error::io(err).add_path(journal.to_path_buf())))?;
+    for (event, time) in events {
+        sink.send(Ok(event), time);
+    }
+    options.create_watcher_type(sink, false)
+}
+
+/// Resolve a root about to be watched, which unlike event paths has to exist unless
+/// `allow_missing`.
+fn canonical_root(path: &Path, allow_missing: bool) -> NifResult<PathBuf> {
+    if allow_missing && !path.exists() {
+        return Ok(event::canonicalize(path));
+    }
+
+    let mut canonical = dunce::canonicalize(path)
+        .map_err(|err| nif_error(notify::Error::io(err).add_path(path.into())))?;
+    longpath::strip_extended(&mut canonical);
+    Ok(canonical)
+}
+
+/// Determine the backend kind the recommended watcher uses on this platform.
+fn recommended_backend_kind() -> WatcherKind {
+    #[cfg(target_os = "linux")]
+    let kind = WatcherKind::Inotify;
+    #[cfg(all(target_os = "macos", not(feature = "macos_kqueue")))]
+    let kind = WatcherKind::Fsevent;
+    #[cfg(any(
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly",
+        target_os = "ios",
+        all(target_os = "macos", feature = "macos_kqueue")
+    ))]
+    let kind = WatcherKind::Kqueue;
+    #[cfg(target_os = "windows")]
+    let kind = WatcherKind::ReadDirectoryChangesWatcher;
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "windows",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly",
+        target_os = "ios"
+    )))]
+    let kind = WatcherKind::PollWatcher;
+    kind
+}
+
+/// Watch each directory sent on the returned channel, along with its subdirectories within
+/// the watcher's recursion limits given how many levels below its root it is, until the
+/// watcher is gone.
+fn spawn_dir_watcher(core: Weak<WatcherCore>) -> Sender<(PathBuf, usize)> {
+    let (new_dirs, received) = mpsc::channel::<(PathBuf, usize)>();
+
+    thread::spawn(move || {
+        for (dir, level) in received {
+            let Some(core) = core.upgrade() else {
+                return;
+            };
+            let mut info = lock_recovered(&core.info);
+            if let Some(watcher_info) = info.as_mut() {
+                // The directory may already be gone again
+                let recursion = watcher_info.options.recursion.below(level);
+                let _ = watcher_info.watcher_type.watch_root(&dir, true, &recursion);
+            }
+        }
+    });
+
+    new_dirs
+}
+
+/// Watch each root sent on the returned channel again, from scratch, until the watcher is
+/// gone. Sent when a symlinked root is replaced, so its new target is watched.
+fn spawn_link_watcher(core: Weak<WatcherCore>) -> Sender<PathBuf> {
+    let (relinked, received) = mpsc::channel::<PathBuf>();
+
+    thread::spawn(move || {
+        for root in received {
+            let Some(core) = core.upgrade() else {
+                return;
+            };
+            let mut info = lock_recovered(&core.info);
+            let Some(watcher_info) = info.as_mut() else {
+                continue;
+            };
+            let recursive = read_recovered(&watcher_info.roots)
+                .iter()
+                .any(|(watched, recursive)| *watched == root && *recursive);
+
+            // A link that was removed rather than retargeted has nothing left to watch
+            let recursion = &watcher_info.options.recursion;
+            let watcher_type = &mut watcher_info.watcher_type;
+            let _ = watcher_type.unwatch_root(&root, recursive, recursion);
+            let _ = watcher_type.watch_root(&root, recursive, recursion);
+        }
+    });
+
+    relinked
+}
+
+/// Look at each pending root sent on the returned channel again, until the watcher is gone.
+fn spawn_pending_watcher(core: Weak<WatcherCore>) -> Sender<PathBuf> {
+    let (pending_changed, received) = mpsc::channel::<PathBuf>();
+
+    thread::spawn(move || {
+        for root in received {
+            let Some(core) = core.upgrade() else {
+                return;
+            };
+            let mut info = lock_recovered(&core.info);
+            if let Some(watcher_info) = info.as_mut() {
+                watcher_info.advance_pending(&root);
+            }
+        }
+    });
+
+    pending_changed
+}
+
+/// How often roots sent to a rearmer are checked for having reappeared.
+const REARM_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watch each root sent on the returned channel again once it exists again, reporting it
+/// as created, until the watcher is gone.
+fn spawn_root_rearmer(core: Weak<WatcherCore>) -> Sender<PathBuf> {
+    let (removed_roots, received) = mpsc::channel::<PathBuf>();
+
+    thread::spawn(move || {
+        let mut pending: Vec<PathBuf> = Vec::new();
+
+        loop {
+            match received.recv_timeout(REARM_INTERVAL) {
+                Ok(root) => pending.push(root),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+            let Some(core) = core.upgrade() else {
+                return;
+            };
+
+            pending.retain(|root| !root.exists() || !rearm_root(&core, root));
+        }
+    });
+
+    removed_roots
+}
+
+/// Move a watcher on to its next backend whenever the one in use fails, until it has none
+/// left to fall back on.
+fn spawn_backend_failover(core: Weak<WatcherCore>) -> Sender<usize> {
+    let (failed, received) = mpsc::channel::<usize>();
+
+    thread::spawn(move || {
+        while let Ok(remaining) = received.recv() {
+            let Some(core) = core.upgrade() else {
+                return;
+            };
+            fail_over(&core, remaining);
+        }
+    });
+
+    failed
+}
+
+/// Replace a backend that failed while the watcher had `remaining` fallbacks left with the
+/// first of them that starts, telling Elixir which one that is.
+fn fail_over(core: &WatcherCore, remaining: usize) {
+    let mut info = lock_recovered(&core.info);
+    let Some(watcher_info) = info.as_mut() else {
+        return;
+    };
+    // Already replaced, or nothing left to replace it with
+    if remaining == 0 || watcher_info.options.fallbacks.len() != remaining {
+        return;
+    }
+
+    let roots = read_recovered(&watcher_info.roots).clone();
+    let mut options = watcher_info.options.clone();
+    let failed = options.backend.name();
+    let mut started = false;
+    while !started && !options.fallbacks.is_empty() {
+        options.backend = options.fallbacks.remove(0);
+        started = watcher_info
+            .rebuild_backend(&options, &roots, false)
+            .is_ok();
+    }
+    if started {
+        logs::warning(format!(
+            "{}: backend {failed} failed, fell back to {}",
+            watcher_info.path,
+            options.backend.name()
+        ));
+        let notice = fallback_notice(&options.backend);
+        EventSink::new(watcher_info.buffer.clone(), watcher_info.filter.clone())
+            .send(Ok(notice), Timestamp::now());
+    } else {
+        logs::error(format!(
+            "{}: backend {failed} failed and no fallback started",
+            watcher_info.path
+        ));
+    }
+    // Given up on once none starts, rather than tried again on every error
+    watcher_info.options = options;
+}
+
+/// Watch a root that reappeared, returning whether it is still to be watched but failed.
+fn rearm_root(core: &WatcherCore, root: &Path) -> bool {
+    let mut info = lock_recovered(&core.info);
+    let Some(watcher_info) = info.as_mut() else {
+        return false;
+    };
+    let Some(recursive) = read_recovered(&watcher_info.roots)
+        .iter()
+        .find(|(watched, _)| watched == root)
+        .map(|(_, recursive)| *recursive)
+    else {
+        // Removed with `remove_path` meanwhile
+        return false;
+    };
+
+    let recursion = &watcher_info.options.recursion;
+    let watcher_type = &mut watcher_info.watcher_type;
+    // The backend may still hold the dead watch
+    let _ = watcher_type.unwatch_root(root, recursive, recursion);
+    if watcher_type.watch_root(root, recursive, recursion).is_err() {
+        return true;
+    }
+
+    let created = Event::new(EventKind::Create(CreateKind::Any)).add_path(root.to_path_buf());
+    watcher_info.buffer.push((Ok(created), Timestamp::now()));
+    false
+}
+
+/// Forward every event from `buffer` to each subscriber as `{:fs_notify, handle, event}`
+/// until the watcher is stopped (closing the buffer). Once nobody is subscribed, the
+/// translator is handed back so events are queued for `get_events` again.
+///
+/// The thread only holds on to the watcher weakly, and sends a new handle with each event,
+/// so the caller's handle can still be garbage collected.
+fn spawn_event_sender(
+    core: Weak<WatcherCore>,
+    buffer: Arc<EventBuffer>,
+    mut translator: EventTranslator,
+) {
+    thread::spawn(move || {
+        let mut env = OwnedEnv::new();
+        let mut rate_limit = None;
+
+        while let Some(first) = buffer.pop_wait() {
+            // Process everything already buffered as one batch so rename halves can be paired
+            let mut events = Vec::new();
+            let rest = std::iter::from_fn(|| buffer.try_pop());
+            for queued in std::iter::once(first).chain(rest) {
+                let (result, time) = queued.received;
+                match result {
+                    Ok(event) => translator.process(event, time, queued.count, &mut events),
+                    Err(err) => {
+                        // Error in file watching, but we'll continue
+                        logs::error(format!("backend error: {err}"));
+                        continue;
+                    }
+                }
+            }
+            translator.flush(&mut events);
+            translator.summarize(&mut events, 0);
+            let Some(handle) = core.upgrade() else {
+                return;
+            };
+            handle.sequence(&mut events);
+
+            let (subscribers, oneshot, demand): (Vec<LocalPid>, bool, Option<Arc<Demand>>) = {
+                let mut info = lock_recovered(&handle.info);
+                let Some(watcher_info) = info.as_mut() else {
+                    return;
+                };
+                if watcher_info.subscribers.is_empty() {
+                    watcher_info.queue.extend(events);
+                    watcher_info.translator = Some(translator);
+                    return;
+                }
+                let subscribers = watcher_info
+                    .subscribers
+                    .iter()
+                    .map(|(pid, _)| *pid)
+                    .collect();
+                let demand = watcher_info
+                    .options
+                    .on_demand
+                    .then(|| watcher_info.demand.clone());
+                // The limit may have been changed with `update_watcher` since the last batch
+                let per_second = watcher_info.options.max_events_per_second;
+                if rate_limit.as_ref().map(RateLimit::per_second) != per_second {
+                    rate_limit = per_second.map(RateLimit::new);
+                }
+                (subscribers, watcher_info.options.oneshot, demand)
+            };
+            // Not kept while waiting for demand or the rate limit
+            drop(handle);
+            if oneshot {
+                if events.is_empty() {
+                    continue;
+                }
+                events.truncate(1);
+            }
+
+            let batch = events.len();
+            let mut deferred = false;
+            for (index, event) in events.into_iter().enumerate() {
+                // Held back, along with everything still buffered, until asked for
+                if demand.as_ref().is_some_and(|demand| !demand.take()) {
+                    return;
+                }
+                // Held back, likewise, until the next second once this one's are sent
+                let waited = rate_limit.as_mut().is_some_and(RateLimit::take);
+                let Some(core) = core.upgrade() else {
+                    return;
+                };
+                if waited {
+                    // This event and the rest of the batch all go out later for it
+                    if !deferred {
+                        let rest = (batch - index) as u64;
+                        core.rate_limited.fetch_add(rest, Ordering::Relaxed);
+                        deferred = true;
+                    }
+                    if lock_recovered(&core.info).is_none() {
+                        return;
+                    }
+                }
+                let handle = ResourceArc::new(WatcherResource::view(core));
+                let mut delivered = false;
+                for pid in &subscribers {
+                    // A subscriber that is gone is removed when its monitor fires
+                    delivered |= env
+                        .send_and_clear(pid, |_| {
+                            (atoms::fs_notify(), handle.clone(), event.clone())
+                        })
+                        .is_ok();
+                }
+                if delivered {
+                    handle.delivered.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            if oneshot {
+                if let Some(core) = core.upgrade() {
+                    env.run(|env| tear_down(env, &mut lock_recovered(&core.info)));
+                }
+                return;
+            }
+        }
+    });
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn start_watcher(env: Env, path: String, recursive: bool) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| {
+        let options = WatchOptions {
+            recursive,
+            ..default_options()
+        };
+        start_watcher_internal(env, path, options)
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn start_watcher_with_pid(
+    env: Env,
+    path: String,
+    recursive: bool,
+    pid: LocalPid,
+) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| {
+        let options = WatchOptions {
+            recursive,
+            subscriber: Some(pid),
+            ..default_options()
+        };
+        start_watcher_internal(env, path, options)
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn start_oneshot_watcher(
+    env: Env,
+    path: String,
+    kinds: Vec<Atom>,
+) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| start_oneshot(env, path, kinds, None))
+}
+
+#[rustler::nif(name = "start_oneshot_watcher", schedule = "DirtyIo")]
+fn start_oneshot_watcher_with_options<'a>(
+    env: Env<'a>,
+    path: String,
+    kinds: Vec<Atom>,
+    options: Term<'a>,
+) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| start_oneshot(env, path, kinds, Some(options)))
+}
+
+/// Start a watcher that sends the caller its first event of one of `kinds` and stops.
+/// `path` need not exist unless `options` say `allow_missing: false`.
+fn start_oneshot<'a>(
+    env: Env<'a>,
+    path: String,
+    kinds: Vec<Atom>,
+    options: Option<Term<'a>>,
+) -> NifResult<(Atom, WatcherHandle)> {
+    let mut watch_options = WatchOptions {
+        allow_missing: true,
+        ..default_options()
+    };
+    if let Some(options) = options {
+        watch_options.apply_term(options)?;
+    }
+    let options = WatchOptions {
+        subscriber: Some(env.pid()),
+        kinds: Some(kinds).filter(|kinds| !kinds.is_empty()),
+        oneshot: true,
+        ..watch_options
+    };
+    start_watcher_internal(env, path, options)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn start_watcher_with_backend<'a>(
+    env: Env<'a>,
+    path: String,
+    recursive: bool,
+    backend: Term<'a>,
+) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| {
+        let (backend, fallbacks) = decode_backends(backend)?;
+        let options = WatchOptions {
+            recursive,
+            backend,
+            fallbacks,
+            ..default_options()
+        };
+        start_watcher_internal(env, path, options)
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn start_watcher_with_backend_config<'a>(
+    env: Env<'a>,
+    path: String,
+    recursive: bool,
+    backend: Term<'a>,
+    config: Term<'a>,
+) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| {
+        let (backend, fallbacks) = decode_backends(backend)?;
+        let mut options = WatchOptions {
+            recursive,
+            backend,
+            fallbacks,
+            ..default_options()
+        };
+        options.backend_config.apply_term(config)?;
+        start_watcher_internal(env, path, options)
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn start_watcher_with_options<'a>(
+    env: Env<'a>,
+    path: String,
+    recursive: bool,
+    options: Term<'a>,
+) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| {
+        let mut watch_options = WatchOptions {
+            recursive,
+            ..default_options()
+        };
+        watch_options.apply_term(options)?;
+        start_watcher_internal(env, path, watch_options)
+    })
+}
+
+/// Start a watcher from one config map holding everything the `start_watcher_with_*` NIFs
+/// take apart: `path` (one path or a list of them), `subscriber` and, as for
+/// `start_watcher_with_options`, `recursive`, `backend`, debouncing, filters, buffer limits
+/// and the rest. Unlike there, `recursive` defaults to true.
+#[rustler::nif(schedule = "DirtyIo")]
+fn start_watcher_with_config<'a>(
+    env: Env<'a>,
+    config: Term<'a>,
+) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| {
+        if !config.is_map() {
+            return Err(invalid_config("config must be a map").into());
+        }
+        let paths = config
+            .map_get(atoms::path())
+            .map_err(|_| invalid_config("config must have a path"))?;
+        let mut paths: Vec<String> = match paths.decode::<String>() {
+            Ok(path) => vec![path],
+            Err(_) => decode_value(paths, "path")?,
+        };
+        if paths.is_empty() {
+            return Err(invalid_config("path list is empty").into());
+        }
+        let mut options = WatchOptions {
+            recursive: true,
+            extra_roots: paths.split_off(1),
+            ..default_options()
+        };
+        if let Ok(subscriber) = config.map_get(atoms::subscriber()) {
+            options.subscriber = Some(decode_value(subscriber, "subscriber")?);
+        }
+
+        let config = config.map_remove(atoms::path())?;
+        let config = config.map_remove(atoms::subscriber())?;
+        options.apply_term(config)?;
+        start_watcher_internal(env, paths.remove(0), options)
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn start_watcher_with_debounce<'a>(
+    env: Env<'a>,
+    path: String,
+    recursive: bool,
+    backend: Term<'a>,
+    debounce_ms: u64,
+) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| {
+        let (backend, fallbacks) = decode_backends(backend)?;
+        let options = WatchOptions {
+            recursive,
+            backend,
+            fallbacks,
+            debounce_ms: Some(debounce_ms),
+            ..default_options()
+        };
+        start_watcher_internal(env, path, options)
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn start_watcher_with_full_debounce<'a>(
+    env: Env<'a>,
+    path: String,
+    recursive: bool,
+    backend: Term<'a>,
+    debounce_ms: u64,
+) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| {
+        let (backend, fallbacks) = decode_backends(backend)?;
+        let options = WatchOptions {
+            recursive,
+            backend,
+            fallbacks,
+            debounce_ms: Some(debounce_ms),
+            debounce_mode: DebounceMode::Full,
+            ..default_options()
+        };
+        start_watcher_internal(env, path, options)
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn start_watcher_with_filters(
+    env: Env,
+    path: String,
+    recursive: bool,
+    include: Vec<String>,
+    exclude: Vec<String>,
+) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| {
+        let defaults = default_options();
+        let options = WatchOptions {
+            recursive,
+            filters: FilterOptions {
+                include,
+                exclude,
+                ..defaults.filters
+            },
+            ..defaults
+        };
+        start_watcher_internal(env, path, options)
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn start_watcher_with_gitignore(
+    env: Env,
+    path: String,
+    recursive: bool,
+) -> NifResult<(Atom, WatcherHandle)> {
+    guarded(|| {
+        let defaults = default_options();
+        let options = WatchOptions {
+            recursive,
+            filters: FilterOptions {
+                gitignore: true,
+                ..defaults.filters
+            },
+            ..defaults
+        };
+        start_watcher_internal(env, path, options)
+    })
+}
+
+/// Set the options every watcher started from now on starts from, in place of the built-in
+/// defaults, as `start_watcher_with_options` takes them. Options a watcher is started with
+/// still take precedence.
+#[rustler::nif]
+fn set_defaults(defaults: Term) -> NifResult<Atom> {
+    guarded(|| {
+        let keys =
+            MapIterator::new(defaults).ok_or_else(|| invalid_config("defaults must be a map"))?;
+        // These only make sense for one watcher at a time
+        let per_watcher = [
+            atoms::recursive(),
+            atoms::label(),
+            atoms::label_events(),
+            atoms::replay(),
+            atoms::event_log(),
+        ];
+        for (key, _) in keys {
+            if key
+                .decode::<Atom>()
+                .is_ok_and(|key| per_watcher.contains(&key))
+            {
+                return Err(invalid_config(
+                    "recursive, label, label_events, replay and event_log can't be defaults",
+                )
+                .into());
+            }
+        }
+
+        let mut options = WatchOptions::default();
+        options.apply_term(defaults)?;
+        *lock_recovered(&DEFAULTS) = Some(options);
+        Ok(atoms::ok())
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn stop_watcher(env: Env, watcher: WatcherHandle) -> NifResult<Atom> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let Some(watcher_info) = info.as_mut() else {
+            return Ok(atoms::watcher_not_found());
+        };
+
+        // Watchers shared between callers keep running until the last one stops them
+        let Some(shared) = watcher_info.release(env.pid()) else {
+            let message = "calling process holds no claim on the shared watcher";
+            return Err(WatchError::new(atoms::not_owner(), message).into());
+        };
+        if watcher.release() || !shared {
+            tear_down(env, &mut info);
+        }
+        Ok(atoms::ok())
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn stop_all_watchers(env: Env) -> NifResult<Atom> {
+    guarded(|| {
+        // Hold the registry for the whole sweep so no watcher can start halfway through
+        let mut watchers = lock_recovered(&WATCHERS);
+
+        for core in watchers.drain(..).filter_map(|watcher| watcher.upgrade()) {
+            tear_down(env, &mut lock_recovered(&core.info));
+        }
+
+        Ok(atoms::ok())
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn subscribe(env: Env, watcher: WatcherHandle, pid: LocalPid) -> NifResult<Atom> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+
+        if !watcher_info
+            .subscribers
+            .iter()
+            .any(|(subscriber, _)| *subscriber == pid)
+        {
+            // Nothing to deliver to if the process is already gone
+            if let Some(monitor) = env.monitor(&watcher, &pid) {
+                watcher_info.subscribers.push((pid, Some(monitor)));
+            }
+        }
+
+        // Start delivering unless a sender thread (holding the translator) already is
+        if let Some(translator) = watcher_info.translator.take() {
+            let core = Arc::downgrade(&watcher.core);
+            spawn_event_sender(core, watcher_info.buffer.clone(), translator);
+        }
+
+        Ok(atoms::ok())
+    })
+}
+
+/// Allow `demand` more events to be pushed to the watcher's subscribers, with `on_demand`.
+#[rustler::nif(schedule = "DirtyIo")]
+fn request_events(watcher: WatcherHandle, demand: u64) -> NifResult<Atom> {
+    guarded(|| {
+        let info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_ref().ok_or_else(WatchError::watcher_not_found)?;
+        if !watcher_info.options.on_demand {
+            return Err(invalid_config("watcher was not started with on_demand: true").into());
+        }
+        if demand == 0 {
+            return Err(invalid_config("demand must be positive").into());
+        }
+
+        watcher_info.demand.request(demand);
+        Ok(atoms::ok())
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn unsubscribe(watcher: WatcherHandle, pid: LocalPid) -> NifResult<Atom> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+
+        watcher_info
+            .subscribers
+            .retain(|(subscriber, _)| *subscriber != pid);
+        if watcher_info.options.subscriber == Some(pid) {
+            // Its exit no longer concerns the watcher
+            watcher_info.subscriber_monitor = None;
+        }
+
+        Ok(atoms::ok())
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn get_events(watcher: WatcherHandle) -> NifResult<Vec<WatchEvent>> {
+    guarded(|| {
+        // The queue holds a buffer's worth at a time, so everything waiting is taken in as
+        // many goes, but not what arrives meanwhile, which could go on for ever
+        let backlog = match lock_recovered(&watcher.info).as_ref() {
+            Some(info) => info.queue_depth(),
+            None => return Err(WatchError::watcher_not_found().into()),
+        };
+        let (mut events, mut more) = take_events(&watcher, usize::MAX)?;
+        while more && events.len() < backlog {
+            let (taken, left) = take_events(&watcher, usize::MAX)?;
+            events.extend(taken);
+            more = left;
+        }
+        Ok(events)
+    })
+}
+
+#[rustler::nif(name = "get_events", schedule = "DirtyIo")]
+fn get_events_max(watcher: WatcherHandle, max: usize) -> NifResult<(Vec<WatchEvent>, bool)> {
+    guarded(|| take_events(&watcher, max))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn await_events(watcher: WatcherHandle, timeout_ms: u64) -> NifResult<Vec<WatchEvent>> {
+    guarded(|| {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+
+        loop {
+            let (events, _) = take_events(&watcher, usize::MAX)?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !events.is_empty() || remaining.is_zero() {
+                return Ok(events);
+            }
+
+            let (buffer, pushed) = match lock_recovered(&watcher.info).as_ref() {
+                Some(info) => (info.buffer.clone(), info.translator.is_none()),
+                None => return Err(WatchError::watcher_not_found().into()),
+            };
+
+            if pushed {
+                // Events go to the subscriber, so none will ever show up here
+                thread::sleep(remaining);
+            } else {
+                // Wait without holding the watcher lock so it can be stopped meanwhile
+                buffer.wait_ready(remaining);
+            }
+        }
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn await_any(watchers: Vec<WatcherHandle>, timeout_ms: u64) -> NifResult<Vec<WatcherHandle>> {
+    guarded(|| {
+        if watchers.is_empty() {
+            return Err(invalid_config("watchers must not be empty").into());
+        }
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        let waker = Arc::new(Waker::default());
+
+        let mut buffers = Vec::with_capacity(watchers.len());
+        for watcher in &watchers {
+            match lock_recovered(&watcher.info).as_ref() {
+                Some(info) => buffers.push(info.buffer.clone()),
+                None => return Err(WatchError::watcher_not_found().into()),
+            }
+        }
+        // Added before looking for events, so none pushed meanwhile goes unnoticed
+        for buffer in &buffers {
+            buffer.add_waker(&waker);
+        }
+
+        let ready = loop {
+            let ready = ready_watchers(&watchers);
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match ready {
+                Ok(ready) if ready.is_empty() && !remaining.is_zero() => waker.wait(remaining),
+                ready => break ready,
+            }
+        };
+        for buffer in &buffers {
+            buffer.remove_waker(&waker);
+        }
+        ready
+    })
+}
+
+/// Those of `watchers` with events to take, translating what they buffered to tell.
+/// Watchers pushing their events to subscribers never have any.
+fn ready_watchers(watchers: &[WatcherHandle]) -> NifResult<Vec<WatcherHandle>> {
+    let mut ready = Vec::new();
+    for watcher in watchers {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+        watcher.fill(watcher_info, 1);
+        if !watcher_info.queue.is_empty() {
+            ready.push(watcher.clone());
+        }
+    }
+    Ok(ready)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn wait_for_quiet(watcher: WatcherHandle, quiet_ms: u64, timeout_ms: u64) -> NifResult<Atom> {
+    guarded(|| {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        let quiet_us = quiet_ms.saturating_mul(1000);
+        let start = Timestamp::now().monotonic;
+
+        loop {
+            let stats = match lock_recovered(&watcher.info).as_ref() {
+                Some(info) => info.buffer.stats(),
+                None => return Err(WatchError::watcher_not_found().into()),
+            };
+
+            // Quiet is measured from the call at the earliest, so a burst that is about to
+            // start isn't mistaken for one that has ended
+            let last = stats
+                .last_event
+                .map_or(start, |time| time.monotonic.max(start));
+            let quiet_at = last.saturating_add(quiet_us);
+            let now = Timestamp::now().monotonic;
+            if now >= quiet_at {
+                return Ok(atoms::ok());
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(
+                    WatchError::new(atoms::timeout(), "watcher did not go quiet in time").into(),
+                );
+            }
+            thread::sleep(Duration::from_micros(quiet_at - now).min(remaining));
+        }
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn wait_for_path(path: String, timeout_ms: u64) -> NifResult<Atom> {
+    guarded(|| {
+        let path = PathBuf::from(path);
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+
+        let (tx, received) = mpsc::channel();
+        let mut watcher =
+            RecommendedWatcher::new(tx, notify::Config::default()).map_err(nif_error)?;
+        let mut watched: Option<PathBuf> = None;
+
+        loop {
+            // Checked after every watch change, so a path created meanwhile isn't missed
+            if path.exists() {
+                return Ok(atoms::ok());
+            }
+
+            // Watch the closest existing ancestor, following directories leading to `path` down
+            // as they appear
+            let ancestor = existing_ancestor(&path)
+                .ok_or_else(|| nif_error(notify::Error::path_not_found().add_path(path.clone())))?;
+            if watched.as_deref() != Some(ancestor) {
+                watcher
+                    .watch(ancestor, RecursiveMode::NonRecursive)
+                    .map_err(nif_error)?;
+                if let Some(previous) = watched.replace(ancestor.to_path_buf()) {
+                    let _ = watcher.unwatch(&previous);
+                }
+                continue;
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(
+                    WatchError::new(atoms::timeout(), "path did not appear in time").into(),
+                );
+            }
+            // Whatever happened, the loop looks at the path again
+            let _ = received.recv_timeout(remaining);
+        }
+    })
+}
+
+/// A directory snapshot taken by `snapshot`, passed to Elixir as an opaque reference.
+pub struct SnapshotResource {
+    snapshot: Snapshot,
+}
+
+#[rustler::resource_impl]
+impl Resource for SnapshotResource {}
+
+/// Decode the options of a tree walk such as `snapshot`: `:recursive` and `:exclude_dirs`,
+/// as for watchers, and whatever `other` accepts.
+fn decode_walk_options(
+    term: Term,
+    mut other: impl FnMut(Atom, Term) -> NifResult<bool>,
+) -> NifResult<(bool, Recursion)> {
+    let iter = MapIterator::new(term).ok_or_else(|| invalid_config("options must be a map"))?;
+    let mut recursive = true;
+    let mut recursion = Recursion::default();
+
+    for (key, value) in iter {
+        let key: Atom = key
+            .decode()
+            .map_err(|_| invalid_config("option keys must be atoms"))?;
+        if key == atoms::recursive() {
+            (recursive, recursion.depth) = decode_recursive(value)?;
+        } else if key == atoms::exclude_dirs() {
+            recursion.exclude_dirs = decode_value(value, "exclude_dirs")?;
+        } else if !other(key, value)? {
+            return Err(invalid_config("unknown option").into());
+        }
+    }
+    Ok((recursive, recursion))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn snapshot(path: String, options: Term) -> NifResult<(Atom, ResourceArc<SnapshotResource>)> {
+    guarded(|| {
+        let (recursive, recursion) = decode_walk_options(options, |_, _| Ok(false))?;
+        let path = PathBuf::from(path);
+        if !path.exists() {
+            return Err(nif_error(notify::Error::path_not_found().add_path(path)));
+        }
+
+        let snapshot = Snapshot::take(&path, recursive, &recursion);
+        Ok((atoms::ok(), ResourceArc::new(SnapshotResource { snapshot })))
+    })
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn diff_snapshots(
+    before: ResourceArc<SnapshotResource>,
+    after: ResourceArc<SnapshotResource>,
+) -> NifResult<SnapshotDiff> {
+    guarded(|| Ok(before.snapshot.diff(&after.snapshot)))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn tree_digest(path: String, options: Term) -> NifResult<(Atom, u64)> {
+    guarded(|| {
+        let mut mode = DigestMode::Metadata;
+        let (recursive, mut recursion) = decode_walk_options(options, |key, value| {
+            if key != atoms::contents() {
+                return Ok(false);
+            }
+            if decode_value(value, "contents")? {
+                mode = DigestMode::Contents;
+            }
+            Ok(true)
+        })?;
+        if !recursive {
+            recursion.depth = Some(1);
+        }
+
+        let path = PathBuf::from(path);
+        if !path.exists() {
+            return Err(nif_error(notify::Error::path_not_found().add_path(path)));
+        }
+        Ok((atoms::ok(), digest::tree_digest(&path, &recursion, mode)))
+    })
+}
+
+#[cfg(target_os = "linux")]
+#[rustler::nif(schedule = "DirtyIo")]
+fn inotify_limits() -> NifResult<(Atom, inotify::InotifyUsage)> {
+    guarded(|| {
+        let usage = inotify::usage().map_err(|err| nif_error(notify::Error::io(err)))?;
+        Ok((atoms::ok(), usage))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+#[rustler::nif]
+fn inotify_limits() -> NifResult<Atom> {
+    guarded(|| Err(WatchError::invalid_backend("inotify is not available on this platform").into()))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn probe_path(path: String) -> NifResult<(Atom, probe::PathProbe)> {
+    guarded(|| {
+        let path = Path::new(&path);
+        path.symlink_metadata()
+            .map_err(|err| nif_error(notify::Error::io(err).add_path(path.to_path_buf())))?;
+        // As `start_watcher_internal` chooses it
+        let recommended = if netfs::is_network(path) {
+            atoms::poll()
+        } else {
+            backend_atom(recommended_backend_kind())
+        };
+        Ok((atoms::ok(), probe::probe(path, recommended)))
+    })
+}
+
+#[cfg(target_os = "linux")]
+#[rustler::nif]
+fn respond(permission_id: u64, decision: Atom) -> NifResult<Atom> {
+    guarded(|| {
+        let allow = if decision == atoms::allow() {
+            true
+        } else if decision == atoms::deny() {
+            false
+        } else {
+            return Err(rustler::Error::BadArg);
+        };
+        match fanotify::respond(permission_id, allow) {
+            Ok(true) => Ok(atoms::ok()),
+            Ok(false) => Err(WatchError::new(
+                atoms::permission_not_found(),
+                "permission event was already answered, timed out or its watcher stopped",
+            )
+            .into()),
+            Err(err) => Err(nif_error(notify::Error::io(err))),
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+#[rustler::nif]
+fn respond(_permission_id: u64, _decision: Atom) -> NifResult<Atom> {
+    guarded(|| {
+        Err(WatchError::invalid_backend("fanotify is not available on this platform").into())
+    })
+}
+
+/// Take up to `max` events, returning them along with whether more are waiting.
+fn take_events(watcher: &WatcherHandle, max: usize) -> NifResult<(Vec<WatchEvent>, bool)> {
+    let mut info = lock_recovered(&watcher.info);
+    let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+
+    // Receive one event more than asked for so we know whether any are left over
+    watcher.fill(watcher_info, max.saturating_add(1));
+
+    let queue = &mut watcher_info.queue;
+    let events: Vec<WatchEvent> = queue.drain(..max.min(queue.len())).collect();
+    watcher
+        .delivered
+        .fetch_add(events.len() as u64, Ordering::Relaxed);
+
+    let more = !queue.is_empty() || watcher_info.buffer.is_ready();
+    Ok((events, more))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn get_events_after(env: Env, watcher: WatcherHandle, seq: u64) -> NifResult<Vec<Term>> {
+    guarded(|| {
+        let path = {
+            let mut info = lock_recovered(&watcher.info);
+            let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+            // Events are logged as they are numbered; they stay queued for `get_events`
+            watcher.fill(watcher_info, usize::MAX);
+            watcher_info.options.event_log.clone()
+        };
+        let path = PathBuf::from(path.ok_or_else(|| invalid_config("watcher has no event_log"))?);
+        let events = eventlog::read_after(&path, seq)
+            .map_err(|err| nif_error(notify::Error::io(err).add_path(path)))?;
+
+        events
+            .iter()
+            .map(|event| {
+                env.binary_to_term(event)
+                    .map(|(term, _)| term)
+                    .ok_or(rustler::Error::BadArg)
+            })
+            .collect()
+    })
+}
+
+/// Events numbered after `seq` still kept in the watcher's history, with `history_size`,
+/// and whether any of them are no longer kept. Nothing is taken from the queue.
+#[rustler::nif(schedule = "DirtyIo")]
+fn get_events_since(watcher: WatcherHandle, seq: u64) -> NifResult<(Vec<WatchEvent>, bool)> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+        let history = watcher
+            .history
+            .as_ref()
+            .ok_or_else(|| invalid_config("watcher has no history_size"))?;
+
+        // Number whatever is buffered so readers see it without anyone taking it
+        watcher.fill(watcher_info, usize::MAX);
+        let (mut events, missed) = history.since(seq);
+        let as_map = watcher.map_events.load(Ordering::Relaxed);
+        let baseline = watcher.baseline_events.load(Ordering::Relaxed);
+        for event in &mut events {
+            event.as_map = as_map;
+            event.baseline = baseline;
+        }
+        Ok((events, missed))
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn peek_events(watcher: WatcherHandle) -> NifResult<Vec<WatchEvent>> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+
+        watcher.fill(watcher_info, usize::MAX);
+        Ok(watcher_info.queue.clone())
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn clear_events(watcher: WatcherHandle) -> NifResult<Atom> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+
+        // Cleared events keep their sequence numbers, so consumers see the gap
+        loop {
+            watcher.fill(watcher_info, usize::MAX);
+            if watcher_info.queue.is_empty() {
+                break;
+            }
+            watcher_info.queue.clear();
+        }
+        Ok(atoms::ok())
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn pending_count(watcher: WatcherHandle) -> NifResult<usize> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+
+        // Events still in the buffer count once each, before any are paired or summarized
+        watcher.fill(watcher_info, usize::MAX);
+        Ok(watcher_info.queue.len() + watcher_info.buffer.stats().len)
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn update_watcher(watcher: WatcherHandle, config: Term) -> NifResult<Atom> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+
+        let current = &watcher_info.options;
+        let mut options = current.clone();
+        options.apply_term(config)?;
+
+        if !options.updatable_from(current) {
+            return Err(invalid_config(
+                "only recursive, exclude_dirs, backend and its options, debouncing, throttle_ms, \
+                 filters, watch_link, rearm_root, allow_missing and max_events_per_second can \
+                 be updated",
+            )
+            .into());
+        }
+
+        let mut roots = read_recovered(&watcher_info.roots).clone();
+        let rearm = options.recursive != current.recursive;
+        if rearm {
+            for (_, recursive) in &mut roots {
+                *recursive = options.recursive;
+            }
+        }
+
+        // Build the new filter and backend before swapping them in, so a bad pattern or
+        // backend leaves the watcher as it was
+        let filter = if options.filters != current.filters {
+            let paths: Vec<PathBuf> = roots.iter().map(|(root, _)| root.clone()).collect();
+            Some(options.filters.build(&paths)?)
+        } else {
+            None
+        };
+
+        let rebuild = options.backend != current.backend
+            || options.fallbacks != current.fallbacks
+            || options.backend_config != current.backend_config
+            || options.debounce_ms != current.debounce_ms
+            || options.debounce_mode != current.debounce_mode
+            || options.debounce_edge != current.debounce_edge
+            || options.throttle_ms != current.throttle_ms
+            || options.recursion != current.recursion
+            || options.watch_link != current.watch_link
+            || options.rearm_root != current.rearm_root
+            || options.allow_missing != current.allow_missing;
+        let old_recursion = current.recursion.clone();
+
+        if rebuild {
+            if options.recursion.is_manual() && watcher_info.new_dirs.is_none() {
+                watcher_info.new_dirs = Some(spawn_dir_watcher(Arc::downgrade(&watcher.core)));
+            }
+            if options.watch_link && watcher_info.relinked.is_none() {
+                watcher_info.relinked = Some(spawn_link_watcher(Arc::downgrade(&watcher.core)));
+            }
+            if options.rearm_root && watcher_info.removed_roots.is_none() {
+                watcher_info.removed_roots =
+                    Some(spawn_root_rearmer(Arc::downgrade(&watcher.core)));
+            }
+            if options.allow_missing && watcher_info.pending_changed.is_none() {
+                watcher_info.pending_changed =
+                    Some(spawn_pending_watcher(Arc::downgrade(&watcher.core)));
+            }
+            if !options.fallbacks.is_empty() && watcher_info.failover.is_none() {
+                watcher_info.failover = Some(spawn_backend_failover(Arc::downgrade(&watcher.core)));
+            }
+
+            // Looking back again only if asked to look back elsewhere
+            let history =
+                options.backend_config.since_event_id != current.backend_config.since_event_id;
+            watcher_info.rebuild_backend(&options, &roots, history)?;
+        } else if rearm {
+            let old_roots = read_recovered(&watcher_info.roots).clone();
+            for ((root, was_recursive), (_, recursive)) in old_roots.iter().zip(&roots) {
+                // Watched for the first time once created
+                if watcher_info.pending_ancestor(root).is_some() {
+                    continue;
+                }
+                let watcher_type = &mut watcher_info.watcher_type;
+                watcher_type
+                    .unwatch_root(root, *was_recursive, &old_recursion)
+                    .map_err(nif_error)?;
+                watcher_type
+                    .watch_root(root, *recursive, &options.recursion)
+                    .map_err(nif_error)?;
+            }
+        }
+
+        if let Some(filter) = filter {
+            *write_recovered(&watcher_info.filter) = filter;
+        }
+        *write_recovered(&watcher_info.roots) = roots;
+        watcher_info.options = options;
+
+        Ok(atoms::ok())
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn set_event_format(watcher: WatcherHandle, format: Term) -> NifResult<Atom> {
+    guarded(|| {
+        let format = EventFormat::decode(format)?;
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+
+        let as_map = format.shape == EventShape::Map;
+        let baseline = format.is_baseline();
+        watcher.map_events.store(as_map, Ordering::Relaxed);
+        watcher.baseline_events.store(baseline, Ordering::Relaxed);
+        // Events already queued are delivered in the new format too
+        for event in watcher_info.queue.iter_mut() {
+            event.as_map = as_map;
+            event.baseline = baseline;
+        }
+        watcher_info.options.event_format = format;
+        Ok(atoms::ok())
+    })
+}
+
+/// Have `{:select, handle, ref, :ready_input}` sent to the caller once the watcher has
+/// events to take, returning `ref`.
+#[cfg(unix)]
+#[rustler::nif(schedule = "DirtyIo")]
+fn select_ready(env: Env, watcher: WatcherHandle) -> NifResult<(Atom, Term)> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+        if watcher_info.translator.is_none() {
+            return Err(invalid_config("events are pushed to subscribers, not taken").into());
+        }
+
+        let pipe = match &watcher_info.ready {
+            Some(pipe) => pipe.clone(),
+            None => {
+                let pipe = Arc::new(ready::ReadyPipe::new()?);
+                watcher_info.buffer.on_ready({
+                    let pipe = pipe.clone();
+                    move || pipe.signal()
+                });
+                watcher_info.ready.insert(pipe).clone()
+            }
+        };
+        let pending = !watcher_info.queue.is_empty() || watcher_info.buffer.is_ready();
+        let reference = pipe.select(env, pending)?;
+        Ok((atoms::ok(), reference))
+    })
+}
+
+#[cfg(not(unix))]
+#[rustler::nif]
+fn select_ready(_watcher: WatcherHandle) -> NifResult<Atom> {
+    guarded(|| Err(WatchError::invalid_backend("enif_select is only available on Unix").into()))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn pause_watcher(watcher: WatcherHandle) -> NifResult<Atom> {
+    guarded(|| set_paused(&watcher, true))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn resume_watcher(watcher: WatcherHandle) -> NifResult<Atom> {
+    guarded(|| set_paused(&watcher, false))
+}
+
+fn set_paused(watcher: &WatcherHandle, paused: bool) -> NifResult<Atom> {
+    let info = lock_recovered(&watcher.info);
+    let watcher_info = info.as_ref().ok_or_else(WatchError::watcher_not_found)?;
+
+    // The OS watch stays registered; only what it reports is discarded
+    watcher_info.buffer.set_paused(paused);
+    Ok(atoms::ok())
+}
+
+/// Health counters returned by `get_stats`.
+#[derive(NifMap)]
+struct WatcherStats {
+    received: u64,
+    delivered: u64,
+    dropped: u64,
+    errors: u64,
+    queue_depth: usize,
+    /// Wall-clock time of the most recent event in microseconds, `nil` before the first
+    last_event_at: Option<u64>,
+    /// FSEvents ID of the most recent event delivered, `nil` on other backends
+    last_event_id: Option<u64>,
+    /// Events subscribers asked for and weren't sent yet, `nil` without `on_demand`
+    demand: Option<u64>,
+    /// Events pushed late, held back by `max_events_per_second`
+    rate_limited: u64,
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn get_stats(watcher: WatcherHandle) -> NifResult<WatcherStats> {
+    guarded(|| {
+        let info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_ref().ok_or_else(WatchError::watcher_not_found)?;
+        let stats = watcher_info.buffer.stats();
+
+        Ok(WatcherStats {
+            received: stats.received,
+            delivered: watcher.delivered.load(Ordering::Relaxed),
+            dropped: stats.dropped,
+            errors: stats.errors,
+            queue_depth: watcher_info.queue_depth(),
+            last_event_at: stats.last_event.map(|time| time.system),
+            last_event_id: Some(watcher.last_event_id.load(Ordering::Relaxed)).filter(|id| *id > 0),
+            demand: watcher_info
+                .options
+                .on_demand
+                .then(|| watcher_info.demand.pending()),
+            rate_limited: watcher.rate_limited.load(Ordering::Relaxed),
+        })
+    })
+}
+
+/// Approximate bytes a watcher holds on to, returned by `memory_info`.
+#[derive(NifMap)]
+struct MemoryInfo {
+    /// Events buffered, queued for `get_events` and kept for `get_events_since`
+    events: usize,
+    /// The watcher's roots, including those that don't exist yet
+    paths: usize,
+    /// What the watcher tracks per path: renames awaiting their pair, coalesced events and
+    /// the paths followed by `settle_ms`, `classify_attrib`, `classify_writes`,
+    /// `hash_contents` and `tail`
+    maps: usize,
+    total: usize,
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn memory_info(watcher: WatcherHandle) -> NifResult<MemoryInfo> {
+    guarded(|| {
+        let info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_ref().ok_or_else(WatchError::watcher_not_found)?;
+
+        let (buffered, positions) = watcher_info.buffer.memory();
+        let queue = &watcher_info.queue;
+        let events = buffered
+            + queue.capacity() * mem::size_of::<WatchEvent>()
+            + queue.iter().map(WatchEvent::memory).sum::<usize>()
+            + watcher.history.as_ref().map_or(0, History::memory);
+
+        let roots = read_recovered(&watcher_info.roots);
+        let pending = read_recovered(&watcher_info.pending);
+        let paths = watcher_info.path.capacity()
+            + roots.capacity() * mem::size_of::<(PathBuf, bool)>()
+            + roots
+                .iter()
+                .map(|(root, _)| memory::path(root))
+                .sum::<usize>()
+            + pending.capacity() * mem::size_of::<(PathBuf, PathBuf)>()
+            + pending
+                .iter()
+                .map(|(root, watched)| memory::path(root) + memory::path(watched))
+                .sum::<usize>();
+
+        let maps = positions
+            + watcher_info
+                .translator
+                .as_ref()
+                .map_or(0, EventTranslator::memory)
+            + watcher_info
+                .settler
+                .as_ref()
+                .map_or(0, |settler| settler.memory())
+            + watcher_info
+                .attribs
+                .as_ref()
+                .map_or(0, |attribs| attribs.memory())
+            + watcher_info
+                .sizes
+                .as_ref()
+                .map_or(0, |sizes| sizes.memory())
+            + watcher_info.hasher.as_ref().map_or(0, |hasher| {
+                memory::path_map(&lock_recovered(&hasher.hashes()))
+            })
+            + watcher_info
+                .tails
+                .as_ref()
+                .map_or(0, |tails| memory::path_map(&lock_recovered(tails)));
+
+        Ok(MemoryInfo {
+            events,
+            paths,
+            maps,
+            total: events + paths + maps,
+        })
+    })
+}
+
+/// Give back the memory a watcher no longer needs once a burst has been taken, keeping
+/// only room for what it still holds.
+#[rustler::nif(schedule = "DirtyIo")]
+fn compact(watcher: WatcherHandle) -> NifResult<Atom> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+
+        watcher_info.buffer.compact();
+        watcher_info.queue.shrink_to_fit();
+        if let Some(history) = &watcher.history {
+            history.compact();
+        }
+        write_recovered(&watcher_info.roots).shrink_to_fit();
+        write_recovered(&watcher_info.pending).shrink_to_fit();
+        if let Some(translator) = &mut watcher_info.translator {
+            translator.compact();
+        }
+        if let Some(settler) = &watcher_info.settler {
+            settler.compact();
+        }
+        if let Some(attribs) = &watcher_info.attribs {
+            attribs.compact();
+        }
+        if let Some(sizes) = &watcher_info.sizes {
+            sizes.compact();
+        }
+        if let Some(hasher) = &watcher_info.hasher {
+            lock_recovered(&hasher.hashes()).shrink_to_fit();
+        }
+        if let Some(tails) = &watcher_info.tails {
+            lock_recovered(tails).shrink_to_fit();
+        }
+        Ok(atoms::ok())
+    })
+}
+
+/// Panic while holding the watcher's lock, so tests can check that the NIF and the
+/// watcher's callbacks recover from it.
+#[cfg(feature = "debug_nifs")]
+#[rustler::nif(schedule = "DirtyIo")]
+fn debug_panic(watcher: WatcherHandle) -> NifResult<Atom> {
+    guarded(|| {
+        let _info = lock_recovered(&watcher.info);
+        panic!("debug_panic called");
+    })
+}
+
+/// Counters across every watcher returned by `telemetry_snapshot`.
+#[derive(NifMap)]
+#[rustler(encode)]
+struct TelemetrySnapshot {
+    events_received: u64,
+    events_dropped: u64,
+    errors: u64,
+    watchers_active: u64,
+    watchers: Vec<WatcherCounters>,
+}
+
+/// One watcher's share of a `TelemetrySnapshot`.
+#[derive(NifMap)]
+#[rustler(encode)]
+struct WatcherCounters {
+    watcher: WatcherHandle,
+    label: Option<Label>,
+    events_received: u64,
+    events_dropped: u64,
+    errors: u64,
+    queue_depth: usize,
+}
+
+#[rustler::nif]
+fn telemetry_snapshot() -> NifResult<TelemetrySnapshot> {
+    guarded(|| {
+        let watchers = live_watchers()
+            .into_iter()
+            .filter_map(|core| {
+                // A watcher busy e.g. replacing its backend is left out rather than waited on
+                let info = match core.info.try_lock() {
+                    Ok(info) => info,
+                    Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                    Err(TryLockError::WouldBlock) => return None,
+                };
+                let watcher_info = info.as_ref()?;
+                let stats = watcher_info.buffer.stats();
+                Some(WatcherCounters {
+                    watcher: ResourceArc::new(WatcherResource::view(core.clone())),
+                    label: watcher_info.options.label.clone(),
+                    events_received: stats.received,
+                    events_dropped: stats.dropped,
+                    errors: stats.errors,
+                    queue_depth: watcher_info.queue_depth(),
+                })
+            })
+            .collect();
+
+        Ok(TelemetrySnapshot {
+            events_received: telemetry::read(&telemetry::EVENTS_RECEIVED),
+            events_dropped: telemetry::read(&telemetry::EVENTS_DROPPED),
+            errors: telemetry::read(&telemetry::ERRORS),
+            watchers_active: telemetry::read(&telemetry::WATCHERS_ACTIVE),
+            watchers,
+        })
+    })
+}
+
+/// Send the NIF's warnings to `pid` as `{:fs_notify_log, level, message, system_time}`, or
+/// keep them for `drain_logs` again with `nil`.
+#[rustler::nif]
+fn set_log_pid(pid: Option<LocalPid>) -> NifResult<Atom> {
+    guarded(|| {
+        logs::set_pid(pid);
+        Ok(atoms::ok())
+    })
+}
+
+#[rustler::nif]
+fn drain_logs() -> NifResult<Vec<logs::LogEntry>> {
+    guarded(|| Ok(logs::drain()))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn add_path(watcher: WatcherHandle, path: String, recursive: bool) -> NifResult<Atom> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+        add_root(watcher_info, path, recursive)?;
+        Ok(atoms::ok())
+    })
+}
+
+/// Watch `path` as another root of the watcher.
+fn add_root(watcher_info: &mut WatcherInfo, path: String, recursive: bool) -> NifResult<()> {
+    let watch_path = if watcher_info.options.canonicalize {
+        canonical_root(Path::new(&path), watcher_info.options.allow_missing)?
+    } else {
+        PathBuf::from(path)
+    };
+
+    let recursion = &watcher_info.options.recursion;
+    if watcher_info.options.allow_missing && !watch_path.exists() {
+        watcher_info
+            .watcher_type
+            .watch_pending(&watch_path, &watcher_info.pending)
+            .map_err(nif_error)?;
+    } else {
+        watcher_info
+            .watcher_type
+            .watch_root(&watch_path, recursive, recursion)
+            .map_err(nif_error)?;
+    }
+    if watcher_info.options.watch_link {
+        watcher_info
+            .watcher_type
+            .watch_link(&watch_path)
+            .map_err(nif_error)?;
+    }
+
+    if let Some(filter) = &*read_recovered(&watcher_info.filter) {
+        filter.add_root(&watch_path);
+    }
+    if let Some(attribs) = &watcher_info.attribs {
+        attribs.spawn_seed(watch_path.clone(), recursive, recursion.clone());
+    }
+    if let Some(sizes) = &watcher_info.sizes {
+        sizes.spawn_seed(watch_path.clone(), recursive, recursion.clone());
+    }
+    if let Some(tails) = &watcher_info.tails {
+        tail::follow(tails, &watch_path);
+    }
+
+    let mut roots = write_recovered(&watcher_info.roots);
+    roots.retain(|(root, _)| *root != watch_path);
+    roots.push((watch_path, recursive));
+
+    Ok(())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn remove_path(watcher: WatcherHandle, path: String) -> NifResult<Atom> {
+    guarded(|| {
+        let mut info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_mut().ok_or_else(WatchError::watcher_not_found)?;
+
+        // The root may be gone by now, so it can't be required to resolve
+        let watch_path = if watcher_info.options.canonicalize {
+            event::canonicalize(Path::new(&path))
+        } else {
+            PathBuf::from(path)
+        };
+
+        let recursive = read_recovered(&watcher_info.roots)
+            .iter()
+            .any(|(root, recursive)| *root == watch_path && *recursive);
+        if let Some(ancestor) = watcher_info.pending_ancestor(&watch_path) {
+            write_recovered(&watcher_info.pending).retain(|(root, _)| *root != watch_path);
+            watcher_info.release_ancestor(&ancestor);
+        } else {
+            let recursion = &watcher_info.options.recursion;
+            watcher_info
+                .watcher_type
+                .unwatch_root(&watch_path, recursive, recursion)
+                .map_err(nif_error)?;
+            if watcher_info.options.watch_link {
+                watcher_info.watcher_type.unwatch_link(&watch_path);
+            }
+        }
+
+        if let Some(filter) = &*read_recovered(&watcher_info.filter) {
+            filter.remove_root(&watch_path);
+        }
+        if let Some(tails) = &watcher_info.tails {
+            tail::unfollow(tails, &watch_path);
+        }
+
+        write_recovered(&watcher_info.roots).retain(|(root, _)| *root != watch_path);
+
+        Ok(atoms::ok())
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn record_events(watcher: WatcherHandle, path: String) -> NifResult<Atom> {
+    guarded(|| {
+        let info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_ref().ok_or_else(WatchError::watcher_not_found)?;
+
+        let path = PathBuf::from(path);
+        let journal = Journal::create(&path)
+            .map_err(|err| nif_error(notify::Error::io(err).add_path(path)))?;
+        // Any journal already being written is finished and replaced
+        let previous = lock_recovered(&watcher_info.recorder).replace(journal);
+        if let Some(previous) = previous {
+            previous
+                .finish()
+                .map_err(|err| nif_error(notify::Error::io(err)))?;
+        }
+        Ok(atoms::ok())
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn stop_recording(watcher: WatcherHandle) -> NifResult<Atom> {
+    guarded(|| {
+        let info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_ref().ok_or_else(WatchError::watcher_not_found)?;
+
+        let journal = lock_recovered(&watcher_info.recorder).take();
+        if let Some(journal) = journal {
+            journal
+                .finish()
+                .map_err(|err| nif_error(notify::Error::io(err)))?;
+        }
+        Ok(atoms::ok())
+    })
+}
+
+/// The effective configuration of a watcher: every option in the form
+/// `start_watcher_with_options` takes, defaults included, along with `path`, the roots
+/// watched, `subscriber` and `active_backend`, the backend actually in use.
+#[rustler::nif(schedule = "DirtyIo")]
+fn get_config(env: Env, watcher: WatcherHandle) -> NifResult<Term> {
+    guarded(|| {
+        let info = lock_recovered(&watcher.info);
+        let watcher_info = info.as_ref().ok_or_else(WatchError::watcher_not_found)?;
+
+        let roots: Vec<String> = read_recovered(&watcher_info.roots)
+            .iter()
+            .map(|(root, _)| root.to_string_lossy().into_owned())
+            .collect();
+        let config = watcher_info.options.to_term(env)?;
+        let config = config.map_put(atoms::path(), roots)?;
+        let config = config.map_put(atoms::subscriber(), watcher_info.options.subscriber)?;
+        config.map_put(atoms::active_backend(), watcher_info.backend)
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn get_watcher_info(
+    watcher: WatcherHandle,
+) -> NifResult<(Atom, String, bool, Atom, Option<Label>, bool)> {
+    guarded(|| {
+        let info = lock_recovered(&watcher.info);
+
+        if let Some(watcher_info) = info.as_ref() {
+            Ok((
+                atoms::ok(),
+                watcher_info.path.clone(),
+                watcher_info.options.recursive,
+                watcher_info.backend,
+                watcher_info.options.label.clone(),
+                watcher_info.options.backend_config.follow_symlinks,
+            ))
+        } else {
+            Err(WatchError::watcher_not_found().into())
+        }
+    })
+}
+
+/// The backend a `{:backend_fallback, backend}` notice names, by `BackendType::name`.
+fn backend_named(name: &str) -> Option<Atom> {
+    Some(match name {
+        "recommended" => atoms::recommended(),
+        "poll" => atoms::poll(),
+        "inotify" => atoms::inotify(),
+        "fanotify" => atoms::fanotify(),
+        "fsevent" => atoms::fsevent(),
+        "kqueue" => atoms::kqueue(),
+        "windows" => atoms::windows(),
+        "watchman" => atoms::watchman(),
+        "null" => atoms::null(),
+        _ => return None,
+    })
+}
+
+fn backend_atom(kind: WatcherKind) -> Atom {
+    match kind {
+        WatcherKind::Inotify => atoms::inotify(),
+        WatcherKind::Fsevent => atoms::fsevent(),
+        WatcherKind::Kqueue => atoms::kqueue(),
+        WatcherKind::PollWatcher => atoms::poll(),
+        WatcherKind::ReadDirectoryChangesWatcher => atoms::windows(),
+        WatcherKind::NullWatcher => atoms::null(),
+        _ => atoms::unknown(),
+    }
+}
+
+type WatcherListing = (
+    WatcherHandle,
+    String,
+    bool,
+    Atom,
+    Option<u64>,
+    usize,
+    Option<Label>,
+);
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn list_watchers() -> NifResult<Vec<WatcherListing>> {
+    guarded(|| {
+        let watchers = live_watchers()
+            .into_iter()
+            .filter_map(|core| {
+                // Skip watchers that were stopped while a handle is still around
+                let info = lock_recovered(&core.info);
+                let watcher_info = info.as_ref()?;
+
+                Some((
+                    ResourceArc::new(WatcherResource::view(core.clone())),
+                    watcher_info.path.clone(),
+                    watcher_info.options.recursive,
+                    watcher_info.backend,
+                    watcher_info.options.debounce_ms,
+                    watcher_info.queue_depth(),
+                    watcher_info.options.label.clone(),
+                ))
+            })
+            .collect();
+        Ok(watchers)
+    })
+}
+
+#[rustler::nif]
+fn list_available_backends() -> NifResult<Vec<Atom>> {
+    guarded(|| {
+        let mut backends = vec![atoms::recommended(), atoms::poll(), atoms::null()];
+
+        #[cfg(target_os = "linux")]
+        backends.push(atoms::inotify());
+
+        #[cfg(target_os = "linux")]
+        if fanotify::available() {
+            backends.push(atoms::fanotify());
+        }
+
+        #[cfg(all(target_os = "macos", not(feature = "macos_kqueue")))]
+        backends.push(atoms::fsevent());
+
+        #[cfg(any(
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd",
+            target_os = "dragonfly",
+            target_os = "ios",
+            all(target_os = "macos", feature = "macos_kqueue")
+        ))]
+        backends.push(atoms::kqueue());
+
+        #[cfg(target_os = "windows")]
+        backends.push(atoms::windows());
+
+        #[cfg(all(unix, feature = "watchman"))]
+        if watchman::available() {
+            backends.push(atoms::watchman());
+        }
+
+        Ok(backends)
+    })
+}
+
+#[cfg(unix)]
+fn load(env: Env, _load_info: Term) -> bool {
+    ready::register(env)
 }
 
-fn path_to_string(path: &std::path::Path) -> String {
-    path.to_string_lossy().into_owned()
+#[cfg(not(unix))]
+fn load(_env: Env, _load_info: Term) -> bool {
+    true
 }
 
-rustler::init!("Elixir.FSNotify.Native");
+rustler::init!("Elixir.FSNotify.Native", load = load);
@@ -0,0 +1,124 @@
+//! Warnings the NIF would otherwise keep to itself, e.g. backend errors, fallbacks and
+//! dropped events. They are sent to the process registered with `set_log_pid` as
+//! `{:fs_notify_log, level, message, system_time}`, or kept for `drain_logs` while there is
+//! none.
+
+use crate::atoms;
+use crate::error::lock_recovered;
+use crate::event::Timestamp;
+use rustler::{Atom, Encoder, Env, LocalPid, OwnedEnv, Term};
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+/// Entries kept for `drain_logs` before the oldest are discarded.
+const CAPACITY: usize = 1000;
+
+/// `{level, message, system_time}`
+pub struct LogEntry {
+    level: Atom,
+    message: String,
+    /// Wall-clock time since the Unix epoch in microseconds
+    time: u64,
+}
+
+impl Encoder for LogEntry {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        (self.level, &self.message, self.time).encode(env)
+    }
+}
+
+struct Logs {
+    /// Process entries are sent to, if any
+    pid: Option<LocalPid>,
+    /// Entries waiting for `drain_logs`
+    entries: VecDeque<LogEntry>,
+    /// Hands entries to the thread sending them, since threads the VM runs NIFs on can't
+    forward: Option<Sender<(LocalPid, LogEntry)>>,
+}
+
+static LOGS: Mutex<Logs> = Mutex::new(Logs {
+    pid: None,
+    entries: VecDeque::new(),
+    forward: None,
+});
+
+pub fn warning(message: impl Into<String>) {
+    log(atoms::warning(), message.into());
+}
+
+pub fn error(message: impl Into<String>) {
+    log(atoms::error(), message.into());
+}
+
+fn log(level: Atom, message: String) {
+    let entry = LogEntry {
+        level,
+        message,
+        time: Timestamp::now().system,
+    };
+    let mut logs = lock_recovered(&LOGS);
+    match (logs.pid, &logs.forward) {
+        (Some(pid), Some(forward)) => {
+            let _ = forward.send((pid, entry));
+        }
+        _ => keep(&mut logs, entry),
+    }
+}
+
+fn keep(logs: &mut Logs, entry: LogEntry) {
+    if logs.entries.len() >= CAPACITY {
+        logs.entries.pop_front();
+    }
+    logs.entries.push_back(entry);
+}
+
+/// Send entries to `pid` from now on, starting with those kept so far, or keep them for
+/// `drain_logs` again with `None`.
+pub fn set_pid(pid: Option<LocalPid>) {
+    let mut logs = lock_recovered(&LOGS);
+    logs.pid = pid;
+    let Some(pid) = pid else {
+        return;
+    };
+
+    let forward = logs.forward.get_or_insert_with(spawn_forwarder).clone();
+    for entry in std::mem::take(&mut logs.entries) {
+        let _ = forward.send((pid, entry));
+    }
+}
+
+/// Take the entries kept so far, oldest first.
+pub fn drain() -> Vec<LogEntry> {
+    lock_recovered(&LOGS).entries.drain(..).collect()
+}
+
+fn spawn_forwarder() -> Sender<(LocalPid, LogEntry)> {
+    let (forward, received) = mpsc::channel::<(LocalPid, LogEntry)>();
+
+    thread::spawn(move || {
+        let mut env = OwnedEnv::new();
+        for (pid, entry) in received {
+            let sent = env.send_and_clear(&pid, |env| {
+                let LogEntry {
+                    level,
+                    message,
+                    time,
+                } = &entry;
+                (atoms::fs_notify_log(), *level, message, *time).encode(env)
+            });
+            if sent.is_err() {
+                // The process is gone, so entries are kept for `drain_logs` until another
+                // is registered
+                let mut logs = lock_recovered(&LOGS);
+                if logs.pid == Some(pid) {
+                    logs.pid = None;
+                }
+                keep(&mut logs, entry);
+            }
+        }
+    });
+
+    forward
+}
@@ -0,0 +1,77 @@
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Longest path (in UTF-16 units, less room for an 8.3 file name) that can be opened as is.
+#[cfg(windows)]
+const MAX_DIR_PATH: usize = 248;
+
+#[cfg(windows)]
+const VERBATIM: &str = r"\\?\";
+#[cfg(windows)]
+const VERBATIM_UNC: &str = r"\\?\UNC\";
+
+/// `path` in a form the OS accepts whatever its length. Windows paths longer than
+/// `MAX_PATH` can only be opened in their extended-length form, `\\?\C:\...` or
+/// `\\?\UNC\server\share\...`, so watches are registered that way when needed. Other
+/// platforms have no such limit.
+#[cfg(windows)]
+pub fn extended(path: &Path) -> Cow<'_, Path> {
+    use std::os::windows::ffi::OsStrExt;
+
+    let long = path.as_os_str().encode_wide().count() >= MAX_DIR_PATH;
+    if !long || path.as_os_str().to_string_lossy().starts_with(VERBATIM) {
+        return Cow::Borrowed(path);
+    }
+    // Verbatim paths skip normalization, so `..` and `/` have to be resolved first
+    let Ok(absolute) = std::path::absolute(path) else {
+        return Cow::Borrowed(path);
+    };
+
+    let absolute = absolute.to_string_lossy().into_owned();
+    let extended = match absolute.strip_prefix(r"\\") {
+        Some(unc) => format!("{VERBATIM_UNC}{unc}"),
+        None => format!("{VERBATIM}{absolute}"),
+    };
+    Cow::Owned(PathBuf::from(extended))
+}
+
+#[cfg(not(windows))]
+pub fn extended(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+/// Whether `path` is on a network share, `\\server\share\...` in either form.
+#[cfg(windows)]
+pub fn is_unc(path: &Path) -> bool {
+    use std::path::{Component, Prefix};
+
+    matches!(
+        path.components().next(),
+        Some(Component::Prefix(prefix))
+            if matches!(prefix.kind(), Prefix::UNC(..) | Prefix::VerbatimUNC(..))
+    )
+}
+
+/// Drop the extended-length prefix from `path`, if it has one.
+#[cfg(windows)]
+pub fn strip_extended(path: &mut PathBuf) {
+    let Some(name) = path.to_str() else {
+        return;
+    };
+
+    let stripped = if let Some(unc) = name.strip_prefix(VERBATIM_UNC) {
+        format!(r"\\{unc}")
+    } else if let Some(local) = name.strip_prefix(VERBATIM) {
+        // Only drive paths; `\\?\Volume{...}` and the like have no other form
+        if local.as_bytes().get(1) != Some(&b':') {
+            return;
+        }
+        local.to_owned()
+    } else {
+        return;
+    };
+    *path = PathBuf::from(stripped);
+}
+
+#[cfg(not(windows))]
+pub fn strip_extended(_path: &mut PathBuf) {}
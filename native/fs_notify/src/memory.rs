@@ -0,0 +1,27 @@
+//! Rough accounting of the memory a watcher holds on to, for `memory_info`: the room its
+//! collections have allocated, plus the paths they hold. Allocator overhead and whatever
+//! the backend keeps to itself, e.g. inotify's watch descriptors, aren't counted.
+
+use std::collections::HashMap;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+/// Bytes a path's name takes up.
+pub fn path(path: &Path) -> usize {
+    path.as_os_str().len()
+}
+
+/// Bytes taken by a list of paths, e.g. those of a backend event.
+pub fn paths(paths: &[PathBuf]) -> usize {
+    mem::size_of_val(paths) + paths.iter().map(|each| path(each)).sum::<usize>()
+}
+
+/// Bytes taken by a map from paths, counting every entry it has room for.
+pub fn path_map<V>(map: &HashMap<PathBuf, V>) -> usize {
+    table::<PathBuf, V>(map.capacity()) + map.keys().map(|key| path(key)).sum::<usize>()
+}
+
+/// Bytes taken by a hash table with room for `capacity` entries, with a control byte each.
+pub fn table<K, V>(capacity: usize) -> usize {
+    capacity * (mem::size_of::<(K, V)>() + 1)
+}
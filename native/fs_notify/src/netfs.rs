@@ -0,0 +1,222 @@
+//! Telling whether a path is on a network or FUSE filesystem, where native backends can't be
+//! relied on: NFS and SMB servers don't pass on changes made by other clients, and FUSE
+//! filesystems only see the changes made through the local kernel. Watchers of such paths
+//! poll instead unless a backend was asked for explicitly. The name of the filesystem is
+//! also looked up here, for `probe_path`.
+
+use std::path::Path;
+
+/// Whether `path`, or its closest existing ancestor if it doesn't exist yet, is on a
+/// network or FUSE filesystem. Filesystems that can't be told apart count as local.
+pub fn is_network(path: &Path) -> bool {
+    path.ancestors()
+        .find_map(filesystem_is_network)
+        .unwrap_or(false)
+}
+
+/// `statfs` magic numbers of the filesystems counted as network ones.
+#[cfg(target_os = "linux")]
+const NETWORK_MAGICS: [u32; 10] = [
+    0x6969,      // NFS
+    0x517b,      // SMB
+    0xff53_4d42, // CIFS
+    0xfe53_4d42, // SMB2
+    0x6573_5546, // FUSE
+    0x7375_7245, // Coda
+    0x5346_414f, // AFS
+    0x6b41_4653, // kAFS
+    0x0102_1997, // 9P
+    0x00c3_6400, // Ceph
+];
+
+#[cfg(target_os = "linux")]
+fn filesystem_is_network(path: &Path) -> Option<bool> {
+    let stat = statfs(path)?;
+    // Only the low 32 bits hold the magic number, whatever the width of the field
+    Some(NETWORK_MAGICS.contains(&(stat.f_type as u32)))
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+fn filesystem_is_network(path: &Path) -> Option<bool> {
+    let name = fs_type(path)?;
+    let network = matches!(
+        &*name,
+        "nfs" | "smbfs" | "afpfs" | "webdav" | "cifs" | "macfuse" | "osxfuse"
+    ) || name.starts_with("fuse");
+    Some(network)
+}
+
+/// Name of the filesystem `path` is on, e.g. `ext4`, `apfs` or `NTFS`.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+pub fn fs_type(path: &Path) -> Option<String> {
+    let stat = statfs(path)?;
+    // SAFETY: the kernel fills in a NUL-terminated name
+    let name = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Name of the filesystem `path` is on, as `/proc/self/mounts` gives it for the mount
+/// point closest to it.
+#[cfg(target_os = "linux")]
+pub fn fs_type(path: &Path) -> Option<String> {
+    let path = path.canonicalize().ok()?;
+    let mounts = std::fs::read_to_string("/proc/self/mounts").ok()?;
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let mount_point = unescape_mount_field(fields.nth(1)?);
+            Some((mount_point, fields.next()?))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        // Later mounts over the same point hide the earlier ones
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .map(|(_, fs_type)| fs_type.to_string())
+}
+
+/// Undo the octal escapes of spaces, tabs, newlines and backslashes in a mount table field.
+#[cfg(target_os = "linux")]
+fn unescape_mount_field(field: &str) -> std::path::PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|digits| {
+            let digits = std::str::from_utf8(digits).ok()?;
+            u8::from_str_radix(digits, 8).ok()
+        });
+        match octal {
+            Some(byte) if bytes[i] == b'\\' => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            _ => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    std::ffi::OsString::from_vec(unescaped).into()
+}
+
+/// Name of the filesystem `path` is on, e.g. `NTFS` or `ReFS`.
+#[cfg(windows)]
+pub fn fs_type(path: &Path) -> Option<String> {
+    use windows_sys::Win32::Storage::FileSystem::GetVolumeInformationW;
+
+    let volume = volume_path(path)?;
+    let mut name = [0u16; 261];
+    // SAFETY: `volume` is NUL-terminated, `name` holds as many units as claimed and the
+    // other outputs are optional
+    let found = unsafe {
+        GetVolumeInformationW(
+            volume.as_ptr(),
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            name.as_mut_ptr(),
+            name.len() as u32,
+        )
+    };
+    if found == 0 {
+        return None;
+    }
+    let len = name
+        .iter()
+        .position(|&unit| unit == 0)
+        .unwrap_or(name.len());
+    Some(String::from_utf16_lossy(&name[..len]))
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+fn statfs(path: &Path) -> Option<libc::statfs> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` has room for the result
+    if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: `statfs` succeeded, so it filled `stat` in
+    Some(unsafe { stat.assume_init() })
+}
+
+#[cfg(windows)]
+fn filesystem_is_network(path: &Path) -> Option<bool> {
+    use windows_sys::Win32::Storage::FileSystem::GetDriveTypeW;
+    use windows_sys::Win32::System::WindowsProgramming::DRIVE_REMOTE;
+
+    if crate::longpath::is_unc(path) {
+        return Some(true);
+    }
+    let volume = volume_path(path)?;
+    // SAFETY: `volume` is a NUL-terminated path
+    Some(unsafe { GetDriveTypeW(volume.as_ptr()) } == DRIVE_REMOTE)
+}
+
+/// The root of the volume `path` is on, e.g. `C:\`, NUL-terminated.
+#[cfg(windows)]
+fn volume_path(path: &Path) -> Option<Vec<u16>> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetVolumePathNameW;
+
+    if !path.exists() {
+        return None;
+    }
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    let mut volume = vec![0u16; wide.len().max(261)];
+    // SAFETY: `wide` is NUL-terminated and `volume` holds as many units as claimed
+    let found =
+        unsafe { GetVolumePathNameW(wide.as_ptr(), volume.as_mut_ptr(), volume.len() as u32) };
+    (found != 0).then_some(volume)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    windows
+)))]
+fn filesystem_is_network(_path: &Path) -> Option<bool> {
+    Some(false)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    windows
+)))]
+pub fn fs_type(_path: &Path) -> Option<String> {
+    None
+}
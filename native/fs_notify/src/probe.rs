@@ -0,0 +1,68 @@
+//! What a path's filesystem is like, as `probe_path` reports it, so a backend can be chosen
+//! before a watcher is started.
+
+use crate::event::file_id;
+use crate::netfs;
+use rustler::{Atom, NifMap};
+use std::fs;
+use std::path::Path;
+
+/// `%{fs_type: name, network: bool, case_sensitive: bool, recommended_backend: backend}`
+#[derive(Debug, NifMap)]
+pub struct PathProbe {
+    /// Name of the filesystem, e.g. `"ext4"` or `"apfs"`, or `nil` if it can't be told
+    pub fs_type: Option<String>,
+    /// Whether it is a network or FUSE filesystem, which native backends can't watch reliably
+    pub network: bool,
+    /// Whether names differing only in case are different files, or `nil` if no name around
+    /// the path has letters to tell by
+    pub case_sensitive: Option<bool>,
+    /// The backend `:recommended` uses for the path
+    pub recommended_backend: Atom,
+}
+
+pub fn probe(path: &Path, recommended_backend: Atom) -> PathProbe {
+    PathProbe {
+        fs_type: netfs::fs_type(path),
+        network: netfs::is_network(path),
+        case_sensitive: case_sensitive(path),
+        recommended_backend,
+    }
+}
+
+/// Whether the filesystem tells names apart by case, found by swapping the case of the
+/// path's name (or an ancestor's, or an entry's if it is a directory) and checking whether
+/// that names the same file.
+fn case_sensitive(path: &Path) -> Option<bool> {
+    let entries = fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .take(16)
+        .map(|entry| entry.path());
+    let mut candidates = path.ancestors().map(Path::to_path_buf).chain(entries);
+
+    candidates.find_map(|candidate| {
+        let name = candidate.file_name()?.to_str()?;
+        let swapped: String = name.chars().map(swap_case).collect();
+        if swapped == name {
+            return None;
+        }
+        let original = fs::symlink_metadata(&candidate).ok()?;
+        let swapped = candidate.with_file_name(swapped);
+        let same = fs::symlink_metadata(&swapped).is_ok_and(|metadata| {
+            file_id(&candidate, &original).is_some()
+                && file_id(&candidate, &original) == file_id(&swapped, &metadata)
+        });
+        Some(!same)
+    })
+}
+
+/// Only ASCII letters are swapped, since other case mappings may change a name's length.
+fn swap_case(c: char) -> char {
+    if c.is_ascii_lowercase() {
+        c.to_ascii_uppercase()
+    } else {
+        c.to_ascii_lowercase()
+    }
+}
@@ -0,0 +1,48 @@
+//! Rate limiting of the events pushed to subscribers, with `max_events_per_second`: once
+//! that many have been sent within a second the rest wait for the next one, in the
+//! watcher's buffer, where its drop policy applies, rather than flooding mailboxes.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SECOND: Duration = Duration::from_secs(1);
+
+pub struct RateLimit {
+    per_second: u32,
+    /// When the current second started
+    started: Instant,
+    /// Events sent since then
+    sent: u32,
+}
+
+impl RateLimit {
+    pub fn new(per_second: u32) -> Self {
+        RateLimit {
+            per_second,
+            started: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Events allowed each second.
+    pub fn per_second(&self) -> u32 {
+        self.per_second
+    }
+
+    /// Count an event as sent, first blocking until the next second if this one's events
+    /// have all been sent. Returns whether it had to wait.
+    pub fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let next = self.started + SECOND;
+        let waited = now < next && self.sent >= self.per_second;
+        if waited {
+            thread::sleep(next - now);
+        }
+        if waited || now >= next {
+            self.started = Instant::now();
+            self.sent = 0;
+        }
+        self.sent += 1;
+        waited
+    }
+}
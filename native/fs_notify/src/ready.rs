@@ -0,0 +1,193 @@
+//! Readiness signaling through `enif_select`, so the process taking a watcher's events can
+//! wait for them in its mailbox rather than polling or keeping a thread of its own. Backends
+//! read their file descriptors on threads of their own, so what is selected on is a pipe
+//! that the watcher's buffer writes to as events arrive.
+
+use crate::atoms;
+use crate::error::WatchError;
+use rustler::sys::{
+    enif_alloc_resource, enif_make_ref, enif_open_resource_type_x, enif_release_resource,
+    enif_select, ErlNifEnv, ErlNifEvent, ErlNifResourceFlags, ErlNifResourceStop,
+    ErlNifResourceType, ErlNifResourceTypeInit, ERL_NIF_SELECT_READ, ERL_NIF_SELECT_STOP,
+};
+use rustler::{Env, NifResult, Term};
+use std::ffi::{c_int, c_void};
+use std::io;
+use std::os::fd::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+/// Type of the resources pipes are selected through; `enif_select` needs one with a stop
+/// callback, which resources registered through rustler don't have.
+static READY_TYPE: OnceLock<ResourceType> = OnceLock::new();
+
+struct ResourceType(*const ErlNifResourceType);
+
+// SAFETY: resource types are never freed and can be used from any thread
+unsafe impl Send for ResourceType {}
+unsafe impl Sync for ResourceType {}
+
+/// Open the resource type, when the NIF is loaded.
+pub fn register(env: Env) -> bool {
+    let init = ErlNifResourceTypeInit {
+        dtor: ptr::null(),
+        stop: stop as *const ErlNifResourceStop,
+        down: ptr::null(),
+        members: 2,
+        dyncall: ptr::null(),
+    };
+    let mut tried = ErlNifResourceFlags::ERL_NIF_RT_CREATE;
+    // SAFETY: the name is NUL-terminated and `init` outlives the call, which copies it
+    let resource_type = unsafe {
+        enif_open_resource_type_x(
+            env.as_c_arg(),
+            c"FSNotify.Ready".as_ptr(),
+            &init,
+            ErlNifResourceFlags::ERL_NIF_RT_CREATE,
+            &mut tried,
+        )
+    };
+    !resource_type.is_null() && READY_TYPE.set(ResourceType(resource_type)).is_ok()
+}
+
+/// Called by the VM once the read end is no longer selected, when it is safe to close.
+unsafe extern "C" fn stop(
+    _env: *mut ErlNifEnv,
+    _obj: *mut c_void,
+    event: ErlNifEvent,
+    _is_direct_call: c_int,
+) {
+    libc::close(event);
+}
+
+/// A pipe that is readable while a watcher has events to take.
+pub struct ReadyPipe {
+    read: RawFd,
+    write: RawFd,
+    /// A byte is waiting in the pipe
+    signaled: AtomicBool,
+    /// The read end has been selected on, so only the VM may close it
+    selected: AtomicBool,
+    /// The read end has been unselected and handed to `stop` to close
+    stopped: AtomicBool,
+    /// Resource the read end is selected through
+    resource: *mut c_void,
+}
+
+// SAFETY: the resource is only handed to the VM, which allows that from any thread
+unsafe impl Send for ReadyPipe {}
+unsafe impl Sync for ReadyPipe {}
+
+impl ReadyPipe {
+    pub fn new() -> NifResult<Self> {
+        let resource_type = READY_TYPE
+            .get()
+            .ok_or_else(|| WatchError::internal("readiness resource type not registered"))?;
+
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for both ends
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io_error("can't create readiness pipe"));
+        }
+        for fd in fds {
+            // SAFETY: `fd` was just opened; neither end may block a scheduler or the backend
+            unsafe {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+
+        // SAFETY: the type was opened at load time
+        let resource = unsafe { enif_alloc_resource(resource_type.0, 0) };
+        Ok(ReadyPipe {
+            read: fds[0],
+            write: fds[1],
+            signaled: AtomicBool::new(false),
+            selected: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            resource,
+        })
+    }
+
+    /// Make the pipe readable, unless it already is.
+    pub fn signal(&self) {
+        if !self.signaled.swap(true, Ordering::SeqCst) {
+            // SAFETY: writing one byte from a live buffer; a full pipe is readable anyway
+            unsafe { libc::write(self.write, [0u8].as_ptr().cast(), 1) };
+        }
+    }
+
+    /// Have `{:select, resource, ref, :ready_input}` sent to the calling process once the
+    /// pipe is readable, returning `ref`. The pipe is emptied first, then signaled again
+    /// at once if `pending`, so a wake-up may be spurious but is never lost.
+    pub fn select<'a>(&self, env: Env<'a>, pending: bool) -> NifResult<Term<'a>> {
+        let mut drained = [0u8; 64];
+        // SAFETY: reading into a live buffer of the size given, until the pipe is empty
+        while unsafe { libc::read(self.read, drained.as_mut_ptr().cast(), drained.len()) } > 0 {}
+        self.signaled.store(false, Ordering::SeqCst);
+        if pending {
+            self.signal();
+        }
+
+        // SAFETY: `env` is the calling NIF's, and `resource` is of a type with a stop
+        // callback, which stays alive for as long as the pipe is selected
+        unsafe {
+            let reference = enif_make_ref(env.as_c_arg());
+            let selected = enif_select(
+                env.as_c_arg(),
+                self.read,
+                ERL_NIF_SELECT_READ,
+                self.resource,
+                ptr::null(),
+                reference,
+            );
+            if selected < 0 {
+                let message = "can't select on readiness pipe";
+                return Err(WatchError::new(atoms::io_error(), message).into());
+            }
+            self.selected.store(true, Ordering::SeqCst);
+            Ok(Term::new(env, reference))
+        }
+    }
+
+    /// Unselect the read end, which the VM then closes. Needs a NIF's or callback's `env`,
+    /// so it is done when a watcher is torn down from Elixir rather than on drop.
+    pub fn stop(&self, env: Env) {
+        if !self.selected.load(Ordering::SeqCst) || self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // SAFETY: as for `select`; pid and ref are ignored when stopping
+        unsafe {
+            enif_select(
+                env.as_c_arg(),
+                self.read,
+                ERL_NIF_SELECT_STOP,
+                self.resource,
+                ptr::null(),
+                atoms::undefined().as_c_arg(),
+            );
+        }
+    }
+}
+
+impl Drop for ReadyPipe {
+    fn drop(&mut self) {
+        // SAFETY: the ends are only ever used through `self`. The VM holds on to the
+        // resource for as long as the read end is selected, so ours can go
+        unsafe {
+            libc::close(self.write);
+            if !self.selected.load(Ordering::SeqCst) {
+                libc::close(self.read);
+            }
+            enif_release_resource(self.resource);
+        }
+    }
+}
+
+/// The last OS error, e.g. from creating the pipe, as a NIF error.
+fn io_error(message: &str) -> rustler::Error {
+    let err = io::Error::last_os_error();
+    WatchError::new(atoms::io_error(), format!("{message}: {err}")).into()
+}
@@ -0,0 +1,90 @@
+//! The backend notify's debouncers run on. They create their watcher themselves, from its
+//! type alone, so `Relay` stands in for that type and starts the backend asked for, which
+//! is handed to it on the side, through a thread-local, along with the leading edge to
+//! report when both edges of a burst are.
+
+use crate::config::BackendConfig;
+use crate::edge::LeadingEdge;
+use crate::{nif_error, BackendType};
+use notify::{Config, Error, EventHandler, RecursiveMode, Result, Watcher, WatcherKind};
+use rustler::{Atom, NifResult};
+use std::cell::RefCell;
+use std::path::Path;
+
+thread_local! {
+    /// Backend the next `Relay` created on this thread starts, then how starting it went.
+    static RELAYED: RefCell<Relayed> = const { RefCell::new(Relayed::None) };
+}
+
+enum Relayed {
+    None,
+    Asked(BackendType, BackendConfig, Option<Box<LeadingEdge>>),
+    Started(Atom),
+    Failed(rustler::Error),
+}
+
+pub struct Relay {
+    watcher: Box<dyn Watcher + Send>,
+}
+
+impl Relay {
+    /// Run `create`, which creates a debouncer over a `Relay`, with that relay starting
+    /// `backend`, and return the debouncer along with the name of the backend. Events go
+    /// through `leading` on their way to the debouncer, if given.
+    pub fn with_backend<D>(
+        backend: &BackendType,
+        config: &BackendConfig,
+        leading: Option<LeadingEdge>,
+        create: impl FnOnce() -> Result<D>,
+    ) -> NifResult<(D, Atom)> {
+        let leading = leading.map(Box::new);
+        RELAYED.set(Relayed::Asked(backend.clone(), config.clone(), leading));
+        let debouncer = create();
+        // The backend's own error says more than the debouncer's account of it
+        match (debouncer, RELAYED.replace(Relayed::None)) {
+            (_, Relayed::Failed(err)) => Err(err),
+            (Ok(debouncer), Relayed::Started(name)) => Ok((debouncer, name)),
+            (Ok(_), _) => Err(nif_error(Error::generic("debouncer created no watcher"))),
+            (Err(err), _) => Err(nif_error(err)),
+        }
+    }
+}
+
+impl Watcher for Relay {
+    fn new<F: EventHandler>(handler: F, _config: Config) -> Result<Self> {
+        let Relayed::Asked(backend, config, leading) = RELAYED.replace(Relayed::None) else {
+            return Err(Error::generic("no backend to relay"));
+        };
+        let created = match leading {
+            Some(leading) => backend.create_watcher((*leading).with_followers(handler), &config),
+            None => backend.create_watcher(handler, &config),
+        };
+        match created {
+            Ok((watcher, name)) => {
+                RELAYED.set(Relayed::Started(name));
+                Ok(Relay { watcher })
+            }
+            Err(err) => {
+                RELAYED.set(Relayed::Failed(err));
+                Err(Error::generic("backend failed to start"))
+            }
+        }
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        self.watcher.watch(path, recursive_mode)
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        self.watcher.unwatch(path)
+    }
+
+    fn configure(&mut self, option: Config) -> Result<bool> {
+        self.watcher.configure(option)
+    }
+
+    fn kind() -> WatcherKind {
+        // Only known once created; `with_backend` gives the name of the one relayed to
+        WatcherKind::NullWatcher
+    }
+}
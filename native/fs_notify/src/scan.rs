@@ -0,0 +1,133 @@
+use crate::event::Timestamp;
+use crate::sink::EventSink;
+use ignore::{Walk, WalkBuilder, WalkParallel};
+use notify::event::CreateKind;
+use notify::{Event, EventKind};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// `Event::info` of an entry found by the initial scan, reported as `:discovered`.
+pub const DISCOVERED: &str = "fs_notify:discovered";
+/// `Event::info` of the marker sent once the initial scan is done, reported as `:ready`.
+pub const READY: &str = "fs_notify:ready";
+
+/// Limits on how far a recursive root is followed. Any limit means the backend can't be
+/// left to recurse on its own, so each directory within them is watched separately.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recursion {
+    /// Levels below the root that are watched, or `None` for all of them
+    pub depth: Option<usize>,
+    /// Names of directories whose subtrees are never watched, e.g. `node_modules`
+    pub exclude_dirs: Vec<String>,
+}
+
+impl Recursion {
+    /// Whether directories have to be watched one at a time.
+    pub fn is_manual(&self) -> bool {
+        self.depth.is_some() || !self.exclude_dirs.is_empty()
+    }
+
+    pub fn is_excluded(&self, dir: &Path) -> bool {
+        dir.file_name()
+            .is_some_and(|name| self.exclude_dirs.iter().any(|excluded| name == &**excluded))
+    }
+
+    /// The limits for a directory `levels` below the root.
+    pub fn below(&self, levels: usize) -> Recursion {
+        Recursion {
+            depth: self.depth.map(|depth| depth.saturating_sub(levels)),
+            exclude_dirs: self.exclude_dirs.clone(),
+        }
+    }
+
+    /// `root` followed by every directory within the limits: the directories to watch
+    /// non-recursively so that events `depth` levels down are still reported.
+    pub fn directories(&self, root: &Path) -> impl Iterator<Item = PathBuf> {
+        self.walk(root, self.depth.map(|depth| depth.saturating_sub(1)))
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry.depth() == 0 || entry.file_type().is_some_and(|kind| kind.is_dir())
+            })
+            .map(|entry| entry.into_path())
+    }
+
+    fn walk(&self, root: &Path, max_depth: Option<usize>) -> Walk {
+        self.walker(root, max_depth).build()
+    }
+
+    /// A walk of `root` within the limits, on several threads at once.
+    pub fn walk_parallel(&self, root: &Path) -> WalkParallel {
+        self.walker(root, self.depth).build_parallel()
+    }
+
+    fn walker(&self, root: &Path, max_depth: Option<usize>) -> WalkBuilder {
+        let limits = self.clone();
+        let mut builder = WalkBuilder::new(root);
+        builder
+            .standard_filters(false)
+            .max_depth(max_depth)
+            .filter_entry(move |entry| entry.depth() == 0 || !limits.is_excluded(entry.path()));
+        builder
+    }
+}
+
+/// `root` and every entry below it within `recursion`, or just its children if it isn't
+/// recursive.
+pub fn entries(
+    root: &Path,
+    recursive: bool,
+    recursion: &Recursion,
+) -> impl Iterator<Item = PathBuf> {
+    let max_depth = if recursive { recursion.depth } else { Some(1) };
+    recursion
+        .walk(root, max_depth)
+        .filter_map(Result::ok)
+        .map(|entry| entry.into_path())
+}
+
+/// Visit the [`entries`] of `root` on a background thread, until `visit` returns false.
+pub fn spawn_walk(
+    root: PathBuf,
+    recursive: bool,
+    recursion: Recursion,
+    mut visit: impl FnMut(PathBuf) -> bool + Send + 'static,
+) {
+    thread::spawn(move || {
+        for path in entries(&root, recursive, &recursion) {
+            if !visit(path) {
+                return;
+            }
+        }
+    });
+}
+
+/// Walk `root` on a background thread, sending an event for every existing entry below it
+/// within `recursion` (just its children if it isn't recursive, or `root` itself if it is
+/// a file) and then a ready marker.
+///
+/// The scan goes through the watcher's sink, so entries are filtered and buffered like
+/// any other event and are interleaved with changes the backend reports meanwhile. It
+/// stops early if the watcher is stopped.
+pub fn spawn_initial_scan(root: PathBuf, recursive: bool, recursion: Recursion, sink: EventSink) {
+    thread::spawn(move || {
+        let max_depth = if recursive { recursion.depth } else { Some(1) };
+
+        for entry in recursion.walk(&root, max_depth).filter_map(Result::ok) {
+            if sink.is_closed() {
+                return;
+            }
+            // The root is only an entry in its own right when it is a file
+            if entry.depth() == 0 && entry.file_type().is_some_and(|kind| kind.is_dir()) {
+                continue;
+            }
+
+            let event = Event::new(EventKind::Create(CreateKind::Any))
+                .add_path(entry.into_path())
+                .set_info(DISCOVERED);
+            sink.send(Ok(event), Timestamp::now());
+        }
+
+        let ready = Event::new(EventKind::Other).set_info(READY);
+        sink.send(Ok(ready), Timestamp::now());
+    });
+}
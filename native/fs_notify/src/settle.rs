@@ -0,0 +1,108 @@
+use crate::buffer::EventBuffer;
+use crate::error::lock_recovered;
+use crate::event::Timestamp;
+use crate::memory;
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// `Event::info` of the event sent once a path has stopped changing, reported as `:settled`.
+pub const SETTLED: &str = "fs_notify:settled";
+
+/// Tracks when each file was last written to, and reports it as settled once it has gone
+/// `window` without changing, e.g. so a large upload is only processed once it is complete.
+/// A file that is removed or moved away before then is never reported.
+pub struct Settler {
+    window: Duration,
+    active: Mutex<HashMap<PathBuf, Instant>>,
+}
+
+impl Settler {
+    /// Start reporting settled files to `buffer`, until it is closed.
+    pub fn spawn(window: Duration, buffer: Arc<EventBuffer>) -> Arc<Settler> {
+        let settler = Arc::new(Settler {
+            window,
+            active: Mutex::new(HashMap::new()),
+        });
+
+        let tracked = settler.clone();
+        thread::spawn(move || {
+            while !buffer.is_closed() {
+                let next = tracked.settle(&buffer);
+                thread::sleep(next);
+            }
+        });
+
+        settler
+    }
+
+    /// Note the writes, removals and renames in `event`.
+    pub fn track(&self, event: &Event) {
+        let mut active = lock_recovered(&self.active);
+        let now = Instant::now();
+        let touch = |active: &mut HashMap<PathBuf, Instant>, path: &Path| {
+            if !path.is_dir() {
+                active.insert(path.to_path_buf(), now);
+            }
+        };
+
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let [from, to] = &event.paths[..] {
+                    active.remove(from);
+                    touch(&mut active, to);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+                for path in &event.paths {
+                    active.remove(path);
+                }
+            }
+            EventKind::Create(_)
+            | EventKind::Modify(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                for path in &event.paths {
+                    touch(&mut active, path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Bytes taken by the files still changing, for `memory_info`.
+    pub fn memory(&self) -> usize {
+        memory::path_map(&lock_recovered(&self.active))
+    }
+
+    /// Give back the room the files still changing no longer need, for `compact`.
+    pub fn compact(&self) {
+        lock_recovered(&self.active).shrink_to_fit();
+    }
+
+    /// Report every file that has settled, returning how long until the next one might.
+    fn settle(&self, buffer: &EventBuffer) -> Duration {
+        let mut active = lock_recovered(&self.active);
+        let now = Instant::now();
+
+        let mut next = self.window;
+        active.retain(|path, last| {
+            let settled_at = *last + self.window;
+            if settled_at > now {
+                next = next.min(settled_at - now);
+                return true;
+            }
+
+            let settled = Event::new(EventKind::Other)
+                .add_path(path.clone())
+                .set_info(SETTLED);
+            buffer.push((Ok(settled), Timestamp::now()));
+            false
+        });
+
+        next
+    }
+}
@@ -0,0 +1,398 @@
+use crate::attrib::AttribTracker;
+use crate::buffer::EventBuffer;
+use crate::error::{lock_recovered, read_recovered};
+use crate::event::Timestamp;
+use crate::filter::SharedFilter;
+use crate::hash::ContentHasher;
+use crate::journal::Recorder;
+use crate::longpath;
+use crate::scan::Recursion;
+use crate::settle::Settler;
+use crate::size::SizeTracker;
+use crate::{PendingRoots, Roots};
+use notify::event::{ModifyKind, RemoveKind};
+use notify::{Event, EventHandler, EventKind};
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+/// `Event::info` of the event standing in for a watched root that was removed or moved
+/// away, reported as `{:root_removed, path}`.
+pub const ROOT_REMOVED: &str = "fs_notify:root_removed";
+/// `Event::info` of the event sent once a root that didn't exist yet is created and
+/// watched, reported as `{:root_created, path}`.
+pub const ROOT_CREATED: &str = "fs_notify:root_created";
+/// Start of the `Event::info` of the notice that a watcher moved on to another backend,
+/// followed by its name, e.g. `fs_notify:backend_fallback:poll`; reported as
+/// `{:backend_fallback, backend}`.
+pub const BACKEND_FALLBACK: &str = "fs_notify:backend_fallback:";
+
+/// Event handler handed to the notify backend or debouncer. Events are filtered here, on
+/// the backend's thread, so anything dropped never reaches the buffer.
+#[derive(Clone)]
+pub struct EventSink {
+    buffer: Arc<EventBuffer>,
+    filter: SharedFilter,
+    dirs: Option<DirWatch>,
+    links: Option<LinkWatch>,
+    root_watch: Option<RootWatch>,
+    pending: Option<PendingWatch>,
+    settler: Option<Arc<Settler>>,
+    attribs: Option<Arc<AttribTracker>>,
+    hasher: Option<Arc<ContentHasher>>,
+    sizes: Option<Arc<SizeTracker>>,
+    recorder: Option<Recorder>,
+    failover: Option<Failover>,
+}
+
+impl EventSink {
+    pub fn new(buffer: Arc<EventBuffer>, filter: SharedFilter) -> Self {
+        EventSink {
+            buffer,
+            filter,
+            dirs: None,
+            links: None,
+            root_watch: None,
+            pending: None,
+            settler: None,
+            attribs: None,
+            hasher: None,
+            sizes: None,
+            recorder: None,
+            failover: None,
+        }
+    }
+
+    /// Report directories created within a root watched one directory at a time, so they
+    /// can be watched too.
+    pub fn with_dirs(self, dirs: Option<DirWatch>) -> Self {
+        EventSink { dirs, ..self }
+    }
+
+    /// Keep only the events about symlinked roots that come from watching their parents.
+    pub fn with_links(self, links: Option<LinkWatch>) -> Self {
+        EventSink { links, ..self }
+    }
+
+    /// Report watched roots going away.
+    pub fn with_root_watch(self, root_watch: RootWatch) -> Self {
+        EventSink {
+            root_watch: Some(root_watch),
+            ..self
+        }
+    }
+
+    /// Follow roots that don't exist yet through the events of their watched ancestors.
+    pub fn with_pending(self, pending: Option<PendingWatch>) -> Self {
+        EventSink { pending, ..self }
+    }
+
+    /// Track writes to report files once they stop changing.
+    pub fn with_settler(self, settler: Option<Arc<Settler>>) -> Self {
+        EventSink { settler, ..self }
+    }
+
+    /// Track attributes to classify metadata changes the backend doesn't describe.
+    pub fn with_attribs(self, attribs: Option<Arc<AttribTracker>>) -> Self {
+        EventSink { attribs, ..self }
+    }
+
+    /// Track sizes to classify writes as appends, truncations or rewrites.
+    pub fn with_sizes(self, sizes: Option<Arc<SizeTracker>>) -> Self {
+        EventSink { sizes, ..self }
+    }
+
+    /// Hash the contents of changed files before their events are buffered.
+    pub fn with_hasher(self, hasher: Option<Arc<ContentHasher>>) -> Self {
+        EventSink { hasher, ..self }
+    }
+
+    /// Write events to a journal, as the backend reported them, whenever one is set.
+    pub fn with_recorder(self, recorder: Recorder) -> Self {
+        EventSink {
+            recorder: Some(recorder),
+            ..self
+        }
+    }
+
+    /// Report the backend failing, so the watcher can move on to its next one.
+    pub fn with_failover(self, failover: Option<Failover>) -> Self {
+        EventSink { failover, ..self }
+    }
+
+    pub fn send(&self, mut event: Result<Event, notify::Error>, time: Timestamp) {
+        // An error about no path in particular means the backend itself is in trouble
+        if let (Err(err), Some(failover)) = (&event, &self.failover) {
+            if err.paths.is_empty() {
+                let _ = failover.failed.send(failover.remaining);
+            }
+        }
+
+        if let Some(recorder) = &self.recorder {
+            let mut journal = lock_recovered(recorder);
+            if let Some(recording) = journal.as_mut() {
+                // A journal that can't be written to is given up on, not the watcher
+                if recording.record(&event, time).is_err() {
+                    journal.take();
+                }
+            }
+        }
+
+        // Roots watched in extended-length form report paths in it too
+        if let Ok(event) = &mut event {
+            event.paths.iter_mut().for_each(longpath::strip_extended);
+        }
+
+        if let (Ok(event), Some(pending)) = (&mut event, &self.pending) {
+            if !pending.retain(event) {
+                return;
+            }
+        }
+
+        // Not subject to filtering: whatever the patterns, nothing more will be reported
+        if let (Ok(event), Some(root_watch)) = (&event, &self.root_watch) {
+            if let Some(root) = root_watch.removed_root(event) {
+                let removed = Event::new(EventKind::Remove(RemoveKind::Any))
+                    .add_path(root)
+                    .set_info(ROOT_REMOVED);
+                self.buffer.push((Ok(removed), time));
+                return;
+            }
+        }
+
+        if let (Ok(event), Some(links)) = (&mut event, &self.links) {
+            if !links.retain(event) {
+                return;
+            }
+        }
+
+        // Before filtering: a directory whose own events are filtered out may still hold
+        // files that aren't
+        if let (Ok(event), Some(dirs)) = (&event, &self.dirs) {
+            dirs.arm(event);
+        }
+
+        if let (Ok(event), Some(filter)) = (&mut event, &*read_recovered(&self.filter)) {
+            if !filter.apply(event) {
+                return;
+            }
+        }
+
+        if let (Ok(event), Some(attribs)) = (&mut event, &self.attribs) {
+            attribs.track(event);
+        }
+
+        if let (Ok(event), Some(sizes)) = (&mut event, &self.sizes) {
+            sizes.track(event);
+        }
+
+        if let (Ok(event), Some(settler)) = (&event, &self.settler) {
+            settler.track(event);
+        }
+
+        match &self.hasher {
+            Some(hasher) => hasher.submit((event, time)),
+            None => self.buffer.push((event, time)),
+        }
+    }
+
+    /// Whether the watcher this sink feeds has been stopped.
+    pub fn is_closed(&self) -> bool {
+        self.buffer.is_closed()
+    }
+}
+
+/// Where a backend with fallbacks reports failing, along with how many fallbacks the
+/// watcher had left when it was started. That tells a failure of the backend in use from a
+/// late one of a backend already replaced, which had more left.
+#[derive(Clone)]
+pub struct Failover {
+    failed: Sender<usize>,
+    remaining: usize,
+}
+
+impl Failover {
+    pub fn new(failed: Sender<usize>, remaining: usize) -> Self {
+        Failover { failed, remaining }
+    }
+}
+
+/// Roots with recursion limits are watched one directory at a time, so a directory created
+/// inside them isn't watched until it is sent here, along with how many levels below its
+/// root it is.
+#[derive(Clone)]
+pub struct DirWatch {
+    roots: Roots,
+    recursion: Recursion,
+    new_dirs: Sender<(PathBuf, usize)>,
+}
+
+impl DirWatch {
+    pub fn new(roots: Roots, recursion: Recursion, new_dirs: Sender<(PathBuf, usize)>) -> Self {
+        DirWatch {
+            roots,
+            recursion,
+            new_dirs,
+        }
+    }
+
+    fn arm(&self, event: &Event) {
+        if !matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+        ) {
+            return;
+        }
+
+        let roots = read_recovered(&self.roots);
+        for path in event.paths.iter().filter(|path| path.is_dir()) {
+            if self.recursion.is_excluded(path) {
+                continue;
+            }
+
+            let level = roots
+                .iter()
+                .filter(|(_, recursive)| *recursive)
+                .filter_map(|(root, _)| path.strip_prefix(root).ok())
+                .map(|relative| relative.components().count())
+                .min();
+
+            let within = |level: &usize| self.recursion.depth.is_none_or(|depth| *level < depth);
+            if let Some(level) = level.filter(within) {
+                // The receiver only goes away along with the watcher
+                let _ = self.new_dirs.send((path.clone(), level));
+            }
+        }
+    }
+}
+
+/// With `watch_link`, the parent of a symlinked root is watched too, so the link being
+/// replaced or retargeted is reported. Events about its siblings are dropped here, and a
+/// root that is replaced is sent to be watched again, following the link to its new target.
+#[derive(Clone)]
+pub struct LinkWatch {
+    roots: Roots,
+    relinked: Sender<PathBuf>,
+}
+
+impl LinkWatch {
+    pub fn new(roots: Roots, relinked: Sender<PathBuf>) -> Self {
+        LinkWatch { roots, relinked }
+    }
+
+    /// Drop the paths outside every root, returning whether any are left.
+    fn retain(&self, event: &mut Event) -> bool {
+        if event.paths.is_empty() {
+            return true;
+        }
+
+        let roots = read_recovered(&self.roots);
+        event
+            .paths
+            .retain(|path| roots.iter().any(|(root, _)| path.starts_with(root)));
+
+        let replaced = matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+        );
+        for path in &event.paths {
+            if replaced && roots.iter().any(|(root, _)| root == path) {
+                // The receiver only goes away along with the watcher
+                let _ = self.relinked.send(path.clone());
+            }
+        }
+
+        !event.paths.is_empty()
+    }
+}
+
+/// Notices a watched root being removed or moved away, which leaves its watch dead. With
+/// `rearm_root` the root is sent to be watched again once it reappears.
+#[derive(Clone)]
+pub struct RootWatch {
+    roots: Roots,
+    removed: Option<Sender<PathBuf>>,
+}
+
+impl RootWatch {
+    pub fn new(roots: Roots, removed: Option<Sender<PathBuf>>) -> Self {
+        RootWatch { roots, removed }
+    }
+
+    /// The root `event` reports gone, if any, sent to be watched again if asked to.
+    fn removed_root(&self, event: &Event) -> Option<PathBuf> {
+        if !matches!(
+            event.kind,
+            EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))
+        ) {
+            return None;
+        }
+
+        let roots = read_recovered(&self.roots);
+        let root = event
+            .paths
+            .iter()
+            .find(|path| roots.iter().any(|(root, _)| root == *path) && !path.exists())?;
+
+        if let Some(removed) = &self.removed {
+            // The receiver only goes away along with the watcher
+            let _ = removed.send(root.clone());
+        }
+        Some(root.clone())
+    }
+}
+
+/// With `allow_missing`, a root that doesn't exist yet is stood in for by a watch on its
+/// closest existing ancestor. Events about the ancestor's other entries are dropped here,
+/// and a pending root is sent to be looked at again whenever it or a directory leading to
+/// it is created.
+#[derive(Clone)]
+pub struct PendingWatch {
+    roots: Roots,
+    pending: PendingRoots,
+    changed: Sender<PathBuf>,
+}
+
+impl PendingWatch {
+    pub fn new(roots: Roots, pending: PendingRoots, changed: Sender<PathBuf>) -> Self {
+        PendingWatch {
+            roots,
+            pending,
+            changed,
+        }
+    }
+
+    /// Drop the paths outside every existing root, returning whether any are left.
+    fn retain(&self, event: &mut Event) -> bool {
+        let pending = read_recovered(&self.pending);
+        if pending.is_empty() || event.paths.is_empty() {
+            return true;
+        }
+
+        if matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+        ) {
+            for (root, _) in pending.iter() {
+                if event.paths.iter().any(|path| root.starts_with(path)) {
+                    // The receiver only goes away along with the watcher
+                    let _ = self.changed.send(root.clone());
+                }
+            }
+        }
+
+        // A pending root itself is reported as `:root_created` once it is watched
+        let roots = read_recovered(&self.roots);
+        event.paths.retain(|path| {
+            roots.iter().any(|(root, _)| path.starts_with(root))
+                && !pending.iter().any(|(root, _)| root == path)
+        });
+        !event.paths.is_empty()
+    }
+}
+
+impl EventHandler for EventSink {
+    fn handle_event(&mut self, event: Result<Event, notify::Error>) {
+        self.send(event, Timestamp::now());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::error::lock_recovered;
+use crate::memory;
+use crate::scan::{self, Recursion};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// `Event::info` of a write that made a file longer, reported as `:appended`.
+pub const APPENDED: &str = "fs_notify:appended";
+/// `Event::info` of a write that made a file shorter, reported as `:truncated`.
+pub const TRUNCATED: &str = "fs_notify:truncated";
+/// `Event::info` of a write that left a file's size alone, reported as `:rewritten`.
+pub const REWRITTEN: &str = "fs_notify:rewritten";
+
+/// Remembers the size of every file seen, so a write to one can be told apart by how its
+/// size changed. A file is first seen through the walk of its root or its first event; a
+/// write before then is reported as a plain modification.
+pub struct SizeTracker {
+    sizes: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl SizeTracker {
+    pub fn new() -> Arc<SizeTracker> {
+        Arc::new(SizeTracker {
+            sizes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Record the size of every file below `root` within `recursion`, on a background
+    /// thread.
+    pub fn spawn_seed(self: &Arc<Self>, root: PathBuf, recursive: bool, recursion: Recursion) {
+        let tracker = Arc::downgrade(self);
+        scan::spawn_walk(root, recursive, recursion, move |path| {
+            // Stop once the watcher is gone
+            let Some(tracker) = tracker.upgrade() else {
+                return false;
+            };
+            if let Some(size) = file_size(&path) {
+                lock_recovered(&tracker.sizes).entry(path).or_insert(size);
+            }
+            true
+        });
+    }
+
+    /// Classify a write in `event` by how it changed the file's size, and note the sizes
+    /// of the paths of any other event.
+    pub fn track(&self, event: &mut Event) {
+        let mut sizes = lock_recovered(&self.sizes);
+
+        match event.kind {
+            EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any) => {
+                let [path] = &event.paths[..] else {
+                    return;
+                };
+                let Some(size) = file_size(path) else {
+                    return;
+                };
+                let Some(previous) = sizes.insert(path.clone(), size) else {
+                    return;
+                };
+
+                let info = match size.cmp(&previous) {
+                    Ordering::Greater => APPENDED,
+                    Ordering::Less => TRUNCATED,
+                    Ordering::Equal => REWRITTEN,
+                };
+                event.attrs.set_info(info);
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let [from, to] = &event.paths[..] {
+                    sizes.remove(from);
+                    note(&mut sizes, to);
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+                for path in &event.paths {
+                    sizes.remove(path);
+                }
+            }
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_)) => {
+                for path in &event.paths {
+                    note(&mut sizes, path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Bytes taken by the sizes known, for `memory_info`.
+    pub fn memory(&self) -> usize {
+        memory::path_map(&lock_recovered(&self.sizes))
+    }
+
+    /// Give back the room the sizes known no longer need, for `compact`.
+    pub fn compact(&self) {
+        lock_recovered(&self.sizes).shrink_to_fit();
+    }
+}
+
+/// Note the current size of the file `path`, or forget it if it's gone.
+fn note(sizes: &mut HashMap<PathBuf, u64>, path: &Path) {
+    match file_size(path) {
+        Some(size) => sizes.insert(path.to_path_buf(), size),
+        None => sizes.remove(path),
+    };
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    path.metadata()
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+}
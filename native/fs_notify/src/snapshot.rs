@@ -0,0 +1,87 @@
+use crate::event;
+use crate::scan::{self, Recursion};
+use rustler::NifMap;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// What is compared of each entry.
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+    file_id: Option<u64>,
+}
+
+/// The entries below a root at one point in time, to be compared with a later snapshot.
+pub struct Snapshot {
+    entries: HashMap<PathBuf, Entry>,
+}
+
+/// Paths that appeared, changed and disappeared between two snapshots, each sorted.
+#[derive(NifMap)]
+pub struct SnapshotDiff {
+    created: Vec<String>,
+    modified: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl Snapshot {
+    /// Record every entry below `root` within `recursion` (or `root` itself if it is a
+    /// file). Entries that vanish while being walked are left out.
+    pub fn take(root: &Path, recursive: bool, recursion: &Recursion) -> Snapshot {
+        let root_is_dir = root.is_dir();
+        let entries = scan::entries(root, recursive, recursion)
+            .filter(|path| !(root_is_dir && path == root))
+            .filter_map(|path| {
+                let metadata = fs::symlink_metadata(&path).ok()?;
+                let entry = Entry {
+                    is_dir: metadata.is_dir(),
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                    file_id: event::file_id(&path, &metadata),
+                };
+                Some((path, entry))
+            })
+            .collect();
+
+        Snapshot { entries }
+    }
+
+    /// What changed from `self` to the later `other`. Directories are only reported as
+    /// created or removed, since their mtime changes with their contents; an entry replaced
+    /// by one of another type is reported as modified.
+    pub fn diff(&self, other: &Snapshot) -> SnapshotDiff {
+        let mut created = Vec::new();
+        let mut modified = Vec::new();
+        for (path, entry) in &other.entries {
+            match self.entries.get(path) {
+                None => created.push(path),
+                Some(old) if old.is_dir && entry.is_dir => {}
+                Some(old) if old != entry => modified.push(path),
+                Some(_) => {}
+            }
+        }
+        let removed = self
+            .entries
+            .keys()
+            .filter(|path| !other.entries.contains_key(*path))
+            .collect();
+
+        SnapshotDiff {
+            created: sorted(created),
+            modified: sorted(modified),
+            removed: sorted(removed),
+        }
+    }
+}
+
+fn sorted(mut paths: Vec<&PathBuf>) -> Vec<String> {
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect()
+}
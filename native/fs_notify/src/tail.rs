@@ -0,0 +1,106 @@
+use crate::error::lock_recovered;
+use crate::event;
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::EventKind;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Most bytes delivered in a single `:appended` event; larger appends are split.
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// Files followed with `tail`, shared between the watcher and its translator so roots can
+/// be added and removed while events are translated elsewhere.
+pub type Tails = Arc<Mutex<HashMap<PathBuf, Tail>>>;
+
+/// How much of a followed file has been delivered, and which file that was.
+#[derive(Debug, Clone, Copy)]
+pub struct Tail {
+    offset: u64,
+    file_id: Option<u64>,
+}
+
+/// What reading a followed file found.
+pub enum TailRead {
+    /// The file was replaced or truncated, so it is read again from the start
+    Rotated,
+    /// Bytes appended since the last read
+    Appended(Vec<u8>),
+}
+
+/// Start following `path` from its current end if it is a file, or from its start once
+/// created if it doesn't exist yet.
+pub fn follow(tails: &Tails, path: &Path) {
+    let tail = match path.metadata() {
+        Ok(metadata) if metadata.is_file() => Tail {
+            offset: metadata.len(),
+            file_id: event::file_id(path, &metadata),
+        },
+        Ok(_) => return,
+        Err(_) => Tail {
+            offset: 0,
+            file_id: None,
+        },
+    };
+    lock_recovered(tails).insert(path.to_path_buf(), tail);
+}
+
+pub fn unfollow(tails: &Tails, path: &Path) {
+    lock_recovered(tails).remove(path);
+}
+
+/// Whether an event of `kind` may have added data to a file.
+pub fn may_append(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(_)
+            | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any)
+            | EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Both))
+            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+    )
+}
+
+/// Read whatever was appended to the followed file `path` since the last read. A file
+/// with another id, or shorter than what was already read, has been rotated; one that
+/// didn't exist when it was first followed hasn't.
+pub fn read(tails: &Tails, path: &Path) -> Vec<TailRead> {
+    let mut tails = lock_recovered(tails);
+    let Some(tail) = tails.get_mut(path) else {
+        return Vec::new();
+    };
+    let Ok(mut file) = File::open(path) else {
+        return Vec::new();
+    };
+    let Ok(metadata) = file.metadata() else {
+        return Vec::new();
+    };
+
+    let mut reads = Vec::new();
+    let file_id = event::file_id(path, &metadata);
+    if tail.file_id.is_none() {
+        tail.file_id = file_id;
+    } else if file_id != tail.file_id || metadata.len() < tail.offset {
+        *tail = Tail { offset: 0, file_id };
+        reads.push(TailRead::Rotated);
+    }
+
+    if file.seek(SeekFrom::Start(tail.offset)).is_err() {
+        return reads;
+    }
+    // Only up to the size just seen, so a writer still appending isn't chased forever
+    let mut appended = Vec::new();
+    let length = metadata.len() - tail.offset;
+    if file.take(length).read_to_end(&mut appended).is_err() {
+        return reads;
+    }
+    tail.offset += appended.len() as u64;
+
+    reads.extend(
+        appended
+            .chunks(MAX_CHUNK)
+            .map(|chunk| TailRead::Appended(chunk.to_vec())),
+    );
+    reads
+}
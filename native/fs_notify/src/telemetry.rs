@@ -0,0 +1,25 @@
+//! Counters across every watcher, kept as atomics so `telemetry_snapshot` can be sampled
+//! often, e.g. by a `:telemetry_poller` measurement, without waiting on any watcher.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Events pushed by every backend
+pub static EVENTS_RECEIVED: AtomicU64 = AtomicU64::new(0);
+/// Events discarded because a watcher's buffer was full
+pub static EVENTS_DROPPED: AtomicU64 = AtomicU64::new(0);
+/// Errors reported by every backend
+pub static ERRORS: AtomicU64 = AtomicU64::new(0);
+/// Watchers started and not yet stopped
+pub static WATCHERS_ACTIVE: AtomicU64 = AtomicU64::new(0);
+
+pub fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn decrement(counter: &AtomicU64) {
+    counter.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn read(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
@@ -0,0 +1,153 @@
+//! Throttling: at most one event per path per interval. Unlike debouncing, which waits for
+//! a path to go quiet, the first change is reported at once and a path that keeps changing
+//! is still reported once every interval, with the latest change held back until then.
+
+use crate::error::lock_recovered;
+use crate::event::Timestamp;
+use crate::sink::EventSink;
+use notify::{Event, EventHandler};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// An interval a path was reported in, and the latest change held back since.
+struct Window {
+    started: Instant,
+    held: Option<(Event, Timestamp)>,
+}
+
+struct Windows {
+    interval: Duration,
+    /// By the paths of the events, so the two sides of a rename count as their own path
+    windows: HashMap<Vec<PathBuf>, Window>,
+    last_pruned: Instant,
+}
+
+impl Windows {
+    /// When the earliest held back change is due.
+    fn next_due(&self) -> Option<Instant> {
+        self.windows
+            .values()
+            .filter(|window| window.held.is_some())
+            .map(|window| window.started + self.interval)
+            .min()
+    }
+
+    /// Forget the paths that went a whole interval without changing, at most once an
+    /// interval.
+    fn prune(&mut self, now: Instant) {
+        let interval = self.interval;
+        if now.duration_since(self.last_pruned) < interval {
+            return;
+        }
+        self.windows.retain(|_, window| {
+            window.held.is_some() || now.duration_since(window.started) < interval
+        });
+        self.last_pruned = now;
+    }
+
+    /// Take the held back changes whose interval is over, starting the next interval for
+    /// each, and forget the paths that went a whole interval without changing.
+    fn take_due(&mut self, now: Instant) -> Vec<(Event, Timestamp)> {
+        let interval = self.interval;
+        let mut due = Vec::new();
+        self.windows.retain(|_, window| {
+            if now.duration_since(window.started) < interval {
+                return true;
+            }
+            match window.held.take() {
+                Some(held) => {
+                    due.push(held);
+                    window.started = now;
+                    true
+                }
+                None => false,
+            }
+        });
+        due.sort_by_key(|(_, time)| time.monotonic);
+        due
+    }
+}
+
+pub struct Throttle {
+    windows: Arc<Mutex<Windows>>,
+    sink: EventSink,
+    /// Wakes the thread reporting held back changes when one is held back
+    held: Sender<()>,
+}
+
+impl Throttle {
+    pub fn new(interval: Duration, sink: EventSink) -> Self {
+        let windows = Arc::new(Mutex::new(Windows {
+            interval,
+            windows: HashMap::new(),
+            last_pruned: Instant::now(),
+        }));
+        let (held, wakes) = mpsc::channel();
+
+        thread::spawn({
+            let windows = windows.clone();
+            let sink = sink.clone();
+            move || loop {
+                let next_due = lock_recovered(&windows).next_due();
+                let woken = match next_due {
+                    Some(due) => wakes.recv_timeout(due.saturating_duration_since(Instant::now())),
+                    None => wakes.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                // Gone with the backend
+                if woken == Err(RecvTimeoutError::Disconnected) {
+                    return;
+                }
+                let due = lock_recovered(&windows).take_due(Instant::now());
+                for (event, time) in due {
+                    sink.send(Ok(event), time);
+                }
+            }
+        });
+
+        Throttle {
+            windows,
+            sink,
+            held,
+        }
+    }
+}
+
+impl EventHandler for Throttle {
+    fn handle_event(&mut self, event: notify::Result<Event>) {
+        let time = Timestamp::now();
+        // Errors, and events about no path in particular, e.g. markers, aren't held back
+        let event = match event {
+            Ok(event) if !event.paths.is_empty() => event,
+            event => {
+                self.sink.send(event, time);
+                return;
+            }
+        };
+
+        let now = Instant::now();
+        let mut windows = lock_recovered(&self.windows);
+        let interval = windows.interval;
+        match windows.windows.get_mut(&event.paths) {
+            Some(window) if now.duration_since(window.started) < interval => {
+                if window.held.replace((event, time)).is_none() {
+                    let _ = self.held.send(());
+                }
+            }
+            // A change still held back when the interval is over is superseded by this one
+            _ => {
+                let window = Window {
+                    started: now,
+                    held: None,
+                };
+                windows.prune(now);
+                windows.windows.insert(event.paths.clone(), window);
+                drop(windows);
+                self.sink.send(Ok(event), time);
+            }
+        }
+    }
+}
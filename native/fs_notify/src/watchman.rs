@@ -0,0 +1,308 @@
+//! Watchman backend: subscribes to a running Watchman daemon through its socket, using the
+//! JSON protocol, and translates the files each subscription reports into notify events.
+//!
+//! Watchman recrawls a tree by itself when the kernel drops its events, and a subscription
+//! that has to start over because of that (or because the daemon restarted) is reported as
+//! a rescan of the watched path.
+
+use crate::error::lock_recovered;
+use notify::event::{CreateKind, EventKind, Flag, ModifyKind, RemoveKind};
+use notify::{Config, Error, Event, EventHandler, RecursiveMode, Result, Watcher, WatcherKind};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long the daemon has to answer a command, which may include crawling a new tree.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+static NEXT_SUBSCRIPTION: AtomicU64 = AtomicU64::new(1);
+
+/// A watched path, as subscribed to.
+struct Subscription {
+    /// Root of the Watchman watch the subscription is on, usually the project's
+    watch_root: String,
+    /// The path being watched
+    path: PathBuf,
+    /// Directory the names reported are relative to
+    dir: PathBuf,
+    recursive: bool,
+    /// Whether the first update, which Watchman sends once it has crawled the tree, came
+    started: bool,
+}
+
+/// Subscriptions by name.
+type Subscriptions = Arc<Mutex<HashMap<String, Subscription>>>;
+
+pub struct WatchmanWatcher {
+    stream: UnixStream,
+    /// Replies to the commands sent, in order
+    replies: mpsc::Receiver<Value>,
+    subscriptions: Subscriptions,
+    /// Subscription name of each watched path, as given
+    names: HashMap<PathBuf, String>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Where the daemon listens: `$WATCHMAN_SOCK`, or what `watchman get-sockname` says, which
+/// starts the daemon if it isn't running.
+fn sockname() -> io::Result<PathBuf> {
+    if let Some(sock) = env::var_os("WATCHMAN_SOCK") {
+        return Ok(sock.into());
+    }
+    let output = Command::new("watchman")
+        .args(["--output-encoding=json", "--no-pretty", "get-sockname"])
+        .output()?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(message.trim().to_string()));
+    }
+    let reply: Value = serde_json::from_slice(&output.stdout)?;
+    reply
+        .get("unix_domain")
+        .or_else(|| reply.get("sockname"))
+        .and_then(Value::as_str)
+        .map(PathBuf::from)
+        .ok_or_else(|| io::Error::other("watchman get-sockname named no socket"))
+}
+
+fn connect() -> io::Result<UnixStream> {
+    UnixStream::connect(sockname()?)
+}
+
+/// Whether a Watchman daemon can be reached.
+pub fn available() -> bool {
+    connect().is_ok()
+}
+
+impl WatchmanWatcher {
+    pub fn create(handler: impl EventHandler) -> Result<Self> {
+        let stream = connect().map_err(Error::io)?;
+        let reader = stream.try_clone().map_err(Error::io)?;
+        let (reply_tx, replies) = mpsc::channel();
+        let subscriptions = Subscriptions::default();
+        let thread = thread::Builder::new()
+            .name("fs_notify watchman".to_string())
+            .spawn({
+                let subscriptions = subscriptions.clone();
+                move || read_pdus(reader, &subscriptions, &reply_tx, handler)
+            })
+            .map_err(Error::io)?;
+
+        Ok(WatchmanWatcher {
+            stream,
+            replies,
+            subscriptions,
+            names: HashMap::new(),
+            thread: Some(thread),
+        })
+    }
+
+    /// Send `command` and wait for its reply, turning one that reports an error into one.
+    fn command(&mut self, command: Value) -> Result<Value> {
+        let mut line = command.to_string().into_bytes();
+        line.push(b'\n');
+        self.stream.write_all(&line).map_err(Error::io)?;
+        let reply = self
+            .replies
+            .recv_timeout(TIMEOUT)
+            .map_err(|_| Error::generic("Watchman didn't answer"))?;
+        if let Some(error) = reply.get("error").and_then(Value::as_str) {
+            return Err(Error::generic(&format!("Watchman: {error}")));
+        }
+        Ok(reply)
+    }
+
+    fn subscribe(&mut self, path: &Path, recursive: bool) -> Result<String> {
+        let target = path.canonicalize().map_err(Error::io)?;
+        // A file is watched through its directory, narrowed down to its name
+        let (dir, file) = if target.is_dir() {
+            (target.clone(), None)
+        } else {
+            match (target.parent(), target.file_name()) {
+                (Some(dir), Some(name)) => (dir.to_path_buf(), Some(name.to_os_string())),
+                _ => return Err(Error::path_not_found()),
+            }
+        };
+        let dir_str = dir
+            .to_str()
+            .ok_or_else(|| Error::generic("Watchman only takes UTF-8 paths"))?;
+
+        let project = self.command(json!(["watch-project", dir_str]))?;
+        let watch_root = project
+            .get("watch")
+            .and_then(Value::as_str)
+            .ok_or_else(|| Error::generic("Watchman named no watch root"))?
+            .to_string();
+        let mut query = json!({
+            "fields": ["name", "exists", "new", "type"],
+            "empty_on_fresh_instance": true,
+        });
+        if let Some(relative) = project.get("relative_path").and_then(Value::as_str) {
+            query["relative_root"] = relative.into();
+        }
+        if let Some(name) = file.as_ref().and_then(|name| name.to_str()) {
+            query["expression"] = json!(["name", name, "wholename"]);
+        }
+
+        let name = format!(
+            "fs_notify-{}",
+            NEXT_SUBSCRIPTION.fetch_add(1, Ordering::Relaxed)
+        );
+        // Updates may arrive before the reply, so they need to find it already
+        let subscription = Subscription {
+            watch_root: watch_root.clone(),
+            path: target,
+            dir,
+            recursive: recursive || file.is_some(),
+            started: false,
+        };
+        lock_recovered(&self.subscriptions).insert(name.clone(), subscription);
+        if let Err(err) = self.command(json!(["subscribe", watch_root, name, query])) {
+            lock_recovered(&self.subscriptions).remove(&name);
+            return Err(err);
+        }
+        Ok(name)
+    }
+
+    fn unsubscribe(&mut self, name: &str) -> Result<()> {
+        let Some(subscription) = lock_recovered(&self.subscriptions).remove(name) else {
+            return Ok(());
+        };
+        self.command(json!(["unsubscribe", subscription.watch_root, name]))?;
+        Ok(())
+    }
+}
+
+impl Watcher for WatchmanWatcher {
+    fn new<F: EventHandler>(handler: F, _config: Config) -> Result<Self> {
+        WatchmanWatcher::create(handler)
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        let recursive = recursive_mode == RecursiveMode::Recursive;
+        let name = self
+            .subscribe(path, recursive)
+            .map_err(|err| err.add_path(path.to_path_buf()))?;
+        if let Some(previous) = self.names.insert(path.to_path_buf(), name) {
+            self.unsubscribe(&previous)?;
+        }
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        let name = self
+            .names
+            .remove(path)
+            .ok_or_else(|| Error::watch_not_found().add_path(path.to_path_buf()))?;
+        self.unsubscribe(&name)
+    }
+
+    fn kind() -> WatcherKind {
+        // notify has no kind for Watchman; this one is never reported
+        WatcherKind::NullWatcher
+    }
+}
+
+impl Drop for WatchmanWatcher {
+    fn drop(&mut self) {
+        // The daemon drops the subscriptions along with the connection; with none left, the
+        // reading thread doesn't take the connection closing for an error
+        lock_recovered(&self.subscriptions).clear();
+        let _ = self.stream.shutdown(Shutdown::Both);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Read what the daemon sends until the connection closes, reporting subscription updates
+/// and passing replies on to `replies`.
+fn read_pdus(
+    stream: UnixStream,
+    subscriptions: &Subscriptions,
+    replies: &mpsc::Sender<Value>,
+    mut handler: impl EventHandler,
+) {
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let Ok(pdu) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        // Only replies lack both; `unilateral` is missing from subscription updates of older
+        // daemons
+        if pdu.get("unilateral").is_none() && pdu.get("subscription").is_none() {
+            let _ = replies.send(pdu);
+            continue;
+        }
+        let Some(name) = pdu.get("subscription").and_then(Value::as_str) else {
+            continue;
+        };
+        let events = match lock_recovered(subscriptions).get_mut(name) {
+            Some(subscription) => translate(&pdu, subscription),
+            None => continue,
+        };
+        for event in events {
+            handler.handle_event(Ok(event));
+        }
+    }
+
+    if !lock_recovered(subscriptions).is_empty() {
+        handler.handle_event(Err(Error::generic("connection to Watchman closed")));
+    }
+}
+
+/// The events a subscription update describes.
+fn translate(pdu: &Value, subscription: &mut Subscription) -> Vec<Event> {
+    let started = mem::replace(&mut subscription.started, true);
+    if pdu.get("is_fresh_instance").and_then(Value::as_bool) == Some(true) {
+        // The first update always is (and empty, as asked); a later one means Watchman
+        // lost track of what changed
+        if !started {
+            return Vec::new();
+        }
+        let rescan = Event::new(EventKind::Other)
+            .set_flag(Flag::Rescan)
+            .add_path(subscription.path.clone());
+        return vec![rescan];
+    }
+    // Updates that only mark a state change, e.g. `state-enter`, carry no files
+    let Some(files) = pdu.get("files").and_then(Value::as_array) else {
+        return Vec::new();
+    };
+
+    let mut events = Vec::new();
+    for file in files {
+        let Some(name) = file.get("name").and_then(Value::as_str) else {
+            continue;
+        };
+        if !subscription.recursive && name.contains('/') {
+            continue;
+        }
+        let is_dir = file.get("type").and_then(Value::as_str) == Some("d");
+        let exists = file.get("exists").and_then(Value::as_bool) != Some(false);
+        let new = file.get("new").and_then(Value::as_bool) == Some(true);
+        let kind = match (exists, new, is_dir) {
+            (false, _, true) => EventKind::Remove(RemoveKind::Folder),
+            (false, _, false) => EventKind::Remove(RemoveKind::File),
+            (true, true, true) => EventKind::Create(CreateKind::Folder),
+            (true, true, false) => EventKind::Create(CreateKind::File),
+            (true, false, _) => EventKind::Modify(ModifyKind::Any),
+        };
+        let path = subscription.dir.join(OsString::from(name));
+        events.push(Event::new(kind).add_path(path));
+    }
+    events
+}
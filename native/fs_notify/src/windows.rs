@@ -0,0 +1,457 @@
+//! ReadDirectoryChangesW backend, in place of notify's so that the size of the buffer
+//! changes are read into and the changes subscribed to can be set, and a buffer that
+//! overflowed is reported as a rescan of the watched path rather than dropped. Events are otherwise translated the way notify
+//! translates them.
+
+use crate::config::{BackendConfig, NotifyFilter};
+use crate::error::lock_recovered;
+use notify::event::{CreateKind, EventKind, Flag, ModifyKind, RemoveKind, RenameMode};
+use notify::{Config, Error, Event, EventHandler, RecursiveMode, Result, Watcher, WatcherKind};
+use std::collections::HashMap;
+use std::ffi::{c_void, OsString};
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use windows_sys::Win32::Foundation::{
+    CloseHandle, ERROR_ACCESS_DENIED, ERROR_NOTIFY_ENUM_DIR, ERROR_OPERATION_ABORTED,
+    ERROR_SUCCESS, HANDLE, INVALID_HANDLE_VALUE, WAIT_OBJECT_0,
+};
+use windows_sys::Win32::Storage::FileSystem::{
+    CreateFileW, ReadDirectoryChangesW, FILE_ACTION_ADDED, FILE_ACTION_MODIFIED,
+    FILE_ACTION_REMOVED, FILE_ACTION_RENAMED_NEW_NAME, FILE_ACTION_RENAMED_OLD_NAME,
+    FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OVERLAPPED, FILE_LIST_DIRECTORY, FILE_NOTIFY_CHANGE,
+    FILE_NOTIFY_CHANGE_ATTRIBUTES, FILE_NOTIFY_CHANGE_CREATION, FILE_NOTIFY_CHANGE_DIR_NAME,
+    FILE_NOTIFY_CHANGE_FILE_NAME, FILE_NOTIFY_CHANGE_LAST_ACCESS, FILE_NOTIFY_CHANGE_LAST_WRITE,
+    FILE_NOTIFY_CHANGE_SECURITY, FILE_NOTIFY_CHANGE_SIZE, FILE_NOTIFY_INFORMATION,
+    FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_EXISTING,
+};
+use windows_sys::Win32::System::Threading::{
+    CreateSemaphoreW, ReleaseSemaphore, WaitForSingleObjectEx, INFINITE,
+};
+use windows_sys::Win32::System::IO::{CancelIo, OVERLAPPED};
+
+type Handler = Arc<Mutex<dyn EventHandler>>;
+
+/// A watched directory, as each read of its changes needs it.
+#[derive(Clone)]
+struct ReadData {
+    dir: PathBuf,
+    /// The file watched in `dir`, if a file rather than the directory is watched
+    file: Option<PathBuf>,
+    /// Released once the last read of the directory has completed
+    complete_sem: HANDLE,
+    recursive: bool,
+    buffer_size: u32,
+    filter: FILE_NOTIFY_CHANGE,
+}
+
+/// A read in progress, owned by the system until its completion routine runs.
+struct ReadRequest {
+    handler: Handler,
+    /// `u32`s so the buffer is DWORD-aligned, as ReadDirectoryChangesW requires
+    buffer: Vec<u32>,
+    handle: HANDLE,
+    data: ReadData,
+    action_tx: Sender<Action>,
+}
+
+enum Action {
+    Watch(PathBuf, bool),
+    Unwatch(PathBuf),
+    Stop,
+}
+
+struct WatchState {
+    dir_handle: HANDLE,
+    complete_sem: HANDLE,
+}
+
+/// Owns the watched directories' handles on a thread of its own, which waits alertably so
+/// that read completion routines run on it.
+struct Server {
+    tx: Sender<Action>,
+    rx: Receiver<Action>,
+    handler: Handler,
+    ack_tx: Sender<Result<PathBuf>>,
+    watches: HashMap<PathBuf, WatchState>,
+    wakeup: Arc<Semaphore>,
+    buffer_size: u32,
+    filter: FILE_NOTIFY_CHANGE,
+}
+
+/// A semaphore, closed once dropped.
+struct Semaphore(HANDLE);
+
+// SAFETY: kernel handles may be used from any thread
+unsafe impl Send for Semaphore {}
+unsafe impl Sync for Semaphore {}
+
+impl Semaphore {
+    fn new() -> Option<Self> {
+        // SAFETY: creating a semaphore has no preconditions
+        let handle = unsafe { CreateSemaphoreW(ptr::null(), 0, 1, ptr::null()) };
+        (!handle.is_null()).then_some(Semaphore(handle))
+    }
+
+    fn release(&self) {
+        // SAFETY: the semaphore is open while borrowed
+        unsafe { ReleaseSemaphore(self.0, 1, ptr::null_mut()) };
+    }
+}
+
+impl Drop for Semaphore {
+    fn drop(&mut self) {
+        // SAFETY: the semaphore is no longer borrowed
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+impl Server {
+    fn start(
+        handler: Handler,
+        ack_tx: Sender<Result<PathBuf>>,
+        wakeup: Arc<Semaphore>,
+        buffer_size: u32,
+        filter: FILE_NOTIFY_CHANGE,
+    ) -> Result<Sender<Action>> {
+        let (tx, rx) = mpsc::channel();
+        let server_tx = tx.clone();
+        thread::Builder::new()
+            .name("fs_notify windows loop".to_string())
+            .spawn(move || {
+                Server {
+                    tx: server_tx,
+                    rx,
+                    handler,
+                    ack_tx,
+                    watches: HashMap::new(),
+                    wakeup,
+                    buffer_size,
+                    filter,
+                }
+                .run()
+            })
+            .map_err(Error::io)?;
+        Ok(tx)
+    }
+
+    fn run(mut self) {
+        loop {
+            while let Ok(action) = self.rx.try_recv() {
+                match action {
+                    Action::Watch(path, recursive) => {
+                        let result = self.add_watch(path, recursive);
+                        let _ = self.ack_tx.send(result);
+                    }
+                    Action::Unwatch(path) => self.remove_watch(&path),
+                    Action::Stop => {
+                        for watch in self.watches.values() {
+                            stop_watch(watch);
+                        }
+                        return;
+                    }
+                }
+            }
+
+            // Alertable, so that completion routines run while waiting
+            // SAFETY: the semaphore is open while the server holds it
+            unsafe { WaitForSingleObjectEx(self.wakeup.0, 100, 1) };
+        }
+    }
+
+    fn add_watch(&mut self, path: PathBuf, recursive: bool) -> Result<PathBuf> {
+        // Files are watched through their directory
+        let (dir, file) = if path.is_dir() {
+            (path.clone(), None)
+        } else if path.is_file() {
+            let dir = path.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+            (dir, Some(path.clone()))
+        } else {
+            return Err(Error::path_not_found().add_path(path));
+        };
+
+        let wide: Vec<u16> = dir.as_os_str().encode_wide().chain(Some(0)).collect();
+        // SAFETY: `wide` is a NUL-terminated path
+        let handle = unsafe {
+            CreateFileW(
+                wide.as_ptr(),
+                FILE_LIST_DIRECTORY,
+                FILE_SHARE_READ | FILE_SHARE_DELETE | FILE_SHARE_WRITE,
+                ptr::null(),
+                OPEN_EXISTING,
+                FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OVERLAPPED,
+                ptr::null_mut(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(Error::path_not_found().add_path(path));
+        }
+
+        // SAFETY: creating a semaphore has no preconditions; it's closed by `stop_watch`
+        let complete_sem = unsafe { CreateSemaphoreW(ptr::null(), 0, 1, ptr::null()) };
+        if complete_sem.is_null() {
+            // SAFETY: `handle` was opened above and isn't used elsewhere
+            unsafe { CloseHandle(handle) };
+            return Err(Error::generic("failed to create semaphore for watch").add_path(path));
+        }
+
+        let data = ReadData {
+            dir,
+            file,
+            complete_sem,
+            recursive,
+            buffer_size: self.buffer_size,
+            filter: self.filter,
+        };
+        self.watches.insert(
+            path.clone(),
+            WatchState {
+                dir_handle: handle,
+                complete_sem,
+            },
+        );
+        start_read(&data, self.handler.clone(), handle, self.tx.clone());
+        Ok(path)
+    }
+
+    fn remove_watch(&mut self, path: &Path) {
+        if let Some(watch) = self.watches.remove(path) {
+            stop_watch(&watch);
+        }
+    }
+}
+
+fn stop_watch(watch: &WatchState) {
+    // SAFETY: the handles belong to this watch, and are closed once its last read is done
+    unsafe {
+        let cancelled = CancelIo(watch.dir_handle);
+        let closed = CloseHandle(watch.dir_handle);
+        // The pending read's request is only freed once its completion routine runs
+        if cancelled != 0 && closed != 0 {
+            while WaitForSingleObjectEx(watch.complete_sem, INFINITE, 1) != WAIT_OBJECT_0 {}
+        }
+        CloseHandle(watch.complete_sem);
+    }
+}
+
+fn start_read(data: &ReadData, handler: Handler, handle: HANDLE, action_tx: Sender<Action>) {
+    let request = Box::into_raw(Box::new(ReadRequest {
+        handler,
+        buffer: vec![0; data.buffer_size as usize / 4],
+        handle,
+        data: data.clone(),
+        action_tx,
+    }));
+
+    let subtree = i32::from(data.file.is_none() && data.recursive);
+
+    // SAFETY: completion routines may use `hEvent` as they like; the request and the
+    // overlapped structure are reclaimed by `handle_event`, or below if the read never starts
+    unsafe {
+        let mut overlapped = Box::new(std::mem::zeroed::<OVERLAPPED>());
+        overlapped.hEvent = request.cast();
+        let overlapped = Box::into_raw(overlapped);
+
+        let started = ReadDirectoryChangesW(
+            handle,
+            (*request).buffer.as_mut_ptr().cast::<c_void>(),
+            data.buffer_size,
+            subtree,
+            data.filter,
+            ptr::null_mut(),
+            overlapped,
+            Some(handle_event),
+        );
+        if started == 0 {
+            drop(Box::from_raw(overlapped));
+            let request = Box::from_raw(request);
+            ReleaseSemaphore(request.data.complete_sem, 1, ptr::null_mut());
+        }
+    }
+}
+
+unsafe extern "system" fn handle_event(error: u32, bytes: u32, overlapped: *mut OVERLAPPED) {
+    // SAFETY: `overlapped` and its request were leaked by `start_read` for this routine
+    let overlapped = unsafe { Box::from_raw(overlapped) };
+    let request = unsafe { Box::from_raw(overlapped.hEvent as *mut ReadRequest) };
+    let data = &request.data;
+
+    match error {
+        // The watch was removed or the watcher stopped
+        ERROR_OPERATION_ABORTED => {
+            // SAFETY: the semaphore is open until `stop_watch` sees it released
+            unsafe { ReleaseSemaphore(data.complete_sem, 1, ptr::null_mut()) };
+            return;
+        }
+        // The buffer overflowed, either on its own or when it can't take any more
+        ERROR_NOTIFY_ENUM_DIR => {}
+        ERROR_SUCCESS => {}
+        // Reported when the watched directory is removed, but also for changes to it
+        ERROR_ACCESS_DENIED if data.dir.exists() => {}
+        _ => {
+            let _ = request.action_tx.send(Action::Unwatch(watched_path(data)));
+            // SAFETY: see above
+            unsafe { ReleaseSemaphore(data.complete_sem, 1, ptr::null_mut()) };
+            return;
+        }
+    }
+
+    // Start the next read before handling this one, so that as little as possible is missed
+    start_read(
+        data,
+        request.handler.clone(),
+        request.handle,
+        request.action_tx.clone(),
+    );
+
+    let mut handler = lock_recovered(&request.handler);
+    if error == ERROR_NOTIFY_ENUM_DIR || bytes == 0 {
+        let event = Event::new(EventKind::Other)
+            .set_flag(Flag::Rescan)
+            .add_path(watched_path(data));
+        handler.handle_event(Ok(event));
+        return;
+    }
+
+    let buffer = request.buffer.as_ptr().cast::<u8>();
+    let mut offset = 0;
+    loop {
+        // SAFETY: the system wrote `bytes` bytes of `FILE_NOTIFY_INFORMATION` entries, each
+        // giving the offset of the next; they may be only 2-byte aligned (e.g. under Wine)
+        let (entry, name) = unsafe {
+            let entry_ptr = buffer.add(offset);
+            let entry = ptr::read_unaligned(entry_ptr.cast::<FILE_NOTIFY_INFORMATION>());
+            let name_ptr = entry_ptr.add(std::mem::offset_of!(FILE_NOTIFY_INFORMATION, FileName));
+            let name = (0..entry.FileNameLength as usize / 2)
+                .map(|i| ptr::read_unaligned(name_ptr.cast::<u16>().add(i)))
+                .collect::<Vec<_>>();
+            (entry, name)
+        };
+        let path = data.dir.join(OsString::from_wide(&name));
+
+        // A watched file's directory reports changes to its other entries too
+        if data.file.as_ref().is_none_or(|file| *file == path) {
+            let kind = match entry.Action {
+                FILE_ACTION_RENAMED_OLD_NAME => {
+                    Some(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+                }
+                FILE_ACTION_RENAMED_NEW_NAME => {
+                    Some(EventKind::Modify(ModifyKind::Name(RenameMode::To)))
+                }
+                FILE_ACTION_ADDED => Some(EventKind::Create(CreateKind::Any)),
+                FILE_ACTION_REMOVED => Some(EventKind::Remove(RemoveKind::Any)),
+                FILE_ACTION_MODIFIED => Some(EventKind::Modify(ModifyKind::Any)),
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                handler.handle_event(Ok(Event::new(kind).add_path(path)));
+            }
+        }
+
+        if entry.NextEntryOffset == 0 {
+            break;
+        }
+        offset += entry.NextEntryOffset as usize;
+        if offset >= bytes as usize {
+            break;
+        }
+    }
+}
+
+/// The path a read is for, as it was watched.
+fn watched_path(data: &ReadData) -> PathBuf {
+    data.file.clone().unwrap_or_else(|| data.dir.clone())
+}
+
+pub struct ReadDirectoryChangesWatcher {
+    tx: Sender<Action>,
+    ack_rx: Receiver<Result<PathBuf>>,
+    /// Breaks the server out of its wait, so that actions are taken at once
+    wakeup: Arc<Semaphore>,
+}
+
+impl ReadDirectoryChangesWatcher {
+    /// Watch for `config.notify_filters` changes, with a buffer of `config.read_buffer_size`
+    /// bytes for each watched directory.
+    pub fn from_config(handler: impl EventHandler, config: &BackendConfig) -> Result<Self> {
+        let wakeup = Semaphore::new()
+            .map(Arc::new)
+            .ok_or_else(|| Error::generic("failed to create wakeup semaphore"))?;
+        let (ack_tx, ack_rx) = mpsc::channel();
+        let handler: Handler = Arc::new(Mutex::new(handler));
+        let filter = config
+            .notify_filters
+            .iter()
+            .fold(0, |filter, kind| filter | notify_change(*kind));
+        let tx = Server::start(
+            handler,
+            ack_tx,
+            wakeup.clone(),
+            config.read_buffer_size,
+            filter,
+        )?;
+        Ok(ReadDirectoryChangesWatcher { tx, ack_rx, wakeup })
+    }
+
+    fn send(&self, action: Action) -> Result<()> {
+        self.tx
+            .send(action)
+            .map_err(|_| Error::generic("watcher thread has stopped"))?;
+        self.wakeup.release();
+        Ok(())
+    }
+}
+
+fn notify_change(filter: NotifyFilter) -> FILE_NOTIFY_CHANGE {
+    match filter {
+        NotifyFilter::FileName => FILE_NOTIFY_CHANGE_FILE_NAME,
+        NotifyFilter::DirName => FILE_NOTIFY_CHANGE_DIR_NAME,
+        NotifyFilter::Attributes => FILE_NOTIFY_CHANGE_ATTRIBUTES,
+        NotifyFilter::Size => FILE_NOTIFY_CHANGE_SIZE,
+        NotifyFilter::LastWrite => FILE_NOTIFY_CHANGE_LAST_WRITE,
+        NotifyFilter::LastAccess => FILE_NOTIFY_CHANGE_LAST_ACCESS,
+        NotifyFilter::Creation => FILE_NOTIFY_CHANGE_CREATION,
+        NotifyFilter::Security => FILE_NOTIFY_CHANGE_SECURITY,
+    }
+}
+
+fn absolute(path: &Path) -> Result<PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(std::env::current_dir().map_err(Error::io)?.join(path))
+    }
+}
+
+impl Watcher for ReadDirectoryChangesWatcher {
+    fn new<F: EventHandler>(handler: F, _config: Config) -> Result<Self> {
+        ReadDirectoryChangesWatcher::from_config(handler, &BackendConfig::default())
+    }
+
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> Result<()> {
+        let path = absolute(path)?;
+        let recursive = recursive_mode == RecursiveMode::Recursive;
+        self.send(Action::Watch(path, recursive))?;
+        self.ack_rx
+            .recv()
+            .map_err(|_| Error::generic("watcher thread has stopped"))?
+            .map(|_| ())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<()> {
+        self.send(Action::Unwatch(absolute(path)?))
+    }
+
+    fn kind() -> WatcherKind {
+        WatcherKind::ReadDirectoryChangesWatcher
+    }
+}
+
+impl Drop for ReadDirectoryChangesWatcher {
+    fn drop(&mut self) {
+        let _ = self.send(Action::Stop);
+    }
+}
@@ -13,17 +13,2168 @@ defmodule FSNotifyTest do
 
       case Native.start_watcher(path, true) do
         {:ok, watcher_id} ->
-          assert is_integer(watcher_id)
-          assert watcher_id > 0
+          assert is_reference(watcher_id)
 
           # Test stopping the watcher
           assert Native.stop_watcher(watcher_id) == :ok
+          assert Native.stop_watcher(watcher_id) == :watcher_not_found
 
         {:error, _reason} ->
           flunk("Failed to start watcher")
       end
     end
 
+    test "returns descriptive errors" do
+      missing = Path.join(File.cwd!(), "does_not_exist")
+
+      assert {:error, {:enoent, message}} = Native.start_watcher(missing, true)
+      assert is_binary(message)
+
+      assert {:error, {:invalid_backend, _}} =
+               Native.start_watcher_with_backend(File.cwd!(), true, :bogus)
+    end
+
+    test "filters events with glob patterns" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_filters")
+      File.mkdir_p!(temp_dir)
+
+      assert {:error, {:invalid_pattern, _}} = Native.start_watcher_with_filters(temp_dir, true, ["a{"], [])
+
+      assert {:ok, watcher_id} = Native.start_watcher_with_filters(temp_dir, true, ["**/*.ex"], [])
+
+      File.write!(Path.join(temp_dir, "ignored.txt"), "hello")
+      File.write!(Path.join(temp_dir, "kept.ex"), "hello")
+      Process.sleep(100)
+
+      paths = watcher_id |> Native.get_events() |> Enum.map(&elem(&1, 1))
+      assert Enum.any?(paths, &String.ends_with?(&1, "kept.ex"))
+      refute Enum.any?(paths, &String.ends_with?(&1, "ignored.txt"))
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "drops events for gitignored paths" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_gitignore")
+      File.mkdir_p!(Path.join(temp_dir, "_build"))
+      File.write!(Path.join(temp_dir, ".gitignore"), "_build/\n")
+
+      assert {:ok, watcher_id} = Native.start_watcher_with_gitignore(temp_dir, true)
+
+      File.write!(Path.join([temp_dir, "_build", "ignored.beam"]), "hello")
+      File.write!(Path.join(temp_dir, "kept.ex"), "hello")
+      Process.sleep(100)
+
+      paths = watcher_id |> Native.get_events() |> Enum.map(&elem(&1, 1))
+      assert Enum.any?(paths, &String.ends_with?(&1, "kept.ex"))
+      refute Enum.any?(paths, &String.contains?(&1, "_build"))
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can add and remove paths" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_paths")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(File.cwd!(), false)
+      assert Native.add_path(watcher_id, temp_dir, true) == :ok
+      assert Native.remove_path(watcher_id, temp_dir) == :ok
+      assert {:error, {_reason, _message}} = Native.remove_path(watcher_id, temp_dir)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can configure the poll interval" do
+      path = File.cwd!()
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_backend_config(path, true, :poll, %{
+                 poll_interval_ms: 500,
+                 compare_contents: true
+               })
+
+      assert {:ok, ^path, true, :poll, nil, true} = Native.get_watcher_info(watcher_id)
+      Native.stop_watcher(watcher_id)
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_backend_config(path, true, :poll, %{poll_interval_ms: 0})
+    end
+
+    test "reports detailed event kinds" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_detail")
+      File.mkdir_p!(temp_dir)
+      file = Path.join(temp_dir, "detail.txt")
+      File.write!(file, "hello")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{event_detail: :detailed})
+
+      File.write!(file, "world")
+      File.chmod!(file, 0o600)
+      Process.sleep(100)
+
+      kinds =
+        for {kind, _path, _file_type, _mono, _sys, _seq} <- Native.get_events(watcher_id),
+            do: kind
+      assert :modified_data in kinds
+      assert :modified_metadata in kinds
+      refute :modified in kinds
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{event_detail: :verbose})
+    end
+
+    test "numbers events consecutively" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_seq")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+
+      File.write!(Path.join(temp_dir, "one.txt"), "1")
+      Process.sleep(100)
+      first = Native.get_events(watcher_id)
+
+      File.write!(Path.join(temp_dir, "two.txt"), "2")
+      Process.sleep(100)
+      second = Native.get_events(watcher_id)
+
+      seqs = for event <- first ++ second, do: elem(event, 5)
+      assert seqs == Enum.to_list(1..length(seqs))
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can get events in bounded batches" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_batches")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+
+      for i <- 1..5, do: File.write!(Path.join(temp_dir, "file#{i}.txt"), "#{i}")
+      Process.sleep(100)
+
+      assert {[_, _], true} = Native.get_events(watcher_id, 2)
+
+      {rest, false} = Native.get_events(watcher_id, 1_000)
+      assert length(rest) >= 3
+      assert {[], false} = Native.get_events(watcher_id, 2)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can wait for events" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_await")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+      assert [] = Native.await_events(watcher_id, 50)
+
+      Task.start(fn ->
+        Process.sleep(50)
+        File.write!(Path.join(temp_dir, "awaited.txt"), "hello")
+      end)
+
+      assert [{_kind, path, _file_type, _mono, _sys, _seq} | _] =
+               Native.await_events(watcher_id, 1_000)
+
+      assert String.ends_with?(path, "awaited.txt")
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "signals through enif_select when events can be taken" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_select_ready")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+
+      case :os.type() do
+        {:unix, _} ->
+          assert {:ok, ref} = Native.select_ready(watcher_id)
+          refute_receive {:select, _, ^ref, :ready_input}, 100
+
+          File.write!(Path.join(temp_dir, "selected.txt"), "hello")
+          assert_receive {:select, _, ^ref, :ready_input}, 1_000
+          Process.sleep(100)
+          assert [_ | _] = Native.get_events(watcher_id)
+
+          assert {:ok, ref} = Native.select_ready(watcher_id)
+          refute_receive {:select, _, ^ref, :ready_input}, 100
+
+        {:win32, _} ->
+          assert {:error, {:invalid_backend, _}} = Native.select_ready(watcher_id)
+      end
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can wait for events across watchers" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_await_any")
+      quiet_dir = Path.join(temp_dir, "quiet")
+      busy_dir = Path.join(temp_dir, "busy")
+      File.mkdir_p!(quiet_dir)
+      File.mkdir_p!(busy_dir)
+
+      assert {:ok, quiet} = Native.start_watcher(quiet_dir, true)
+      assert {:ok, busy} = Native.start_watcher(busy_dir, true)
+      assert [] = Native.await_any([quiet, busy], 50)
+
+      Task.start(fn ->
+        Process.sleep(50)
+        File.write!(Path.join(busy_dir, "awaited.txt"), "hello")
+      end)
+
+      assert [^busy] = Native.await_any([quiet, busy], 1_000)
+      assert [_ | _] = Native.get_events(busy)
+      assert {:error, {:invalid_config, _}} = Native.await_any([], 50)
+
+      Native.stop_watcher(quiet)
+      assert {:error, {:watcher_not_found, _}} = Native.await_any([quiet, busy], 50)
+
+      Native.stop_watcher(busy)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can inspect and clear queued events" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_peek")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+
+      File.write!(Path.join(temp_dir, "peeked.txt"), "hello")
+      Process.sleep(100)
+
+      count = Native.pending_count(watcher_id)
+      assert count > 0
+      assert length(Native.peek_events(watcher_id)) == count
+      assert Native.peek_events(watcher_id) == Native.get_events(watcher_id)
+
+      File.write!(Path.join(temp_dir, "cleared.txt"), "hello")
+      Process.sleep(100)
+
+      assert :ok = Native.clear_events(watcher_id)
+      assert Native.pending_count(watcher_id) == 0
+      assert [] = Native.get_events(watcher_id)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "reports an overflow when the buffer is full" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_buffer")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 buffer_capacity: 2,
+                 drop_policy: :drop_newest
+               })
+
+      for i <- 1..10, do: File.write!(Path.join(temp_dir, "file#{i}.txt"), "#{i}")
+      Process.sleep(100)
+
+      assert [{:overflow, ^temp_dir, _mono, _sys, _seq} | rest] = Native.get_events(watcher_id)
+      assert length(rest) == 2
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{drop_policy: :drop_all})
+    end
+
+    test "takes every waiting event even past a buffer's worth" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_buffer_backlog")
+      File.mkdir_p!(temp_dir)
+      later = Path.join(temp_dir, "later.txt")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 buffer_capacity: 2,
+                 drop_policy: :drop_newest
+               })
+
+      File.write!(Path.join(temp_dir, "first.txt"), "hello")
+      Process.sleep(100)
+      # Fills the queue, leaving the buffer free for more
+      peeked = Native.peek_events(watcher_id)
+
+      File.write!(later, "hello")
+      Process.sleep(100)
+
+      events = Native.get_events(watcher_id)
+      assert Enum.take(events, length(peeked)) == peeked
+      assert Enum.any?(events, &(elem(&1, 1) == later))
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "counts the events lost to a full buffer" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_buffer_lost")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 buffer_capacity: 2,
+                 event_format: :map
+               })
+
+      for i <- 1..10, do: File.write!(Path.join(temp_dir, "file#{i}.txt"), "#{i}")
+      Process.sleep(100)
+
+      assert Native.pending_count(watcher_id) <= 3
+      assert [%{kind: :overflow, path: ^temp_dir, count: lost} | rest] =
+               Native.get_events(watcher_id)
+
+      assert lost > 0
+      assert length(rest) == 2
+      assert %{dropped: ^lost, queue_depth: 0} = Native.get_stats(watcher_id)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "reports watcher stats" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_stats")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+
+      assert %{received: 0, delivered: 0, dropped: 0, queue_depth: 0, last_event_at: nil} =
+               Native.get_stats(watcher_id)
+
+      assert %{last_event_id: nil} = Native.get_stats(watcher_id)
+
+      File.write!(Path.join(temp_dir, "stats.txt"), "hello")
+      Process.sleep(100)
+
+      stats = Native.get_stats(watcher_id)
+      assert stats.received > 0
+      assert stats.queue_depth == stats.received
+      assert is_integer(stats.last_event_at)
+
+      events = Native.get_events(watcher_id)
+      assert %{delivered: delivered, queue_depth: 0} = Native.get_stats(watcher_id)
+      assert delivered == length(events)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "estimates the memory a watcher holds on to" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_memory")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+      assert %{events: idle, paths: paths, total: total} = Native.memory_info(watcher_id)
+      assert paths >= byte_size(temp_dir)
+      assert total >= idle + paths
+
+      for i <- 1..20, do: File.write!(Path.join(temp_dir, "file#{i}.txt"), "#{i}")
+      Process.sleep(100)
+
+      assert %{events: busy} = Native.memory_info(watcher_id)
+      assert busy > idle
+
+      Native.stop_watcher(watcher_id)
+      assert {:error, {:watcher_not_found, _}} = Native.memory_info(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "gives back the memory a burst left behind" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_compact")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+
+      for i <- 1..2_000, do: File.write!(Path.join(temp_dir, "file#{i}.txt"), "#{i}")
+      Process.sleep(200)
+
+      Native.clear_events(watcher_id)
+      assert %{events: burst} = Native.memory_info(watcher_id)
+
+      assert :ok = Native.compact(watcher_id)
+      assert %{events: compacted} = Native.memory_info(watcher_id)
+      assert compacted < burst
+
+      Native.stop_watcher(watcher_id)
+      assert {:error, {:watcher_not_found, _}} = Native.compact(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can pause and resume a watcher" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_pause")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+      assert :ok = Native.pause_watcher(watcher_id)
+
+      File.write!(Path.join(temp_dir, "ignored.txt"), "hello")
+      Process.sleep(100)
+      assert [] = Native.get_events(watcher_id)
+
+      assert :ok = Native.resume_watcher(watcher_id)
+
+      File.write!(Path.join(temp_dir, "seen.txt"), "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: elem(event, 1)
+      assert Enum.any?(paths, &String.ends_with?(&1, "seen.txt"))
+      refute Enum.any?(paths, &String.ends_with?(&1, "ignored.txt"))
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can update a running watcher" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_update")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+
+      File.write!(Path.join(temp_dir, "queued.txt"), "hello")
+      Process.sleep(100)
+
+      assert :ok =
+               Native.update_watcher(watcher_id, %{
+                 include: ["**/*.ex"],
+                 recursive: false,
+                 backend: :poll,
+                 poll_interval_ms: 50
+               })
+
+      assert {:ok, ^temp_dir, false, :poll, nil, true} = Native.get_watcher_info(watcher_id)
+
+      File.write!(Path.join(temp_dir, "dropped.txt"), "hello")
+      File.write!(Path.join(temp_dir, "kept.ex"), "hello")
+      Process.sleep(300)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "queued.txt" in paths
+      assert "kept.ex" in paths
+      refute "dropped.txt" in paths
+
+      assert {:error, {:invalid_config, _}} =
+               Native.update_watcher(watcher_id, %{event_detail: :detailed})
+
+      # Options that only matter at start are fixed too
+      assert {:error, {:invalid_config, _}} =
+               Native.update_watcher(watcher_id, %{initial_scan: true})
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "lists running watchers" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_list")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_debounce(temp_dir, false, :recommended, 50)
+
+      assert {listed, ^temp_dir, false, _backend, 50, 0, nil} =
+               Enum.find(Native.list_watchers(), &(elem(&1, 1) == temp_dir))
+
+      assert :ok = Native.stop_watcher(listed)
+      assert Native.stop_watcher(watcher_id) == :watcher_not_found
+      refute Enum.any?(Native.list_watchers(), &(elem(&1, 1) == temp_dir))
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "labels watchers and their events" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_label")
+      File.mkdir_p!(temp_dir)
+      label = {:project, 42}
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{
+                 label: label,
+                 label_events: true
+               })
+
+      assert {:ok, ^temp_dir, false, _backend, ^label, true} = Native.get_watcher_info(watcher_id)
+
+      File.write!(Path.join(temp_dir, "labelled.txt"), "hello")
+      Process.sleep(100)
+
+      events = Native.get_events(watcher_id)
+      assert events != []
+      assert Enum.all?(events, &(elem(&1, tuple_size(&1) - 1) == {:label, label}))
+
+      assert {:error, {:invalid_config, _}} =
+               Native.update_watcher(watcher_id, %{label: :other})
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, false, %{label_events: true})
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "shares watchers started on the same path" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_shared")
+      File.mkdir_p!(temp_dir)
+
+      shared = %{share: true}
+      assert {:ok, first} = Native.start_watcher_with_options(temp_dir, false, shared)
+      assert {:ok, second} = Native.start_watcher_with_options(temp_dir, false, shared)
+      assert {:ok, other} = Native.start_watcher_with_options(temp_dir, true, shared)
+      assert {:ok, unshared} = Native.start_watcher(temp_dir, false)
+
+      assert [_, _, _] = Enum.filter(Native.list_watchers(), &(elem(&1, 1) == temp_dir))
+
+      # Only those holding a claim can stop a shared watcher
+      task = Task.async(fn -> Native.stop_watcher(first) end)
+      assert {:error, {:not_owner, _}} = Task.await(task)
+
+      assert :ok = Native.stop_watcher(first)
+      File.write!(Path.join(temp_dir, "shared.txt"), "hello")
+      Process.sleep(100)
+      assert Native.get_events(second) != []
+
+      assert :ok = Native.stop_watcher(second)
+      assert Native.stop_watcher(first) == :watcher_not_found
+
+      Native.stop_watcher(other)
+      Native.stop_watcher(unshared)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "keeps a shared watcher until every claim on it is gone" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_shared_claims")
+      File.mkdir_p!(temp_dir)
+      shared = %{share: true}
+      listed? = fn -> Enum.any?(Native.list_watchers(), &(elem(&1, 1) == temp_dir)) end
+
+      assert {:ok, kept} = Native.start_watcher_with_options(temp_dir, false, shared)
+
+      # Shared in a function of its own, so nothing here refers to it afterwards
+      share = fn ->
+        {:ok, _} = Native.start_watcher_with_options(temp_dir, false, shared)
+        :ok
+      end
+
+      share.()
+      :erlang.garbage_collect()
+      Process.sleep(100)
+      assert listed?.()
+
+      assert :ok = Native.stop_watcher(kept)
+
+      assert Enum.any?(1..20, fn _ ->
+               :erlang.garbage_collect()
+               Process.sleep(50)
+               not listed?.()
+             end)
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "reports existing entries before the ready marker" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_scan")
+      File.mkdir_p!(Path.join(temp_dir, "nested"))
+      File.write!(Path.join(temp_dir, "nested/existing.txt"), "hello")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{initial_scan: true})
+
+      Process.sleep(200)
+      events = Native.get_events(watcher_id)
+
+      discovered =
+        for {:discovered, path, type, _, _, _} <- events,
+            do: {Path.relative_to(path, temp_dir), type}
+
+      assert {"nested", :directory} in discovered
+      assert {"nested/existing.txt", :file} in discovered
+      assert {:ready, _, _, _} = List.last(events)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "limits recursion depth" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_depth")
+      File.mkdir_p!(Path.join(temp_dir, "one/two"))
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{recursive: {:depth, 2}})
+
+      File.write!(Path.join(temp_dir, "one/shallow.txt"), "hello")
+      File.write!(Path.join(temp_dir, "one/two/deep.txt"), "hello")
+      File.mkdir_p!(Path.join(temp_dir, "new"))
+      Process.sleep(100)
+      File.write!(Path.join(temp_dir, "new/later.txt"), "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "shallow.txt" in paths
+      assert "later.txt" in paths
+      refute "deep.txt" in paths
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{recursive: {:depth, 0}})
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "skips excluded directories while recursing" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_exclude_dirs")
+      File.mkdir_p!(Path.join(temp_dir, "node_modules/pkg"))
+      File.mkdir_p!(Path.join(temp_dir, "lib"))
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 exclude_dirs: ["node_modules"]
+               })
+
+      File.write!(Path.join(temp_dir, "node_modules/pkg/index.js"), "hello")
+      File.write!(Path.join(temp_dir, "lib/kept.ex"), "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "kept.ex" in paths
+      refute "index.js" in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "ignores hidden files" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_hidden")
+      File.mkdir_p!(Path.join(temp_dir, ".git"))
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{ignore_hidden: true})
+
+      File.write!(Path.join(temp_dir, ".git/index.lock"), "hello")
+      File.write!(Path.join(temp_dir, ".env"), "hello")
+      File.write!(Path.join(temp_dir, "visible.txt"), "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "visible.txt" in paths
+      refute "index.lock" in paths
+      refute ".env" in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "ignores editor and OS junk files with presets" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_presets")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{
+                 ignore_presets: [:editors, :os_junk]
+               })
+
+      junk = [".notes.txt.swp", "notes.txt~", "notes.txt___jb_tmp___", ".DS_Store"]
+
+      for name <- junk ++ ["notes.txt"] do
+        File.write!(Path.join(temp_dir, name), "hello")
+      end
+
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert Enum.uniq(paths) == ["notes.txt"]
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, false, %{ignore_presets: [:bogus]})
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "filters events by extension" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_extensions")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{extensions: ["ex", ".heex"]})
+
+      for name <- ["app.ex", "page.heex", "notes.txt", "Makefile"] do
+        File.write!(Path.join(temp_dir, name), "hello")
+      end
+
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert Enum.sort(Enum.uniq(paths)) == ["app.ex", "page.heex"]
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "only reports the requested kinds" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_kinds")
+      File.mkdir_p!(temp_dir)
+      file = Path.join(temp_dir, "kinds.txt")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{kinds: [:created, :removed]})
+
+      File.write!(file, "hello")
+      File.write!(file, "hello again")
+      File.rm!(file)
+      Process.sleep(100)
+
+      kinds = for event <- Native.get_events(watcher_id), do: elem(event, 0)
+      assert :created in kinds
+      assert :removed in kinds
+      refute :modified in kinds
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "filters events with regular expressions" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_regex")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{
+                 include_regex: ["^report_\\d+\\.csv$"],
+                 exclude_regex: ["_draft"]
+               })
+
+      for name <- ["report_1.csv", "report_2_draft.csv", "report_x.csv"] do
+        File.write!(Path.join(temp_dir, name), "hello")
+      end
+
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert Enum.uniq(paths) == ["report_1.csv"]
+
+      assert {:error, {:invalid_pattern, _}} =
+               Native.start_watcher_with_options(temp_dir, false, %{include_regex: ["("]})
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can deliver non-UTF-8 paths as raw bytes" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_raw_paths")
+      File.mkdir_p!(temp_dir)
+      name = <<"latin1_caf", 0xE9>>
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{raw_paths: true})
+
+      File.write!(temp_dir <> "/" <> name, "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: elem(event, 1)
+      assert temp_dir <> "/" <> name in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can report paths relative to the root" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_relative")
+      File.mkdir_p!(Path.join(temp_dir, "sub"))
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{relative_paths: true})
+
+      assert {:ok, ^temp_dir, true, _backend, nil, true} = Native.get_watcher_info(watcher_id)
+
+      File.write!(Path.join(temp_dir, "sub/relative.txt"), "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: elem(event, 1)
+      assert "sub/relative.txt" in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can canonicalize the root and event paths" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_canonical")
+      File.mkdir_p!(Path.join(temp_dir, "sub"))
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(Path.join(temp_dir, "sub/.."), false, %{
+                 canonicalize: true
+               })
+
+      assert {:ok, ^temp_dir, false, _backend, nil, true} = Native.get_watcher_info(watcher_id)
+
+      File.write!(Path.join(temp_dir, "canonical.txt"), "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: elem(event, 1)
+      assert Path.join(temp_dir, "canonical.txt") in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can normalize event paths to NFC" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_nfc")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{normalize_unicode: :nfc})
+
+      File.write!(Path.join(temp_dir, :unicode.characters_to_nfd_binary("café.txt")), "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert :unicode.characters_to_nfc_binary("café.txt") in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can watch without following symlinks" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_no_follow")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{follow_symlinks: false})
+
+      assert {:ok, ^temp_dir, true, _backend, nil, false} = Native.get_watcher_info(watcher_id)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can report a symlinked root being retargeted" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_watch_link")
+      File.mkdir_p!(Path.join(temp_dir, "releases/1"))
+      File.mkdir_p!(Path.join(temp_dir, "releases/2"))
+      link = Path.join(temp_dir, "current")
+      File.ln_s!(Path.join(temp_dir, "releases/1"), link)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(link, true, %{watch_link: true})
+
+      File.ln_s!(Path.join(temp_dir, "releases/2"), Path.join(temp_dir, "current.tmp"))
+      File.rename!(Path.join(temp_dir, "current.tmp"), link)
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: elem(event, 1)
+      assert link in paths
+      refute Path.join(temp_dir, "current.tmp") in paths
+
+      File.write!(Path.join(link, "deployed.txt"), "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "deployed.txt" in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "reports the root being removed and rearms once it reappears" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_root_removed")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{rearm_root: true})
+
+      File.rm_rf!(temp_dir)
+      Process.sleep(100)
+
+      events = Native.get_events(watcher_id)
+      assert Enum.any?(events, &match?({:root_removed, ^temp_dir, _, _, _}, &1))
+
+      File.mkdir_p!(temp_dir)
+      Process.sleep(700)
+      File.write!(Path.join(temp_dir, "rearmed.txt"), "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "rearmed.txt" in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can watch a root that doesn't exist yet" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_missing")
+      File.mkdir_p!(temp_dir)
+      root = Path.join(temp_dir, "logs/app")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(root, true, %{allow_missing: true})
+
+      File.write!(Path.join(temp_dir, "unrelated.txt"), "hello")
+      File.mkdir_p!(root)
+      Process.sleep(100)
+
+      events = Native.get_events(watcher_id)
+      assert Enum.any?(events, &match?({:root_created, ^root, _, _, _}, &1))
+      refute Enum.any?(events, &(Path.basename(elem(&1, 1)) == "unrelated.txt"))
+
+      File.write!(Path.join(root, "app.log"), "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "app.log" in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "one-shot watcher delivers the first event and stops" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_oneshot")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_oneshot_watcher(temp_dir, [:created])
+
+      File.write!(Path.join(temp_dir, "first.txt"), "hello")
+      assert_receive {:fs_notify, _watcher, {:created, path, _, _, _, _}}, 1000
+      assert Path.basename(path) == "first.txt"
+
+      File.write!(Path.join(temp_dir, "second.txt"), "hello")
+      refute_receive {:fs_notify, _, _}, 200
+      assert Native.stop_watcher(watcher_id) == :watcher_not_found
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "one-shot watcher takes options" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_oneshot_options")
+      File.mkdir_p!(temp_dir)
+      missing = Path.join(temp_dir, "missing.txt")
+
+      assert {:ok, watcher_id} = Native.start_oneshot_watcher(missing, [], %{})
+      Native.stop_watcher(watcher_id)
+
+      assert {:error, {:enoent, _}} =
+               Native.start_oneshot_watcher(missing, [], %{allow_missing: false})
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can wait for a path to appear" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_wait_for_path")
+      File.mkdir_p!(temp_dir)
+      path = Path.join(temp_dir, "nested/ready.txt")
+
+      assert {:error, {:timeout, _}} = Native.wait_for_path(path, 50)
+
+      Task.start(fn ->
+        Process.sleep(50)
+        File.mkdir_p!(Path.dirname(path))
+        File.write!(path, "hello")
+      end)
+
+      assert :ok = Native.wait_for_path(path, 2000)
+      assert :ok = Native.wait_for_path(path, 0)
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can wait for a watcher to go quiet" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_quiet")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, false)
+
+      Task.start(fn ->
+        for i <- 1..10 do
+          File.write!(Path.join(temp_dir, "chunk_#{i}.txt"), "hello")
+          Process.sleep(20)
+        end
+      end)
+
+      assert {:error, {:timeout, _}} = Native.wait_for_quiet(watcher_id, 100, 100)
+      assert :ok = Native.wait_for_quiet(watcher_id, 100, 2000)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "chunk_10.txt" in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "reports files once they have settled" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_settled")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{
+                 settle_ms: 100,
+                 kinds: [:settled]
+               })
+
+      path = Path.join(temp_dir, "upload.bin")
+
+      for _ <- 1..5 do
+        File.write!(path, "chunk", [:append])
+        Process.sleep(20)
+      end
+
+      assert Native.get_events(watcher_id) == []
+      Process.sleep(300)
+
+      assert [{:settled, ^path, :file, _, _, _}] = Native.get_events(watcher_id)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "reports atomic saves as a single modification" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_coalesce")
+      File.mkdir_p!(temp_dir)
+      path = Path.join(temp_dir, "file.txt")
+      File.write!(path, "old")
+      report = Path.join(temp_dir, "report")
+      File.write!(report <> ".txt", "old")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{coalesce_saves: true})
+
+      Process.sleep(100)
+      File.write!(path <> ".tmp", "new")
+      File.rename!(path <> ".tmp", path)
+      Process.sleep(100)
+
+      assert [{:modified, ^path, :file, _, _, _}] = Native.get_events(watcher_id)
+
+      # Names merely containing the target's aren't temporary files
+      File.rename!(report <> ".txt", report)
+      Process.sleep(100)
+
+      assert [{:renamed, _, ^report, _, _, _}] = Native.get_events(watcher_id)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "reports files closed after writing as written" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_written")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{kinds: [:written]})
+
+      Process.sleep(100)
+      path = Path.join(temp_dir, "report.csv")
+      File.write!(path, "a,b,c")
+      Process.sleep(100)
+
+      assert [{:written, ^path, :file, _, _, _}] = Native.get_events(watcher_id)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "reports and ignores attribute changes" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_attrib")
+      File.mkdir_p!(temp_dir)
+      path = Path.join(temp_dir, "script.sh")
+      File.write!(path, "echo hello")
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, false)
+
+      assert {:ok, quiet_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{ignore_attrib: true})
+
+      Process.sleep(100)
+      File.chmod!(path, 0o755)
+      Process.sleep(100)
+
+      assert [{:attrib_changed, ^path, :file, _, _, _} | _] = Native.get_events(watcher_id)
+      assert Native.get_events(quiet_id) == []
+
+      Native.stop_watcher(watcher_id)
+      Native.stop_watcher(quiet_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "classifies attribute changes" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_classify_attrib")
+      File.mkdir_p!(temp_dir)
+      path = Path.join(temp_dir, "config.yml")
+      File.write!(path, "key: value")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{classify_attrib: true})
+
+      Process.sleep(100)
+      File.chmod!(path, 0o600)
+      Process.sleep(50)
+      File.touch!(path, 0)
+      Process.sleep(100)
+
+      kinds = for event <- Native.get_events(watcher_id), do: elem(event, 0)
+      assert :permissions in kinds
+      assert :timestamps in kinds
+      refute :attrib_changed in kinds
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    @tag :linux
+    test "classifies extended attribute changes" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_classify_xattr")
+      File.mkdir_p!(temp_dir)
+      path = Path.join(temp_dir, "download.bin")
+      File.write!(path, "data")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{classify_attrib: true})
+
+      Process.sleep(100)
+      assert {_, 0} = System.cmd("setfattr", ["-n", "user.origin", "-v", "web", path])
+      Process.sleep(100)
+
+      assert [{:xattr_changed, ^path, :file, _, _, _} | _] = Native.get_events(watcher_id)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "includes size and mtime in events" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_include_stat")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{
+                 include_stat: true,
+                 kinds: [:created]
+               })
+
+      Process.sleep(100)
+      path = Path.join(temp_dir, "sized.txt")
+      File.write!(path, "12345")
+      Process.sleep(100)
+
+      assert [{:created, ^path, :file, _, _, _, %{size: 5, mtime: mtime}} = tuple] =
+               Native.get_events(watcher_id)
+
+      assert is_integer(mtime)
+      assert %Event{size: 5, mtime: ^mtime} = Event.from_tuple(tuple)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "keeps file ids across renames" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_file_id")
+      File.mkdir_p!(temp_dir)
+      path = Path.join(temp_dir, "draft.txt")
+      File.write!(path, "hello")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{include_stat: true})
+
+      Process.sleep(100)
+      File.write!(path, "hello again", [:append])
+      Process.sleep(100)
+      File.rename!(path, Path.join(temp_dir, "final.txt"))
+      Process.sleep(100)
+
+      events = Native.get_events(watcher_id)
+
+      file_ids =
+        for {_kind, _path, _to, _mono, _sys, _seq, %{file_id: id}} <- events, id != nil, do: id
+
+      assert [_ | _] = file_ids
+      assert [_] = Enum.uniq(file_ids)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "hashes the contents of changed files" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_hash_contents")
+      File.mkdir_p!(temp_dir)
+      first = Path.join(temp_dir, "first.txt")
+      second = Path.join(temp_dir, "second.txt")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{
+                 hash_contents: true,
+                 kinds: [:written]
+               })
+
+      Process.sleep(100)
+      File.write!(first, "same contents")
+      File.write!(second, "same contents")
+      Process.sleep(100)
+
+      assert [
+               {:written, ^first, :file, _, _, _, %{hash: hash}},
+               {:written, ^second, :file, _, _, _, %{hash: hash}}
+             ] = Native.get_events(watcher_id)
+
+      assert is_integer(hash)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "delivers data appended to followed files" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_tail")
+      File.mkdir_p!(temp_dir)
+      path = Path.join(temp_dir, "app.log")
+      File.write!(path, "before\n")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(path, false, %{
+                 tail: true,
+                 kinds: [:appended, :rotated]
+               })
+
+      Process.sleep(100)
+      File.write!(path, "first\n", [:append])
+      File.write!(path, "second\n", [:append])
+      Process.sleep(100)
+
+      data = for {:appended, ^path, data, _, _, _} <- Native.get_events(watcher_id), do: data
+      assert IO.iodata_to_binary(data) == "first\nsecond\n"
+
+      File.write!(path, "")
+      File.write!(path, "after\n", [:append])
+      Process.sleep(100)
+
+      assert [{:rotated, ^path, :file, _, _, _} | appended] = Native.get_events(watcher_id)
+      assert IO.iodata_to_binary(for event <- appended, do: elem(event, 2)) == "after\n"
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "classifies writes by how they change the size" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_classify_writes")
+      File.mkdir_p!(temp_dir)
+      path = Path.join(temp_dir, "data.log")
+      File.write!(path, "0123456789")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, false, %{
+                 classify_writes: true,
+                 kinds: [:appended, :truncated, :rewritten]
+               })
+
+      Process.sleep(100)
+      File.write!(path, "more", [:append])
+      Process.sleep(50)
+      {:ok, file} = :file.open(path, [:read, :write])
+      :ok = :file.pwrite(file, 0, "X")
+      :ok = :file.close(file)
+      Process.sleep(50)
+      {:ok, file} = :file.open(path, [:read, :write])
+      {:ok, _} = :file.position(file, 4)
+      :ok = :file.truncate(file)
+      :ok = :file.close(file)
+      Process.sleep(100)
+
+      kinds = for event <- Native.get_events(watcher_id), do: elem(event, 0)
+      assert kinds == [:appended, :rewritten, :truncated]
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "diffs directory snapshots" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_snapshot")
+      File.mkdir_p!(Path.join(temp_dir, "sub"))
+      kept = Path.join(temp_dir, "kept.txt")
+      changed = Path.join(temp_dir, "sub/changed.txt")
+      removed = Path.join(temp_dir, "removed.txt")
+      File.write!(kept, "same")
+      File.write!(changed, "old")
+      File.write!(removed, "gone soon")
+
+      assert {:ok, before} = Native.snapshot(temp_dir, %{})
+
+      File.write!(changed, "longer contents")
+      File.rm!(removed)
+      created = Path.join(temp_dir, "sub/created.txt")
+      File.write!(created, "new")
+
+      assert {:ok, after_changes} = Native.snapshot(temp_dir, %{recursive: true})
+
+      assert %{created: [^created], modified: [^changed], removed: [^removed]} =
+               Native.diff_snapshots(before, after_changes)
+
+      assert {:error, _} = Native.snapshot(Path.join(temp_dir, "missing"), %{})
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "digests directory trees" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_tree_digest")
+      File.mkdir_p!(Path.join(temp_dir, "sub"))
+      file = Path.join(temp_dir, "sub/file.txt")
+      File.write!(file, "contents")
+
+      assert {:ok, digest} = Native.tree_digest(temp_dir, %{})
+      assert {:ok, ^digest} = Native.tree_digest(temp_dir, %{recursive: true})
+      assert {:ok, hashed} = Native.tree_digest(temp_dir, %{contents: true})
+
+      File.touch!(file, System.os_time(:second) + 60)
+
+      assert {:ok, touched} = Native.tree_digest(temp_dir, %{})
+      assert touched != digest
+      assert {:ok, ^hashed} = Native.tree_digest(temp_dir, %{contents: true})
+
+      File.write!(file, "CONTENTS")
+
+      assert {:ok, rewritten} = Native.tree_digest(temp_dir, %{contents: true})
+      assert rewritten != hashed
+
+      assert {:error, _} = Native.tree_digest(Path.join(temp_dir, "missing"), %{})
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "replays recorded events" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_journal")
+      File.mkdir_p!(temp_dir)
+      journal = Path.join(File.cwd!(), "test_temp_journal.bin")
+
+      {:ok, watcher} = Native.start_watcher(temp_dir, true)
+      assert :ok = Native.record_events(watcher, journal)
+      File.write!(Path.join(temp_dir, "recorded.txt"), "content")
+      Process.sleep(100)
+      assert :ok = Native.stop_recording(watcher)
+
+      recorded = Native.get_events(watcher)
+      assert length(recorded) > 0
+      Native.stop_watcher(watcher)
+
+      {:ok, replayed} = Native.start_watcher_with_options(temp_dir, true, %{replay: journal})
+      Process.sleep(100)
+
+      # Recorded times are kept, so replayed events are identical
+      assert Native.get_events(replayed) == recorded
+
+      Native.stop_watcher(replayed)
+      File.rm_rf!(temp_dir)
+      File.rm!(journal)
+    end
+
+    test "catches up on logged events after a restart" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_event_log")
+      File.mkdir_p!(temp_dir)
+      log = Path.join(File.cwd!(), "test_temp_event_log.bin")
+      options = %{event_log: log}
+
+      {:ok, watcher} = Native.start_watcher_with_options(temp_dir, true, options)
+      File.write!(Path.join(temp_dir, "first.txt"), "content")
+      Process.sleep(100)
+
+      first = Native.get_events_after(watcher, 0)
+      assert length(first) > 0
+      last_seq = first |> List.last() |> elem(5)
+      # Reading the log leaves the events queued
+      assert Native.get_events(watcher) == first
+      Native.stop_watcher(watcher)
+
+      {:ok, watcher} = Native.start_watcher_with_options(temp_dir, true, options)
+      File.write!(Path.join(temp_dir, "second.txt"), "content")
+      Process.sleep(100)
+
+      missed = Native.get_events_after(watcher, last_seq)
+      assert length(missed) > 0
+      assert Enum.all?(missed, &(elem(&1, 5) > last_seq))
+      assert Native.get_events_after(watcher, 0) == first ++ missed
+
+      Native.stop_watcher(watcher)
+      File.rm_rf!(temp_dir)
+      File.rm!(log)
+    end
+
+    test "accepts an FSEvents event id to resume from" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_since_event_id")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher} =
+               Native.start_watcher_with_options(temp_dir, true, %{since_event_id: 1})
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{since_event_id: -1})
+
+      Native.stop_watcher(watcher)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "accepts an FSEvents latency" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_latency_ms")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher} =
+               Native.start_watcher_with_options(temp_dir, true, %{latency_ms: 500})
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{latency_ms: 0.5})
+
+      Native.stop_watcher(watcher)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "adds FSEvents stream flags to events" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_fsevent_flags")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher} =
+               Native.start_watcher_with_options(temp_dir, false, %{
+                 fsevent_flags: true,
+                 kinds: [:created]
+               })
+
+      Process.sleep(100)
+      path = Path.join(temp_dir, "flagged.txt")
+      File.write!(path, "hello")
+      Process.sleep(100)
+
+      assert [{:created, ^path, :file, _, _, _, {:fsevent_flags, flags}} = tuple | _] =
+               Native.get_events(watcher)
+
+      assert %Event{path: ^path, fsevent_flags: ^flags} = Event.from_tuple(tuple)
+
+      Native.stop_watcher(watcher)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "accepts a ReadDirectoryChangesW buffer size" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_read_buffer_size")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher} =
+               Native.start_watcher_with_options(temp_dir, true, %{read_buffer_size: 65536})
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{read_buffer_size: 1001})
+
+      Native.stop_watcher(watcher)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "accepts Windows notify filters" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_notify_filters")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 notify_filters: [:file_name, :dir_name, :last_write]
+               })
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{notify_filters: []})
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{notify_filters: [:contents]})
+
+      Native.stop_watcher(watcher)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "reports inotify limits" do
+      assert {:ok, watcher} = Native.start_watcher(File.cwd!(), false)
+
+      case :os.type() do
+        {:unix, :linux} ->
+          assert {:ok,
+                  %{
+                    max_user_watches: max_watches,
+                    watches: watches,
+                    remaining_watches: remaining,
+                    instances: instances
+                  }} = Native.inotify_limits()
+
+          assert remaining == max(max_watches - watches, 0)
+          assert instances >= 1
+
+        _ ->
+          assert {:error, {:invalid_backend, _}} = Native.inotify_limits()
+      end
+
+      Native.stop_watcher(watcher)
+    end
+
+    test "adds the responsible process ID to events" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_include_os_pid")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher} =
+               Native.start_watcher_with_options(temp_dir, false, %{
+                 include_os_pid: true,
+                 kinds: [:created]
+               })
+
+      Process.sleep(100)
+      path = Path.join(temp_dir, "attributed.txt")
+      File.write!(path, "hello")
+      Process.sleep(100)
+
+      # Only fanotify knows who made a change
+      assert [{:created, ^path, :file, _, _, _, {:os_pid, nil}} = tuple | _] =
+               Native.get_events(watcher)
+
+      assert %Event{path: ^path, os_pid: nil} = Event.from_tuple(tuple)
+
+      Native.stop_watcher(watcher)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "starts a fanotify watcher only where it's available" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_fanotify")
+      File.mkdir_p!(temp_dir)
+
+      if :fanotify in Native.list_available_backends() do
+        assert {:ok, watcher} = Native.start_watcher_with_backend(temp_dir, true, :fanotify)
+        assert {:ok, _, true, :fanotify, _, _} = Native.get_watcher_info(watcher)
+        Native.stop_watcher(watcher)
+      else
+        assert {:error, {:invalid_backend, _}} =
+                 Native.start_watcher_with_backend(temp_dir, true, :fanotify)
+      end
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "answers fanotify permission events" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_permission_events")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 permission_events: true,
+                 permission_timeout_ms: 1000
+               })
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{permission_timeout_ms: 0})
+
+      case :os.type() do
+        {:unix, :linux} ->
+          assert {:error, {:permission_not_found, _}} = Native.respond(1_000_000, :allow)
+          assert_raise ArgumentError, fn -> Native.respond(1, :maybe) end
+
+        _ ->
+          assert {:error, {:invalid_backend, _}} = Native.respond(1_000_000, :allow)
+      end
+
+      Native.stop_watcher(watcher)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "starts a Watchman watcher only where it's available" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_watchman")
+      File.mkdir_p!(temp_dir)
+
+      if :watchman in Native.list_available_backends() do
+        assert {:ok, watcher} = Native.start_watcher_with_backend(temp_dir, true, :watchman)
+        assert {:ok, _, true, :watchman, _, _} = Native.get_watcher_info(watcher)
+        Native.stop_watcher(watcher)
+      else
+        assert {:error, {:invalid_backend, _}} =
+                 Native.start_watcher_with_backend(temp_dir, true, :watchman)
+      end
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "debounces events from the backend asked for" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_debounce_backend")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 backend: :poll,
+                 poll_interval_ms: 50,
+                 debounce_ms: 50
+               })
+
+      assert {:ok, ^temp_dir, true, :poll, nil, true} = Native.get_watcher_info(watcher_id)
+
+      File.write!(Path.join(temp_dir, "debounced.txt"), "hello")
+      Process.sleep(500)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "debounced.txt" in paths
+      Native.stop_watcher(watcher_id)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_full_debounce(temp_dir, true, :poll, 50)
+
+      assert {:ok, ^temp_dir, true, :poll, nil, true} = Native.get_watcher_info(watcher_id)
+      Native.stop_watcher(watcher_id)
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "pairs renames with the full debouncer" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_full_debounce_rename")
+      File.mkdir_p!(temp_dir)
+      from = Path.join(temp_dir, "draft.txt")
+      to = Path.join(temp_dir, "final.txt")
+      File.write!(from, "hello")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_full_debounce(temp_dir, true, :recommended, 50)
+
+      Process.sleep(100)
+      File.rename!(from, to)
+      Process.sleep(300)
+
+      events = Native.get_events(watcher_id)
+      assert Enum.any?(events, &match?({:renamed, ^from, ^to, _, _, _}, &1))
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "debounces each path on its own" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_debounce_per_path")
+      File.mkdir_p!(temp_dir)
+      busy = Path.join(temp_dir, "busy.txt")
+      File.write!(busy, "")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_debounce(temp_dir, true, :recommended, 300)
+
+      File.write!(busy, "0", [:append])
+      Process.sleep(100)
+      File.write!(Path.join(temp_dir, "quiet.txt"), "hello")
+
+      # The quiet file is reported while the busy one is still being written
+      for i <- 1..8 do
+        File.write!(busy, "#{i}", [:append])
+        Process.sleep(50)
+      end
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "quiet.txt" in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "reports the leading edge of a burst of changes" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_debounce_edge")
+      File.mkdir_p!(temp_dir)
+      file = Path.join(temp_dir, "leading.txt")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 debounce_ms: 1000,
+                 debounce_edge: :leading
+               })
+
+      File.write!(file, "hello")
+      Process.sleep(200)
+
+      # Reported well before a trailing debouncer would have
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "leading.txt" in paths
+
+      File.write!(file, "world")
+      Process.sleep(200)
+      assert Native.get_events(watcher_id) == []
+
+      Native.stop_watcher(watcher_id)
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 debounce_ms: 100,
+                 debounce_edge: :middle
+               })
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "throttles changes to a path" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_throttle")
+      File.mkdir_p!(temp_dir)
+      file = Path.join(temp_dir, "throttled.txt")
+      File.write!(file, "")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{throttle_ms: 300})
+
+      # Written continuously, which a debouncer would never report
+      for i <- 1..20 do
+        File.write!(file, "#{i}", [:append])
+        Process.sleep(50)
+      end
+
+      Process.sleep(400)
+      events = Native.get_events(watcher_id)
+      assert length(events) in 3..6
+      assert Enum.all?(events, &(elem(&1, 1) == file))
+
+      Native.stop_watcher(watcher_id)
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 throttle_ms: 100,
+                 debounce_ms: 100
+               })
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "coalesces events about the same path" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_coalesce_paths")
+      File.mkdir_p!(temp_dir)
+      file = Path.join(temp_dir, "busy.txt")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{coalesce_paths: true})
+
+      for i <- 1..20, do: File.write!(file, "#{i}", [:append])
+      File.write!(Path.join(temp_dir, "other.txt"), "hello")
+      Process.sleep(100)
+
+      events = Enum.map(Native.get_events(watcher_id), &FSNotify.Event.from_tuple/1)
+      assert [busy] = Enum.filter(events, &(&1.path == file))
+      assert busy.count >= 20
+      assert Enum.any?(events, &(Path.basename(&1.path) == "other.txt"))
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "summarizes many events about one directory" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_summarize")
+      bulk_dir = Path.join(temp_dir, "bulk")
+      File.mkdir_p!(bulk_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{summarize_threshold: 10})
+
+      for i <- 1..50, do: File.write!(Path.join(bulk_dir, "file#{i}.txt"), "hello")
+      File.write!(Path.join(temp_dir, "other.txt"), "hello")
+      Process.sleep(100)
+
+      events = Enum.map(Native.get_events(watcher_id), &FSNotify.Event.from_tuple/1)
+      assert [bulk] = Enum.filter(events, &(&1.kind == :bulk_change))
+      assert bulk.path == bulk_dir
+      assert bulk.count >= 50
+      refute Enum.any?(events, &(Path.dirname(&1.path || "") == bulk_dir))
+      assert Enum.any?(events, &(&1.path == Path.join(temp_dir, "other.txt")))
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{summarize_threshold: 0})
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "delivers events as maps" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_event_format")
+      File.mkdir_p!(temp_dir)
+      file = Path.join(temp_dir, "mapped.txt")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 event_format: :map,
+                 label: :mapped,
+                 label_events: true
+               })
+
+      File.write!(file, "hello")
+      Process.sleep(100)
+
+      events = Native.get_events(watcher_id)
+      assert [%{kind: :created, path: ^file, type: :file, label: :mapped} = map | _] = events
+      assert is_integer(map.ts) and is_integer(map.seq)
+
+      event = FSNotify.Event.from_map(map)
+      assert event.kind == :created and event.path == file and event.label == :mapped
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{event_format: :json})
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "negotiates the version of the event format" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_event_version")
+      File.mkdir_p!(temp_dir)
+      file = Path.join(temp_dir, "versioned.txt")
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{event_format: {:tuple, 1}})
+
+      File.write!(file, "hello")
+      Process.sleep(100)
+      assert [{:created, ^file, :file} | _] = Native.peek_events(watcher_id)
+
+      assert :ok = Native.set_event_format(watcher_id, :map)
+      assert [%{kind: :created, path: ^file} | _] = Native.get_events(watcher_id)
+
+      assert {:error, {:invalid_config, _}} =
+               Native.set_event_format(watcher_id, {:map, 1_000})
+
+      assert {:error, {:invalid_config, _}} = Native.set_event_format(watcher_id, {:map, 1})
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{event_format: {:tuple, 0}})
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "starts a watcher from a single config map" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_start_config")
+      dir_a = Path.join(temp_dir, "a")
+      dir_b = Path.join(temp_dir, "b")
+      File.mkdir_p!(dir_a)
+      File.mkdir_p!(dir_b)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_config(%{
+                 path: [dir_a, dir_b],
+                 backend: :poll,
+                 poll_interval_ms: 50,
+                 exclude: ["*.tmp"],
+                 subscriber: self()
+               })
+
+      kept_a = Path.join(dir_a, "kept.txt")
+      kept_b = Path.join(dir_b, "kept.txt")
+      skipped = Path.join(dir_b, "skipped.tmp")
+      File.write!(kept_a, "hello")
+      File.write!(skipped, "hello")
+      File.write!(kept_b, "hello")
+
+      assert_receive {:fs_notify, _, {_, ^kept_a, _, _, _, _}}, 1_000
+      assert_receive {:fs_notify, _, {_, ^kept_b, _, _, _, _}}, 1_000
+      refute_received {:fs_notify, _, {_, ^skipped, _, _, _, _}}
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_config(%{recursive: true})
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "reports the effective configuration of a watcher" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_get_config")
+      extra_dir = Path.join(temp_dir, "extra")
+      File.mkdir_p!(extra_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{
+                 backend: :poll,
+                 exclude: ["*.tmp"]
+               })
+
+      assert :ok = Native.add_path(watcher_id, extra_dir, false)
+      assert :ok = Native.update_watcher(watcher_id, %{debounce_ms: 100})
+
+      config = Native.get_config(watcher_id)
+      assert config.path == [temp_dir, extra_dir]
+      assert config.recursive == true
+      assert config.backend == :poll and config.active_backend == :poll
+      assert config.exclude == ["*.tmp"]
+      assert config.debounce_ms == 100
+      assert is_integer(config.poll_interval_ms)
+      assert config.event_format == {:tuple, 2}
+      assert config.subscriber == nil
+      refute Map.has_key?(config, :label)
+
+      Native.stop_watcher(watcher_id)
+      assert {:error, _} = Native.get_config(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "starts watchers from the defaults set" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_set_defaults")
+      File.mkdir_p!(temp_dir)
+
+      assert :ok = Native.set_defaults(%{ignore_presets: [:editors], buffer_capacity: 500})
+
+      try do
+        assert {:ok, defaulted} = Native.start_watcher(temp_dir, true)
+        assert %{ignore_presets: [:editors], buffer_capacity: 500} = Native.get_config(defaulted)
+        Native.stop_watcher(defaulted)
+
+        assert {:ok, overridden} =
+                 Native.start_watcher_with_options(temp_dir, true, %{buffer_capacity: 100})
+
+        assert %{ignore_presets: [:editors], buffer_capacity: 100} =
+                 Native.get_config(overridden)
+
+        Native.stop_watcher(overridden)
+
+        assert {:error, {:invalid_config, _}} = Native.set_defaults(%{label: :shared})
+      after
+        Native.set_defaults(%{})
+      end
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+      assert %{ignore_presets: [], buffer_capacity: 65_536} = Native.get_config(watcher_id)
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "samples counters across every watcher" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_telemetry")
+      File.mkdir_p!(temp_dir)
+
+      before = Native.telemetry_snapshot()
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{label: :telemetry})
+
+      File.write!(Path.join(temp_dir, "counted.txt"), "hello")
+      Process.sleep(100)
+
+      snapshot = Native.telemetry_snapshot()
+      assert snapshot.events_received > before.events_received
+      assert snapshot.watchers_active >= 1
+      assert %{events_received: received, errors: 0} =
+               Enum.find(snapshot.watchers, &(&1.label == :telemetry))
+
+      assert received > 0
+      assert %{errors: 0} = Native.get_stats(watcher_id)
+
+      Native.stop_watcher(watcher_id)
+      refute Enum.any?(Native.telemetry_snapshot().watchers, &(&1.label == :telemetry))
+      File.rm_rf!(temp_dir)
+    end
+
+    test "keeps native warnings until drained or sends them to a process" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_logs")
+      File.mkdir_p!(temp_dir)
+      Native.drain_logs()
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{buffer_capacity: 1})
+
+      for i <- 1..5, do: File.write!(Path.join(temp_dir, "file#{i}.txt"), "hello")
+      Process.sleep(100)
+
+      assert [{:warning, message, time} | _] = Native.drain_logs()
+      assert message =~ "buffer full"
+      assert is_integer(time)
+      assert Native.drain_logs() == []
+
+      Native.clear_events(watcher_id)
+      assert :ok = Native.set_log_pid(self())
+
+      try do
+        for i <- 1..5, do: File.write!(Path.join(temp_dir, "again#{i}.txt"), "hello")
+        assert_receive {:fs_notify_log, :warning, message, _}, 1000
+        assert message =~ "buffer full"
+      after
+        Native.set_log_pid(nil)
+      end
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "pushes events only as they are asked for" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_on_demand")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_config(%{
+                 path: temp_dir,
+                 subscriber: self(),
+                 on_demand: true
+               })
+
+      for i <- 1..3, do: File.write!(Path.join(temp_dir, "demanded#{i}.txt"), "hello")
+      refute_receive {:fs_notify, _, _}, 200
+
+      assert :ok = Native.request_events(watcher_id, 1)
+      assert_receive {:fs_notify, _, _}, 1_000
+      refute_receive {:fs_notify, _, _}, 100
+      assert %{demand: 0} = Native.get_stats(watcher_id)
+
+      assert :ok = Native.request_events(watcher_id, 1_000)
+      assert_receive {:fs_notify, _, _}, 1_000
+      assert {:error, {:invalid_config, _}} = Native.request_events(watcher_id, 0)
+      Native.stop_watcher(watcher_id)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+      assert {:error, {:invalid_config, _}} = Native.request_events(watcher_id, 1)
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "changes the limit on events pushed each second" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_rate_limit_update")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_config(%{
+                 path: temp_dir,
+                 subscriber: self(),
+                 max_events_per_second: 1
+               })
+
+      File.mkdir!(Path.join(temp_dir, "limited"))
+      assert_receive {:fs_notify, _, _}, 1_000
+
+      # Lifted from the next batch on, so these don't take a second each
+      assert :ok = Native.update_watcher(watcher_id, %{max_events_per_second: nil})
+      for i <- 1..3, do: File.mkdir!(Path.join(temp_dir, "unlimited#{i}"))
+      for _ <- 1..3, do: assert_receive({:fs_notify, _, _}, 500)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "limits the events pushed each second" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_rate_limit")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_config(%{
+                 path: temp_dir,
+                 subscriber: self(),
+                 max_events_per_second: 2
+               })
+
+      for i <- 1..5, do: File.write!(Path.join(temp_dir, "limited#{i}.txt"), "hello")
+      assert_receive {:fs_notify, _, _}, 1_000
+      assert_receive {:fs_notify, _, _}, 1_000
+      refute_receive {:fs_notify, _, _}, 500
+      assert_receive {:fs_notify, _, _}, 1_000
+      assert %{rate_limited: limited} = Native.get_stats(watcher_id)
+      assert limited > 0
+
+      Native.stop_watcher(watcher_id)
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_options(temp_dir, true, %{max_events_per_second: 0})
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "keeps a history of recent events for readers to catch up from" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_history")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} =
+               Native.start_watcher_with_options(temp_dir, true, %{history_size: 2})
+
+      for i <- 1..5, do: File.write!(Path.join(temp_dir, "history#{i}.txt"), "hello")
+      Process.sleep(100)
+
+      assert {[_, second], true} = Native.get_events_since(watcher_id, 0)
+      {_, _, _, _, _, seq} = second
+      assert {[], false} = Native.get_events_since(watcher_id, seq)
+
+      # Readers don't take events from the queue
+      assert [_ | _] = Native.get_events(watcher_id)
+      assert {[_, _], true} = Native.get_events_since(watcher_id, 0)
+      Native.stop_watcher(watcher_id)
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+      assert {:error, {:invalid_config, _}} = Native.get_events_since(watcher_id, 0)
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "recovers from a panic while a watcher is locked" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_panic")
+      File.mkdir_p!(temp_dir)
+      parent = self()
+
+      owner =
+        spawn(fn ->
+          {:ok, watcher_id} = Native.start_watcher(temp_dir, true)
+          send(parent, {:watcher, watcher_id})
+          Process.sleep(:infinity)
+        end)
+
+      assert_receive {:watcher, watcher_id}, 1_000
+      assert {:error, {:internal, _}} = Native.debug_panic(watcher_id)
+      assert %{received: _} = Native.get_stats(watcher_id)
+
+      # The owner exiting tears the watcher down rather than aborting the VM
+      Process.exit(owner, :kill)
+      Process.sleep(50)
+      assert {:error, {:watcher_not_found, _}} = Native.get_stats(watcher_id)
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "starts the first available backend of a list" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_backend_list")
+      File.mkdir_p!(temp_dir)
+
+      backends = [:watchman, :fanotify, :poll]
+      expected = Enum.find(backends, &(&1 in Native.list_available_backends()))
+      assert {:ok, watcher} = Native.start_watcher_with_backend(temp_dir, true, backends)
+      assert {:ok, _, true, ^expected, _, _} = Native.get_watcher_info(watcher)
+      Native.stop_watcher(watcher)
+
+      assert {:error, {:invalid_config, _}} =
+               Native.start_watcher_with_backend(temp_dir, true, [])
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "probes the filesystem of a path" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_probe_path")
+      File.mkdir_p!(temp_dir)
+      File.write!(Path.join(temp_dir, "Probe.txt"), "hello")
+
+      assert {:ok, probe} = Native.probe_path(temp_dir)
+      assert is_binary(probe.fs_type)
+      assert is_boolean(probe.network)
+      assert is_boolean(probe.case_sensitive)
+      assert probe.recommended_backend in Native.list_available_backends()
+
+      assert {:error, {:enoent, _}} = Native.probe_path(Path.join(temp_dir, "missing"))
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "can stop every watcher at once" do
+      assert {:ok, first} = Native.start_watcher(File.cwd!(), true)
+      assert {:ok, second} = Native.start_watcher(File.cwd!(), false)
+
+      assert :ok = Native.stop_all_watchers()
+
+      assert Native.stop_watcher(first) == :watcher_not_found
+      assert Native.stop_watcher(second) == :watcher_not_found
+      assert [] = Native.list_watchers()
+    end
+
+    test "watcher is stopped when its owner exits" do
+      parent = self()
+
+      owner =
+        spawn(fn ->
+          {:ok, watcher_id} = Native.start_watcher(File.cwd!(), true)
+          send(parent, {:watcher, watcher_id})
+        end)
+
+      assert_receive {:watcher, watcher_id}, 1000
+
+      ref = Process.monitor(owner)
+      assert_receive {:DOWN, ^ref, :process, ^owner, _reason}, 1000
+      Process.sleep(50)
+
+      assert Native.stop_watcher(watcher_id) == :watcher_not_found
+    end
+
     test "can get events from a watcher" do
       path = File.cwd!()
 
@@ -48,6 +2199,102 @@ defmodule FSNotifyTest do
           flunk("Failed to start watcher")
       end
     end
+
+    test "pushes events to a subscribing process" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_push")
+      File.mkdir_p!(temp_dir)
+
+      assert {:ok, watcher_id} = Native.start_watcher_with_pid(temp_dir, true, self())
+
+      File.write!(Path.join(temp_dir, "pushed.txt"), "hello")
+
+      assert_receive {:fs_notify, pushed, {_event_type, path, _file_type, _mono, _sys, 1}}, 1000
+      assert String.ends_with?(path, "pushed.txt")
+
+      # Events carry a reference of their own to the same watcher
+      assert {:ok, ^temp_dir, true, _, _, _} = Native.get_watcher_info(pushed)
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
+
+    test "collects a pushing watcher once its reference is dropped" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_push_collected")
+      File.mkdir_p!(temp_dir)
+
+      # Started in a function of its own, so nothing here refers to it afterwards
+      start = fn ->
+        {:ok, _} = Native.start_watcher_with_pid(temp_dir, true, self())
+        :ok
+      end
+
+      start.()
+      assert Enum.any?(Native.list_watchers(), &(elem(&1, 1) == temp_dir))
+
+      assert Enum.any?(1..20, fn _ ->
+               :erlang.garbage_collect()
+               Process.sleep(50)
+               not Enum.any?(Native.list_watchers(), &(elem(&1, 1) == temp_dir))
+             end)
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "collects a subscribed watcher once its reference is dropped" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_subscribe_collected")
+      File.mkdir_p!(temp_dir)
+
+      # Started in a function of its own, so nothing here refers to it afterwards
+      start = fn ->
+        {:ok, watcher} = Native.start_watcher(temp_dir, true)
+        :ok = Native.subscribe(watcher, self())
+      end
+
+      start.()
+      assert Enum.any?(Native.list_watchers(), &(elem(&1, 1) == temp_dir))
+
+      assert Enum.any?(1..20, fn _ ->
+               :erlang.garbage_collect()
+               Process.sleep(50)
+               not Enum.any?(Native.list_watchers(), &(elem(&1, 1) == temp_dir))
+             end)
+
+      File.rm_rf!(temp_dir)
+    end
+
+    test "fans events out to every subscriber" do
+      temp_dir = Path.join(File.cwd!(), "test_temp_subscribe")
+      File.mkdir_p!(temp_dir)
+      parent = self()
+
+      assert {:ok, watcher_id} = Native.start_watcher(temp_dir, false)
+
+      subscriber =
+        spawn(fn ->
+          receive do
+            {:fs_notify, _watcher, event} -> send(parent, {:relayed, event})
+          end
+        end)
+
+      assert :ok = Native.subscribe(watcher_id, self())
+      assert :ok = Native.subscribe(watcher_id, subscriber)
+
+      File.write!(Path.join(temp_dir, "fanned.txt"), "hello")
+
+      assert_receive {:fs_notify, _watcher, event}, 1000
+      assert_receive {:relayed, ^event}, 1000
+      assert Native.get_events(watcher_id) == []
+
+      assert :ok = Native.unsubscribe(watcher_id, self())
+      File.write!(Path.join(temp_dir, "queued.txt"), "hello")
+      Process.sleep(100)
+
+      paths = for event <- Native.get_events(watcher_id), do: Path.basename(elem(event, 1))
+      assert "queued.txt" in paths
+
+      Native.stop_watcher(watcher_id)
+      File.rm_rf!(temp_dir)
+    end
   end
 
   describe "FSNotify.Event" do
@@ -61,6 +2308,68 @@ defmodule FSNotifyTest do
       assert event.file_type == :file
     end
 
+    test "can create timestamped event from tuple" do
+      now = System.system_time(:microsecond)
+      event = Event.from_tuple({:modified, "/test/path", :file, 42, now, 7})
+
+      assert event.kind == :modified
+      assert event.monotonic_time == 42
+      assert event.seq == 7
+      assert DateTime.to_unix(Event.timestamp(event), :microsecond) == now
+
+      event = Event.from_tuple({:renamed, "/test/old", "/test/new", 42, now, 8})
+      assert event.from == "/test/old"
+      assert event.system_time == now
+    end
+
+    test "can create overflow event from tuple" do
+      event = Event.from_tuple({:overflow, "/test", 1, 2, 3})
+
+      assert Event.overflow?(event)
+      assert event.path == "/test"
+      assert event.seq == 3
+    end
+
+    test "can create labelled events from tuple" do
+      event = Event.from_tuple({:created, "/test/path", :file, 1, 2, 3, {:label, :docs}})
+      assert event.kind == :created
+      assert event.seq == 3
+      assert event.label == :docs
+
+      event = Event.from_tuple({:overflow, "/test", 1, 2, 3, {:label, :docs}})
+      assert Event.overflow?(event)
+      assert event.label == :docs
+
+      # Labels that look like the other tagged elements stay labels
+      event = Event.from_tuple({:created, "/test/path", :file, 1, 2, 3, {:label, {:count, 1}}})
+      assert event.label == {:count, 1}
+      assert event.count == nil
+
+      event = Event.from_tuple({:created, "/test/path", :file, 1, 2, 3, {:count, 2}, {:label, 7}})
+      assert event.count == 2
+      assert event.label == 7
+    end
+
+    test "can create backend fallback event from tuple" do
+      event = Event.from_tuple({:backend_fallback, :poll, 1, 2, 3})
+      assert event.kind == :backend_fallback
+      assert event.backend == :poll
+      assert event.path == nil
+      assert event.seq == 3
+
+      event = Event.from_tuple({:backend_fallback, :poll, 1, 2, 3, {:label, :docs}})
+      assert event.backend == :poll
+      assert event.label == :docs
+    end
+
+    test "can create rename event from tuple" do
+      event = Event.from_tuple({:renamed, "/test/old", "/test/new"})
+
+      assert Event.renamed?(event)
+      assert event.from == "/test/old"
+      assert event.path == "/test/new"
+    end
+
     test "event type predicates work correctly" do
       created_event = %Event{kind: :created, path: "/test", file_type: :file}
       modified_event = %Event{kind: :modified, path: "/test", file_type: :directory}